@@ -0,0 +1,5146 @@
+use crate::{datagen::*, filter::*, ingest::*, persist::*, query::*, sketch::*, tree::*};
+use crate::heap::{self, HeapProfile};
+use crate::{format_bytes, format_count, HUMAN_OUTPUT};
+use chrono::Utc;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use memuse::DynamicUsage;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Command line arguments
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
+pub(crate) struct Args {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+
+    /// TOML file supplying any of the options below by name, e.g. `leaf_size = 32`.
+    /// Options given on the command line override the file.
+    #[arg(long)]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Number of documents to generate
+    #[arg(short, long, default_value_t = 10_000_000)]
+    pub(crate) num_docs: usize,
+
+    /// Percentage of documents to include in filtered query (0-100)
+    #[arg(short, long, default_value_t = 10, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(0..=100))]
+    pub(crate) filter_percentage: usize,
+
+    /// Distinct log levels in the generated documents; levels are the group-by term
+    #[arg(long, default_value_t = 5, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) levels: usize,
+
+    /// Distinct source regions in the generated documents
+    #[arg(long, default_value_t = 5, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) regions: usize,
+
+    /// Distinct source hosts in the generated documents
+    #[arg(long, default_value_t = 20, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) hosts: usize,
+
+    /// Distinct user ids in the generated documents
+    #[arg(long, default_value_t = 49_000,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) users: usize,
+
+    /// Distinct tags in the generated documents
+    #[arg(long, default_value_t = 49, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) tags: usize,
+
+    /// Spread the generated documents evenly over this many hours, with payload
+    /// sizes and response times following a daily cycle and bursts
+    #[arg(long)]
+    pub(crate) time_span_hours: Option<f64>,
+
+    /// Swing of the daily cycle as a fraction of the mean, from 0 to 1
+    #[arg(long, default_value_t = 0.6)]
+    pub(crate) diurnal_amplitude: f64,
+
+    /// Chance that a burst of load starts at any document outside one
+    #[arg(long, default_value_t = 0.0002)]
+    pub(crate) burst_probability: f64,
+
+    /// Documents a burst lasts
+    #[arg(long, default_value_t = 500)]
+    pub(crate) burst_docs: usize,
+
+    /// How much a burst scales payload sizes and response times
+    #[arg(long, default_value_t = 4.0)]
+    pub(crate) burst_multiplier: f64,
+
+    /// Skew the filter towards hot entities: docs are picked with a chance
+    /// following a Zipf distribution with this exponent over their entity
+    #[arg(long)]
+    pub(crate) zipf_exponent: Option<f64>,
+
+    /// Entity the skewed filter ranks docs by
+    #[arg(long, value_enum, default_value_t = LogEntity::User)]
+    pub(crate) hot_entity: LogEntity,
+
+    /// Leaf size for AIT, from 2 to 1048576
+    #[arg(short, long, default_value_t = 64,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new()
+              .range(MIN_LEAF_SIZE as u64..=MAX_LEAF_SIZE as u64))]
+    pub(crate) leaf_size: usize,
+
+    /// Number of times to run each query for averaging
+    #[arg(short, long, default_value_t = 5, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) iterations: usize,
+
+    /// Build the AIT without doc ids (global and range queries only)
+    #[arg(long)]
+    pub(crate) no_doc_ids: bool,
+
+    /// Build the AIT without the position map; filtered queries locate each
+    /// position by descending the tree instead
+    #[arg(long)]
+    pub(crate) no_position_map: bool,
+
+    /// Record runs of repeated values in the AIT and report the most frequent
+    /// values, checking their counts against the columnar scan
+    #[arg(long)]
+    pub(crate) value_runs: bool,
+
+    /// Attach t-digests to large AIT nodes and compare approximate percentiles
+    /// with exact ones for accuracy and latency
+    #[arg(long)]
+    pub(crate) digests: bool,
+
+    /// Attach log-bucketed histograms to large AIT nodes and report p50 / p99 /
+    /// p99.9 from them against the exact percentiles
+    #[arg(long)]
+    pub(crate) histograms: bool,
+
+    /// Keep central moments per AIT node and report skewness and kurtosis,
+    /// checked against a two-pass scan
+    #[arg(long)]
+    pub(crate) extended_stats: bool,
+
+    /// Keep sums of logarithms per AIT node and report the geometric mean and
+    /// product, checked against a scan
+    #[arg(long)]
+    pub(crate) geometric_mean: bool,
+
+    /// How the geometric mean treats zeros and negatives: skip, reject (fails
+    /// the build) or floor:<value> to raise smaller values to it
+    #[arg(long, default_value_t = NonPositive::Skip)]
+    pub(crate) non_positive: NonPositive,
+
+    /// Attach each doc's entity to the AIT as a secondary id column and report
+    /// approximate distinct entities from per-node HyperLogLog sketches
+    #[arg(long)]
+    pub(crate) distinct: bool,
+
+    /// Attach each doc's secondary field to the AIT as a weight column and
+    /// report the weighted sum and average, checked against a columnar scan
+    #[arg(long)]
+    pub(crate) weights: bool,
+
+    /// Bucket the AIT's values into fixed-width buckets of this width and check
+    /// each bucket's count, sum, min and max against a columnar scan
+    #[arg(long, value_parser = parse_positive_width)]
+    pub(crate) histogram_interval: Option<f64>,
+
+    /// Attach each doc's timestamp to the AIT and bucket the values by minute,
+    /// hour, day, week or a width in ms, checking each bucket against a scan
+    #[arg(long)]
+    pub(crate) date_histogram: Option<DateInterval>,
+
+    /// Keep the AIT's values in doc-id order too, so bitmap queries can run as
+    /// a columnar scan
+    #[arg(long)]
+    pub(crate) doc_values: bool,
+
+    /// Also benchmark each AIT query strategy in isolation on the filter bitmap
+    #[arg(long)]
+    pub(crate) strategies: bool,
+
+    /// Also compare Roaring bitmap and sorted id list filters across selectivities
+    #[arg(long)]
+    pub(crate) compare_filters: bool,
+
+    /// How the segmented index merges segments: tiered suits continuous ingest,
+    /// leveled suits bulk load then query
+    #[arg(long, value_enum, default_value_t = MergeKind::Tiered)]
+    pub(crate) merge_policy: MergeKind,
+
+    /// Segments per tier (tiered) or size ratio between levels (leveled)
+    #[arg(long, default_value_t = 10)]
+    pub(crate) merge_fanout: usize,
+
+    /// Segments with at least this many docs are not merged further
+    #[arg(long, default_value_t = 4_000_000)]
+    pub(crate) max_segment_docs: usize,
+
+    /// Merge the smallest segments whenever there are more than this many
+    #[arg(long, default_value_t = 32)]
+    pub(crate) max_segments: usize,
+
+    /// Bytes of per-term state a terms + stats query may hold at once; beyond it
+    /// the terms are aggregated over several passes
+    #[arg(long)]
+    pub(crate) group_by_memory_limit: Option<usize>,
+
+    /// Translated filter bitmaps kept for reuse when doc ids are remapped
+    #[arg(long, default_value_t = 64)]
+    pub(crate) translation_cache_entries: usize,
+
+    /// Width of the time partitions doc ids are allocated in, in milliseconds
+    #[arg(long, default_value_t = 5_000)]
+    pub(crate) time_partition_ms: i64,
+
+    /// Persist a segmented index here, merge and persist it again, and reopen it
+    #[arg(long)]
+    pub(crate) segment_dir: Option<PathBuf>,
+
+    /// JSON file holding planner thresholds and query observations. Loaded before
+    /// the build; with --strategies, new observations are recorded and refit.
+    #[arg(long)]
+    pub(crate) planner_profile: Option<PathBuf>,
+
+    /// Leaves per stored aggregation in the memory-constrained sampled index
+    #[arg(long, default_value_t = 16)]
+    pub(crate) sample_every: usize,
+
+    /// Value-range partitions built in parallel and stitched in the partitioned build benchmark
+    #[arg(long, default_value_t = 4)]
+    pub(crate) partitions: usize,
+
+    /// Checkpoint finished partitions of the partitioned build here and resume
+    /// from any that are intact
+    #[arg(long)]
+    pub(crate) checkpoint_dir: Option<PathBuf>,
+
+    /// Expected query mix as global,filtered,range weights. Decides whether the
+    /// hybrid field set indexes the field with a tree or a zone map.
+    #[arg(long, default_value = "1,1,1")]
+    pub(crate) query_mix: FieldQueryMix,
+
+    /// Derived metrics evaluated over the field set, e.g. `sum(payload_size) / count`.
+    /// Supports min/max/sum/count/avg(field), a bare `count` of matching docs,
+    /// numbers, + - * / and parentheses.
+    #[arg(long = "expr", value_delimiter = ',', allow_hyphen_values = true,
+          default_values = ["sum(payload_size) / count", "sum(payload_size) / sum(login_time_ms)"])]
+    pub(crate) exprs: Vec<DerivedMetric>,
+
+    /// Export the (doc_id, value, timestamp) rows selected by the filter bitmap here
+    #[arg(long)]
+    pub(crate) export: Option<PathBuf>,
+
+    /// Format of --export
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    pub(crate) export_format: ExportFormat,
+
+    /// Save the built AIT to this directory, and the cross-field indexes with
+    /// their term dictionaries under its fields/ subdirectory
+    #[arg(long)]
+    pub(crate) save_index: Option<PathBuf>,
+
+    /// Save the run's filter bitmap with the saved index under this name, for
+    /// the query subcommand's --filter
+    #[arg(long, requires = "save_index")]
+    pub(crate) save_filter: Option<String>,
+
+    /// Absolute tolerance when verifying AIT results against the columnar baseline
+    #[arg(long, default_value_t = 0.001)]
+    pub(crate) abs_tolerance: f64,
+
+    /// Relative tolerance (fraction of the larger magnitude) when verifying results
+    #[arg(long, default_value_t = 1e-9)]
+    pub(crate) rel_tolerance: f64,
+
+    /// Stop at the first verification mismatch instead of reporting all of them
+    /// at the end
+    #[arg(long, global = true)]
+    pub(crate) strict: bool,
+
+    /// Seed for the generated documents and queries; a random one is picked and
+    /// printed when omitted
+    #[arg(long)]
+    pub(crate) seed: Option<u64>,
+
+    /// Log build and query spans (with strategy, cardinality and nodes visited)
+    /// to stderr when they close
+    #[arg(long)]
+    pub(crate) trace: bool,
+
+    /// Export build and query spans to this OTLP/HTTP traces endpoint, e.g.
+    /// http://localhost:4318/v1/traces
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    pub(crate) otlp_endpoint: Option<String>,
+
+    /// Output format: human-readable tables, a JSON document for scripts, or
+    /// nothing but errors
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table, global = true)]
+    pub(crate) format: OutputFormat,
+}
+
+// A bucket width, which must be a positive finite number
+fn parse_positive_width(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(width) if width.is_finite() && width > 0.0 => Ok(width),
+        _ => Err(format!("expected a positive, finite width, got '{}'", text)),
+    }
+}
+
+// Commands other than the default benchmark run
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Print the metadata and global aggregations of a saved index
+    Stats {
+        /// Index directory written with --save-index, or a legacy single-file index
+        index: PathBuf,
+    },
+    /// Aggregate a saved index, over all docs or a filter saved with it
+    Query {
+        /// Index directory written with --save-index
+        index: PathBuf,
+        /// Name of a filter saved with the index (see --save-filter)
+        #[arg(long, conflicts_with = "filter_file")]
+        filter: Option<String>,
+        /// Filter bitmap exported by another system, e.g. a search engine
+        #[arg(long)]
+        filter_file: Option<PathBuf>,
+        /// Encoding of --filter-file
+        #[arg(long, value_enum, default_value_t = FilterFormat::Roaring, requires = "filter_file")]
+        filter_format: FilterFormat,
+        /// Load and plan the query, print the chosen strategy and its cost
+        /// estimate, and stop without executing it
+        #[arg(long)]
+        explain_only: bool,
+    },
+    /// Save a filter bitmap exported by another system under the index, so it
+    /// can be used by name with `query --filter`
+    ImportFilter {
+        /// Index directory written with --save-index
+        index: PathBuf,
+        /// Name to save the filter under
+        name: String,
+        /// File holding the bitmap
+        file: PathBuf,
+        /// Encoding of the file
+        #[arg(long, value_enum, default_value_t = FilterFormat::Roaring)]
+        filter_format: FilterFormat,
+    },
+    /// Dump a subtree of a saved index: node aggregations, split values and
+    /// leaf contents, flagging splits that disagree with their children
+    Inspect {
+        /// Index directory written with --save-index, or a legacy single-file index
+        index: PathBuf,
+        /// Node to start from; the root is node 0, and dumps list node numbers
+        #[arg(long, default_value_t = 0)]
+        node: usize,
+        /// Levels below the node to include
+        #[arg(long, default_value_t = 3)]
+        depth: usize,
+        /// Leaf entries to list per leaf
+        #[arg(long, default_value_t = 8)]
+        leaf_limit: usize,
+        /// Write a Graphviz DOT graph instead of the table or JSON output
+        #[arg(long)]
+        dot: bool,
+        /// Write an HTML report of depth, leaf fill and value ranges per level,
+        /// drawing the top --depth levels, instead of the table or JSON output
+        #[arg(long, conflicts_with = "dot")]
+        html: bool,
+    },
+    /// Check a saved index's position map, rebuild it if needed, and save the
+    /// index in the current format
+    Repair {
+        /// Index directory or legacy single-file index
+        index: PathBuf,
+        /// Directory to write the repaired index to; must differ from the index
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Measure where the bitmap query strategies cross over on this machine and
+    /// a saved index, and write the fitted thresholds into a planner profile
+    Calibrate {
+        /// Index directory written with --save-index, or a legacy single-file index
+        index: PathBuf,
+        /// Planner profile to update, as loaded by --planner-profile
+        #[arg(long, default_value = "planner-profile.json")]
+        profile: PathBuf,
+        /// Timed runs per strategy and selectivity
+        #[arg(long, default_value_t = 3)]
+        runs: usize,
+    },
+    /// Check the AIT against a columnar scan on small random datasets and queries
+    Fuzz {
+        /// Number of random cases to run
+        #[arg(long, default_value_t = 100)]
+        cases: u64,
+        /// Seed of the first case; case i uses seed + i. Random when omitted.
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Upper bound on the documents in a case
+        #[arg(long, default_value_t = 10_000)]
+        max_docs: usize,
+        /// Where minimized failing cases are written
+        #[arg(long, default_value = "fuzz-fixtures")]
+        fixture_dir: PathBuf,
+        /// Re-run a single case from a fixture file instead of generating cases
+        #[arg(long)]
+        replay: Option<PathBuf>,
+    },
+    /// Time the AIT on canned worst cases: all-equal values, strictly descending
+    /// input, a filter of every other doc, and single-doc filters in a tight loop
+    Adversarial {
+        /// Documents in each scenario
+        #[arg(long, default_value_t = 1_000_000)]
+        docs: usize,
+        /// Single-doc filtered queries run back to back
+        #[arg(long, default_value_t = 100_000)]
+        single_doc_queries: usize,
+    },
+    /// Compare layouts for the doc id to position lookup behind filtered
+    /// queries: hash map, dense vector, sorted array and PGM index
+    Lookups {
+        /// Doc ids mapped
+        #[arg(long, default_value_t = 1_000_000)]
+        docs: usize,
+        /// Doc ids looked up per run, in ascending order as a bitmap yields them
+        #[arg(long, default_value_t = 1_000_000)]
+        lookups: usize,
+        /// Id space per doc in the sparse case: ids are drawn from docs * sparsity
+        #[arg(long, default_value_t = 64)]
+        sparsity: u32,
+        /// Maximum rank error of the PGM index's linear segments
+        #[arg(long, default_value_t = 32)]
+        pgm_epsilon: usize,
+    },
+    /// Time the leaf aggregation kernel (process_position_batch) alone across
+    /// batch sizes from 16 to 8192, over sorted and random positions
+    Kernel {
+        /// Documents in the tree
+        #[arg(long, default_value_t = 1_000_000)]
+        docs: usize,
+        /// Positions aggregated per run
+        #[arg(long, default_value_t = 100_000)]
+        positions: usize,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OutputFormat {
+    Table,
+    Json,
+    Quiet,
+}
+
+// Floating-point comparison tolerance used for result verification.
+// Large sums accumulate rounding error proportional to their magnitude, so a
+// fixed absolute epsilon is not enough on its own.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Tolerance {
+    pub(crate) absolute: f64,
+    pub(crate) relative: f64,
+}
+
+impl Tolerance {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        Tolerance {
+            absolute: args.abs_tolerance,
+            relative: args.rel_tolerance,
+        }
+    }
+
+    // Values are equal if they are within either the absolute or the relative bound
+    pub(crate) fn approx_eq(&self, a: f64, b: f64) -> bool {
+        if a == b {
+            return true;
+        }
+        let diff = (a - b).abs();
+        diff <= self.absolute || diff <= self.relative * a.abs().max(b.abs())
+    }
+}
+
+impl MergePolicy {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        MergePolicy {
+            kind: args.merge_policy,
+            fanout: args.merge_fanout.max(2),
+            max_segment_docs: args.max_segment_docs,
+            max_segments: args.max_segments.max(1),
+        }
+    }
+}
+
+impl TimePattern {
+    pub(crate) fn from_args(args: &Args) -> Option<Self> {
+        let hours = args.time_span_hours?;
+        Some(TimePattern {
+            doc_interval_ms: hours * 3_600_000.0 / args.num_docs.max(1) as f64,
+            diurnal_amplitude: args.diurnal_amplitude,
+            burst_probability: args.burst_probability,
+            burst_docs: args.burst_docs,
+            burst_multiplier: args.burst_multiplier,
+        })
+    }
+}
+
+impl Cardinalities {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        Cardinalities {
+            levels: args.levels,
+            regions: args.regions,
+            hosts: args.hosts,
+            users: args.users,
+            tags: args.tags,
+        }
+    }
+}
+
+pub(crate) fn relative_error(actual: f64, expected: f64) -> f64 {
+    if actual == expected {
+        return 0.0;
+    }
+    (actual - expected).abs() / actual.abs().max(expected.abs())
+}
+
+// Process exit codes, distinct so scripts can tell failures apart. Clap
+// already exits with 2 for invalid arguments.
+pub(crate) const EXIT_RUNTIME_ERROR: i32 = 1;
+pub(crate) const EXIT_CONFIG_ERROR: i32 = 2;
+pub(crate) const EXIT_VERIFICATION_FAILED: i32 = 3;
+
+// A result that disagreed with the columnar reference. Values are None where
+// the query has no answer, e.g. the min of an empty selection.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Mismatch {
+    pub(crate) check: String,
+    pub(crate) statistic: &'static str,
+    pub(crate) expected: Option<f64>,
+    pub(crate) actual: Option<f64>,
+    // Seed of the run or fuzz case that produced the inputs, and a command
+    // that re-runs just that case
+    pub(crate) seed: Option<u64>,
+    pub(crate) reproducer: Option<String>,
+}
+
+impl Mismatch {
+    // Multi-line expected/actual diff for stderr, colored when it is a terminal
+    pub(crate) fn print_diff(&self) {
+        let color = io::stderr().is_terminal();
+        let paint = |text: String, code: &str| {
+            if color { format!("\x1b[{}m{}\x1b[0m", code, text) } else { text }
+        };
+        let show = |value: Option<f64>| value.map_or("n/a".to_string(), |value| value.to_string());
+        let seed = self.seed.map_or(String::new(), |seed| format!(" (seed {})", seed));
+        eprintln!("{} {} {}{}", paint("mismatch:".to_string(), "1;31"), self.check, self.statistic, seed);
+        eprintln!("  {}", paint(format!("- expected: {}", show(self.expected)), "32"));
+        eprintln!("  {}", paint(format!("+ actual:   {}", show(self.actual)), "31"));
+        if let (Some(actual), Some(expected)) = (self.actual, self.expected) {
+            eprintln!("    abs error {:e}, rel error {:e}", (actual - expected).abs(), relative_error(actual, expected));
+        }
+        if let Some(reproducer) = &self.reproducer {
+            eprintln!("    reproduce: {}", paint(reproducer.clone(), "36"));
+        }
+    }
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |value: Option<f64>| value.map_or("n/a".to_string(), |value| value.to_string());
+        write!(f, "{} {}: expected {}, got {}", self.check, self.statistic, show(self.expected), show(self.actual))?;
+        if let (Some(actual), Some(expected)) = (self.actual, self.expected) {
+            write!(f, " (relative error {:e})", relative_error(actual, expected))?;
+        }
+        Ok(())
+    }
+}
+
+// Checks benchmark results against the reference and collects mismatches
+// rather than panicking, so a run reports every disagreement. In strict mode
+// the first mismatch ends the process.
+pub(crate) struct Verifier {
+    pub(crate) tolerance: Tolerance,
+    pub(crate) strict: bool,
+    // Print each mismatch as it is recorded. Off while minimizing fuzz cases.
+    pub(crate) verbose: bool,
+    pub(crate) mismatches: RefCell<Vec<Mismatch>>,
+    // Seed and reproducer command of the inputs being checked
+    pub(crate) seed: Cell<Option<u64>>,
+    pub(crate) reproducer: RefCell<Option<String>>,
+}
+
+impl Verifier {
+    pub(crate) fn new(tolerance: Tolerance, strict: bool) -> Self {
+        Verifier {
+            tolerance,
+            strict,
+            verbose: true,
+            mismatches: RefCell::new(Vec::new()),
+            seed: Cell::new(None),
+            reproducer: RefCell::new(None),
+        }
+    }
+
+    // Attach a seed and reproducer command to mismatches recorded from now on
+    pub(crate) fn set_case(&self, seed: u64, reproducer: String) {
+        self.seed.set(Some(seed));
+        *self.reproducer.borrow_mut() = Some(reproducer);
+    }
+
+    // Compare a value within the tolerance; a missing value only matches a missing value
+    pub(crate) fn value(&self, check: &str, statistic: &'static str, actual: Option<f64>, expected: Option<f64>) -> bool {
+        let matches = match (actual, expected) {
+            (Some(actual), Some(expected)) => self.tolerance.approx_eq(actual, expected),
+            (actual, expected) => actual == expected,
+        };
+        if !matches {
+            self.record(Mismatch {
+                check: check.to_string(),
+                statistic,
+                expected,
+                actual,
+                seed: self.seed.get(),
+                reproducer: self.reproducer.borrow().clone(),
+            });
+        }
+        matches
+    }
+
+    pub(crate) fn count(&self, check: &str, statistic: &'static str, actual: u64, expected: u64) -> bool {
+        if actual != expected {
+            self.record(Mismatch {
+                check: check.to_string(),
+                statistic,
+                expected: Some(expected as f64),
+                actual: Some(actual as f64),
+                seed: self.seed.get(),
+                reproducer: self.reproducer.borrow().clone(),
+            });
+        }
+        actual == expected
+    }
+
+    pub(crate) fn aggregations(&self, check: &str, actual: &NodeAggregations, expected: &NodeAggregations) -> bool {
+        // Every statistic is checked so the report shows all that disagree
+        let min = self.value(check, "min", Some(actual.min_value), Some(expected.min_value));
+        let max = self.value(check, "max", Some(actual.max_value), Some(expected.max_value));
+        let sum = self.value(check, "sum", Some(actual.sum), Some(expected.sum));
+        let count = self.count(check, "count", actual.count as u64, expected.count as u64);
+        let variance = self.value(check, "variance", actual.variance(), expected.variance());
+        min && max && sum && count && variance
+    }
+
+    // Collects mismatches without printing them or stopping, for repeatedly
+    // re-checking candidate inputs
+    pub(crate) fn silent(tolerance: Tolerance) -> Self {
+        Verifier {
+            verbose: false,
+            ..Verifier::new(tolerance, false)
+        }
+    }
+
+    pub(crate) fn record(&self, mismatch: Mismatch) {
+        if self.verbose {
+            mismatch.print_diff();
+        }
+        self.mismatches.borrow_mut().push(mismatch);
+        if self.strict {
+            eprintln!("Stopping at the first mismatch (--strict)");
+            exit(EXIT_VERIFICATION_FAILED);
+        }
+    }
+}
+
+// Traditional columnar storage for comparison for correctness only
+#[derive(Debug, Clone)]
+pub(crate) struct ColumnarStorage {
+    pub(crate) values: Vec<f64>,
+}
+
+impl DynamicUsage for ColumnarStorage {
+    fn dynamic_usage(&self) -> usize {
+        std::mem::size_of::<ColumnarStorage>() + 
+        self.values.capacity() * std::mem::size_of::<f64>()
+    }
+
+    fn dynamic_usage_bounds(&self) -> (usize, Option<usize>) {
+        // Provide a simple implementation for bounds
+        (self.dynamic_usage(), Some(self.dynamic_usage()))
+    }
+}
+
+// Resident set size of the process as reported by /proc/self/status
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MemorySnapshot {
+    pub(crate) current_rss: usize,
+    pub(crate) peak_rss: usize,
+}
+
+impl MemorySnapshot {
+    // Returns None on platforms without procfs
+    pub(crate) fn read() -> Option<MemorySnapshot> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let read_kb = |key: &str| -> Option<usize> {
+            let line = status.lines().find(|line| line.starts_with(key))?;
+            let kb = line[key.len()..].trim().trim_end_matches("kB").trim();
+            kb.parse::<usize>().ok().map(|kb| kb * 1024)
+        };
+        Some(MemorySnapshot {
+            current_rss: read_kb("VmRSS:")?,
+            peak_rss: read_kb("VmHWM:")?,
+        })
+    }
+
+    // Reset the kernel's peak RSS counter so the next snapshot reports the peak
+    // of the following phase only. Returns false if the reset is unsupported.
+    pub(crate) fn reset_peak() -> bool {
+        std::fs::write("/proc/self/clear_refs", "5").is_ok()
+    }
+}
+
+// Records the peak RSS reached during each build phase. Final memory numbers
+// hide transient buffers (source docs, sort scratch, copies into leaves), so
+// the peak is tracked separately per phase.
+pub(crate) struct PhaseMemoryTracker {
+    pub(crate) phases: Vec<(String, MemorySnapshot)>,
+    // Whether peaks are per phase or cumulative since process start
+    pub(crate) per_phase_peaks: bool,
+}
+
+impl PhaseMemoryTracker {
+    pub(crate) fn new() -> Self {
+        PhaseMemoryTracker {
+            phases: Vec::new(),
+            per_phase_peaks: MemorySnapshot::reset_peak(),
+        }
+    }
+
+    // Run a phase and record its peak and resulting RSS
+    pub(crate) fn measure<T, F: FnOnce() -> T>(&mut self, name: &str, phase: F) -> T {
+        if self.per_phase_peaks {
+            MemorySnapshot::reset_peak();
+        }
+        let result = phase();
+        self.record(name);
+        result
+    }
+
+    pub(crate) fn record(&mut self, name: &str) {
+        if let Some(snapshot) = MemorySnapshot::read() {
+            self.phases.push((name.to_string(), snapshot));
+        }
+    }
+
+    pub(crate) fn rename_last(&mut self, name: String) {
+        if let Some(last) = self.phases.last_mut() {
+            last.0 = name;
+        }
+    }
+
+    pub(crate) fn print_report(&self) {
+        if self.phases.is_empty() {
+            report!("\nPeak memory tracking is not available on this platform");
+            return;
+        }
+        let peak_kind = if self.per_phase_peaks { "peak during phase" } else { "cumulative peak" };
+        report!("\nMemory by build phase ({}, RSS after phase):", peak_kind);
+        for (name, snapshot) in &self.phases {
+            report!("  {}: {} peak, {} after",
+                     name,
+                     format_bytes(snapshot.peak_rss),
+                     format_bytes(snapshot.current_rss));
+        }
+    }
+}
+
+// Traditional aggregation functions for comparison
+impl ColumnarStorage {
+    pub(crate) fn get_global_aggregations(&self) -> NodeAggregations {
+        if self.values.is_empty() {
+            return NodeAggregations::empty();
+        }
+        
+        let mut result = NodeAggregations::empty();
+        for &value in &self.values {
+            result.add_value(value);
+        }
+        result
+    }
+    
+    // Bitmap-driven parallel scan: splits the doc-id space into ranges and only
+    // visits the selected ids within each range on the rayon pool
+    pub(crate) fn query_with_bitmap(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        const RANGE_SIZE: usize = 1 << 16;
+        let num_ranges = self.values.len().div_ceil(RANGE_SIZE);
+
+        (0..num_ranges)
+            .into_par_iter()
+            .map(|range_idx| {
+                let start = range_idx * RANGE_SIZE;
+                let end = (start + RANGE_SIZE).min(self.values.len());
+                let mut local_result = NodeAggregations::empty();
+
+                for doc_id in bitmap.range(start as u32..end as u32) {
+                    local_result.add_value(self.values[doc_id as usize]);
+                }
+
+                local_result
+            })
+            .reduce(NodeAggregations::empty, |a, b| a + b)
+    }
+
+    // Vectorized scan: materializes the bitmap into a selection vector once, then
+    // aggregates chunks of it in parallel with SIMD gathers over the doc-ordered values
+    pub(crate) fn query_with_selection_vector(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        const SELECTION_CHUNK_SIZE: usize = 1 << 16;
+        let selection: Vec<u32> = bitmap.range(0..self.values.len() as u32).collect();
+
+        selection
+            .par_chunks(SELECTION_CHUNK_SIZE)
+            .map(|chunk| aggregate_selection(&self.values, chunk))
+            .reduce(NodeAggregations::empty, |a, b| a + b)
+    }
+
+    // Parallel scan aggregating all values within [min, max]
+    pub(crate) fn query_value_range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.values
+            .par_iter()
+            .filter(|&&value| value >= min && value <= max)
+            .fold(NodeAggregations::empty, |mut acc, &value| {
+                acc.add_value(value);
+                acc
+            })
+            .reduce(NodeAggregations::empty, |a, b| a + b)
+    }
+
+    // Single-threaded reference for value range aggregations
+    pub(crate) fn query_value_range_naive(&self, min: f64, max: f64) -> NodeAggregations {
+        let mut result = NodeAggregations::empty();
+        for &value in &self.values {
+            if value >= min && value <= max {
+                result.add_value(value);
+            }
+        }
+        result
+    }
+
+    // Reference for AggregationIndexTree::term_aggregations
+    pub(crate) fn term_aggregations_naive(&self, terms: &TermColumn, bitmap: Option<&RoaringBitmap>) -> Vec<NodeAggregations> {
+        let mut aggregations = vec![NodeAggregations::empty(); terms.terms.len()];
+        for (doc_id, &value) in self.values.iter().enumerate() {
+            if bitmap.is_none_or(|bitmap| bitmap.contains(doc_id as u32)) {
+                if let Some(code) = terms.code(doc_id as u32) {
+                    aggregations[code].add_value(value);
+                }
+            }
+        }
+        aggregations
+    }
+
+    // Reference for AggregationIndexTree::percentile_rank
+    pub(crate) fn percentile_rank_naive(&self, bitmap: &RoaringBitmap, value: f64) -> Option<f64> {
+        let (mut selected, mut below, mut ties) = (0, 0, 0);
+        for (doc_id, &doc_value) in self.values.iter().enumerate() {
+            if bitmap.contains(doc_id as u32) {
+                selected += 1;
+                if doc_value < value {
+                    below += 1;
+                } else if doc_value == value {
+                    ties += 1;
+                }
+            }
+        }
+        (selected > 0).then(|| 100.0 * (below as f64 + ties as f64 / 2.0) / selected as f64)
+    }
+
+    // Reference for AggregationIndexTree::percentiles: sorts the selected values
+    pub(crate) fn percentiles_naive(&self, ps: &[f64], bitmap: Option<&RoaringBitmap>) -> Vec<Option<f64>> {
+        let mut selected: Vec<f64> = match bitmap {
+            Some(bitmap) => bitmap.iter().filter_map(|doc_id| self.values.get(doc_id as usize).copied()).collect(),
+            None => self.values.clone(),
+        };
+        selected.sort_by(f64::total_cmp);
+        ps.iter()
+            .map(|&p| {
+                let rank = p.clamp(0.0, 100.0) / 100.0 * selected.len().checked_sub(1)? as f64;
+                let (lower, upper) = (selected[rank.floor() as usize], selected[rank.ceil() as usize]);
+                Some(lower + (upper - lower) * rank.fract())
+            })
+            .collect()
+    }
+
+    // Reference for AggregationIndexTree::aggregate_between_quantiles: sorts the
+    // selected values and keeps the same rank range
+    pub(crate) fn between_quantiles_naive(&self, q_low: f64, q_high: f64, bitmap: Option<&RoaringBitmap>)
+        -> NodeAggregations {
+        let mut selected: Vec<f64> = match bitmap {
+            Some(bitmap) => bitmap.iter().filter_map(|doc_id| self.values.get(doc_id as usize).copied()).collect(),
+            None => self.values.clone(),
+        };
+        selected.sort_by(f64::total_cmp);
+        let rank = |q: f64| (q.clamp(0.0, 1.0) * selected.len() as f64).round() as usize;
+        let mut result = NodeAggregations::empty();
+        for &value in selected.get(rank(q_low)..rank(q_high)).unwrap_or_default() {
+            result.add_value(value);
+        }
+        result
+    }
+
+    // Naive reference: scans every value single-threaded and probes the bitmap per doc
+    pub(crate) fn query_with_bitmap_naive(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        let mut result = NodeAggregations::empty();
+        
+        for (doc_id, &value) in self.values.iter().enumerate() {
+            if bitmap.contains(doc_id as u32) {
+                result.add_value(value);
+            }
+        }
+        
+        result
+    }
+}
+
+// Input shared by every benchmark subject at build time
+pub(crate) struct BenchInput<'a> {
+    // Field values indexed by doc id
+    pub(crate) doc_values: &'a [f64],
+    // (doc_id, value) pairs sorted by value. The tree build consumes them, so
+    // only one subject may take them.
+    pub(crate) sorted_values: Vec<(u32, f64)>,
+    pub(crate) options: BuildOptions,
+    // Provenance recorded by subjects that keep index metadata
+    pub(crate) source: &'a str,
+    pub(crate) field: &'a str,
+    // Expected workload, used by subjects that adapt their representation
+    pub(crate) query_mix: FieldQueryMix,
+    // Secondary ids indexed by doc id, for subjects that count distinct ids
+    pub(crate) distinct_ids: Option<&'a [u64]>,
+    // Weights indexed by doc id, for subjects that aggregate weighted values
+    pub(crate) weights: Option<&'a [f64]>,
+    // Epoch milliseconds indexed by doc id, for subjects that bucket by time
+    pub(crate) timestamps: Option<&'a [i64]>,
+}
+
+// A data structure that can be registered into the benchmark matrix
+pub(crate) trait BenchSubject {
+    fn build(input: &mut BenchInput) -> Self
+    where
+        Self: Sized;
+    fn name(&self) -> &'static str;
+    fn global(&self) -> NodeAggregations;
+    fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError>;
+    // Aggregate all values within [min, max]
+    fn range(&self, min: f64, max: f64) -> NodeAggregations;
+    fn memory_usage(&self) -> usize;
+
+    // Direct access to the tree for subjects that wrap one
+    fn as_tree(&self) -> Option<&AggregationIndexTree> {
+        None
+    }
+}
+
+pub(crate) type SubjectBuilder = fn(&mut BenchInput) -> Box<dyn BenchSubject>;
+
+pub(crate) fn build_boxed<S: BenchSubject + 'static>(input: &mut BenchInput) -> Box<dyn BenchSubject> {
+    Box::new(S::build(input))
+}
+
+// Subjects run by the benchmark, in report order. The first entry is the
+// subject under test and the second is the baseline used for speedups.
+pub(crate) fn registered_subjects() -> Vec<SubjectBuilder> {
+    vec![
+        build_boxed::<AggregationIndexTree>,
+        build_boxed::<ColumnarStorage>,
+        build_boxed::<SimdColumnar>,
+        build_boxed::<NaiveColumnar>,
+        build_boxed::<HybridField>,
+        build_boxed::<SampledIndex>,
+    ]
+}
+
+impl BenchSubject for AggregationIndexTree {
+    fn build(input: &mut BenchInput) -> Self {
+        let mut tree = build_aggregation_index_tree(std::mem::take(&mut input.sorted_values), &input.options)
+            .expect("benchmark doc ids are unique")
+            .with_provenance(input.source, input.field);
+        if let Some(ids_by_doc) = input.distinct_ids {
+            tree.attach_distinct_column(ids_by_doc).expect("every benchmark doc has an entity");
+        }
+        if let Some(weights_by_doc) = input.weights {
+            tree.attach_weight_column(weights_by_doc).expect("every benchmark doc has a weight");
+        }
+        if let Some(timestamps_by_doc) = input.timestamps {
+            tree.attach_timestamp_column(timestamps_by_doc).expect("every benchmark doc has a timestamp");
+        }
+        tree
+    }
+
+    fn name(&self) -> &'static str {
+        "AIT"
+    }
+
+    fn global(&self) -> NodeAggregations {
+        self.get_global_aggregations()
+    }
+
+    fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        self.query_with_bitmap(bitmap)
+    }
+
+    fn range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.query_value_range(min, max)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.dynamic_usage()
+    }
+
+    fn as_tree(&self) -> Option<&AggregationIndexTree> {
+        Some(self)
+    }
+}
+
+impl BenchSubject for ColumnarStorage {
+    fn build(input: &mut BenchInput) -> Self {
+        ColumnarStorage {
+            values: input.doc_values.to_vec(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "Columnar"
+    }
+
+    fn global(&self) -> NodeAggregations {
+        self.get_global_aggregations()
+    }
+
+    fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        Ok(self.query_with_bitmap(bitmap))
+    }
+
+    fn range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.query_value_range(min, max)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.dynamic_usage()
+    }
+}
+
+// Columnar storage queried through selection vectors and SIMD gathers
+pub(crate) struct SimdColumnar(pub(crate) ColumnarStorage);
+
+impl BenchSubject for SimdColumnar {
+    fn build(input: &mut BenchInput) -> Self {
+        SimdColumnar(ColumnarStorage::build(input))
+    }
+
+    fn name(&self) -> &'static str {
+        "Columnar (SIMD selection vector)"
+    }
+
+    fn global(&self) -> NodeAggregations {
+        self.0.get_global_aggregations()
+    }
+
+    fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        Ok(self.0.query_with_selection_vector(bitmap))
+    }
+
+    fn range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.0.query_value_range(min, max)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0.dynamic_usage()
+    }
+}
+
+// The benchmark field held in a FieldIndexSet, which picks its representation
+// from the configured query mix
+pub(crate) struct HybridField {
+    pub(crate) set: FieldIndexSet,
+    pub(crate) field: String,
+}
+
+impl HybridField {
+    pub(crate) fn index(&self) -> &FieldIndex {
+        self.set.field(&self.field).expect("benchmark field is indexed")
+    }
+}
+
+impl BenchSubject for HybridField {
+    fn build(input: &mut BenchInput) -> Self {
+        let mut set = FieldIndexSet::default();
+        set.add_field(input.field, input.doc_values, &input.query_mix, &input.options)
+            .expect("benchmark doc ids are unique");
+        HybridField {
+            set,
+            field: input.field.to_string(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self.index().representation() {
+            FieldRepresentation::Tree => "FieldIndexSet (tree)",
+            FieldRepresentation::ZoneMap => "FieldIndexSet (zone map)",
+        }
+    }
+
+    fn global(&self) -> NodeAggregations {
+        self.index().global()
+    }
+
+    fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        self.index().filtered(bitmap)
+    }
+
+    fn range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.index().range(min, max)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.index().memory_usage()
+    }
+}
+
+impl BenchSubject for SampledIndex {
+    fn build(input: &mut BenchInput) -> Self {
+        SampledIndex::new(input.doc_values.to_vec(), &input.options)
+    }
+
+    fn name(&self) -> &'static str {
+        "Sampled index"
+    }
+
+    fn global(&self) -> NodeAggregations {
+        self.get_global_aggregations()
+    }
+
+    fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        Ok(self.query_with_bitmap(bitmap))
+    }
+
+    fn range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.query_value_range(min, max)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.dynamic_usage()
+    }
+}
+
+// Single-threaded full scan, kept as a secondary reference
+pub(crate) struct NaiveColumnar(pub(crate) ColumnarStorage);
+
+impl BenchSubject for NaiveColumnar {
+    fn build(input: &mut BenchInput) -> Self {
+        NaiveColumnar(ColumnarStorage::build(input))
+    }
+
+    fn name(&self) -> &'static str {
+        "Columnar (naive scan)"
+    }
+
+    fn global(&self) -> NodeAggregations {
+        self.0.get_global_aggregations()
+    }
+
+    fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        Ok(self.0.query_with_bitmap_naive(bitmap))
+    }
+
+    fn range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.0.query_value_range_naive(min, max)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0.dynamic_usage()
+    }
+}
+
+// Fraction of the docs in `bitmap` that belong to its `top` most frequent entities
+pub(crate) fn hot_entity_share(entities: &TermColumn, bitmap: &RoaringBitmap, top: usize) -> f64 {
+    if bitmap.is_empty() {
+        return 0.0;
+    }
+    let mut counts = vec![0u64; entities.terms.len()];
+    for doc_id in bitmap {
+        if let Some(code) = entities.code(doc_id) {
+            counts[code] += 1;
+        }
+    }
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    counts.iter().take(top).sum::<u64>() as f64 / bitmap.len() as f64
+}
+
+// Benchmark functions
+// Run the benchmark, verifying every result through `verifier`. Errors are
+// problems with the configuration, such as an unreadable planner profile.
+pub(crate) fn run_benchmark<G: RecordGenerator>(args: &Args, verifier: &Verifier, mut generator: G)
+    -> Result<(), String> {
+    let mut memory_tracker = PhaseMemoryTracker::new();
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let mut rng = StdRng::seed_from_u64(seed);
+    verifier.set_case(seed, benchmark_reproducer(args, seed));
+    report!("Generating {} random documents (seed {})...", format_count(args.num_docs as u64), seed);
+    
+    // Generate documents
+    let start = Instant::now();
+    let docs: Vec<G::Record> = memory_tracker.measure("Document generation", || {
+        (0..args.num_docs)
+            .map(|i| generator.generate(i, &mut rng))
+            .collect()
+    });
+    let generation_time = start.elapsed();
+    report!("Document generation time: {:?}", generation_time);
+    
+    // Extract payload_size values, releasing the source documents as soon as
+    // the field has been copied out
+    report!("Extracting {} values...", G::VALUE_FIELD);
+    let start = Instant::now();
+    let (columns, mut values) = memory_tracker.measure("Value extraction", || {
+        let columns = GeneratedColumns::extract(&generator, &docs);
+        drop(docs);
+        let values: Vec<(u32, f64)> = columns
+            .values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| (i as u32, value))
+            .collect();
+        (columns, values)
+    });
+    let GeneratedColumns {
+        values: doc_values,
+        secondary: doc_login_times,
+        timestamps: doc_timestamps,
+        terms: doc_levels,
+        entities: doc_entities,
+    } = columns;
+    let extraction_time = start.elapsed();
+    report!("Value extraction time: {:?}", extraction_time);
+    
+    // Sort values for AIT construction
+    report!("Sorting values for AIT construction...");
+    let start = Instant::now();
+    memory_tracker.measure("Value sorting", || {
+        values.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    });
+    let sorting_time = start.elapsed();
+    report!("Value sorting time: {:?}", sorting_time);
+
+    // Pick a value range covering roughly the filter percentage of documents
+    let filter_count = (args.num_docs * args.filter_percentage) / 100;
+    let (range_min, range_max) = if values.is_empty() {
+        (0.0, 0.0)
+    } else {
+        let span = filter_count.clamp(1, values.len());
+        let first = rng.gen_range(0..=values.len() - span);
+        (values[first].1, values[first + span - 1].1)
+    };
+
+    let mut planner_profile = match &args.planner_profile {
+        Some(path) => PlannerProfile::load(path)
+            .map_err(|err| format!("Failed to load planner profile {}: {}", path.display(), err))?,
+        None => PlannerProfile::default(),
+    };
+    report!("Planner thresholds: parallel from {} ids, complement above {:.2} of docs",
+             planner_profile.thresholds.parallel_min_len, planner_profile.thresholds.complement_min_fraction);
+
+    // Build every registered subject; the sorted values are handed over to the
+    // tree build rather than copied
+    // Entities as the secondary ids of distinct counts
+    let distinct_ids: Vec<u64> =
+        if args.distinct { doc_entities.codes.iter().map(|&code| code as u64).collect() } else { Vec::new() };
+    let mut input = BenchInput {
+        doc_values: &doc_values,
+        sorted_values: values,
+        options: BuildOptions {
+            leaf_size: args.leaf_size,
+            store_doc_ids: !args.no_doc_ids,
+            planner: planner_profile.thresholds,
+            sample_every: args.sample_every,
+            keep_doc_values: args.doc_values,
+            position_map: !args.no_position_map,
+            value_runs: args.value_runs,
+            digests: args.digests,
+            histograms: args.histograms,
+            extended_stats: args.extended_stats,
+            geometric_mean: args.geometric_mean,
+            non_positive: args.non_positive,
+        },
+        source: G::SOURCE,
+        field: G::VALUE_FIELD,
+        query_mix: args.query_mix,
+        distinct_ids: args.distinct.then_some(&distinct_ids[..]),
+        weights: args.weights.then_some(&doc_login_times[..]),
+        timestamps: args.date_histogram.map(|_| &doc_timestamps[..]),
+    };
+    let mut subjects = Vec::new();
+    let mut build_times = Vec::new();
+    // Heap use of each subject's build by component, with heap-profile
+    let mut heap_profiles = Vec::new();
+    for builder in registered_subjects() {
+        let heap_before = heap::profile();
+        let start = Instant::now();
+        let subject = memory_tracker.measure("Subject build", || builder(&mut input));
+        let build_time = start.elapsed();
+        heap_profiles.push(heap_before.zip(heap::profile()).map(|(before, after)| after.since(&before)));
+        memory_tracker.rename_last(format!("{} build", subject.name()));
+        report!("{} build time: {:?}", subject.name(), build_time);
+        subjects.push(subject);
+        build_times.push(build_time);
+    }
+    if let Some(ait) = subjects[0].as_tree() {
+        let metadata = IndexMetadata { statistics: None, ..ait.metadata.clone() };
+        report!("AIT metadata: {}", serde_json::to_string(&metadata).expect("index metadata serializes"));
+        if let Some(statistics) = &ait.metadata.statistics {
+            report!("AIT statistics: {}", statistics);
+        }
+        if let Some(path) = &args.save_index {
+            let start = Instant::now();
+            match ait.save(path) {
+                Ok(()) => report!("Saved AIT to {} in {:?}", path.display(), start.elapsed()),
+                Err(err) => eprintln!("Failed to save AIT to {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    // Reference results are always computed with the naive columnar scan
+    let reference = ColumnarStorage::build(&mut input);
+    if let Some(ait) = subjects[0].as_tree().filter(|_| args.value_runs) {
+        let histogram = ait.value_histogram(5);
+        let listed: Vec<String> = histogram.iter().map(|bucket| format!("{} x{}", bucket.value, bucket.count)).collect();
+        report!("Most frequent {} values: {}", G::VALUE_FIELD, listed.join(", "));
+        for bucket in &histogram {
+            let expected = reference.query_value_range_naive(bucket.value, bucket.value).count as u64;
+            verifier.count(&format!("Value run {}", bucket.value), "count", bucket.count as u64, expected);
+            verifier.count(&format!("count_eq {}", bucket.value), "count", ait.count_eq(bucket.value) as u64, expected);
+        }
+    }
+
+    // Time-ordered segment tree for time-bounded queries
+    let start = Instant::now();
+    let time_tree = memory_tracker.measure("Time segment tree build", || {
+        TimeSegmentTree::new(&doc_timestamps, &doc_values)
+    });
+    report!("Time segment tree build time: {:?}", start.elapsed());
+
+    // Experimental wavelet matrix for value-range counts within doc-id ranges
+    let start = Instant::now();
+    let wavelet = memory_tracker.measure("Wavelet matrix build", || WaveletMatrix::new(&doc_values));
+    report!("Wavelet matrix build time: {:?}", start.elapsed());
+
+    // drop vars which are no longer needed
+    let input_options = input.options;
+    drop(input);
+    drop(doc_values);
+    memory_tracker.record("After dropping build inputs");
+    memory_tracker.print_report();
+
+    sleep(std::time::Duration::from_secs(10));
+    
+    // Generate random document IDs for filtered query
+    let mut filter_bitmap = RoaringBitmap::new();
+    if let Some(exponent) = args.zipf_exponent {
+        report!("Generating document IDs skewed towards hot entities (Zipf exponent {})...", exponent);
+        filter_bitmap = zipf_filter(&doc_entities, filter_count, exponent, &mut rng);
+        report!("Top 10 of {} entities hold {:.1}% of the filter", format_count(doc_entities.terms.len() as u64),
+                 hot_entity_share(&doc_entities, &filter_bitmap, 10) * 100.0);
+    } else {
+        report!("Generating random document IDs for filtered query...");
+        let mut unique_ids = std::collections::HashSet::new(); // To ensure uniqueness
+
+        while unique_ids.len() < filter_count {
+            let random_id = rng.gen_range(0..args.num_docs as u32);
+            unique_ids.insert(random_id);
+        }
+
+        // Insert unique IDs into the bitmap
+        for id in unique_ids {
+            filter_bitmap.insert(id);
+        }
+    }
+    drop(doc_entities);
+    if let (Some(path), Some(name)) = (&args.save_index, &args.save_filter) {
+        match FilterStore::for_index(path).save(name, &filter_bitmap) {
+            Ok(()) => report!("Saved the filter ({} docs) as {:?} with the index", format_count(filter_bitmap.len()), name),
+            Err(err) => eprintln!("Failed to save the filter as {:?}: {}", name, err),
+        }
+    }
+    
+    // Memory usage: the live heap each build left behind where heap-profile
+    // measures it, DynamicUsage estimates otherwise
+    let memory: Vec<usize> = subjects
+        .iter()
+        .zip(&heap_profiles)
+        .map(|(subject, profile)| profile.as_ref().map_or_else(|| subject.memory_usage(), HeapProfile::live_bytes))
+        .collect();
+    report!("\nMemory Usage ({}):", if heap_profiles[0].is_some() { "measured heap" } else { "estimated" });
+    for (subject, &bytes) in subjects.iter().zip(&memory) {
+        report!("{}: {}", subject.name(), format_bytes(bytes));
+    }
+    report!("Ratio: {:.2}x", memory[0] as f64 / memory[1] as f64);
+    if let Some(ait) = subjects[0].as_tree() {
+        let arena = ait.arena_stats();
+        report!("AIT leaf arenas: {} in {} chunk(s) for {} leaves ({} referenced)",
+                format_bytes(arena.allocated_bytes), arena.chunks, format_count(arena.leaves as u64),
+                format_bytes(arena.referenced_bytes));
+    }
+    if let Some(profile) = &heap_profiles[0] {
+        report!("\n{} heap by component (measured over its build):", subjects[0].name());
+        for (component, usage) in profile.components.iter().filter(|(_, usage)| usage.allocations > 0) {
+            report!("  {}: {} live, {} allocations", component.name(), format_bytes(usage.live_bytes),
+                    format_count(usage.allocations));
+        }
+    }
+    
+    // Benchmark global aggregations
+    report!("\nBenchmarking global aggregations...");
+    let global_times = bench_query(&subjects, args.iterations, &reference.get_global_aggregations(),
+                                   verifier, "Global", |s| Ok(s.global()));
+    
+    // Benchmark filtered aggregations
+    report!("\nBenchmarking filtered aggregations ({} documents, {}%)...", 
+             format_count(filter_bitmap.len()), args.filter_percentage);
+    report!("Filter bitmap: {}", describe_bitmap(&filter_bitmap));
+    if let Some(ait) = subjects[0].as_tree() {
+        report!("AIT cost estimate: {}", ait.estimate_cost(&filter_bitmap));
+    }
+    let filtered_times = bench_query(&subjects, args.iterations,
+                                     &reference.query_with_bitmap_naive(&filter_bitmap),
+                                     verifier, "Filtered", |s| s.filtered(&filter_bitmap));
+
+    // Export the filtered rows for inspection in other tools
+    let export = match (&args.export, subjects[0].as_tree()) {
+        (Some(path), Some(ait)) => {
+            let start = Instant::now();
+            match ait.export(&filter_bitmap, args.export_format, path, Some(&doc_timestamps)) {
+                Ok(rows) => {
+                    let time = start.elapsed();
+                    verifier.count("Export", "rows", rows, filter_bitmap.len());
+                    report!("Exported {} rows to {} as {} in {:?}", format_count(rows), path.display(),
+                            args.export_format.name(), time);
+                    Some(ExportReport { path: path.clone(), format: args.export_format, rows, ns: time.as_nanos() as u64 })
+                }
+                Err(err) => {
+                    eprintln!("Failed to export to {}: {}", path.display(), err);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // Strategy micro-benchmarks need direct access to the tree
+    let strategy_ait = subjects[0].as_tree().filter(|_| args.strategies);
+    let strategy_times = strategy_ait.map(|ait| {
+        report!("\nBenchmarking AIT strategies in isolation...");
+        bench_strategies(ait, &filter_bitmap, args.iterations,
+                         &reference.query_with_bitmap_naive(&filter_bitmap), verifier)
+    });
+
+    // Feed the strategy timings back into the planner profile and refit it
+    if let (Some(path), Some(Ok(times))) = (&args.planner_profile, &strategy_times) {
+        for &(strategy, time) in times {
+            planner_profile.record(&filter_bitmap, reference.values.len() as u32, strategy, time);
+        }
+        let previous = planner_profile.thresholds;
+        planner_profile.refit();
+        if let Err(err) = planner_profile.save(path) {
+            eprintln!("Failed to save planner profile {}: {}", path.display(), err);
+        }
+        report!("Planner thresholds refit from {} observations: {:?} -> {:?}",
+                 planner_profile.observations.len(), previous, planner_profile.thresholds);
+    }
+
+    // Bitmap versus sorted id list inputs to the sequential lookup path
+    let filter_input_times = match subjects[0].as_tree().filter(|_| args.compare_filters) {
+        Some(ait) => {
+            report!("\nComparing Roaring bitmap and sorted id list filters...");
+            // Own rng, so the flag doesn't shift the queries picked after it
+            bench_filter_inputs(ait, &reference, args.iterations, &mut StdRng::seed_from_u64(seed), verifier)
+        }
+        None => Ok(Vec::new()),
+    };
+
+    // Single-metric queries use the planner's early-exit rules
+    let metric_times = subjects[0].as_tree().map(|ait| {
+        report!("\nBenchmarking AIT single-metric queries...");
+        bench_metrics(ait, &filter_bitmap, (range_min, range_max), args.iterations, &reference, verifier)
+    });
+
+    // Where the top of the value range sits among the filtered docs
+    let percentile_rank = subjects[0].as_tree().filter(|ait| ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT percentile rank...");
+        bench_percentile_rank(ait, &filter_bitmap, range_max, args.iterations, &reference, verifier)
+    });
+
+    // Latency-style percentiles over all docs and under the filter
+    let percentiles = subjects[0].as_tree().filter(|ait| ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT exact percentiles...");
+        bench_percentiles(ait, &filter_bitmap, &[50.0, 95.0, 99.0], args.iterations, &reference, verifier)
+    });
+
+    // Approximate percentiles from node digests against the exact ones, under
+    // the scattered filter and under a value range, whose docs are contiguous
+    let approx_percentiles = subjects[0].as_tree().filter(|ait| args.digests && ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT approximate percentiles...");
+        let clustered = ait.doc_ids_in_value_range(range_min, range_max).unwrap_or_default();
+        bench_approx_percentiles(ait, &[("all docs", None), ("filtered", Some(&filter_bitmap)),
+                                        ("value range", Some(&clustered))],
+                                 &[50.0, 95.0, 99.0], args.iterations)
+    });
+
+    // Tail percentiles from node histograms, over all docs and under the filter
+    let histograms = subjects[0].as_tree().filter(|ait| args.histograms && ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT histograms...");
+        bench_histograms(ait, &filter_bitmap, &[50.0, 99.0, 99.9], args.iterations, &reference, verifier)
+    });
+
+    // Skewness and kurtosis from node moments, over all docs and under the filter
+    let extended_stats = subjects[0].as_tree().filter(|ait| args.extended_stats && ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT extended stats...");
+        bench_extended_stats(ait, &filter_bitmap, args.iterations, &reference, verifier)
+    });
+
+    // Geometric mean from node log sums, over all docs and under the filter
+    let geometric_mean = subjects[0].as_tree().filter(|ait| args.geometric_mean && ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT geometric means...");
+        bench_geometric_mean(ait, &filter_bitmap, args.non_positive, args.iterations, &reference, verifier)
+    });
+
+    // Distinct entities from node sketches, over all docs and under the filter
+    let distinct = subjects[0].as_tree().filter(|ait| ait.distinct.is_some()).map(|ait| {
+        report!("\nBenchmarking AIT distinct counts...");
+        bench_distinct(ait, &filter_bitmap, &distinct_ids, args.iterations)
+    });
+
+    // Values weighted by the secondary field, over all docs and under the filter
+    let weighted = subjects[0].as_tree().filter(|ait| ait.weights.is_some()).map(|ait| {
+        report!("\nBenchmarking AIT weighted aggregations...");
+        bench_weighted(ait, &filter_bitmap, &doc_login_times, args.iterations, &reference, verifier)
+    });
+
+    // Fixed-width buckets, over all docs and under the filter
+    let interval_histogram = args.histogram_interval.and_then(|interval| {
+        let ait = subjects[0].as_tree().filter(|ait| ait.has_doc_ids)?;
+        report!("\nBenchmarking AIT fixed-width histograms...");
+        Some((interval, bench_interval_histogram(ait, &filter_bitmap, interval, args.iterations, &reference, verifier)))
+    });
+
+    // Date buckets by doc timestamp, over all docs and under the filter
+    let date_histogram = args.date_histogram.and_then(|interval| {
+        let ait = subjects[0].as_tree().filter(|ait| ait.timestamps.is_some())?;
+        report!("\nBenchmarking AIT date histograms...");
+        Some((interval, bench_date_histogram(ait, &filter_bitmap, interval, &doc_timestamps, args.iterations,
+                                             &reference, verifier)))
+    });
+
+    // Interquartile mean over all docs and under the filter
+    let interquartile = subjects[0].as_tree().filter(|ait| ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT interquartile aggregations...");
+        bench_between_quantiles(ait, &filter_bitmap, (0.25, 0.75), args.iterations, &reference, verifier)
+    });
+
+    // Docs holding any of a few values drawn from the data, with their own rng
+    // so the queries picked after them don't shift
+    let membership = subjects[0].as_tree().filter(|ait| ait.has_doc_ids && !reference.values.is_empty()).map(|ait| {
+        report!("\nBenchmarking AIT value set membership...");
+        let mut value_rng = StdRng::seed_from_u64(seed);
+        let values: Vec<f64> =
+            (0..8).map(|_| reference.values[value_rng.gen_range(0..reference.values.len())]).collect();
+        bench_value_membership(ait, &values, args.iterations, &reference, verifier)
+    });
+
+    // Largest and smallest payloads among the filtered docs
+    let extremes = subjects[0].as_tree().filter(|ait| ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT top-k and bottom-k...");
+        bench_extremes(ait, &filter_bitmap, 10, args.iterations, &reference, verifier)
+    });
+
+    // Per-level payload stats, over all docs and under the filter
+    let level_stats = subjects[0].as_tree().filter(|ait| ait.has_doc_ids).map(|ait| {
+        report!("\nBenchmarking AIT terms + stats by level...");
+        bench_terms_stats(ait, &doc_levels, &filter_bitmap, args.iterations, args.group_by_memory_limit, &reference,
+                          verifier)
+    });
+
+    // Cross-field queries over payload_size and a second field
+    let field_columns: [(&str, &[f64]); 2] =
+        [(G::VALUE_FIELD, &reference.values), (G::SECONDARY_FIELD, &doc_login_times)];
+    let mut field_set = FieldIndexSet::default();
+    for (name, doc_values) in field_columns {
+        field_set.add_field(name, doc_values, &args.query_mix, &input_options).map_err(|err| err.to_string())?;
+    }
+    field_set.add_terms(G::TERM_FIELD, doc_levels.clone());
+    report!("\nEvaluating derived metrics...");
+    let derived_metrics = bench_derived_metrics(&args.exprs, &field_set, &field_columns, &filter_bitmap, verifier);
+
+    // Payload sizes in the value range from users with below-median login times
+    let predicates = [(G::VALUE_FIELD, range_min, range_max), (G::SECONDARY_FIELD, 0.0, 750.0)];
+    report!("\nBenchmarking multi-field range conjunctions...");
+    let multi_range = bench_multi_range(&field_set, &field_columns, &predicates, args.iterations, verifier);
+
+    // The same conjunction with a term predicate, listed least selective
+    // first so the planner has to reorder it
+    let conjunction_predicates = [
+        Predicate::Range { field: G::SECONDARY_FIELD.to_string(), min: 0.0, max: 750.0 },
+        Predicate::Term { field: G::TERM_FIELD.to_string(), term: "error".to_string() },
+        Predicate::Range { field: G::VALUE_FIELD.to_string(), min: range_min, max: range_max },
+    ];
+    report!("\nBenchmarking planned conjunctions...");
+    let conjunction = bench_conjunction(&field_set, &field_columns, &conjunction_predicates, args.iterations,
+                                        verifier);
+
+    // Save the field set beside the index and check that the reopened one
+    // answers the conjunction the same way
+    if let Some(path) = &args.save_index {
+        match save_field_set(&field_set, &path.join(INDEX_FIELD_SET_DIR), &conjunction_predicates, verifier) {
+            Ok((save_time, load_time)) => report!("Saved the field set to {} in {:?}, reopened in {:?}",
+                                                  path.join(INDEX_FIELD_SET_DIR).display(), save_time, load_time),
+            Err(err) => eprintln!("Failed to save or reopen the field set: {}", err),
+        }
+    }
+
+    // Doc-order sums under point updates, against the other AggIndex backends
+    report!("\nBenchmarking Fenwick doc-order sums...");
+    let fenwick_times = bench_fenwick(&reference.values, &input_options, subjects[0].as_tree(), args.iterations,
+                                      &mut rng, verifier);
+
+    // Build value-range partitions in parallel and stitch them
+    report!("\nBuilding {} value-range partitions and stitching them...", args.partitions);
+    let checkpoint = args.checkpoint_dir.clone().map(|dir| BuildCheckpoint { dir });
+    let stitch = bench_stitch(&reference, &input_options, args.partitions, checkpoint.as_ref(), &filter_bitmap,
+                              (range_min, range_max), verifier);
+
+    // Delete the filtered docs and compact the id space
+    let compaction = subjects[0].as_tree().map(|ait| {
+        report!("\nCompacting AIT after deleting the filtered docs...");
+        bench_compaction(ait, &filter_bitmap, &reference.values, verifier)
+    });
+
+    // Build two halves of the docs separately and merge them
+    report!("\nMerging AITs built from each half of the docs...");
+    let merge = bench_merge(&reference, &input_options, &filter_bitmap, verifier);
+
+    // Ingest the docs into a segmented index one at a time and in batches
+    report!("\nIngesting docs into a segmented index...");
+    let ingest = bench_ingest(&reference, &input_options, MergePolicy::from_args(args), &filter_bitmap,
+                              (range_min, range_max), verifier);
+
+    // Persist a segmented index before and after a full merge, then reopen it
+    let segment_store = args.segment_dir.as_ref().map(|dir| {
+        report!("\nPersisting a segmented index to {}...", dir.display());
+        bench_segment_store(&reference, &input_options, MergePolicy::from_args(args), &filter_bitmap,
+                            (range_min, range_max), dir, verifier)
+    });
+
+    // Ingest again, leaving flushes and merges to background maintenance
+    report!("\nIngesting with background maintenance...");
+    let maintenance = bench_maintenance(&reference, &input_options, MergePolicy::from_args(args), verifier);
+
+    // Query a many-segment index while a background task compacts it
+    report!("\nQuerying a segmented index during background compaction...");
+    let concurrent_compaction = bench_concurrent_compaction(&reference, &input_options, MergePolicy::from_args(args),
+                                                            (range_min, range_max), verifier);
+
+    // Benchmark value range aggregations
+    report!("\nBenchmarking range aggregations ([{}, {}])...", range_min, range_max);
+    let range_times = bench_query(&subjects, args.iterations,
+                                  &reference.query_value_range_naive(range_min, range_max),
+                                  verifier, "Range", |s| Ok(s.range(range_min, range_max)));
+
+    // Value-range counts restricted to the first half of the doc-id space
+    let doc_range = 0..(reference.values.len() / 2) as u32;
+    report!("\nBenchmarking value-range counts within doc ids {:?}...", doc_range);
+    let rank_times = bench_rank_range(&wavelet, subjects[0].as_tree(), &reference.values,
+                                      (range_min, range_max), doc_range, args.iterations, verifier);
+
+    // Time windows covering roughly the filter percentage of documents
+    let time_window = time_tree.pick_window(filter_count, &mut rng);
+    let time_times = time_window.map(|(from, to)| {
+        report!("\nBenchmarking time-window aggregations ([{}, {}] ms)...", from, to);
+        bench_time_window(&time_tree, &doc_timestamps, &reference.values, (from, to), args.iterations, verifier)
+    });
+    let value_order = subjects[0].as_tree().map(|ait| {
+        report!("\nBenchmarking value-ordered doc ids...");
+        let cache = Arc::new(TranslationCache::new(args.translation_cache_entries));
+        bench_value_ordered_ids(ait, &reference, &filter_bitmap, (range_min, range_max), &cache, args.iterations,
+                                verifier)
+    });
+    let time_partitions = time_window.map(|window| {
+        report!("\nBenchmarking time-partitioned doc ids ({} ms partitions)...", args.time_partition_ms);
+        bench_time_partitioned_ids(&doc_timestamps, &reference.values, window, args.time_partition_ms,
+                                   args.iterations, verifier)
+    });
+    
+    report!("\nPerformance Results (averaged over {} iterations):", args.iterations);
+    print_query_results("Global Aggregations", &subjects, &global_times);
+    print_query_results("Filtered Aggregations", &subjects, &filtered_times);
+    report!("  Filter bitmap: {}", describe_bitmap(&filter_bitmap));
+    print_query_results("Range Aggregations", &subjects, &range_times);
+    report!("\nValue-Range Counts within Doc-Id Range:");
+    report!("  Wavelet matrix: {:?} ({}{})", rank_times.wavelet, format_bytes(wavelet.dynamic_usage()),
+             if wavelet.exact { "" } else { ", approximate: values share buckets" });
+    match rank_times.ait {
+        Some(time) => report!("  AIT: {:?} ({})", time, format_bytes(memory[0])),
+        None => report!("  AIT: n/a"),
+    }
+    report!("  Columnar scan: {:?}", rank_times.scan);
+    if let Some((tree_time, scan_time)) = time_times {
+        report!("\nTime-Window Aggregations:");
+        report!("  Time segment tree: {:?}", tree_time);
+        report!("  Columnar scan: {:?}", scan_time);
+        report!("  Speedup: {:.2}x", speedup(scan_time, tree_time));
+    }
+    match &value_order {
+        Some(Ok(stats)) => {
+            report!("\nValue-Ordered Doc Ids (built in {:?}):", stats.build_time);
+            report!("  Range filter bitmap, original ids: {}", stats.original_bitmap);
+            report!("  Range filter bitmap, value-ordered ids: {}", stats.remapped_bitmap);
+            report!("  Range filter query: {:?} with original ids, {:?} with value-ordered ids ({:.2}x)",
+                    stats.original_time, stats.remapped_time, speedup(stats.original_time, stats.remapped_time));
+            report!("  Random filter query: {:?} with original ids, {:?} with value-ordered ids",
+                    stats.random_original_time, stats.random_remapped_time);
+            report!("  Filter translation: {:?} uncached, {:?} from the cache ({} hits, {} misses)",
+                    stats.translate_time, stats.cached_translate_time, stats.cache_hits, stats.cache_misses);
+        }
+        Some(Err(err)) => report!("\nValue-Ordered Doc Ids skipped: {}", err),
+        None => {}
+    }
+    match &time_partitions {
+        Some(Ok(stats)) => {
+            report!("\nTime-Partitioned Doc Ids ({} partitions, allocated in {:?}):", stats.partitions,
+                    stats.allocation_time);
+            report!("  Window bitmap, arrival ids: {}", stats.arrival_bitmap);
+            report!("  Window bitmap, time-partitioned ids: {}", stats.partitioned_bitmap);
+            report!("  Zone-map window query: {:?} with arrival ids, {:?} with time-partitioned ids ({:.2}x)",
+                    stats.arrival_time, stats.partitioned_time, speedup(stats.arrival_time, stats.partitioned_time));
+            report!("  Expired at window start: {} docs in {} partitions, removed by the expiry task in {:?}",
+                    format_count(stats.expired_docs), stats.expired_partitions, stats.expiry_time);
+        }
+        Some(Err(err)) => report!("\nTime-Partitioned Doc Ids skipped: {}", err),
+        None => {}
+    }
+    match &strategy_times {
+        Some(Ok(strategy_times)) => {
+            report!("\nAIT Strategies (filtered query):");
+            for (strategy, time) in strategy_times {
+                report!("  {}: {:?}", strategy.name(), time);
+            }
+        }
+        Some(Err(err)) => report!("\nAIT Strategies skipped: {}", err),
+        None => {}
+    }
+    match &filter_input_times {
+        Ok(times) if !times.is_empty() => {
+            report!("\nAIT Filter Inputs (sequential lookups):");
+            report!("  {:>12} {:>12} {:>14} {:>14} {:>8}", "selectivity", "ids", "roaring", "sorted vec", "ratio");
+            for times in times {
+                report!("  {:>11}% {:>12} {:>14} {:>14} {:>7.2}x", times.selectivity * 100.0,
+                        format_count(times.ids), format!("{:?}", times.roaring), format!("{:?}", times.sorted),
+                        speedup(times.roaring, times.sorted));
+            }
+        }
+        Ok(_) => {}
+        Err(err) => report!("\nAIT Filter Inputs skipped: {}", err),
+    }
+    if let Some(metric_times) = &metric_times {
+        report!("\nAIT Single-Metric Queries (filtered / range):");
+        for (metric, (filtered, range)) in Metric::ALL.iter().zip(metric_times) {
+            let filtered = filtered.map_or("n/a".to_string(), |time| format!("{:?}", time));
+            report!("  {}: {} / {:?}", metric.name(), filtered, range);
+        }
+    }
+    if let Some(Err(err)) = &level_stats {
+        report!("\nAIT Terms + Stats by Level failed: {}", err);
+    }
+    if let Some(Ok(stats)) = &level_stats {
+        report!("\nAIT Terms + Stats by Level (all docs / filtered): {:?} / {:?}", stats.global_time, stats.filtered_time);
+        report!("  {:>8} {:>10} {:>10} {:>10} {:>12}", G::TERM_FIELD, "count", "min", "max", "avg");
+        for bucket in &stats.filtered {
+            report!("  {:>8} {:>10} {:>10} {:>10} {:>12.2}", bucket.term, format_count(bucket.count as u64),
+                    bucket.min, bucket.max, bucket.avg);
+        }
+    }
+    match &derived_metrics {
+        Ok(metrics) if !metrics.is_empty() => {
+            report!("\nDerived Metrics (all docs / filtered):");
+            let show = |value: Option<f64>| value.map_or("n/a".to_string(), |value| format!("{:.4}", value));
+            for metric in metrics {
+                report!("  {}: {} / {}", metric.expr, show(metric.global), show(metric.filtered));
+            }
+        }
+        Ok(_) => {}
+        Err(err) => return Err(format!("derived metrics: {}", err)),
+    }
+    match &multi_range {
+        Ok(stats) => {
+            let predicates: Vec<String> = predicates
+                .iter()
+                .map(|(name, min, max)| format!("{} in [{}, {}]", name, min, max))
+                .collect();
+            report!("\nMulti-Field Range ({}): {} docs in {:?}", predicates.join(" and "),
+                    format_count(stats.docs), stats.time);
+        }
+        Err(err) => report!("\nMulti-Field Range failed: {}", err),
+    }
+    match &conjunction {
+        Ok(stats) => {
+            report!("\nPlanned Conjunction: {} docs in {:?}", format_count(stats.docs), stats.time);
+            report!("  Plan: {}", stats.plan);
+            report!("  Count estimates:");
+            for estimate in &stats.estimates {
+                report!("    {}: {} in {}ns (actual {})", estimate.predicate, estimate.estimate,
+                        estimate.estimate_ns, format_count(estimate.actual));
+            }
+        }
+        Err(err) => report!("\nPlanned Conjunction failed: {}", err),
+    }
+    if let Some(times) = percentiles {
+        report!("\nAIT Exact Percentiles (p50 / p95 / p99):");
+        for (label, time, values) in times {
+            let values: Vec<String> =
+                values.iter().map(|value| value.map_or("n/a".to_string(), |value| format!("{:.2}", value))).collect();
+            report!("  {}: {} ({:?})", label, values.join(" / "), time);
+        }
+    }
+    if let Some(comparisons) = approx_percentiles {
+        report!("\nAIT Approximate Percentiles (p50 / p95 / p99, digests vs exact):");
+        for comparison in comparisons {
+            report!("  {}: {:?} vs {:?}, max relative error {:.4}%", comparison.label, comparison.approx_time,
+                    comparison.exact_time, comparison.max_relative_error * 100.0);
+        }
+    }
+    if let Some(results) = histograms {
+        report!("\nAIT Histogram Percentiles (p50 / p99 / p99.9):");
+        for result in results {
+            let values: Vec<String> =
+                result.values.iter().map(|value| value.map_or("n/a".to_string(), |value| format!("{:.2}", value))).collect();
+            report!("  {}: {} over {} buckets ({:?}), max relative error {:.4}%", result.label, values.join(" / "),
+                    format_count(result.buckets as u64), result.time, result.max_relative_error * 100.0);
+        }
+    }
+    if let Some(results) = extended_stats {
+        report!("\nAIT Extended Stats:");
+        let format = |value: Option<f64>| value.map_or("n/a".to_string(), |value| format!("{:.4}", value));
+        for result in results {
+            report!("  {}: std dev {}, skewness {}, kurtosis {} ({:?})", result.label, format(result.moments.std_dev()),
+                    format(result.moments.skewness()), format(result.moments.kurtosis()), result.time);
+        }
+    }
+    if let Some(results) = geometric_mean {
+        report!("\nAIT {} Geometric Mean (non-positive: {}):", G::VALUE_FIELD, args.non_positive);
+        for result in results {
+            let mean = result.log_sum.geometric_mean().map_or("n/a".to_string(), |mean| format!("{:.4}", mean));
+            // The product itself overflows past a few hundred values
+            report!("  {}: geometric mean {}, ln(product) {:.2}, {} skipped ({:?})", result.label, mean,
+                    result.log_sum.log_sum, format_count(result.log_sum.skipped as u64), result.time);
+        }
+    }
+    if let Some(results) = distinct {
+        report!("\nAIT Distinct Entities (HyperLogLog vs exact):");
+        for result in results {
+            report!("  {}: ~{} vs {} ({:?}), relative error {:.2}%", result.label, format_count(result.approx),
+                    format_count(result.exact), result.time, result.relative_error() * 100.0);
+        }
+    }
+    if let Some(results) = weighted {
+        report!("\nAIT {} Weighted By {}:", G::VALUE_FIELD, G::SECONDARY_FIELD);
+        for result in results {
+            let avg = result.sums.weighted_avg().map_or("n/a".to_string(), |avg| format!("{:.2}", avg));
+            report!("  {}: weighted sum {:.2}, weighted avg {} ({:?})", result.label, result.sums.weighted_sum, avg,
+                    result.time);
+        }
+    }
+    if let Some((interval, results)) = interval_histogram {
+        report!("\nAIT {} Histogram (interval {}):", G::VALUE_FIELD, interval);
+        for result in results {
+            let fullest = result.buckets.iter().max_by_key(|bucket| bucket.aggregations.count);
+            let fullest = fullest.map_or("none".to_string(), |bucket| {
+                let docs = format_count(bucket.aggregations.count as u64);
+                format!("[{}, {}) with {} docs", bucket.key, bucket.key + interval, docs)
+            });
+            report!("  {}: {} buckets, fullest {} ({:?})", result.label, format_count(result.buckets.len() as u64),
+                    fullest, result.time);
+        }
+    }
+    if let Some((interval, results)) = date_histogram {
+        report!("\nAIT {} Date Histogram (per {}):", G::VALUE_FIELD, interval);
+        for result in results {
+            let fullest = result.buckets.iter().max_by_key(|bucket| bucket.aggregations.count);
+            let fullest = fullest.map_or("none".to_string(), |bucket| {
+                let key = chrono::DateTime::from_timestamp_millis(bucket.key_ms)
+                    .map_or(bucket.key_ms.to_string(), |time| time.to_rfc3339());
+                let mean = bucket.aggregations.avg().map_or("n/a".to_string(), |mean| format!("{:.2}", mean));
+                format!("{} with {} docs, mean {}", key, format_count(bucket.aggregations.count as u64), mean)
+            });
+            report!("  {}: {} buckets, fullest {} ({:?})", result.label, format_count(result.buckets.len() as u64),
+                    fullest, result.time);
+        }
+    }
+    if let Some(times) = interquartile {
+        report!("\nAIT Interquartile Aggregations:");
+        for (label, time, aggregations) in times {
+            let mean = aggregations.avg().map_or("n/a".to_string(), |mean| format!("{:.2}", mean));
+            report!("  {}: mean {} over {} docs ({:?})", label, mean, format_count(aggregations.count as u64), time);
+        }
+    }
+    if let Some(extremes) = extremes {
+        for extreme in extremes {
+            let listed: Vec<String> =
+                extreme.docs.iter().take(3).map(|(doc_id, value)| format!("{} (doc {})", value, doc_id)).collect();
+            report!("\nAIT {} 10 Filtered Docs: {}, ... ({:?})", extreme.label, listed.join(", "), extreme.time);
+        }
+    }
+    if let Some((docs, time)) = membership {
+        report!("\nAIT Docs With {} In 8 Values: {} ({:?})", G::VALUE_FIELD, format_count(docs), time);
+    }
+    if let Some((rank, time)) = percentile_rank {
+        let rank = rank.map_or("n/a".to_string(), |rank| format!("{:.2}", rank));
+        report!("\nAIT Percentile Rank of {:.2} under the filter: {} ({:?})", range_max, rank, time);
+    }
+    
+    match &stitch {
+        Ok(stats) => {
+            report!("\nAIT Partitioned Build ({} partitions):", args.partitions);
+            report!("  Partition builds: {:?} ({} resumed from checkpoint)", stats.build_time, stats.resumed);
+            report!("  Stitch: {:?}", stats.stitch_time);
+        }
+        Err(err) => report!("\nAIT Partitioned Build failed: {}", err),
+    }
+    match &merge {
+        Ok(stats) => {
+            report!("\nAIT Merge (two halves of {} docs):", reference.values.len());
+            report!("  Half builds: {:?}", stats.build_time);
+            report!("  Merge: {:?}", stats.merge_time);
+        }
+        Err(err) => report!("\nAIT Merge failed: {}", err),
+    }
+    match &ingest {
+        Ok(stats) => {
+            report!("\nSegmented Ingestion ({} docs, batches of {}):", format_count(reference.values.len() as u64),
+                    format_count(INGEST_BATCH_SIZE as u64));
+            report!("  Per-doc inserts: {:?} ({} segments)", stats.insert_time, stats.insert_segments);
+            for policy in &stats.policies {
+                report!("  Batches, {} merges: {:?} ({} segments; {} merges rewrote {} docs, {:.2}x write amplification, in {:?})",
+                        policy.policy.name(), policy.time, policy.segments, policy.merge_stats.merges,
+                        format_count(policy.merge_stats.docs_merged),
+                        policy.merge_stats.docs_merged as f64 / reference.values.len().max(1) as f64,
+                        policy.merge_stats.time);
+            }
+            report!("  Sorted batches, {} merges: {:?}", args.merge_policy.name(), stats.sorted_batch_time);
+            report!("  Merge {} segments into one: {:?}", stats.final_segments, stats.final_merge_time);
+        }
+        Err(err) => report!("\nSegmented Ingestion failed: {}", err),
+    }
+    match &segment_store {
+        Some(Ok(stats)) => {
+            report!("\nSegment Store:");
+            report!("  Persist {} segments: {:?}", stats.segments, stats.persist_time);
+            report!("  Merge and persist again: {:?}", stats.merged_persist_time);
+            report!("  Reopen: {:?}", stats.open_time);
+            report!("  Mapped reader refresh to generation {}: {:?}", stats.reader_generation, stats.refresh_time);
+        }
+        Some(Err(err)) => report!("\nSegment Store failed: {}", err),
+        None => {}
+    }
+    match &maintenance {
+        Ok(stats) => {
+            report!("\nBackground Maintenance:");
+            report!("  Ingest: {:?} ({} segments at the end, {} after the scheduler stopped)",
+                    stats.ingest_time, stats.segments_at_ingest_end, stats.segments_at_stop);
+            for task in &stats.tasks {
+                report!("  {}: {} runs, {} with work, {} errors, {:?} busy",
+                        task.name, task.runs, task.busy_runs, task.errors, task.busy_time);
+            }
+        }
+        Err(err) => report!("\nBackground Maintenance failed: {}", err),
+    }
+    match &concurrent_compaction {
+        Ok(stats) => {
+            let docs = reference.values.len().max(1) as f64;
+            report!("\nQueries During Compaction:");
+            report!("  Ingest: {:?} ({:.0} docs/s, {} segments)", stats.ingest_time,
+                    docs / stats.ingest_time.as_secs_f64().max(f64::EPSILON), stats.segments_before);
+            report!("  Compaction: {:?} to {} segments ({} merges rewrote {} docs, {:.2}x write amplification, {} errors)",
+                    stats.compaction_time, stats.segments_after, stats.merge_stats.merges,
+                    format_count(stats.merge_stats.docs_merged), stats.merge_stats.docs_merged as f64 / docs,
+                    stats.task.errors);
+            report!("  Range queries before: {}", stats.before);
+            report!("  Range queries during: {}", stats.during);
+            report!("  Range queries after: {}", stats.after);
+        }
+        Err(err) => report!("\nQueries During Compaction failed: {}", err),
+    }
+    if let Some(ait) = subjects[0].as_tree() {
+        report!("\nAIT position map fallbacks: {}", ait.position_map_fallbacks());
+        report!("\nAIT bitmap query latencies by strategy and selectivity:");
+        for summary in ait.query_latencies() {
+            report!("  {}", summary);
+        }
+    }
+    match &compaction {
+        Some(Ok(stats)) => {
+            report!("\nAIT Compaction ({} docs deleted):", format_count(filter_bitmap.len()));
+            report!("  Time: {:?}", stats.time);
+            report!("  Memory: {} -> {}", format_bytes(stats.memory_before), format_bytes(stats.memory_after));
+        }
+        Some(Err(err)) => report!("\nAIT Compaction skipped: {}", err),
+        None => {}
+    }
+    if let Some(times) = fenwick_times {
+        report!("\nFenwick doc-order sums:");
+        for (backend, time) in &times.doc_range_sums {
+            report!("  {} doc-range sum: {:?}", backend, time);
+        }
+        report!("  point update: {:?}", times.update);
+    }
+    
+    report!("\nSummary:");
+    report!("- {} build time: {:?}", subjects[0].name(), build_times[0]);
+    report!("- {} memory overhead: {:.2}x", subjects[0].name(), memory[0] as f64 / memory[1] as f64);
+    report!("- Global query speedup: {}", format_speedup(global_times[1], global_times[0]));
+    report!("- Filtered query speedup: {}", format_speedup(filtered_times[1], filtered_times[0]));
+    report!("- Range query speedup: {}", format_speedup(range_times[1], range_times[0]));
+
+    if args.format == OutputFormat::Json {
+        let nanos = |time: &Option<Duration>| time.map(|time| time.as_nanos() as u64);
+        let report = BenchReport {
+            num_docs: args.num_docs,
+            filter_percentage: args.filter_percentage,
+            filter_docs: filter_bitmap.len(),
+            leaf_size: args.leaf_size,
+            iterations: args.iterations,
+            subjects: subjects
+                .iter()
+                .enumerate()
+                .map(|(i, subject)| SubjectReport {
+                    name: subject.name(),
+                    build_ns: build_times[i].as_nanos() as u64,
+                    memory_bytes: memory[i],
+                    global_ns: nanos(&global_times[i]),
+                    filtered_ns: nanos(&filtered_times[i]),
+                    range_ns: nanos(&range_times[i]),
+                })
+                .collect(),
+            strategies: match &strategy_times {
+                Some(Ok(times)) => times
+                    .iter()
+                    .map(|&(strategy, time)| StrategyReport { strategy, ns: time.as_nanos() as u64 })
+                    .collect(),
+                _ => Vec::new(),
+            },
+            ingest_policies: ingest.as_ref().map_or(Vec::new(), |stats| stats.policies.iter().collect()),
+            maintenance_tasks: maintenance.as_ref().map_or(Vec::new(), |stats| stats.tasks.iter().collect()),
+            concurrent_compaction: concurrent_compaction.as_ref().ok(),
+            filter_inputs: filter_input_times.as_ref().map_or(Vec::new(), |times| {
+                times
+                    .iter()
+                    .map(|times| FilterInputReport {
+                        selectivity: times.selectivity,
+                        ids: times.ids,
+                        roaring_ns: times.roaring.as_nanos() as u64,
+                        sorted_ns: times.sorted.as_nanos() as u64,
+                    })
+                    .collect()
+            }),
+            position_map_fallbacks: subjects[0].as_tree().map(|ait| ait.position_map_fallbacks()),
+            query_latencies: subjects[0].as_tree().map_or(Vec::new(), |ait| ait.query_latencies()),
+            level_stats: level_stats.and_then(Result::ok).map_or(Vec::new(), |stats| stats.filtered),
+            derived_metrics: derived_metrics.unwrap_or_default(),
+            multi_range: multi_range
+                .ok()
+                .map(|stats| MultiRangeReport { docs: stats.docs, ns: stats.time.as_nanos() as u64 }),
+            conjunction: conjunction.ok().map(|stats| ConjunctionReport {
+                docs: stats.docs,
+                ns: stats.time.as_nanos() as u64,
+                order: stats.plan.steps.iter().map(|step| step.predicate.to_string()).collect(),
+                estimates: stats.estimates,
+            }),
+            value_order: value_order.and_then(Result::ok).map(|stats| ValueOrderReport {
+                original_ns: stats.original_time.as_nanos() as u64,
+                remapped_ns: stats.remapped_time.as_nanos() as u64,
+            }),
+            export,
+            time_partitions: time_partitions.and_then(Result::ok).map(|stats| TimePartitionReport {
+                partitions: stats.partitions,
+                arrival_ns: stats.arrival_time.as_nanos() as u64,
+                partitioned_ns: stats.partitioned_time.as_nanos() as u64,
+            }),
+            mismatches: verifier.mismatches.borrow().clone(),
+        };
+        println!("{}", serde_json::to_string_pretty(&report).expect("benchmark report serializes"));
+    }
+    Ok(())
+}
+
+// Benchmark results for --format json. Field names are part of the output
+// contract; add fields rather than renaming them. Times are in nanoseconds and
+// null where a subject can't answer the query.
+#[derive(Serialize)]
+pub(crate) struct BenchReport<'a> {
+    pub(crate) num_docs: usize,
+    pub(crate) filter_percentage: usize,
+    pub(crate) filter_docs: u64,
+    pub(crate) leaf_size: usize,
+    pub(crate) iterations: usize,
+    pub(crate) subjects: Vec<SubjectReport>,
+    pub(crate) strategies: Vec<StrategyReport>,
+    pub(crate) filter_inputs: Vec<FilterInputReport>,
+    pub(crate) ingest_policies: Vec<&'a PolicyIngestStats>,
+    pub(crate) maintenance_tasks: Vec<&'a TaskStats>,
+    pub(crate) concurrent_compaction: Option<&'a ConcurrentCompactionStats>,
+    pub(crate) position_map_fallbacks: Option<u64>,
+    pub(crate) query_latencies: Vec<LatencySummary>,
+    pub(crate) level_stats: Vec<TermBucket>,
+    pub(crate) derived_metrics: Vec<DerivedMetricReport>,
+    pub(crate) multi_range: Option<MultiRangeReport>,
+    pub(crate) conjunction: Option<ConjunctionReport>,
+    pub(crate) time_partitions: Option<TimePartitionReport>,
+    pub(crate) value_order: Option<ValueOrderReport>,
+    pub(crate) export: Option<ExportReport>,
+    pub(crate) mismatches: Vec<Mismatch>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ExportReport {
+    pub(crate) path: PathBuf,
+    pub(crate) format: ExportFormat,
+    pub(crate) rows: u64,
+    pub(crate) ns: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ValueOrderReport {
+    // Value-range filter query with the original and the value-ordered ids
+    pub(crate) original_ns: u64,
+    pub(crate) remapped_ns: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TimePartitionReport {
+    pub(crate) partitions: usize,
+    // Zone-map time-window query with arrival-order and time-partitioned ids
+    pub(crate) arrival_ns: u64,
+    pub(crate) partitioned_ns: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ConjunctionReport {
+    pub(crate) docs: u64,
+    pub(crate) ns: u64,
+    // Predicates in evaluation order
+    pub(crate) order: Vec<String>,
+    pub(crate) estimates: Vec<EstimateReport>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct MultiRangeReport {
+    pub(crate) docs: u64,
+    pub(crate) ns: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DerivedMetricReport {
+    pub(crate) expr: String,
+    pub(crate) global: Option<f64>,
+    pub(crate) filtered: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SubjectReport {
+    pub(crate) name: &'static str,
+    pub(crate) build_ns: u64,
+    pub(crate) memory_bytes: usize,
+    pub(crate) global_ns: Option<u64>,
+    pub(crate) filtered_ns: Option<u64>,
+    pub(crate) range_ns: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StrategyReport {
+    pub(crate) strategy: QueryStrategy,
+    pub(crate) ns: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FilterInputReport {
+    pub(crate) selectivity: f64,
+    pub(crate) ids: u64,
+    pub(crate) roaring_ns: u64,
+    pub(crate) sorted_ns: u64,
+}
+
+// Time a query against every subject, verifying the first iteration against the
+// reference result, and return the average duration per subject. Subjects that
+// cannot answer the query are reported and get no timing.
+pub(crate) fn bench_query<F>(subjects: &[Box<dyn BenchSubject>], iterations: usize, expected: &NodeAggregations,
+                  verifier: &Verifier, label: &str, query: F) -> Vec<Option<Duration>>
+where
+    F: Fn(&dyn BenchSubject) -> Result<NodeAggregations, AitError>,
+{
+    let mut averages = Vec::with_capacity(subjects.len());
+    'subjects: for (idx, subject) in subjects.iter().enumerate() {
+        let mut times = Vec::with_capacity(iterations);
+        for i in 0..iterations {
+            let start = Instant::now();
+            let result = match query(subject.as_ref()) {
+                Ok(result) => result,
+                Err(err) => {
+                    report!("{} skipped: {}", subject.name(), err);
+                    averages.push(None);
+                    continue 'subjects;
+                }
+            };
+            times.push(start.elapsed());
+
+            // Verify results match
+            if i == 0 {
+                // Use approximate equality for floating point comparisons
+                verifier.aggregations(&format!("{} {}", label, subject.name()), &result, expected);
+
+                if idx == 0 {
+                    report!("{} aggregation results:", label);
+                    report!("  Min: {}", result.min_value);
+                    report!("  Max: {}", result.max_value);
+                    report!("  Sum: {}", result.sum);
+                    report!("  Count: {}", result.count);
+                    report!("  Avg: {}", result.avg().map_or("-".to_string(), |avg| avg.to_string()));
+                    report!("  Std dev: {}", result.std_dev().map_or("-".to_string(), |std_dev| std_dev.to_string()));
+                }
+            }
+        }
+        averages.push(Some(average_duration(&times)));
+    }
+    averages
+}
+
+// Time every AIT query strategy on the same bitmap, bypassing the planner
+pub(crate) fn bench_strategies(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, iterations: usize, expected: &NodeAggregations,
+                    verifier: &Verifier) -> Result<Vec<(QueryStrategy, Duration)>, AitError> {
+    // The columnar scan only differs from sequential lookups when doc values are kept
+    QueryStrategy::ALL
+        .iter()
+        .filter(|&&strategy| strategy != QueryStrategy::ColumnarScan || ait.doc_values.is_some())
+        .map(|&strategy| {
+            let mut times = Vec::with_capacity(iterations);
+            for i in 0..iterations {
+                let start = Instant::now();
+                let result = ait.query_with_strategy(bitmap, strategy)?;
+                times.push(start.elapsed());
+
+                if i == 0 {
+                    verifier.aggregations(&format!("Strategy {}", strategy.name()), &result, expected);
+                }
+            }
+            Ok((strategy, average_duration(&times)))
+        })
+        .collect()
+}
+
+// Average sequential-lookup times for one filter selectivity
+pub(crate) struct FilterInputTimes {
+    pub(crate) selectivity: f64,
+    pub(crate) ids: u64,
+    pub(crate) roaring: Duration,
+    pub(crate) sorted: Duration,
+}
+
+// Time the AIT's sequential lookup path with the same random ids given as a
+// Roaring bitmap and as a sorted id list, across selectivities
+pub(crate) fn bench_filter_inputs(ait: &AggregationIndexTree, reference: &ColumnarStorage, iterations: usize,
+                       rng: &mut impl Rng, verifier: &Verifier) -> Result<Vec<FilterInputTimes>, AitError> {
+    const SELECTIVITIES: [f64; 5] = [0.0001, 0.001, 0.01, 0.1, 0.5];
+    let num_docs = reference.values.len() as u32;
+    SELECTIVITIES
+        .iter()
+        .map(|&selectivity| {
+            let ids: Vec<u32> = (0..num_docs).filter(|_| rng.gen_bool(selectivity)).collect();
+            let bitmap: RoaringBitmap = ids.iter().copied().collect();
+            let sorted = SortedIds::new(ids);
+            let expected = reference.query_with_bitmap_naive(&bitmap);
+
+            let mut roaring_times = Vec::with_capacity(iterations);
+            let mut sorted_times = Vec::with_capacity(iterations);
+            for i in 0..iterations {
+                let start = Instant::now();
+                let from_bitmap = ait.query_with_filter(&bitmap)?;
+                roaring_times.push(start.elapsed());
+
+                let start = Instant::now();
+                let from_sorted = ait.query_with_filter(&sorted)?;
+                sorted_times.push(start.elapsed());
+
+                if i == 0 {
+                    verifier.aggregations("Filter input roaring", &from_bitmap, &expected);
+                    verifier.aggregations("Filter input sorted vec", &from_sorted, &expected);
+                }
+            }
+            Ok(FilterInputTimes {
+                selectivity,
+                ids: sorted.cardinality(),
+                roaring: average_duration(&roaring_times),
+                sorted: average_duration(&sorted_times),
+            })
+        })
+        .collect()
+}
+
+// Time every single-metric query on the filter bitmap and the value range,
+// verifying against the full reference aggregations. Bitmap timings are None
+// when the tree cannot answer bitmap queries.
+pub(crate) fn bench_metrics(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, (range_min, range_max): (f64, f64),
+                 iterations: usize, reference: &ColumnarStorage, verifier: &Verifier)
+                 -> Vec<(Option<Duration>, Duration)> {
+    let expected_filtered = reference.query_with_bitmap_naive(bitmap);
+    let expected_range = reference.query_value_range_naive(range_min, range_max);
+    let check = |label: &str, metric: Metric, actual: Option<f64>, expected: &NodeAggregations| {
+        let expected = (expected.count > 0 || metric == Metric::Count).then(|| metric.of(expected));
+        verifier.value(&format!("{} single-metric AIT", label), metric.name(), actual, expected);
+    };
+
+    Metric::ALL
+        .iter()
+        .map(|&metric| {
+            let mut filtered_times = Vec::with_capacity(iterations);
+            let mut range_times = Vec::with_capacity(iterations);
+            for i in 0..iterations {
+                let start = Instant::now();
+                let filtered = ait.query_metric_with_bitmap(bitmap, metric);
+                filtered_times.push(start.elapsed());
+
+                let start = Instant::now();
+                let range = ait.query_metric_value_range(range_min, range_max, metric);
+                range_times.push(start.elapsed());
+
+                if i == 0 {
+                    if let Ok(filtered) = filtered {
+                        check("Filtered", metric, filtered, &expected_filtered);
+                    }
+                    check("Range", metric, range, &expected_range);
+                }
+            }
+            let filtered_time = ait.has_doc_ids.then(|| average_duration(&filtered_times));
+            (filtered_time, average_duration(&range_times))
+        })
+        .collect()
+}
+
+// Evaluate the derived metrics over all docs and under the filter from a field
+// set holding the given fields, verifying them against the same expressions
+// over reference scans
+pub(crate) fn bench_derived_metrics(metrics: &[DerivedMetric], set: &FieldIndexSet, fields: &[(&str, &[f64])],
+                         bitmap: &RoaringBitmap, verifier: &Verifier) -> Result<Vec<DerivedMetricReport>, String> {
+    let references: HashMap<&str, ColumnarStorage> = fields
+        .iter()
+        .map(|&(name, doc_values)| (name, ColumnarStorage { values: doc_values.to_vec() }))
+        .collect();
+
+    let global = set.evaluate(metrics, None)?;
+    let filtered = set.evaluate(metrics, Some(bitmap))?;
+    let reference = |filter: Option<&RoaringBitmap>, expr: &DerivedExpr| {
+        expr.eval(&|name| {
+            let column = references
+                .get(name.unwrap_or(fields[0].0))
+                .ok_or_else(|| format!("unknown field {:?}", name.unwrap_or_default()))?;
+            Ok(filter.map_or_else(|| column.get_global_aggregations(), |bitmap| column.query_with_bitmap_naive(bitmap)))
+        })
+    };
+
+    metrics
+        .iter()
+        .zip(global.into_iter().zip(filtered))
+        .map(|(metric, (global, filtered))| {
+            let check = format!("Derived metric {}", metric.text);
+            verifier.value(&check, "all docs", global, reference(None, &metric.expr)?);
+            verifier.value(&check, "filtered", filtered, reference(Some(bitmap), &metric.expr)?);
+            Ok(DerivedMetricReport { expr: metric.text.clone(), global, filtered })
+        })
+        .collect()
+}
+
+pub(crate) struct MultiRangeStats {
+    pub(crate) docs: u64,
+    pub(crate) time: Duration,
+}
+
+// Time a conjunction of range predicates on the field set, verifying the
+// matched docs against a scan of the doc-ordered columns
+pub(crate) fn bench_multi_range(set: &FieldIndexSet, fields: &[(&str, &[f64])], predicates: &[(&str, f64, f64)],
+                     iterations: usize, verifier: &Verifier) -> Result<MultiRangeStats, String> {
+    let mut times = Vec::with_capacity(iterations);
+    let mut matched = RoaringBitmap::new();
+    for _ in 0..iterations {
+        let start = Instant::now();
+        matched = set.query_multi_range(predicates)?;
+        times.push(start.elapsed());
+    }
+
+    let columns: Vec<&[f64]> = predicates
+        .iter()
+        .map(|(name, _, _)| fields.iter().find(|(field, _)| field == name).map(|&(_, values)| values))
+        .collect::<Option<_>>()
+        .ok_or("multi-range predicate on a field without a reference column")?;
+    let expected = RoaringBitmap::from_iter((0..columns[0].len() as u32).filter(|&doc_id| {
+        predicates
+            .iter()
+            .zip(&columns)
+            .all(|(&(_, min, max), column)| (min..=max).contains(&column[doc_id as usize]))
+    }));
+    verifier.count("Multi-field range", "docs", matched.len(), expected.len());
+    verifier.count("Multi-field range", "mismatched docs", (&matched ^ &expected).len(), 0);
+
+    Ok(MultiRangeStats { docs: matched.len(), time: average_duration(&times) })
+}
+
+pub(crate) struct ConjunctionStats {
+    pub(crate) docs: u64,
+    pub(crate) time: Duration,
+    pub(crate) plan: ConjunctionPlan,
+    pub(crate) estimates: Vec<EstimateReport>,
+}
+
+// A predicate's count estimate next to the docs it actually matches
+#[derive(Serialize)]
+pub(crate) struct EstimateReport {
+    pub(crate) predicate: String,
+    pub(crate) estimate: CountEstimate,
+    pub(crate) estimate_ns: u64,
+    pub(crate) actual: u64,
+}
+
+// Time a planned conjunction on the field set, verifying the matched docs
+// against a scan of the doc-ordered columns and the set's term columns
+pub(crate) fn bench_conjunction(set: &FieldIndexSet, fields: &[(&str, &[f64])], predicates: &[Predicate], iterations: usize,
+                     verifier: &Verifier) -> Result<ConjunctionStats, String> {
+    let mut times = Vec::with_capacity(iterations);
+    let (mut matched, mut plan) = (RoaringBitmap::new(), ConjunctionPlan { steps: Vec::new() });
+    for _ in 0..iterations {
+        let start = Instant::now();
+        (matched, plan) = set.query_conjunction(predicates)?;
+        times.push(start.elapsed());
+    }
+
+    let docs = fields.first().map_or(0, |(_, values)| values.len() as u32);
+    let mut expected = RoaringBitmap::new();
+    expected.insert_range(0..docs);
+    for predicate in predicates {
+        expected = match predicate {
+            Predicate::Range { field, min, max } => {
+                let (_, values) = fields
+                    .iter()
+                    .find(|(name, _)| name == field)
+                    .ok_or("conjunction predicate on a field without a reference column")?;
+                expected.iter().filter(|&doc_id| (*min..=*max).contains(&values[doc_id as usize])).collect()
+            }
+            Predicate::Term { field, term } => {
+                let column = set.term_field(field)?;
+                expected.iter().filter(|&doc_id| column.code(doc_id) == column.term_code(term)).collect()
+            }
+        };
+    }
+    verifier.count("Planned conjunction", "docs", matched.len(), expected.len());
+    verifier.count("Planned conjunction", "mismatched docs", (&matched ^ &expected).len(), 0);
+
+    // Estimates must match exactly when they claim to
+    let mut estimates = Vec::with_capacity(predicates.len());
+    for predicate in predicates {
+        let start = Instant::now();
+        let estimate = set.estimate_count(predicate)?;
+        let estimate_ns = start.elapsed().as_nanos() as u64;
+        let actual = set.predicate_doc_ids(predicate)?.len();
+        if estimate.exact {
+            verifier.count(&format!("Count estimate ({})", predicate), "docs", estimate.docs, actual);
+        }
+        estimates.push(EstimateReport { predicate: predicate.to_string(), estimate, estimate_ns, actual });
+    }
+
+    Ok(ConjunctionStats { docs: matched.len(), time: average_duration(&times), plan, estimates })
+}
+
+// Terms + stats timings, with the filtered buckets for the report
+pub(crate) struct TermsStatsTimes {
+    pub(crate) global_time: Duration,
+    pub(crate) filtered_time: Duration,
+    pub(crate) filtered: Vec<TermBucket>,
+}
+
+// Time terms_stats over all docs and under the filter bitmap, verifying each
+// term's aggregations against a scan of the reference
+pub(crate) fn bench_terms_stats(ait: &AggregationIndexTree, terms: &TermColumn, bitmap: &RoaringBitmap, iterations: usize,
+                     memory_limit: Option<usize>, reference: &ColumnarStorage, verifier: &Verifier)
+                     -> Result<TermsStatsTimes, AitError> {
+    let time = |filter: Option<&RoaringBitmap>, label: &str| -> Result<_, AitError> {
+        let mut times = Vec::with_capacity(iterations);
+        let mut buckets = Vec::new();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            buckets = ait.terms_stats(terms, filter, memory_limit)?;
+            times.push(start.elapsed());
+        }
+        let expected = reference.term_aggregations_naive(terms, filter);
+        let actual = ait.term_aggregations(terms, filter, 0..terms.terms.len())?;
+        for ((term, actual), expected) in terms.terms.iter().zip(&actual).zip(&expected) {
+            verifier.aggregations(&format!("AIT terms stats ({}, {})", label, term), actual, expected);
+        }
+        let expected_buckets = expected.iter().filter(|aggregations| aggregations.count > 0).count();
+        let check = format!("AIT terms stats ({})", label);
+        verifier.count(&check, "buckets", buckets.len() as u64, expected_buckets as u64);
+
+        // Streamed by doc count, the buckets come out as terms_stats sorts them;
+        // streamed by term, the same buckets in term order
+        let streamed: Vec<TermBucket> =
+            ait.terms_stats_stream(terms, filter, TermOrder::DocCount, memory_limit)?.collect();
+        let matching = streamed.iter().zip(&buckets)
+            .filter(|(streamed, bucket)| streamed.term == bucket.term && streamed.count == bucket.count).count();
+        let check = format!("AIT streamed terms stats ({})", label);
+        verifier.count(&check, "buckets in order", matching as u64, buckets.len() as u64);
+        verifier.count(&check, "buckets", streamed.len() as u64, expected_buckets as u64);
+        let by_term: Vec<TermBucket> = ait.terms_stats_stream(terms, filter, TermOrder::Term, memory_limit)?.collect();
+        let sorted = by_term.windows(2).filter(|pair| pair[0].term < pair[1].term).count() + 1;
+        verifier.count(&check, "buckets in term order", sorted as u64, expected_buckets.max(1) as u64);
+        Ok((average_duration(&times), buckets))
+    };
+    let (global_time, _) = time(None, "all docs")?;
+    let (filtered_time, filtered) = time(Some(bitmap), "filtered")?;
+    Ok(TermsStatsTimes { global_time, filtered_time, filtered })
+}
+
+// Save a field set to `dir` and load it back, verifying that the reopened set
+// matches the same docs for each predicate and for their conjunction.
+// Returns the save and load times.
+pub(crate) fn save_field_set(set: &FieldIndexSet, dir: &Path, predicates: &[Predicate], verifier: &Verifier)
+    -> Result<(Duration, Duration), String> {
+    let start = Instant::now();
+    set.save(dir).map_err(|err| err.to_string())?;
+    let save_time = start.elapsed();
+    let start = Instant::now();
+    let reopened = FieldIndexSet::load(dir).map_err(|err| err.to_string())?;
+    let load_time = start.elapsed();
+    for predicate in predicates {
+        let (expected, actual) = (set.predicate_doc_ids(predicate)?, reopened.predicate_doc_ids(predicate)?);
+        verifier.count(&format!("Reopened field set ({})", predicate), "mismatched docs", (&expected ^ &actual).len(), 0);
+    }
+    let (expected, actual) = (set.query_conjunction(predicates)?.0, reopened.query_conjunction(predicates)?.0);
+    verifier.count("Reopened field set conjunction", "mismatched docs", (&expected ^ &actual).len(), 0);
+    Ok((save_time, load_time))
+}
+
+// Time percentile_rank of `value` under the filter bitmap, verifying the rank
+// against a scan of the reference
+pub(crate) fn bench_percentile_rank(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, value: f64, iterations: usize,
+                         reference: &ColumnarStorage, verifier: &Verifier) -> (Option<f64>, Duration) {
+    let mut times = Vec::with_capacity(iterations);
+    let mut rank = None;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        rank = ait.percentile_rank(bitmap, value).ok().flatten();
+        times.push(start.elapsed());
+    }
+    verifier.value("AIT percentile rank", "rank", rank, reference.percentile_rank_naive(bitmap, value));
+    (rank, average_duration(&times))
+}
+
+// Time percentiles over all docs and under the filter bitmap, verifying both
+// against the sorted reference values
+pub(crate) fn bench_percentiles(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, ps: &[f64], iterations: usize,
+                                reference: &ColumnarStorage, verifier: &Verifier)
+    -> Vec<(&'static str, Duration, Vec<Option<f64>>)> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut values = Vec::new();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            values = ait.percentiles(ps, filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let expected = reference.percentiles_naive(ps, filter);
+        for ((p, &actual), &expected) in ps.iter().zip(&values).zip(&expected) {
+            verifier.value(&format!("AIT p{} ({})", p, label), "percentile", actual, expected);
+        }
+        results.push((label, average_duration(&times), values));
+    }
+    results
+}
+
+// Latency of approximate and exact percentiles under one filter, and the
+// largest relative error of the approximation
+pub(crate) struct ApproxPercentileComparison {
+    pub(crate) label: &'static str,
+    pub(crate) approx_time: Duration,
+    pub(crate) exact_time: Duration,
+    pub(crate) max_relative_error: f64,
+}
+
+// Time approx_percentiles against percentiles under each filter. Sketches are
+// approximate by design, so the error is reported rather than verified.
+pub(crate) fn bench_approx_percentiles(ait: &AggregationIndexTree, filters: &[(&'static str, Option<&RoaringBitmap>)],
+                                       ps: &[f64], iterations: usize) -> Vec<ApproxPercentileComparison> {
+    let mut comparisons = Vec::new();
+    for &(label, filter) in filters {
+        let (mut approx_times, mut exact_times) = (Vec::with_capacity(iterations), Vec::with_capacity(iterations));
+        let (mut approx, mut exact) = (Vec::new(), Vec::new());
+        for _ in 0..iterations {
+            let start = Instant::now();
+            approx = ait.approx_percentiles(ps, filter).unwrap_or_default();
+            approx_times.push(start.elapsed());
+            let start = Instant::now();
+            exact = ait.percentiles(ps, filter).unwrap_or_default();
+            exact_times.push(start.elapsed());
+        }
+        let max_relative_error = approx.iter().zip(&exact).filter_map(|(&approx, &exact)| {
+            let (approx, exact) = (approx?, exact?);
+            Some((approx - exact).abs() / exact.abs().max(f64::EPSILON))
+        }).fold(0.0, f64::max);
+        comparisons.push(ApproxPercentileComparison {
+            label,
+            approx_time: average_duration(&approx_times),
+            exact_time: average_duration(&exact_times),
+            max_relative_error,
+        });
+    }
+    comparisons
+}
+
+// Percentiles from query_histogram under one filter, with its latency, bucket
+// count and largest relative error against the exact percentiles
+pub(crate) struct HistogramResult {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) buckets: usize,
+    pub(crate) values: Vec<Option<f64>>,
+    pub(crate) max_relative_error: f64,
+}
+
+// Time query_histogram over all docs and under the filter bitmap, verifying the
+// doc count it holds; bucketed percentiles are reported, not verified
+pub(crate) fn bench_histograms(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, ps: &[f64], iterations: usize,
+                               reference: &ColumnarStorage, verifier: &Verifier) -> Vec<HistogramResult> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut histogram = LogHistogram::default();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            histogram = ait.query_histogram(filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let expected_count = match filter {
+            Some(bitmap) => reference.query_with_bitmap_naive(bitmap).count as u64,
+            None => reference.values.len() as u64,
+        };
+        verifier.count(&format!("AIT histogram ({})", label), "count", histogram.count(), expected_count);
+        let values: Vec<Option<f64>> = ps.iter().map(|&p| histogram.percentile(p)).collect();
+        let max_relative_error = values.iter().zip(reference.percentiles_naive(ps, filter)).filter_map(|(&actual, expected)| {
+            let (actual, expected) = (actual?, expected?);
+            Some((actual - expected).abs() / expected.abs().max(f64::EPSILON))
+        }).fold(0.0, f64::max);
+        results.push(HistogramResult {
+            label,
+            time: average_duration(&times),
+            buckets: histogram.buckets().len(),
+            values,
+            max_relative_error,
+        });
+    }
+    results
+}
+
+// Moments under one filter and the latency
+pub(crate) struct ExtendedStatsResult {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) moments: Moments,
+}
+
+// Time extended_stats over all docs and under the filter bitmap, verifying the
+// standard deviation, skewness and kurtosis against a two-pass scan of the
+// reference values
+pub(crate) fn bench_extended_stats(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, iterations: usize,
+                                   reference: &ColumnarStorage, verifier: &Verifier) -> Vec<ExtendedStatsResult> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut moments = Moments::default();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            moments = ait.extended_stats(filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let values: Vec<f64> = match filter {
+            Some(bitmap) => bitmap.iter().filter_map(|doc_id| reference.values.get(doc_id as usize).copied()).collect(),
+            None => reference.values.clone(),
+        };
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let central = |power: i32| values.iter().map(|value| (value - mean).powi(power)).sum::<f64>() / n;
+        let (m2, m3, m4) = (central(2), central(3), central(4));
+        let present = !values.is_empty();
+        let spread = present && m2 > 0.0;
+        let check = format!("AIT extended stats ({})", label);
+        verifier.count(&check, "count", moments.count as u64, values.len() as u64);
+        verifier.value(&check, "std dev", moments.std_dev(), present.then(|| m2.sqrt()));
+        verifier.value(&check, "skewness", moments.skewness(), spread.then(|| m3 / m2.powf(1.5)));
+        verifier.value(&check, "kurtosis", moments.kurtosis(), spread.then(|| m4 / (m2 * m2) - 3.0));
+        results.push(ExtendedStatsResult { label, time: average_duration(&times), moments });
+    }
+    results
+}
+
+// Sum of logarithms under one filter and the latency
+pub(crate) struct GeometricMeanResult {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) log_sum: LogSum,
+}
+
+// Time geometric_mean over all docs and under the filter bitmap, verifying
+// the counts and the mean against a scan of the reference values under the
+// same non-positive policy
+pub(crate) fn bench_geometric_mean(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, non_positive: NonPositive,
+                                   iterations: usize, reference: &ColumnarStorage, verifier: &Verifier)
+                                   -> Vec<GeometricMeanResult> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut log_sum = LogSum::default();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            log_sum = ait.geometric_mean(filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let values: Vec<f64> = match filter {
+            Some(bitmap) => bitmap.iter().filter_map(|doc_id| reference.values.get(doc_id as usize).copied()).collect(),
+            None => reference.values.clone(),
+        };
+        let logs: Vec<f64> = match non_positive {
+            NonPositive::Floor(floor) => values.iter().map(|value| value.max(floor).ln()).collect(),
+            _ => values.iter().filter(|&&value| value > 0.0).map(|value| value.ln()).collect(),
+        };
+        let expected = (!logs.is_empty()).then(|| (logs.iter().sum::<f64>() / logs.len() as f64).exp());
+        let check = format!("AIT geometric mean ({})", label);
+        verifier.count(&check, "count", log_sum.count as u64, logs.len() as u64);
+        verifier.count(&check, "skipped", log_sum.skipped as u64, (values.len() - logs.len()) as u64);
+        verifier.value(&check, "geometric mean", log_sum.geometric_mean(), expected);
+        results.push(GeometricMeanResult { label, time: average_duration(&times), log_sum });
+    }
+    results
+}
+
+// Approximate distinct ids under one filter, the exact count and the latency
+pub(crate) struct DistinctResult {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) approx: u64,
+    pub(crate) exact: u64,
+}
+
+impl DistinctResult {
+    pub(crate) fn relative_error(&self) -> f64 {
+        self.approx.abs_diff(self.exact) as f64 / self.exact.max(1) as f64
+    }
+}
+
+// Time approx_distinct over all docs and under the filter bitmap against an
+// exact count of the ids. Sketches are approximate by design, so the error
+// is reported rather than verified.
+pub(crate) fn bench_distinct(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, ids_by_doc: &[u64], iterations: usize)
+    -> Vec<DistinctResult> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut approx = 0;
+        for _ in 0..iterations {
+            let start = Instant::now();
+            approx = ait.approx_distinct(filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let exact = match filter {
+            Some(bitmap) => bitmap.iter().map(|doc_id| ids_by_doc[doc_id as usize]).collect::<std::collections::HashSet<_>>(),
+            None => ids_by_doc.iter().copied().collect(),
+        };
+        results.push(DistinctResult { label, time: average_duration(&times), approx, exact: exact.len() as u64 });
+    }
+    results
+}
+
+// Weighted sums under one filter and the latency
+pub(crate) struct WeightedResult {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) sums: WeightedSums,
+}
+
+// Time weighted_aggregations over all docs and under the filter bitmap,
+// verifying both against a scan of the reference values and the weights
+pub(crate) fn bench_weighted(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, weights_by_doc: &[f64],
+                             iterations: usize, reference: &ColumnarStorage, verifier: &Verifier)
+    -> Vec<WeightedResult> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut sums = WeightedSums::default();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            sums = ait.weighted_aggregations(filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let mut expected = WeightedSums::default();
+        let mut add = |doc_id: usize| expected.add_weighted(reference.values[doc_id], weights_by_doc[doc_id]);
+        match filter {
+            Some(bitmap) => bitmap.iter().for_each(|doc_id| add(doc_id as usize)),
+            None => (0..reference.values.len()).for_each(add),
+        }
+        let check = format!("AIT weighted aggregations ({})", label);
+        verifier.value(&check, "weight", Some(sums.weight), Some(expected.weight));
+        verifier.value(&check, "weighted sum", Some(sums.weighted_sum), Some(expected.weighted_sum));
+        results.push(WeightedResult { label, time: average_duration(&times), sums });
+    }
+    results
+}
+
+// Fixed-width buckets under one filter and the latency
+pub(crate) struct IntervalHistogramResult {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) buckets: Vec<IntervalBucket>,
+}
+
+// Time histogram over all docs and under the filter bitmap, verifying every
+// bucket against one built by scanning the reference values
+pub(crate) fn bench_interval_histogram(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, interval: f64,
+                                       iterations: usize, reference: &ColumnarStorage, verifier: &Verifier)
+    -> Vec<IntervalHistogramResult> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut buckets = Vec::new();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            buckets = ait.histogram(interval, filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let mut expected: BTreeMap<i64, NodeAggregations> = BTreeMap::new();
+        let mut add = |value: f64| expected.entry((value / interval).floor() as i64).or_default().add_value(value);
+        match filter {
+            Some(bitmap) => bitmap.iter().for_each(|doc_id| add(reference.values[doc_id as usize])),
+            None => reference.values.iter().for_each(|&value| add(value)),
+        }
+        let check = format!("AIT histogram ({})", label);
+        verifier.count(&check, "buckets", buckets.len() as u64, expected.len() as u64);
+        for (bucket, (&index, expected)) in buckets.iter().zip(&expected) {
+            let check = format!("{} bucket {}", check, index as f64 * interval);
+            verifier.value(&check, "key", Some(bucket.key), Some(index as f64 * interval));
+            verifier.count(&check, "count", bucket.aggregations.count as u64, expected.count as u64);
+            verifier.value(&check, "sum", Some(bucket.aggregations.sum), Some(expected.sum));
+            verifier.value(&check, "min", Some(bucket.aggregations.min_value), Some(expected.min_value));
+            verifier.value(&check, "max", Some(bucket.aggregations.max_value), Some(expected.max_value));
+        }
+        results.push(IntervalHistogramResult { label, time: average_duration(&times), buckets });
+    }
+    results
+}
+
+// Date buckets under one filter and the latency
+pub(crate) struct DateHistogramResult {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) buckets: Vec<DateBucket>,
+}
+
+// Time date_histogram over all docs and under the filter bitmap, verifying
+// every bucket against one built by scanning the timestamps and values
+pub(crate) fn bench_date_histogram(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, interval: DateInterval,
+                                   doc_timestamps: &[i64], iterations: usize, reference: &ColumnarStorage,
+                                   verifier: &Verifier) -> Vec<DateHistogramResult> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut buckets = Vec::new();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            buckets = ait.date_histogram(interval, filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let mut expected: BTreeMap<i64, NodeAggregations> = BTreeMap::new();
+        let mut add = |doc_id: usize| {
+            let key_ms = interval.bucket_start(doc_timestamps[doc_id]);
+            expected.entry(key_ms).or_default().add_value(reference.values[doc_id]);
+        };
+        match filter {
+            Some(bitmap) => bitmap.iter().for_each(|doc_id| add(doc_id as usize)),
+            None => (0..reference.values.len()).for_each(add),
+        }
+        let check = format!("AIT date histogram ({})", label);
+        verifier.count(&check, "buckets", buckets.len() as u64, expected.len() as u64);
+        for (bucket, (&key_ms, expected)) in buckets.iter().zip(&expected) {
+            let check = format!("{} bucket {}", check, key_ms);
+            verifier.value(&check, "key", Some(bucket.key_ms as f64), Some(key_ms as f64));
+            verifier.count(&check, "count", bucket.aggregations.count as u64, expected.count as u64);
+            verifier.value(&check, "sum", Some(bucket.aggregations.sum), Some(expected.sum));
+            verifier.value(&check, "min", Some(bucket.aggregations.min_value), Some(expected.min_value));
+            verifier.value(&check, "max", Some(bucket.aggregations.max_value), Some(expected.max_value));
+        }
+        results.push(DateHistogramResult { label, time: average_duration(&times), buckets });
+    }
+    results
+}
+
+// Time aggregate_between_quantiles over all docs and under the filter bitmap,
+// verifying both against the sorted reference values
+pub(crate) fn bench_between_quantiles(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, (q_low, q_high): (f64, f64),
+                                      iterations: usize, reference: &ColumnarStorage, verifier: &Verifier)
+    -> Vec<(&'static str, Duration, NodeAggregations)> {
+    let mut results = Vec::new();
+    for (label, filter) in [("all docs", None), ("filtered", Some(bitmap))] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut result = NodeAggregations::empty();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            result = ait.aggregate_between_quantiles(q_low, q_high, filter).unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        verifier.aggregations(&format!("AIT between quantiles ({})", label), &result,
+                              &reference.between_quantiles_naive(q_low, q_high, filter));
+        results.push((label, average_duration(&times), result));
+    }
+    results
+}
+
+// Docs from top_k or bottom_k with the query latency
+pub(crate) struct ExtremeDocs {
+    pub(crate) label: &'static str,
+    pub(crate) time: Duration,
+    pub(crate) docs: Vec<(u32, f64)>,
+}
+
+// Time top_k and bottom_k under the filter bitmap. Ties may pick different
+// docs than the reference, so the values are verified rank by rank, and each
+// returned doc must be selected and hold its value.
+pub(crate) fn bench_extremes(ait: &AggregationIndexTree, bitmap: &RoaringBitmap, k: usize, iterations: usize,
+                             reference: &ColumnarStorage, verifier: &Verifier)
+    -> Vec<ExtremeDocs> {
+    let mut selected: Vec<f64> = bitmap.iter().map(|doc_id| reference.values[doc_id as usize]).collect();
+    selected.sort_by(f64::total_cmp);
+    let mut results = Vec::new();
+    for (label, highest) in [("Top", true), ("Bottom", false)] {
+        let mut times = Vec::with_capacity(iterations);
+        let mut docs = Vec::new();
+        for _ in 0..iterations {
+            let start = Instant::now();
+            docs = if highest { ait.top_k(k, bitmap) } else { ait.bottom_k(k, bitmap) }.unwrap_or_default();
+            times.push(start.elapsed());
+        }
+        let expected: Vec<f64> = if highest {
+            selected.iter().rev().take(k).copied().collect()
+        } else {
+            selected.iter().take(k).copied().collect()
+        };
+        let check = format!("AIT {}-k", label.to_lowercase());
+        verifier.count(&check, "docs", docs.len() as u64, expected.len() as u64);
+        for (rank, (&(_, actual), &expected)) in docs.iter().zip(&expected).enumerate() {
+            verifier.value(&format!("{} rank {}", check, rank + 1), "value", Some(actual), Some(expected));
+        }
+        let wrong = docs.iter().filter(|&&(doc_id, value)| {
+            !bitmap.contains(doc_id) || reference.values.get(doc_id as usize) != Some(&value)
+        });
+        verifier.count(&check, "docs not selected or with other values", wrong.count() as u64, 0);
+        results.push(ExtremeDocs { label, time: average_duration(&times), docs });
+    }
+    results
+}
+
+// Time docs_with_value_in over `values`, verifying the matched docs against a
+// scan of the reference
+pub(crate) fn bench_value_membership(ait: &AggregationIndexTree, values: &[f64], iterations: usize,
+                                     reference: &ColumnarStorage, verifier: &Verifier) -> (u64, Duration) {
+    let mut times = Vec::with_capacity(iterations);
+    let mut docs = RoaringBitmap::new();
+    for _ in 0..iterations {
+        let start = Instant::now();
+        docs = ait.docs_with_value_in(values).unwrap_or_default();
+        times.push(start.elapsed());
+    }
+    let expected: RoaringBitmap = (0..reference.values.len() as u32)
+        .filter(|&doc_id| values.contains(&reference.values[doc_id as usize]))
+        .collect();
+    verifier.count("AIT value set membership", "docs", docs.len(), expected.len());
+    verifier.count("AIT value set membership", "docs outside the reference", (&docs - &expected).len(), 0);
+    (docs.len(), average_duration(&times))
+}
+
+pub(crate) fn print_query_results(title: &str, subjects: &[Box<dyn BenchSubject>], times: &[Option<Duration>]) {
+    report!("\n{}:", title);
+    for (subject, time) in subjects.iter().zip(times) {
+        match time {
+            Some(time) => report!("  {}: {:?}", subject.name(), time),
+            None => report!("  {}: n/a", subject.name()),
+        }
+    }
+    for (subject, &time) in subjects.iter().zip(times).skip(1) {
+        report!("  Speedup vs {}: {}", subject.name(), format_speedup(time, times[0]));
+    }
+}
+
+pub(crate) fn format_speedup(baseline: Option<Duration>, candidate: Option<Duration>) -> String {
+    match (baseline, candidate) {
+        (Some(baseline), Some(candidate)) => format!("{:.2}x", speedup(baseline, candidate)),
+        _ => "n/a".to_string(),
+    }
+}
+
+pub(crate) fn speedup(baseline: Duration, candidate: Duration) -> f64 {
+    baseline.as_nanos() as f64 / candidate.as_nanos() as f64
+}
+
+pub(crate) fn average_duration(durations: &[Duration]) -> Duration {
+    if durations.is_empty() {
+        return Duration::ZERO;
+    }
+    let total_nanos: u128 = durations.iter().map(|d| d.as_nanos()).sum();
+    Duration::from_nanos((total_nanos / durations.len() as u128) as u64)
+}
+
+// Timings for a partitioned build
+pub(crate) struct StitchStats {
+    pub(crate) build_time: Duration,
+    pub(crate) stitch_time: Duration,
+    // Partitions loaded from a checkpoint rather than built
+    pub(crate) resumed: usize,
+}
+
+// Partition the docs by value, build each partition's tree on the rayon pool,
+// stitch them and verify the stitched tree against the columnar values
+pub(crate) fn bench_stitch(reference: &ColumnarStorage, options: &BuildOptions, partitions: usize,
+                checkpoint: Option<&BuildCheckpoint>, bitmap: &RoaringBitmap,
+                (range_min, range_max): (f64, f64), verifier: &Verifier) -> Result<StitchStats, AitError> {
+    let start = Instant::now();
+    let mut values: Vec<(u32, f64)> = reference
+        .values
+        .iter()
+        .enumerate()
+        .map(|(doc_id, &value)| (doc_id as u32, value))
+        .collect();
+    values.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    let chunk_size = values.len().div_ceil(partitions.max(1)).max(1);
+    let (parts, resumed) = match checkpoint {
+        Some(checkpoint) => {
+            let chunks: Vec<&[(u32, f64)]> = values.chunks(chunk_size).collect();
+            checkpoint
+                .build_partitions(&chunks, options)
+                .unwrap_or_else(|err| panic!("Checkpointed build in {} failed: {}", checkpoint.dir.display(), err))
+        }
+        None => {
+            let parts = values
+                .par_chunks(chunk_size)
+                .map(|chunk| build_aggregation_index_tree(chunk.to_vec(), options))
+                .collect::<Result<_, _>>()?;
+            (parts, 0)
+        }
+    };
+    drop(values);
+    let build_time = start.elapsed();
+
+    let start = Instant::now();
+    let stitched = AggregationIndexTree::stitch(parts)?;
+    let stitch_time = start.elapsed();
+
+    verifier.aggregations("Stitched AIT", &stitched.get_global_aggregations(),
+                        &reference.get_global_aggregations());
+    verifier.aggregations("Stitched AIT", &stitched.query_value_range(range_min, range_max),
+                        &reference.query_value_range_naive(range_min, range_max));
+    if stitched.has_doc_ids {
+        verifier.aggregations("Stitched AIT", &stitched.query_with_bitmap(bitmap)?,
+                            &reference.query_with_bitmap_naive(bitmap));
+    }
+
+    Ok(StitchStats { build_time, stitch_time, resumed })
+}
+
+// Timings for building two half trees and merging them
+pub(crate) struct MergeStats {
+    pub(crate) build_time: Duration,
+    pub(crate) merge_time: Duration,
+}
+
+// Build trees over the first and second half of the doc ids, merge them and
+// verify the merged tree against the columnar values
+pub(crate) fn bench_merge(reference: &ColumnarStorage, options: &BuildOptions, bitmap: &RoaringBitmap,
+               verifier: &Verifier) -> Result<MergeStats, AitError> {
+    let doc_values = &reference.values;
+    let build_half = |docs: std::ops::Range<usize>| {
+        let mut values: Vec<(u32, f64)> = docs.map(|doc_id| (doc_id as u32, doc_values[doc_id])).collect();
+        values.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        build_aggregation_index_tree(values, options)
+    };
+
+    let start = Instant::now();
+    let middle = doc_values.len() / 2;
+    let first = build_half(0..middle)?;
+    let second = build_half(middle..doc_values.len())?;
+    let build_time = start.elapsed();
+
+    let start = Instant::now();
+    let merged = first.merge(&second)?;
+    let merge_time = start.elapsed();
+
+    verifier.aggregations("Merged AIT", &merged.get_global_aggregations(), &reference.get_global_aggregations());
+    if merged.has_doc_ids {
+        verifier.aggregations("Merged AIT", &merged.query_with_bitmap(bitmap)?,
+                            &reference.query_with_bitmap_naive(bitmap));
+    }
+
+    Ok(MergeStats { build_time, merge_time })
+}
+
+pub(crate) const INGEST_BATCH_SIZE: usize = 65_536;
+
+// Timings for filling a segmented index
+pub(crate) struct IngestStats {
+    pub(crate) insert_time: Duration,
+    pub(crate) insert_segments: usize,
+    // Unsorted batches under each merge policy kind
+    pub(crate) policies: Vec<PolicyIngestStats>,
+    pub(crate) sorted_batch_time: Duration,
+    // Final merge of the configured policy's segments into one
+    pub(crate) final_segments: usize,
+    pub(crate) final_merge_time: Duration,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PolicyIngestStats {
+    pub(crate) policy: MergeKind,
+    #[serde(rename = "time_ns", serialize_with = "serialize_nanos")]
+    pub(crate) time: Duration,
+    pub(crate) segments: usize,
+    pub(crate) merge_stats: SegmentMergeStats,
+}
+
+// Fill segmented indexes with every doc: one at a time, in batches in doc-id
+// order under each merge policy kind, and in the same batches pre-sorted by
+// value. Then merge the configured policy's segments into one. Every index
+// is verified against the columnar values.
+pub(crate) fn bench_ingest(reference: &ColumnarStorage, options: &BuildOptions, policy: MergePolicy, bitmap: &RoaringBitmap,
+                (range_min, range_max): (f64, f64), verifier: &Verifier) -> Result<IngestStats, AitError> {
+    let pairs: Vec<(u32, f64)> = reference
+        .values
+        .iter()
+        .enumerate()
+        .map(|(doc_id, &value)| (doc_id as u32, value))
+        .collect();
+    let check = |label: &str, index: &SegmentedIndex| -> Result<(), AitError> {
+        verifier.aggregations(label, &index.get_global_aggregations(), &reference.get_global_aggregations());
+        verifier.aggregations(label, &index.query_value_range(range_min, range_max),
+                              &reference.query_value_range_naive(range_min, range_max));
+        if options.store_doc_ids {
+            verifier.aggregations(label, &index.query_with_bitmap(bitmap)?,
+                                  &reference.query_with_bitmap_naive(bitmap));
+        }
+        Ok(())
+    };
+
+    let start = Instant::now();
+    let mut inserted = SegmentedIndex::new(*options, INGEST_BATCH_SIZE, policy);
+    for &(doc_id, value) in &pairs {
+        inserted.insert(doc_id, value)?;
+    }
+    let insert_time = start.elapsed();
+    check("Segmented index (inserts)", &inserted)?;
+    let insert_segments = inserted.segment_count();
+    drop(inserted);
+
+    let mut policies = Vec::new();
+    let mut configured = None;
+    for kind in [MergeKind::Tiered, MergeKind::Leveled] {
+        let start = Instant::now();
+        let mut batched = SegmentedIndex::new(*options, INGEST_BATCH_SIZE, MergePolicy { kind, ..policy });
+        for batch in pairs.chunks(INGEST_BATCH_SIZE) {
+            batched.ingest_batch(batch.to_vec())?;
+        }
+        let time = start.elapsed();
+        check(&format!("Segmented index ({} batches)", kind.name()), &batched)?;
+        policies.push(PolicyIngestStats {
+            policy: kind,
+            time,
+            segments: batched.segment_count(),
+            merge_stats: batched.merge_stats(),
+        });
+        if kind == policy.kind {
+            configured = Some(batched);
+        }
+    }
+
+    // Sorting happens outside the timing: this measures the sorted-run path
+    let sorted_batches: Vec<Vec<(u32, f64)>> = pairs
+        .chunks(INGEST_BATCH_SIZE)
+        .map(|batch| {
+            let mut batch = batch.to_vec();
+            batch.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+            batch
+        })
+        .collect();
+    let start = Instant::now();
+    let mut presorted = SegmentedIndex::new(*options, INGEST_BATCH_SIZE, policy);
+    for batch in sorted_batches {
+        presorted.ingest_batch(batch)?;
+    }
+    let sorted_batch_time = start.elapsed();
+    check("Segmented index (sorted batches)", &presorted)?;
+    drop(presorted);
+
+    let mut batched = configured.expect("configured merge kind is benchmarked");
+    let final_segments = batched.segment_count();
+    let start = Instant::now();
+    batched.merge_segments()?;
+    let final_merge_time = start.elapsed();
+    check("Segmented index (merged)", &batched)?;
+
+    Ok(IngestStats {
+        insert_time,
+        insert_segments,
+        policies,
+        sorted_batch_time,
+        final_segments,
+        final_merge_time,
+    })
+}
+
+// Timings for persisting and reopening a segmented index
+pub(crate) struct SegmentStoreStats {
+    pub(crate) segments: usize,
+    pub(crate) persist_time: Duration,
+    pub(crate) merged_persist_time: Duration,
+    pub(crate) open_time: Duration,
+    pub(crate) reader_generation: u64,
+    pub(crate) refresh_time: Duration,
+}
+
+// Ingest every doc in batches, persist the segments, merge them into one and
+// persist again (which removes the replaced segments), then reopen the
+// directory and verify it. A mapped reader opened before the merge follows
+// the writer to the merged generation.
+pub(crate) fn bench_segment_store(reference: &ColumnarStorage, options: &BuildOptions, policy: MergePolicy,
+                       bitmap: &RoaringBitmap, (range_min, range_max): (f64, f64), dir: &Path,
+                       verifier: &Verifier) -> io::Result<SegmentStoreStats> {
+    let mut index = SegmentedIndex::new(*options, INGEST_BATCH_SIZE, policy);
+    for (batch_idx, batch) in reference.values.chunks(INGEST_BATCH_SIZE).enumerate() {
+        let first_doc = batch_idx * INGEST_BATCH_SIZE;
+        let pairs = batch.iter().enumerate().map(|(offset, &value)| ((first_doc + offset) as u32, value)).collect();
+        index.ingest_batch(pairs).map_err(io::Error::other)?;
+    }
+    let segments = index.segment_count();
+
+    let start = Instant::now();
+    index.persist(dir)?;
+    let persist_time = start.elapsed();
+    let mut reader = SegmentReader::open(dir)?;
+    verifier.aggregations("Mapped segment reader", &reader.get_global_aggregations(),
+                          &reference.get_global_aggregations());
+
+    let start = Instant::now();
+    index.merge_segments().map_err(io::Error::other)?;
+    index.persist(dir)?;
+    let merged_persist_time = start.elapsed();
+    drop(index);
+
+    // The reader still serves the segments the merge replaced: they are
+    // unlinked but stay mapped until the refresh
+    verifier.aggregations("Mapped segment reader (stale)", &reader.query_value_range(range_min, range_max),
+                          &reference.query_value_range_naive(range_min, range_max));
+    let start = Instant::now();
+    reader.refresh()?;
+    let refresh_time = start.elapsed();
+    let reader_generation = reader.generation().unwrap_or(0);
+    verifier.count("Mapped segment reader", "segments", reader.segment_count() as u64, 1);
+    verifier.aggregations("Mapped segment reader", &reader.query_value_range(range_min, range_max),
+                          &reference.query_value_range_naive(range_min, range_max));
+    if options.store_doc_ids {
+        verifier.aggregations("Mapped segment reader", &reader.query_with_bitmap(bitmap).map_err(io::Error::other)?,
+                              &reference.query_with_bitmap_naive(bitmap));
+    }
+
+    let start = Instant::now();
+    let reopened = SegmentedIndex::open(dir, *options, INGEST_BATCH_SIZE, policy)?;
+    let open_time = start.elapsed();
+    verifier.aggregations("Reopened segmented index", &reopened.get_global_aggregations(),
+                          &reference.get_global_aggregations());
+    verifier.count("Reopened segmented index", "segments", reopened.segment_count() as u64, 1);
+
+    Ok(SegmentStoreStats { segments, persist_time, merged_persist_time, open_time, reader_generation, refresh_time })
+}
+
+// Outcome of ingesting with background maintenance
+pub(crate) struct MaintenanceStats {
+    pub(crate) ingest_time: Duration,
+    pub(crate) segments_at_ingest_end: usize,
+    pub(crate) segments_at_stop: usize,
+    pub(crate) tasks: Vec<TaskStats>,
+}
+
+// Insert every doc into a segmented index that defers merges, with flush and
+// compaction tasks running in the background. The scheduler is paused for
+// the second half of the ingest to exercise pause and resume, then left to
+// catch up before it is stopped and the index verified.
+pub(crate) fn bench_maintenance(reference: &ColumnarStorage, options: &BuildOptions, policy: MergePolicy,
+                     verifier: &Verifier) -> Result<MaintenanceStats, AitError> {
+    const INSERT_CHUNK: usize = 4096;
+    let index = Arc::new(RwLock::new(SegmentedIndex::new(*options, INGEST_BATCH_SIZE, policy).defer_merges()));
+    let scheduler = MaintenanceScheduler::start(vec![
+        Box::new(FlushTask { index: Arc::clone(&index), interval: Duration::from_millis(50) }),
+        Box::new(CompactionTask { index: Arc::clone(&index), interval: Duration::from_millis(10) }),
+    ]);
+
+    let start = Instant::now();
+    let middle = reference.values.len() / 2;
+    for (chunk_idx, chunk) in reference.values.chunks(INSERT_CHUNK).enumerate() {
+        let first_doc = chunk_idx * INSERT_CHUNK;
+        if first_doc >= middle && first_doc < middle + INSERT_CHUNK {
+            scheduler.pause();
+        }
+        let mut index = index.write().expect("segmented index lock poisoned");
+        for (offset, &value) in chunk.iter().enumerate() {
+            index.insert((first_doc + offset) as u32, value)?;
+        }
+    }
+    let ingest_time = start.elapsed();
+    let segments_at_ingest_end = index.read().expect("segmented index lock poisoned").segment_count();
+
+    // Let the background tasks catch up on the paused half
+    scheduler.resume();
+    sleep(Duration::from_millis(200));
+    let tasks = scheduler.shutdown();
+
+    let index = index.read().expect("segmented index lock poisoned");
+    verifier.aggregations("Segmented index (background maintenance)", &index.get_global_aggregations(),
+                          &reference.get_global_aggregations());
+    Ok(MaintenanceStats {
+        ingest_time,
+        segments_at_ingest_end,
+        segments_at_stop: index.segment_count(),
+        tasks,
+    })
+}
+
+// Latency distribution of a run of queries
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct LatencyPercentiles {
+    pub(crate) queries: usize,
+    #[serde(rename = "p50_ns", serialize_with = "serialize_nanos")]
+    pub(crate) p50: Duration,
+    #[serde(rename = "p99_ns", serialize_with = "serialize_nanos")]
+    pub(crate) p99: Duration,
+    #[serde(rename = "max_ns", serialize_with = "serialize_nanos")]
+    pub(crate) max: Duration,
+}
+
+impl LatencyPercentiles {
+    pub(crate) fn from_times(mut times: Vec<Duration>) -> Self {
+        if times.is_empty() {
+            return LatencyPercentiles::default();
+        }
+        times.sort_unstable();
+        let at = |quantile: f64| times[((times.len() - 1) as f64 * quantile).round() as usize];
+        LatencyPercentiles { queries: times.len(), p50: at(0.5), p99: at(0.99), max: times[times.len() - 1] }
+    }
+}
+
+impl std::fmt::Display for LatencyPercentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} queries, p50 {:?}, p99 {:?}, max {:?}", self.queries, self.p50, self.p99, self.max)
+    }
+}
+
+// Outcome of querying a segmented index while a background task compacts it
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ConcurrentCompactionStats {
+    #[serde(rename = "ingest_ns", serialize_with = "serialize_nanos")]
+    pub(crate) ingest_time: Duration,
+    pub(crate) segments_before: usize,
+    pub(crate) segments_after: usize,
+    #[serde(rename = "compaction_ns", serialize_with = "serialize_nanos")]
+    pub(crate) compaction_time: Duration,
+    pub(crate) merge_stats: SegmentMergeStats,
+    pub(crate) task: TaskStats,
+    pub(crate) before: LatencyPercentiles,
+    pub(crate) during: LatencyPercentiles,
+    pub(crate) after: LatencyPercentiles,
+}
+
+// Ingest every doc in small batches into a segmented index that defers its
+// merges, leaving many segments behind. Then time value-range queries on it
+// before, while and after a background compaction task merges it down. The
+// compaction counts as done on its first idle run after one that merged.
+pub(crate) fn bench_concurrent_compaction(reference: &ColumnarStorage, options: &BuildOptions, policy: MergePolicy,
+                               (range_min, range_max): (f64, f64), verifier: &Verifier)
+                               -> Result<ConcurrentCompactionStats, AitError> {
+    const BATCH: usize = 4096;
+    const IDLE_QUERIES: usize = 200;
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    let index = Arc::new(RwLock::new(SegmentedIndex::new(*options, BATCH, policy).defer_merges()));
+
+    let start = Instant::now();
+    for (batch_idx, batch) in reference.values.chunks(BATCH).enumerate() {
+        let first_doc = batch_idx * BATCH;
+        let pairs = batch.iter().enumerate().map(|(offset, &value)| ((first_doc + offset) as u32, value)).collect();
+        index.write().expect("segmented index lock poisoned").ingest_batch(pairs)?;
+    }
+    let ingest_time = start.elapsed();
+    let segments_before = index.read().expect("segmented index lock poisoned").segment_count();
+
+    let expected_range = reference.query_value_range_naive(range_min, range_max);
+    let query = |times: &mut Vec<Duration>| {
+        let start = Instant::now();
+        let result = index.read().expect("segmented index lock poisoned").query_value_range(range_min, range_max);
+        times.push(start.elapsed());
+        result
+    };
+    let idle_queries = |label: &str| {
+        let mut times = Vec::with_capacity(IDLE_QUERIES);
+        for _ in 0..IDLE_QUERIES {
+            query(&mut times);
+        }
+        verifier.aggregations(label, &query(&mut times), &expected_range);
+        LatencyPercentiles::from_times(times)
+    };
+    let before = idle_queries("Segmented index before compaction (range)");
+
+    let scheduler = MaintenanceScheduler::start(vec![
+        Box::new(CompactionTask { index: Arc::clone(&index), interval: Duration::from_millis(10) }),
+    ]);
+    let start = Instant::now();
+    let mut times = Vec::new();
+    loop {
+        let result = query(&mut times);
+        verifier.aggregations("Segmented index during compaction (range)", &result, &expected_range);
+        let stats = &scheduler.stats()[0];
+        let finished = stats.busy_runs > 0 && stats.runs > stats.busy_runs + stats.errors;
+        if finished || stats.errors > 0 || start.elapsed() > TIMEOUT {
+            break;
+        }
+    }
+    let compaction_time = start.elapsed();
+    let during = LatencyPercentiles::from_times(times);
+    let task = scheduler.shutdown().remove(0);
+
+    let after = idle_queries("Segmented index after compaction (range)");
+    let index = index.read().expect("segmented index lock poisoned");
+    verifier.aggregations("Segmented index after compaction", &index.get_global_aggregations(),
+                          &reference.get_global_aggregations());
+    Ok(ConcurrentCompactionStats {
+        ingest_time,
+        segments_before,
+        segments_after: index.segment_count(),
+        compaction_time,
+        merge_stats: index.merge_stats(),
+        task,
+        before,
+        during,
+        after,
+    })
+}
+
+// Outcome of compacting the tree after deletes
+pub(crate) struct CompactionStats {
+    pub(crate) time: Duration,
+    pub(crate) memory_before: usize,
+    pub(crate) memory_after: usize,
+}
+
+// Compact the tree with `deleted` removed, checking the surviving aggregations
+// against the columnar values and that remapped ids still resolve to their values
+pub(crate) fn bench_compaction(ait: &AggregationIndexTree, deleted: &RoaringBitmap, doc_values: &[f64],
+                    verifier: &Verifier) -> Result<CompactionStats, AitError> {
+    let start = Instant::now();
+    let (compacted, remap) = ait.compact(deleted)?;
+    let time = start.elapsed();
+
+    let mut expected = NodeAggregations::empty();
+    for (doc_id, &value) in doc_values.iter().enumerate() {
+        if !deleted.contains(doc_id as u32) {
+            expected.add_value(value);
+        }
+    }
+    verifier.aggregations("Compacted AIT", &compacted.get_global_aggregations(), &expected);
+
+    // Deleted docs must have no new id, and survivors must keep their value
+    for old_id in (0..doc_values.len() as u32).step_by(997) {
+        let value = remap
+            .new_id(old_id)
+            .map(|new_id| compacted.get_value_at_position(compacted.doc_id_map[&new_id]));
+        let expected = (!deleted.contains(old_id)).then(|| doc_values[old_id as usize]);
+        verifier.value(&format!("Compacted AIT doc {}", old_id), "value", value, expected);
+    }
+
+    Ok(CompactionStats {
+        time,
+        memory_before: ait.dynamic_usage(),
+        memory_after: compacted.dynamic_usage(),
+    })
+}
+
+pub(crate) struct ValueOrderStats {
+    pub(crate) build_time: Duration,
+    pub(crate) translate_time: Duration,
+    // Repeated translations of the random filter
+    pub(crate) cached_translate_time: Duration,
+    pub(crate) cache_hits: u64,
+    pub(crate) cache_misses: u64,
+    pub(crate) original_bitmap: String,
+    pub(crate) remapped_bitmap: String,
+    pub(crate) original_time: Duration,
+    pub(crate) remapped_time: Duration,
+    pub(crate) random_original_time: Duration,
+    pub(crate) random_remapped_time: Duration,
+}
+
+// Rebuild the tree with value-ordered doc ids and compare filter queries over
+// the original and the translated ids: a filter selecting the docs in a value
+// range, which becomes a single id run, and the random filter, whose repeated
+// translations go through the cache
+pub(crate) fn bench_value_ordered_ids(ait: &AggregationIndexTree, reference: &ColumnarStorage, random_filter: &RoaringBitmap,
+                           (min, max): (f64, f64), cache: &Arc<TranslationCache>, iterations: usize,
+                           verifier: &Verifier) -> Result<ValueOrderStats, AitError> {
+    let mut values: Vec<(u32, f64)> =
+        reference.values.iter().enumerate().map(|(doc_id, &value)| (doc_id as u32, value)).collect();
+    values.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    let start = Instant::now();
+    let (remapped, mapping) = build_value_ordered_tree(values, &ait.metadata.config)?;
+    let build_time = start.elapsed();
+
+    let range_filter: RoaringBitmap = reference
+        .values
+        .iter()
+        .enumerate()
+        .filter(|&(_, &value)| value >= min && value <= max)
+        .map(|(doc_id, _)| doc_id as u32)
+        .collect();
+    let start = Instant::now();
+    let remapped_range_filter = mapping.translate(&range_filter);
+    let translate_time = start.elapsed();
+    let remapped_random_filter = mapping.translate(random_filter);
+    let mut cached_times = Vec::with_capacity(iterations);
+    for i in 0..iterations + 1 {
+        let start = Instant::now();
+        let cached = cache.get_or_translate(random_filter, |filter| mapping.translate(filter));
+        // The first lookup fills the cache
+        if i > 0 {
+            cached_times.push(start.elapsed());
+        }
+        verifier.count("Translation cache", "differing ids", (&*cached ^ &remapped_random_filter).len(), 0);
+    }
+    // An eviction sweep keeps the entry the lookups used; the next one, with
+    // no lookups in between, evicts it
+    let cache_hits = cache.hits();
+    let cache_misses = cache.misses();
+    let mut eviction = CacheEvictionTask::new(Arc::clone(cache), Duration::ZERO);
+    for expect_evicted in [false, cache.capacity > 0] {
+        let evicted = eviction.run() == Ok(true);
+        verifier.count("Translation cache eviction", "sweeps evicting", u64::from(evicted), u64::from(expect_evicted));
+    }
+
+    // Translation must round-trip
+    for doc_id in range_filter.iter().step_by(997) {
+        let round_trip = mapping.internal_id(doc_id).and_then(|internal| mapping.external_id(internal));
+        verifier.value(&format!("Value-ordered id of doc {}", doc_id), "id", round_trip.map(f64::from),
+                       Some(doc_id as f64));
+    }
+
+    let expected_range = reference.query_with_bitmap_naive(&range_filter);
+    let expected_random = reference.query_with_bitmap_naive(random_filter);
+    let mut times = [const { Vec::new() }; 4];
+    for i in 0..iterations {
+        let runs = [
+            (&range_filter, ait, &expected_range, "Range filter, original ids"),
+            (&remapped_range_filter, &remapped, &expected_range, "Range filter, value-ordered ids"),
+            (random_filter, ait, &expected_random, "Random filter, original ids"),
+            (&remapped_random_filter, &remapped, &expected_random, "Random filter, value-ordered ids"),
+        ];
+        for ((filter, tree, expected, label), times) in runs.into_iter().zip(&mut times) {
+            let start = Instant::now();
+            let result = tree.query_with_bitmap(filter)?;
+            times.push(start.elapsed());
+            if i == 0 {
+                verifier.aggregations(label, &result, expected);
+            }
+        }
+    }
+
+    Ok(ValueOrderStats {
+        build_time,
+        translate_time,
+        cached_translate_time: average_duration(&cached_times),
+        cache_hits,
+        cache_misses,
+        original_bitmap: describe_bitmap(&range_filter),
+        remapped_bitmap: describe_bitmap(&remapped_range_filter),
+        original_time: average_duration(&times[0]),
+        remapped_time: average_duration(&times[1]),
+        random_original_time: average_duration(&times[2]),
+        random_remapped_time: average_duration(&times[3]),
+    })
+}
+
+// Doc-range sum timings per AggIndex backend, and the Fenwick tree's point updates
+pub(crate) struct FenwickTimes {
+    pub(crate) doc_range_sums: Vec<(&'static str, Duration)>,
+    pub(crate) update: Duration,
+}
+
+// Time doc-range sums through the AggIndex backends, the Fenwick tree among
+// them, each checked against a slice scan. Then time random point updates on
+// the Fenwick tree, checking the maintained total afterwards. The AIT takes
+// part when it has doc ids. Returns None for an empty column.
+pub(crate) fn bench_fenwick(values: &[f64], options: &BuildOptions, ait: Option<&AggregationIndexTree>,
+                 iterations: usize, rng: &mut impl Rng, verifier: &Verifier) -> Option<FenwickTimes> {
+    const UPDATES: usize = 10_000;
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut fenwick = FenwickSums::new(values.to_vec());
+    let sampled = SampledIndex::new(values.to_vec(), options);
+    let zone_map = ZoneMapColumn::new(values.to_vec());
+    let mut backends: Vec<&dyn AggIndex> = vec![&fenwick, &sampled, &zone_map];
+    backends.extend(ait.filter(|ait| ait.has_doc_ids).map(|ait| ait as &dyn AggIndex));
+    let mut query_times = vec![Vec::with_capacity(iterations); backends.len()];
+    for _ in 0..iterations {
+        let start_doc = rng.gen_range(0..values.len());
+        let end_doc = rng.gen_range(start_doc..=values.len());
+        let docs = start_doc as u32..end_doc as u32;
+        let expected: f64 = values[start_doc..end_doc].iter().sum();
+        for (backend, times) in backends.iter().zip(&mut query_times) {
+            let start = Instant::now();
+            let sum = backend.metric_in_doc_range(docs.clone(), Metric::Sum);
+            times.push(start.elapsed());
+            let sum = sum.expect("every backend keeps sums").unwrap_or(0.0);
+            verifier.value(&format!("{} doc-range", backend.name()), "sum", Some(sum), Some(expected));
+        }
+    }
+    let doc_range_sums = backends
+        .iter()
+        .zip(&query_times)
+        .map(|(backend, times)| (backend.name(), average_duration(times)))
+        .collect();
+
+    let start = Instant::now();
+    for _ in 0..UPDATES {
+        let doc_id = rng.gen_range(0..values.len()) as u32;
+        fenwick.update(doc_id, rng.gen_range(50.0..20480.0)).expect("doc id drawn from the indexed docs");
+    }
+    let update = start.elapsed() / UPDATES as u32;
+
+    let total = fenwick.prefix_sum(values.len() as u32);
+    let expected: f64 = fenwick.values.iter().sum();
+    verifier.value("Fenwick after updates", "sum", Some(total), Some(expected));
+
+    Some(FenwickTimes { doc_range_sums, update })
+}
+
+// Timings for counting docs in a value range within a doc-id range
+pub(crate) struct RankRangeTimes {
+    pub(crate) wavelet: Duration,
+    // None when the tree has no doc ids
+    pub(crate) ait: Option<Duration>,
+    pub(crate) scan: Duration,
+}
+
+// Count docs in a value range within a doc-id range with the wavelet matrix,
+// the AIT and a columnar scan, checking all three agree
+pub(crate) fn bench_rank_range(wavelet: &WaveletMatrix, ait: Option<&AggregationIndexTree>, doc_values: &[f64],
+                    (min, max): (f64, f64), docs: std::ops::Range<u32>, iterations: usize,
+                    verifier: &Verifier) -> RankRangeTimes {
+    let mut wavelet_times = Vec::with_capacity(iterations);
+    let mut ait_times = Vec::with_capacity(iterations);
+    let mut scan_times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let expected = doc_values[docs.start as usize..docs.end as usize]
+            .iter()
+            .filter(|&&value| value >= min && value <= max)
+            .count();
+        scan_times.push(start.elapsed());
+
+        let start = Instant::now();
+        let count = wavelet.count_value_range_in_docs(min, max, docs.clone());
+        wavelet_times.push(start.elapsed());
+        // Values sharing a bucket make the count approximate
+        if wavelet.exact {
+            verifier.count("Wavelet matrix doc-range", "count", count as u64, expected as u64);
+        }
+
+        let start = Instant::now();
+        if let Some(Ok(count)) = ait.map(|ait| ait.count_value_range_in_docs(min, max, docs.clone())) {
+            ait_times.push(start.elapsed());
+            verifier.count("AIT doc-range", "count", count as u64, expected as u64);
+        }
+    }
+
+    RankRangeTimes {
+        wavelet: average_duration(&wavelet_times),
+        ait: (!ait_times.is_empty()).then(|| average_duration(&ait_times)),
+        scan: average_duration(&scan_times),
+    }
+}
+
+// Time a time-window query on the segment tree and as a columnar scan over the
+// doc timestamps, verifying the tree against the scan
+pub(crate) fn bench_time_window(tree: &TimeSegmentTree, doc_timestamps: &[i64], doc_values: &[f64], (from, to): (i64, i64),
+                     iterations: usize, verifier: &Verifier) -> (Duration, Duration) {
+    let mut tree_times = Vec::with_capacity(iterations);
+    let mut scan_times = Vec::with_capacity(iterations);
+    for i in 0..iterations {
+        let start = Instant::now();
+        let result = tree.query_time_range(from, to);
+        tree_times.push(start.elapsed());
+
+        let start = Instant::now();
+        let mut expected = NodeAggregations::empty();
+        for (&timestamp, &value) in doc_timestamps.iter().zip(doc_values) {
+            if timestamp >= from && timestamp <= to {
+                expected.add_value(value);
+            }
+        }
+        scan_times.push(start.elapsed());
+
+        if i == 0 {
+            verifier.aggregations("Time segment tree", &result, &expected);
+        }
+    }
+    (average_duration(&tree_times), average_duration(&scan_times))
+}
+
+pub(crate) struct TimePartitionStats {
+    pub(crate) partitions: usize,
+    pub(crate) allocation_time: Duration,
+    pub(crate) arrival_bitmap: String,
+    pub(crate) partitioned_bitmap: String,
+    pub(crate) arrival_time: Duration,
+    pub(crate) partitioned_time: Duration,
+    pub(crate) expired_partitions: usize,
+    pub(crate) expired_docs: u64,
+    pub(crate) expiry_time: Duration,
+}
+
+// Compare a time-window filter over arrival-order doc ids with the same filter
+// over time-partitioned ids, each answered by a zone map over its doc order,
+// and check which docs a TTL expiring at the window start would drop, and
+// that an expiry task drops them from a segmented index
+pub(crate) fn bench_time_partitioned_ids(doc_timestamps: &[i64], doc_values: &[f64], (from, to): (i64, i64),
+                              partition_ms: i64, iterations: usize, verifier: &Verifier)
+    -> Result<TimePartitionStats, String> {
+    let start = Instant::now();
+    let (allocator, allocated) = TimePartitionedIds::allocate(doc_timestamps, partition_ms);
+    let allocation_time = start.elapsed();
+
+    let mut partitioned_values = vec![0.0; doc_values.len()];
+    for (&id, &value) in allocated.iter().zip(doc_values) {
+        partitioned_values[id as usize] = value;
+    }
+    let arrival_column = ZoneMapColumn::new(doc_values.to_vec());
+    let partitioned_column = ZoneMapColumn::new(partitioned_values);
+
+    let mut expected = NodeAggregations::empty();
+    let mut arrival_ids = RoaringBitmap::new();
+    for (doc_id, (&timestamp, &value)) in doc_timestamps.iter().zip(doc_values).enumerate() {
+        if timestamp >= from && timestamp <= to {
+            expected.add_value(value);
+            arrival_ids.insert(doc_id as u32);
+        }
+    }
+    let partitioned_ids = allocator.ids_in_time_range(from, to);
+    verifier.count("Time-partitioned ids", "window docs", partitioned_ids.len(), arrival_ids.len());
+
+    let mut arrival_times = Vec::with_capacity(iterations);
+    let mut partitioned_times = Vec::with_capacity(iterations);
+    for i in 0..iterations {
+        let start = Instant::now();
+        let arrival = arrival_column.query_with_bitmap(&arrival_ids);
+        arrival_times.push(start.elapsed());
+
+        let start = Instant::now();
+        let partitioned = partitioned_column.query_with_bitmap(&partitioned_ids);
+        partitioned_times.push(start.elapsed());
+
+        if i == 0 {
+            verifier.aggregations("Zone map, arrival ids", &arrival, &expected);
+            verifier.aggregations("Zone map, time-partitioned ids", &partitioned, &expected);
+        }
+    }
+
+    let (expired_partitions, expired) = allocator.expired_ids(from);
+    let partition_ms = partition_ms.max(1);
+    let expected_expired = doc_timestamps
+        .iter()
+        .filter(|&&timestamp| timestamp.div_euclid(partition_ms) * partition_ms + partition_ms <= from)
+        .count();
+    verifier.count("Time-partitioned ids", "expired docs", expired.len(), expected_expired as u64);
+
+    let mut index = SegmentedIndex::new(BuildOptions::default(), INGEST_BATCH_SIZE, MergePolicy::default());
+    index.ingest_batch(allocated.iter().copied().zip(doc_values.iter().copied()).collect())
+        .map_err(|err| err.to_string())?;
+    let index = Arc::new(RwLock::new(index));
+    let mut expiry = ExpiryTask::new(Arc::clone(&index), Arc::new(allocator), Duration::ZERO, Duration::ZERO,
+                                     Box::new(move || from));
+    let start = Instant::now();
+    expiry.run()?;
+    let expiry_time = start.elapsed();
+    let remaining = index.read().expect("segmented index lock poisoned").get_global_aggregations().count;
+    verifier.count("Expiry task", "remaining docs", remaining as u64, (doc_values.len() - expected_expired) as u64);
+    let allocator = &expiry.ids;
+
+    Ok(TimePartitionStats {
+        partitions: allocator.partitions.len(),
+        allocation_time,
+        arrival_bitmap: describe_bitmap(&arrival_ids),
+        partitioned_bitmap: describe_bitmap(&partitioned_ids),
+        arrival_time: average_duration(&arrival_times),
+        partitioned_time: average_duration(&partitioned_times),
+        expired_partitions,
+        expired_docs: expired.len(),
+        expiry_time,
+    })
+}
+
+// Print what a saved index contains. Split-layout indexes only need their
+// metadata file; legacy single files are loaded in full.
+pub(crate) fn print_index_stats(path: &Path, format: OutputFormat) -> io::Result<()> {
+    let manifest = if path.is_dir() {
+        IndexManifest::read(path)?
+    } else {
+        AggregationIndexTree::load(path)?.manifest()
+    };
+    match format {
+        OutputFormat::Table => {}
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&manifest)?);
+            return Ok(());
+        }
+        OutputFormat::Quiet => return Ok(()),
+    }
+    let metadata = &manifest.metadata;
+    let global = manifest.global;
+    println!("Index: {}", path.display());
+    println!("- Source: {}", metadata.source);
+    println!("- Field: {}", metadata.field);
+    println!("- Rows: {}", format_count(metadata.row_count));
+    println!("- Built at: {}", metadata.built_at);
+    println!("- Crate version: {}", metadata.crate_version);
+    println!("- Leaf size: {}", metadata.config.leaf_size);
+    println!("- Store doc ids: {}", manifest.has_doc_ids);
+    println!("- Keep doc-order values: {}", metadata.config.keep_doc_values);
+    println!("- Position map: {}", metadata.config.position_map);
+    println!("- Value runs: {}", metadata.config.value_runs);
+    println!("- Node digests: {}", metadata.config.digests);
+    println!("- Node histograms: {}", metadata.config.histograms);
+    println!("- Node moments: {}", metadata.config.extended_stats);
+    println!("- Node log sums: {} (non-positive: {})", metadata.config.geometric_mean, metadata.config.non_positive);
+    println!("- Nodes: {} ({} leaves)", format_count(manifest.node_count),
+             format_count(manifest.leaf_count));
+    println!("- Global: min={}, max={}, sum={}, count={}",
+             global.min_value, global.max_value, global.sum, format_count(global.count as u64));
+    if let Some(statistics) = &metadata.statistics {
+        println!("- Statistics: {}", statistics);
+    }
+    if path.is_dir() {
+        let filters = FilterStore::for_index(path);
+        let mut listed = Vec::new();
+        for name in filters.names()? {
+            listed.push(format!("{} ({} docs)", name, format_count(filters.load(&name)?.len())));
+        }
+        if !listed.is_empty() {
+            println!("- Saved filters: {}", listed.join(", "));
+        }
+    }
+    let field_set = path.join(INDEX_FIELD_SET_DIR);
+    if field_set.join(FIELD_SET_MANIFEST_FILE).exists() {
+        let fields = FieldSetManifest::read(&field_set)?;
+        let listed: Vec<String> = fields.fields.iter().map(|field| format!("{} ({})", field.name, field.kind)).collect();
+        println!("- Field set: {}; term fields: {}", listed.join(", "), fields.terms.join(", "));
+    }
+    Ok(())
+}
+
+// Aggregate a saved index over all docs or a filter saved with it
+// Where the `query` subcommand takes its filter from
+pub(crate) enum FilterSource<'a> {
+    // Saved with the index under this name
+    Saved(&'a str),
+    // Exported by another system
+    File(&'a Path, FilterFormat),
+}
+
+// With `explain_only` the filter is loaded and planned but not executed
+pub(crate) fn query_saved_index(index: &Path, source: Option<FilterSource>, explain_only: bool, format: OutputFormat)
+    -> io::Result<()> {
+    let ait = AggregationIndexTree::load(index)?;
+    let start = Instant::now();
+    let bitmap = match &source {
+        Some(FilterSource::Saved(name)) => Some(FilterStore::for_index(index).load(name)?),
+        Some(FilterSource::File(path, filter_format)) => Some(import_filter(path, *filter_format)?),
+        None => None,
+    };
+    let filter_time = start.elapsed();
+    let filter = match &source {
+        Some(FilterSource::Saved(name)) => Some(name.to_string()),
+        Some(FilterSource::File(path, filter_format)) => Some(format!("{} file {}", filter_format.name(), path.display())),
+        None => None,
+    };
+    if explain_only {
+        explain_query(index, &ait, filter.as_deref(), bitmap.as_ref(), filter_time, format)?;
+        return Ok(());
+    }
+    let start = Instant::now();
+    let aggregations = match &bitmap {
+        Some(bitmap) => ait.query_with_bitmap(bitmap).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+        None => ait.get_global_aggregations(),
+    };
+    let time = start.elapsed();
+    match format {
+        OutputFormat::Table => {
+            let docs = bitmap.as_ref().map_or("all docs".to_string(), |bitmap| format!("{} docs", format_count(bitmap.len())));
+            println!("Index: {}", index.display());
+            println!("- Filter: {} ({})", filter.as_deref().unwrap_or("none"), docs);
+            println!("- Aggregations: min={}, max={}, sum={}, count={}", aggregations.min_value, aggregations.max_value,
+                     aggregations.sum, format_count(aggregations.count as u64));
+            println!("- Query time: {:?}", time);
+        }
+        OutputFormat::Json => {
+            let report = serde_json::json!({
+                "index": index,
+                "filter": filter,
+                "filter_docs": bitmap.as_ref().map(RoaringBitmap::len),
+                "aggregations": aggregations,
+                "query_time_ns": time.as_nanos() as u64,
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        OutputFormat::Quiet => {}
+    }
+    Ok(())
+}
+
+// What inspect writes: a subtree dump in the output format, a DOT graph of
+// it, or the HTML structure report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InspectOutput {
+    Dump,
+    Dot,
+    Html,
+}
+
+pub(crate) fn inspect_index(index: &Path, node: usize, depth: usize, leaf_limit: usize, output: InspectOutput,
+                            format: OutputFormat) -> io::Result<()> {
+    let ait = AggregationIndexTree::load(index)?;
+    let invalid = |err: AitError| io::Error::new(io::ErrorKind::InvalidInput, err);
+    if output == InspectOutput::Html {
+        let structure = ait.structure(node, depth).map_err(invalid)?;
+        print!("{}", structure.to_html(&format!("Index {}", index.display())));
+        return Ok(());
+    }
+    let subtree = ait.subtree(node, depth, leaf_limit).map_err(invalid)?;
+    if output == InspectOutput::Dot {
+        print!("{}", subtree.to_dot());
+        return Ok(());
+    }
+    match format {
+        OutputFormat::Table => {
+            println!("Index: {} ({} nodes)", index.display(), format_count(ait.nodes.len() as u64));
+            print_subtree(&subtree, 0);
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&subtree)?),
+        OutputFormat::Quiet => {}
+    }
+    Ok(())
+}
+
+// One line per node, indented by depth
+pub(crate) fn print_subtree(subtree: &SubtreeNode, indent: usize) {
+    let aggregations = &subtree.aggregations;
+    let mut line = format!("{:indent$}node {} @ {}: count {}, min {}, max {}, sum {}", "", subtree.node, subtree.start,
+                           format_count(aggregations.count as u64), aggregations.min_value, aggregations.max_value,
+                           aggregations.sum, indent = indent * 2);
+    if let Some(split_value) = subtree.split_value {
+        line.push_str(&format!(", split {}", split_value));
+    }
+    if subtree.split_mismatch {
+        line.push_str(" (SPLIT MISMATCH)");
+    }
+    println!("{}", line);
+    if !subtree.values.is_empty() {
+        let entries: Vec<String> = if subtree.doc_ids.is_empty() {
+            subtree.values.iter().map(f64::to_string).collect()
+        } else {
+            subtree.values.iter().zip(&subtree.doc_ids).map(|(value, doc_id)| format!("{}:{}", doc_id, value)).collect()
+        };
+        println!("{:indent$}  {}", "", entries.join(" "), indent = indent * 2);
+    }
+    for child in &subtree.children {
+        print_subtree(child, indent + 1);
+    }
+    if subtree.omitted > 0 {
+        let what = if subtree.split_value.is_some() { "children" } else { "entries" };
+        println!("{:indent$}  ... {} more {}", "", subtree.omitted, what, indent = indent * 2);
+    }
+}
+
+// Print how a query would run: the filter's cardinality, the strategy the
+// planner picks and its cost estimate, with the thresholds behind the choice
+pub(crate) fn explain_query(index: &Path, ait: &AggregationIndexTree, filter: Option<&str>,
+                            bitmap: Option<&RoaringBitmap>, filter_time: Duration, format: OutputFormat)
+    -> io::Result<()> {
+    let start = Instant::now();
+    let cost = bitmap.map(|bitmap| ait.estimate_cost(bitmap));
+    let planning_time = start.elapsed();
+    // Ids outside the index's doc-id range select nothing
+    let in_range = match (bitmap, ait.doc_id_range) {
+        (Some(bitmap), Some((min, max))) => Some(bitmap.range_cardinality(min..=max)),
+        _ => None,
+    };
+    let planner = &ait.planner;
+    match format {
+        OutputFormat::Table => {
+            println!("Index: {} ({} docs)", index.display(), format_count(ait.get_global_aggregations().count as u64));
+            match (bitmap, in_range) {
+                (Some(bitmap), Some(in_range)) => println!("- Filter: {} ({} docs, {} within the index's doc ids)",
+                                                          filter.unwrap_or("none"), format_count(bitmap.len()),
+                                                          format_count(in_range)),
+                (Some(bitmap), None) => println!("- Filter: {} ({} docs)", filter.unwrap_or("none"), format_count(bitmap.len())),
+                (None, _) => println!("- Filter: none (all docs)"),
+            }
+            match cost {
+                Some(cost) => println!("- Plan: {}", cost),
+                None => println!("- Plan: root aggregations, no lookups needed"),
+            }
+            println!("- Planner thresholds: parallel from {} ids, complement above {:.0}% of docs, columnar scan from {} ids{}",
+                     format_count(planner.parallel_min_len), planner.complement_min_fraction * 100.0,
+                     format_count(planner.columnar_min_len),
+                     if ait.doc_values.is_some() { "" } else { " (no doc-order values kept)" });
+            println!("- Filter load time: {:?}", filter_time);
+            println!("- Planning time: {:?}", planning_time);
+            println!("- Not executed (--explain-only)");
+        }
+        OutputFormat::Json => {
+            let report = serde_json::json!({
+                "index": index,
+                "filter": filter,
+                "filter_docs": bitmap.map(RoaringBitmap::len),
+                "filter_docs_in_range": in_range,
+                "cost": cost,
+                "planner": planner,
+                "columnar_scan_available": ait.doc_values.is_some(),
+                "filter_load_time_ns": filter_time.as_nanos() as u64,
+                "planning_time_ns": planning_time.as_nanos() as u64,
+                "executed": false,
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        OutputFormat::Quiet => {}
+    }
+    Ok(())
+}
+
+// Mean latency of each strategy on random filters of one selectivity
+#[derive(Serialize)]
+pub(crate) struct CalibrationPoint {
+    pub(crate) selectivity: f64,
+    pub(crate) ids: u64,
+    pub(crate) strategies: Vec<StrategyReport>,
+}
+
+// Time every strategy on random filters across selectivities, record the
+// timings in the planner profile and refit its thresholds from them
+pub(crate) fn calibrate_planner(index: &Path, profile_path: &Path, runs: usize, format: OutputFormat) -> io::Result<()> {
+    const SELECTIVITIES: [f64; 14] = [0.0001, 0.001, 0.005, 0.01, 0.05, 0.1, 0.2, 0.3, 0.5, 0.7, 0.8, 0.9, 0.95, 0.99];
+    let ait = AggregationIndexTree::load(index)?;
+    let Some((min_id, max_id)) = ait.doc_id_range else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, AitError::DocIdsNotStored));
+    };
+    let mut profile = PlannerProfile::load(profile_path)?;
+    let total = ait.get_global_aggregations().count;
+    // The columnar scan only differs from sequential lookups when doc values are kept
+    let strategies: Vec<QueryStrategy> = QueryStrategy::ALL
+        .into_iter()
+        .filter(|&strategy| strategy != QueryStrategy::ColumnarScan || ait.doc_values.is_some())
+        .collect();
+    if ait.doc_values.is_none() {
+        report!("Index keeps no doc-order values; the columnar scan threshold is left as is");
+    }
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut points = Vec::with_capacity(SELECTIVITIES.len());
+    report!("{:>12} {:>12} {}", "selectivity", "ids",
+            strategies.iter().map(|strategy| format!("{:>14}", strategy.name())).collect::<String>());
+    for selectivity in SELECTIVITIES {
+        let bitmap: RoaringBitmap = (min_id..=max_id).filter(|_| rng.gen_bool(selectivity)).collect();
+        let mut expected_count = None;
+        let mut timings = Vec::with_capacity(strategies.len());
+        for &strategy in &strategies {
+            let mut times = Vec::with_capacity(runs);
+            for _ in 0..runs.max(1) {
+                let start = Instant::now();
+                let result = ait.query_with_strategy(&bitmap, strategy).map_err(io::Error::other)?;
+                let time = start.elapsed();
+                profile.record(&bitmap, total, strategy, time);
+                times.push(time);
+                // Strategies that disagree would make the timings meaningless
+                if *expected_count.get_or_insert(result.count) != result.count {
+                    return Err(io::Error::other(format!("{} strategy counted {} docs at selectivity {}, expected {}",
+                                                        strategy.name(), result.count, selectivity,
+                                                        expected_count.unwrap_or_default())));
+                }
+            }
+            timings.push(StrategyReport { strategy, ns: average_duration(&times).as_nanos() as u64 });
+        }
+        report!("{:>11}% {:>12} {}", selectivity * 100.0, format_count(bitmap.len()),
+                timings.iter().map(|timing| format!("{:>14?}", Duration::from_nanos(timing.ns))).collect::<String>());
+        points.push(CalibrationPoint { selectivity, ids: bitmap.len(), strategies: timings });
+    }
+
+    let previous = profile.thresholds;
+    profile.refit();
+    profile.save(profile_path)?;
+    let thresholds = profile.thresholds;
+    let share = |ids: u64| 100.0 * ids as f64 / total.max(1) as f64;
+    report!("\nCross-overs on {} docs, written to {}:", format_count(total as u64), profile_path.display());
+    report!("- Parallel lookups from {} ids ({:.3}%), was {}", format_count(thresholds.parallel_min_len),
+            share(thresholds.parallel_min_len), format_count(previous.parallel_min_len));
+    report!("- Complement above {:.1}% of docs, was {:.1}%", thresholds.complement_min_fraction * 100.0,
+            previous.complement_min_fraction * 100.0);
+    report!("- Columnar scan from {} ids ({:.3}%), was {}", format_count(thresholds.columnar_min_len),
+            share(thresholds.columnar_min_len), format_count(previous.columnar_min_len));
+    if format == OutputFormat::Json {
+        let report = serde_json::json!({
+            "index": index,
+            "profile": profile_path,
+            "points": points,
+            "previous": previous,
+            "thresholds": thresholds,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    Ok(())
+}
+
+// Verify a saved index's position map, rebuild it when entries disagree with
+// the tree, and write a copy in the current format. The copy never replaces
+// the index being repaired, so a failed repair leaves it as it was.
+pub(crate) fn repair_index(index: &Path, output: &Path, format: OutputFormat) -> io::Result<()> {
+    if output.exists() && fs::canonicalize(output)? == fs::canonicalize(index)? {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "--output must differ from the index being repaired"));
+    }
+
+    let mut ait = AggregationIndexTree::load(index)?;
+    let mismatched = ait.verify_position_map();
+    if mismatched > 0 {
+        report!("Position map: {} entries disagree with the tree; rebuilding", format_count(mismatched as u64));
+        ait.rebuild_position_map();
+    } else {
+        report!("Position map: ok");
+    }
+    ait.save(output)?;
+    report!("Saved repaired index to {}", output.display());
+    if format == OutputFormat::Json {
+        let report = serde_json::json!({
+            "index": index,
+            "output": output,
+            "mismatched_entries": mismatched,
+            "rebuilt": mismatched > 0,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    Ok(())
+}
+
+// Command re-running the benchmark with the same documents and queries,
+// stopping at the first mismatch
+pub(crate) fn benchmark_reproducer(args: &Args, seed: u64) -> String {
+    let mut command = format!("{} -n {} -f {} -l {} -i 1 --seed {} --abs-tolerance {} --rel-tolerance {} --strict",
+                              env!("CARGO_PKG_NAME"), args.num_docs, args.filter_percentage, args.leaf_size,
+                              seed, args.abs_tolerance, args.rel_tolerance);
+    if args.no_doc_ids {
+        command.push_str(" --no-doc-ids");
+    }
+    if args.no_position_map {
+        command.push_str(" --no-position-map");
+    }
+    if args.value_runs {
+        command.push_str(" --value-runs");
+    }
+    if args.digests {
+        command.push_str(" --digests");
+    }
+    if args.histograms {
+        command.push_str(" --histograms");
+    }
+    if args.extended_stats {
+        command.push_str(" --extended-stats");
+    }
+    if args.geometric_mean {
+        command.push_str(" --geometric-mean");
+    }
+    if args.non_positive != NonPositive::Skip {
+        command.push_str(&format!(" --non-positive {}", args.non_positive));
+    }
+    if args.distinct {
+        command.push_str(" --distinct");
+    }
+    if args.weights {
+        command.push_str(" --weights");
+    }
+    if let Some(interval) = args.histogram_interval {
+        command.push_str(&format!(" --histogram-interval {}", interval));
+    }
+    if let Some(interval) = args.date_histogram {
+        command.push_str(&format!(" --date-histogram {}", interval));
+    }
+    if args.doc_values {
+        command.push_str(" --doc-values");
+    }
+    command
+}
+
+// A small random dataset with a bitmap and a value range to query it with.
+// Minimized failing cases are saved as JSON fixtures.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FuzzCase {
+    pub(crate) seed: u64,
+    pub(crate) values: Vec<f64>,
+    pub(crate) leaf_size: usize,
+    // Selected doc ids, ascending
+    pub(crate) bitmap: Vec<u32>,
+    pub(crate) range: (f64, f64),
+}
+
+impl FuzzCase {
+    pub(crate) fn generate(seed: u64, max_docs: usize) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let num_docs = rng.gen_range(0..=max_docs);
+        // Half the cases draw from a handful of values, so duplicates straddle
+        // leaf boundaries and split points
+        let distinct: u32 = if rng.gen_bool(0.5) { rng.gen_range(1..=16) } else { 1 << 20 };
+        let values: Vec<f64> = (0..num_docs).map(|_| rng.gen_range(0..distinct) as f64 * 0.5).collect();
+        let density: f64 = rng.gen();
+        let bitmap = (0..num_docs as u32).filter(|_| rng.gen_bool(density)).collect();
+        let a = rng.gen_range(-1.0..distinct as f64 * 0.5 + 1.0);
+        let b = rng.gen_range(-1.0..distinct as f64 * 0.5 + 1.0);
+        FuzzCase {
+            seed,
+            values,
+            leaf_size: rng.gen_range(MIN_LEAF_SIZE..=128),
+            bitmap,
+            range: (a.min(b), a.max(b)),
+        }
+    }
+
+    // Build an AIT over the case and check global, bitmap (planned and with
+    // every strategy) and value range queries against a columnar scan
+    pub(crate) fn check(&self, verifier: &Verifier) -> bool {
+        let reference = ColumnarStorage { values: self.values.clone() };
+        let bitmap: RoaringBitmap = self.bitmap.iter().copied().collect();
+        let mut sorted: Vec<(u32, f64)> = self.values.iter().enumerate().map(|(i, &v)| (i as u32, v)).collect();
+        sorted.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        let options = BuildOptions { leaf_size: self.leaf_size, ..BuildOptions::default() };
+        let ait = build_aggregation_index_tree(sorted, &options).expect("fuzz doc ids are unique");
+
+        let mut ok = verifier.aggregations("Fuzz global", &ait.get_global_aggregations(),
+                                           &reference.get_global_aggregations());
+        let expected = reference.query_with_bitmap_naive(&bitmap);
+        match ait.query_with_bitmap(&bitmap) {
+            Ok(result) => ok &= verifier.aggregations("Fuzz filtered", &result, &expected),
+            Err(err) => eprintln!("Fuzz filtered query failed: {}", err),
+        }
+        for &strategy in QueryStrategy::ALL.iter() {
+            if let Ok(result) = ait.query_with_strategy(&bitmap, strategy) {
+                ok &= verifier.aggregations(&format!("Fuzz strategy {}", strategy.name()), &result, &expected);
+            }
+        }
+        let (min, max) = self.range;
+        ok &= verifier.aggregations("Fuzz range", &ait.query_value_range(min, max),
+                                    &reference.query_value_range_naive(min, max));
+        ok
+    }
+
+    pub(crate) fn fails(&self, tolerance: Tolerance) -> bool {
+        !self.check(&Verifier::silent(tolerance))
+    }
+
+    // The case restricted to the docs at `keep` (ascending), renumbered from zero
+    pub(crate) fn with_docs(&self, keep: &[usize]) -> FuzzCase {
+        let mut new_ids = vec![None; self.values.len()];
+        for (new_id, &old_id) in keep.iter().enumerate() {
+            new_ids[old_id] = Some(new_id as u32);
+        }
+        FuzzCase {
+            values: keep.iter().map(|&doc| self.values[doc]).collect(),
+            bitmap: self.bitmap.iter().filter_map(|&doc| new_ids[doc as usize]).collect(),
+            ..self.clone()
+        }
+    }
+
+    // Shrink a failing case by delta debugging: drop chunks of docs, then of
+    // bitmap ids, keeping each removal that still fails, and halve the leaf
+    // size while it still fails
+    pub(crate) fn minimize(&self, tolerance: Tolerance) -> FuzzCase {
+        let docs: Vec<usize> = (0..self.values.len()).collect();
+        let docs = ddmin(docs, |docs| self.with_docs(docs).fails(tolerance));
+        let mut case = self.with_docs(&docs);
+
+        let bitmap = ddmin(case.bitmap.clone(), |bitmap| {
+            FuzzCase { bitmap: bitmap.to_vec(), ..case.clone() }.fails(tolerance)
+        });
+        case.bitmap = bitmap;
+
+        while case.leaf_size > MIN_LEAF_SIZE {
+            let smaller = FuzzCase { leaf_size: (case.leaf_size / 2).max(MIN_LEAF_SIZE), ..case.clone() };
+            if !smaller.fails(tolerance) {
+                break;
+            }
+            case = smaller;
+        }
+        case
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+}
+
+// Complement-based delta debugging: repeatedly split `items` into chunks and
+// drop any chunk whose removal keeps `fails` true, refining the chunks when
+// none can be dropped. The result still fails and no single chunk at the
+// finest granularity (one item) can be removed.
+pub(crate) fn ddmin<T: Clone>(mut items: Vec<T>, fails: impl Fn(&[T]) -> bool) -> Vec<T> {
+    let mut chunks = 2;
+    while !items.is_empty() {
+        let chunk_len = items.len().div_ceil(chunks);
+        let mut reduced = false;
+        for start in (0..items.len()).step_by(chunk_len) {
+            let end = (start + chunk_len).min(items.len());
+            let candidate: Vec<T> = items[..start].iter().chain(&items[end..]).cloned().collect();
+            if fails(&candidate) {
+                items = candidate;
+                chunks = (chunks - 1).max(2);
+                reduced = true;
+                break;
+            }
+        }
+        if !reduced {
+            if chunk_len == 1 {
+                break;
+            }
+            chunks = (chunks * 2).min(items.len());
+        }
+    }
+    items
+}
+
+// One worst-case scenario: the build it needs, if any, and the average time
+// of its queries. Times are in nanoseconds in JSON output.
+#[derive(Debug, Serialize)]
+pub(crate) struct ScenarioReport {
+    pub(crate) name: &'static str,
+    #[serde(rename = "build_ns", serialize_with = "serialize_optional_nanos")]
+    pub(crate) build: Option<Duration>,
+    #[serde(rename = "query_ns", serialize_with = "serialize_nanos")]
+    pub(crate) query: Duration,
+    pub(crate) queries: usize,
+}
+
+pub(crate) fn serialize_optional_nanos<S: serde::Serializer>(duration: &Option<Duration>, serializer: S)
+    -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serializer.serialize_some(&(duration.as_nanos() as u64)),
+        None => serializer.serialize_none(),
+    }
+}
+
+// Run the canned worst cases over `docs` docs, verifying every result against
+// a columnar scan. The filter scenarios query the tree built from descending input.
+pub(crate) fn run_adversarial(docs: usize, single_doc_queries: usize, leaf_size: usize, iterations: usize,
+                              verifier: &Verifier) -> Result<Vec<ScenarioReport>, AitError> {
+    let options = BuildOptions { leaf_size, ..BuildOptions::default() };
+    let iterations = iterations.max(1);
+    let mut scenarios = Vec::new();
+    let build = |values: &[f64]| -> Result<(AggregationIndexTree, Duration), AitError> {
+        let pairs: Vec<(u32, f64)> = values.iter().enumerate().map(|(i, &value)| (i as u32, value)).collect();
+        let start = Instant::now();
+        let tree = build_aggregation_index_tree(pairs, &options)?;
+        Ok((tree, start.elapsed()))
+    };
+
+    // Every value ties, so range bounds can't separate leaves
+    report!("Scenario: all-equal values...");
+    let equal = ColumnarStorage { values: vec![42.0; docs] };
+    let (tree, build_time) = build(&equal.values)?;
+    let expected = equal.query_value_range_naive(42.0, 42.0);
+    let mut times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let result = tree.query_value_range(42.0, 42.0);
+        times.push(start.elapsed());
+        verifier.aggregations("Adversarial all-equal range", &result, &expected);
+    }
+    scenarios.push(ScenarioReport {
+        name: "all-equal values, range query",
+        build: Some(build_time),
+        query: average_duration(&times),
+        queries: iterations,
+    });
+    drop(tree);
+
+    // Doc order is the reverse of value order, so the build sorts everything
+    // and every doc id maps to a distant position
+    report!("Scenario: strictly descending input...");
+    let descending = ColumnarStorage { values: (0..docs).rev().map(|value| value as f64).collect() };
+    let (tree, build_time) = build(&descending.values)?;
+    let expected = descending.get_global_aggregations();
+    let mut times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let result = tree.get_global_aggregations();
+        times.push(start.elapsed());
+        verifier.aggregations("Adversarial descending global", &result, &expected);
+    }
+    scenarios.push(ScenarioReport {
+        name: "strictly descending input, global query",
+        build: Some(build_time),
+        query: average_duration(&times),
+        queries: iterations,
+    });
+
+    // Half the docs, spread so no container or leaf is skipped or taken whole
+    report!("Scenario: filter of every other doc...");
+    let every_other: RoaringBitmap = (0..docs as u32).step_by(2).collect();
+    let expected = descending.query_with_bitmap_naive(&every_other);
+    let mut times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let result = tree.query_with_bitmap(&every_other)?;
+        times.push(start.elapsed());
+        verifier.aggregations("Adversarial every-other-doc filter", &result, &expected);
+    }
+    scenarios.push(ScenarioReport {
+        name: "filter of every other doc",
+        build: None,
+        query: average_duration(&times),
+        queries: iterations,
+    });
+
+    // Per-query overhead dominates when each filter selects a single doc
+    report!("Scenario: single-doc filters in a tight loop...");
+    if docs > 0 && single_doc_queries > 0 {
+        // A stride coprime with most sizes visits docs all over the id space
+        let doc_ids: Vec<u32> = (0..single_doc_queries).map(|i| ((i as u64 * 7_919) % docs as u64) as u32).collect();
+        let mut results = Vec::with_capacity(doc_ids.len());
+        let start = Instant::now();
+        for &doc_id in &doc_ids {
+            let mut bitmap = RoaringBitmap::new();
+            bitmap.insert(doc_id);
+            results.push(tree.query_with_bitmap(&bitmap)?);
+        }
+        let elapsed = start.elapsed();
+        for (&doc_id, result) in doc_ids.iter().zip(&results) {
+            let mut expected = NodeAggregations::empty();
+            expected.add_value(descending.values[doc_id as usize]);
+            verifier.aggregations("Adversarial single-doc filter", result, &expected);
+        }
+        scenarios.push(ScenarioReport {
+            name: "single-doc filters in a tight loop",
+            build: None,
+            query: elapsed / single_doc_queries as u32,
+            queries: single_doc_queries,
+        });
+    }
+    Ok(scenarios)
+}
+
+// A doc id to position translation, as the tree's doc_id_map does
+pub(crate) trait PositionLookup {
+    fn name(&self) -> &'static str;
+    fn position(&self, doc_id: u32) -> Option<usize>;
+    fn memory_usage(&self) -> usize;
+}
+
+impl PositionLookup for HashMap<u32, usize> {
+    fn name(&self) -> &'static str {
+        "hash map"
+    }
+
+    fn position(&self, doc_id: u32) -> Option<usize> {
+        self.get(&doc_id).copied()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.capacity() * (std::mem::size_of::<u32>() + std::mem::size_of::<usize>())
+    }
+}
+
+// Positions indexed by doc id minus the smallest id; u32::MAX marks ids
+// without a doc. Memory grows with the id span rather than the doc count.
+pub(crate) struct DenseLookup {
+    pub(crate) base: u32,
+    pub(crate) positions: Vec<u32>,
+}
+
+impl DenseLookup {
+    pub(crate) fn new(pairs: &[(u32, usize)]) -> Self {
+        let base = pairs.iter().map(|&(doc_id, _)| doc_id).min().unwrap_or(0);
+        let span = pairs.iter().map(|&(doc_id, _)| doc_id - base + 1).max().unwrap_or(0);
+        let mut positions = vec![u32::MAX; span as usize];
+        for &(doc_id, position) in pairs {
+            positions[(doc_id - base) as usize] = position as u32;
+        }
+        DenseLookup { base, positions }
+    }
+}
+
+impl PositionLookup for DenseLookup {
+    fn name(&self) -> &'static str {
+        "dense vector"
+    }
+
+    fn position(&self, doc_id: u32) -> Option<usize> {
+        let slot = *self.positions.get(doc_id.checked_sub(self.base)? as usize)?;
+        (slot != u32::MAX).then_some(slot as usize)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.positions.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+// Doc ids sorted ascending with the position of each, found by binary search
+pub(crate) struct SortedLookup {
+    pub(crate) doc_ids: Vec<u32>,
+    pub(crate) positions: Vec<u32>,
+}
+
+impl SortedLookup {
+    pub(crate) fn new(pairs: &[(u32, usize)]) -> Self {
+        let mut pairs = pairs.to_vec();
+        pairs.sort_unstable();
+        SortedLookup {
+            doc_ids: pairs.iter().map(|&(doc_id, _)| doc_id).collect(),
+            positions: pairs.iter().map(|&(_, position)| position as u32).collect(),
+        }
+    }
+
+    pub(crate) fn memory(&self) -> usize {
+        (self.doc_ids.capacity() + self.positions.capacity()) * std::mem::size_of::<u32>()
+    }
+}
+
+impl PositionLookup for SortedLookup {
+    fn name(&self) -> &'static str {
+        "sorted array"
+    }
+
+    fn position(&self, doc_id: u32) -> Option<usize> {
+        let rank = self.doc_ids.binary_search(&doc_id).ok()?;
+        Some(self.positions[rank] as usize)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory()
+    }
+}
+
+// Piecewise geometric model index over the sorted doc ids: linear segments
+// predict an id's rank to within `epsilon`, and only that window of the
+// sorted array is searched. Segments are fitted greedily, each extended
+// while some slope keeps every point it covers within the error bound.
+pub(crate) struct PgmLookup {
+    pub(crate) sorted: SortedLookup,
+    pub(crate) epsilon: usize,
+    // First doc id covered by each segment, ascending
+    pub(crate) segment_keys: Vec<u32>,
+    // Rank of the segment's first id and the ranks per id after it
+    pub(crate) segments: Vec<(usize, f64)>,
+}
+
+impl PgmLookup {
+    pub(crate) fn new(pairs: &[(u32, usize)], epsilon: usize) -> Self {
+        let sorted = SortedLookup::new(pairs);
+        let mut segment_keys = Vec::new();
+        let mut segments = Vec::new();
+        let error = epsilon as f64;
+        let mut start = 0;
+        while start < sorted.doc_ids.len() {
+            let first = sorted.doc_ids[start];
+            let (mut low, mut high) = (0.0f64, f64::INFINITY);
+            let mut end = start + 1;
+            while end < sorted.doc_ids.len() {
+                let dx = (sorted.doc_ids[end] - first) as f64;
+                let dy = (end - start) as f64;
+                let (next_low, next_high) = (low.max((dy - error) / dx), high.min((dy + error) / dx));
+                if next_low > next_high {
+                    break;
+                }
+                (low, high) = (next_low, next_high);
+                end += 1;
+            }
+            let slope = if high.is_finite() { (low + high) / 2.0 } else { low };
+            segment_keys.push(first);
+            segments.push((start, slope));
+            start = end;
+        }
+        PgmLookup { sorted, epsilon, segment_keys, segments }
+    }
+}
+
+impl PositionLookup for PgmLookup {
+    fn name(&self) -> &'static str {
+        "PGM index"
+    }
+
+    fn position(&self, doc_id: u32) -> Option<usize> {
+        let segment = self.segment_keys.partition_point(|&key| key <= doc_id).checked_sub(1)?;
+        let (first_rank, slope) = self.segments[segment];
+        let predicted = first_rank + (slope * (doc_id - self.segment_keys[segment]) as f64) as usize;
+        // One extra slot either side absorbs rounding of the prediction
+        let from = predicted.saturating_sub(self.epsilon + 1);
+        let to = (predicted + self.epsilon + 2).min(self.sorted.doc_ids.len());
+        let rank = from + self.sorted.doc_ids.get(from..to)?.binary_search(&doc_id).ok()?;
+        Some(self.sorted.positions[rank] as usize)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.sorted.memory()
+            + self.segment_keys.capacity() * std::mem::size_of::<u32>()
+            + self.segments.capacity() * std::mem::size_of::<(usize, f64)>()
+    }
+}
+
+// One layout's lookup cost over one id space
+#[derive(Debug, Serialize)]
+pub(crate) struct LookupReport {
+    pub(crate) layout: &'static str,
+    pub(crate) id_space: &'static str,
+    pub(crate) ns_per_lookup: f64,
+    pub(crate) memory_bytes: usize,
+}
+
+// Time every lookup layout over a dense id space (0..docs) and a sparse one
+// (docs ids spread over docs * sparsity), with positions a random
+// permutation as value order gives them. Each layout's answers are checked
+// against the hash map's.
+pub(crate) fn bench_position_lookups(docs: usize, lookups: usize, sparsity: u32, pgm_epsilon: usize, iterations: usize,
+                                     verifier: &Verifier) -> Vec<LookupReport> {
+    let mut rng = StdRng::seed_from_u64(docs as u64 ^ sparsity as u64);
+    let mut reports = Vec::new();
+    for (id_space, spread) in [("dense", 1u64), ("sparse", sparsity.max(1) as u64)] {
+        let span = (docs as u64 * spread).min(u32::MAX as u64 + 1);
+        let doc_ids: Vec<u32> = if spread == 1 {
+            (0..docs as u32).collect()
+        } else {
+            let mut ids = std::collections::HashSet::with_capacity(docs);
+            while ids.len() < docs.min(span as usize) {
+                ids.insert(rng.gen_range(0..span) as u32);
+            }
+            ids.into_iter().collect()
+        };
+        let mut positions: Vec<usize> = (0..doc_ids.len()).collect();
+        for i in (1..positions.len()).rev() {
+            positions.swap(i, rng.gen_range(0..=i));
+        }
+        let pairs: Vec<(u32, usize)> = doc_ids.iter().copied().zip(positions).collect();
+        let probe_count = if doc_ids.is_empty() { 0 } else { lookups };
+        let mut probes: Vec<u32> = (0..probe_count).map(|_| doc_ids[rng.gen_range(0..doc_ids.len())]).collect();
+        probes.sort_unstable();
+
+        let map: HashMap<u32, usize> = pairs.iter().copied().collect();
+        let expected: u64 = probes.iter().map(|doc_id| map[doc_id] as u64).sum();
+        let layouts: Vec<Box<dyn PositionLookup>> = vec![
+            Box::new(map),
+            Box::new(DenseLookup::new(&pairs)),
+            Box::new(SortedLookup::new(&pairs)),
+            Box::new(PgmLookup::new(&pairs, pgm_epsilon)),
+        ];
+        for layout in &layouts {
+            let mut times = Vec::with_capacity(iterations);
+            let mut total = 0;
+            for _ in 0..iterations.max(1) {
+                let start = Instant::now();
+                total = probes.iter().filter_map(|&doc_id| layout.position(doc_id)).map(|position| position as u64).sum();
+                times.push(start.elapsed());
+            }
+            verifier.count(&format!("Position lookup ({}, {})", layout.name(), id_space), "position sum", total, expected);
+            reports.push(LookupReport {
+                layout: layout.name(),
+                id_space,
+                ns_per_lookup: average_duration(&times).as_nanos() as f64 / probes.len().max(1) as f64,
+                memory_bytes: layout.memory_usage(),
+            });
+        }
+    }
+    reports
+}
+
+// Cost of the leaf aggregation kernel at one batch size and position order
+#[derive(Debug, Serialize)]
+pub(crate) struct KernelReport {
+    pub(crate) order: &'static str,
+    pub(crate) batch_size: usize,
+    pub(crate) ns_per_position: f64,
+}
+
+// Time process_position_batch alone, feeding it `positions` random positions
+// of a tree over `docs` random values in batches of 16 to 8192. Sorted
+// positions are what the direct strategies pass; random ones show what the
+// sort buys. Each result is checked against a plain fold over the positions.
+pub(crate) fn bench_position_kernel(docs: usize, positions: usize, leaf_size: usize, iterations: usize,
+                                    verifier: &Verifier) -> Result<Vec<KernelReport>, AitError> {
+    let mut rng = StdRng::seed_from_u64(docs as u64 ^ positions as u64);
+    let pairs: Vec<(u32, f64)> = (0..docs as u32).map(|doc_id| (doc_id, rng.gen_range(0.0..1000.0))).collect();
+    let tree = build_aggregation_index_tree(pairs, &BuildOptions { leaf_size, ..BuildOptions::default() })?;
+    let total = tree.position_count();
+    let probe_count = if total == 0 { 0 } else { positions };
+    let mut random: Vec<usize> = (0..probe_count).map(|_| rng.gen_range(0..total)).collect();
+    let mut sorted = random.clone();
+    sorted.sort_unstable();
+    for i in (1..random.len()).rev() {
+        random.swap(i, rng.gen_range(0..=i));
+    }
+
+    let mut reports = Vec::new();
+    for (order, positions) in [("sorted", &sorted), ("random", &random)] {
+        let mut expected = NodeAggregations::empty();
+        for &pos in positions.iter() {
+            expected.add_value(tree.get_value_at_position(pos));
+        }
+        for batch_size in (4..=13).map(|shift| 1usize << shift) {
+            let mut times = Vec::with_capacity(iterations);
+            let mut result = NodeAggregations::empty();
+            for _ in 0..iterations.max(1) {
+                let start = Instant::now();
+                result = NodeAggregations::empty();
+                for batch in positions.chunks(batch_size) {
+                    tree.process_position_batch(&mut result, batch);
+                }
+                times.push(start.elapsed());
+            }
+            verifier.aggregations(&format!("Leaf kernel ({}, batch {})", order, batch_size), &result, &expected);
+            reports.push(KernelReport {
+                order,
+                batch_size,
+                ns_per_position: average_duration(&times).as_nanos() as f64 / positions.len().max(1) as f64,
+            });
+        }
+    }
+    Ok(reports)
+}
+
+// Run `cases` fuzz cases with consecutive seeds, returning how many failed.
+// Each failing case is minimized and saved to `fixture_dir`.
+pub(crate) fn run_fuzz(cases: u64, seed: u64, max_docs: usize, fixture_dir: &Path, verifier: &Verifier) -> u64 {
+    report!("Fuzzing {} cases of up to {} docs from seed {}", cases, format_count(max_docs as u64), seed);
+    let mut failed = 0;
+    for case in 0..cases {
+        let case_seed = seed.wrapping_add(case);
+        verifier.set_case(case_seed, format!("{} fuzz --seed {} --cases 1 --max-docs {}",
+                                             env!("CARGO_PKG_NAME"), case_seed, max_docs));
+        let fuzz_case = FuzzCase::generate(case_seed, max_docs);
+        if fuzz_case.check(verifier) {
+            continue;
+        }
+        failed += 1;
+
+        let minimized = fuzz_case.minimize(verifier.tolerance);
+        let path = fixture_dir.join(format!("case-{}.json", case_seed));
+        match minimized.save(&path) {
+            Ok(()) => eprintln!("Minimized case {} from {} to {} docs and {} to {} bitmap ids; replay with: {} fuzz --replay {}",
+                                case_seed, fuzz_case.values.len(), minimized.values.len(), fuzz_case.bitmap.len(),
+                                minimized.bitmap.len(), env!("CARGO_PKG_NAME"), path.display()),
+            Err(err) => eprintln!("Failed to save minimized case {}: {}", path.display(), err),
+        }
+    }
+    report!("{} of {} cases failed", failed, cases);
+    failed
+}
+
+// Parse the command line, then re-parse it with the options from --config
+// placed first. Later occurrences of an option win, so command-line flags
+// override the file.
+pub(crate) fn parse_args() -> Result<Args, String> {
+    let cli: Vec<String> = std::env::args().collect();
+    let args = Args::parse_from(&cli);
+    let Some(path) = &args.config else {
+        return Ok(args);
+    };
+
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read config {}: {}", path.display(), err))?;
+    let table: toml::Table = text.parse()
+        .map_err(|err| format!("Failed to parse config {}: {}", path.display(), err))?;
+
+    let command = Args::command();
+    let mut argv = vec![cli[0].clone()];
+    for (key, value) in table {
+        let name = key.replace('_', "-");
+        if name == "config" || !command.get_arguments().any(|arg| arg.get_long() == Some(name.as_str())) {
+            return Err(format!("Unknown option `{}` in config {}", key, path.display()));
+        }
+        let flag = format!("--{}", name);
+        match value {
+            toml::Value::Boolean(true) => argv.push(flag),
+            toml::Value::Boolean(false) => {}
+            toml::Value::String(text) => argv.extend([flag, text]),
+            toml::Value::Integer(n) => argv.extend([flag, n.to_string()]),
+            toml::Value::Float(x) => argv.extend([flag, x.to_string()]),
+            // Lists such as the query mix are comma-separated on the command line
+            toml::Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(|item| match item {
+                    toml::Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                }).collect();
+                argv.extend([flag, items.join(",")]);
+            }
+            other => {
+                return Err(format!("Unsupported value for `{}` in config {}: {}", key, path.display(), other));
+            }
+        }
+    }
+    argv.extend(cli.into_iter().skip(1));
+
+    Args::try_parse_from(argv)
+        .map_err(|err| format!("Invalid config {}: {}", path.display(), err.to_string().trim_end()))
+}
+
+// Run the CLI, generating benchmark documents with the default log record schema
+pub fn main() {
+    run_cli(|args| {
+        let generator = LogRecordGenerator::new(Utc::now(), Cardinalities::from_args(args)).with_entity(args.hot_entity);
+        match TimePattern::from_args(args) {
+            Some(pattern) => generator.with_time_pattern(pattern),
+            None => generator,
+        }
+    });
+}
+
+// Run the CLI with the benchmark generating its documents from `generator`,
+// for plugging in another record schema or value distribution
+pub fn main_with<G: RecordGenerator>(generator: G) {
+    run_cli(|_| generator);
+}
+
+// Tracer provider exporting spans with --otlp-endpoint, shut down on exit
+#[cfg(feature = "otel")]
+pub(crate) static OTLP_PROVIDER: std::sync::OnceLock<opentelemetry_sdk::trace::SdkTracerProvider> =
+    std::sync::OnceLock::new();
+
+// Spans go through `tracing`; a service embedding the index installs its own
+// subscriber, with otel::tracing_layer to export them. The benchmark logs them
+// to stderr on close with --trace and exports them with --otlp-endpoint.
+pub(crate) fn install_tracing(args: &Args) -> Result<(), String> {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    let log = args.trace.then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(io::stderr)
+    });
+    let registry = tracing_subscriber::registry().with(log);
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &args.otlp_endpoint {
+        let provider = crate::otel::otlp_provider(endpoint)
+            .map_err(|err| format!("Failed to set up OTLP export to {}: {}", endpoint, err))?;
+        registry.with(crate::otel::tracing_layer(&provider)).init();
+        let _ = OTLP_PROVIDER.set(provider);
+        return Ok(());
+    }
+    if args.trace {
+        registry.init();
+    }
+    Ok(())
+}
+
+// Exit with `code`, first sending any spans still waiting for export
+pub(crate) fn exit(code: i32) -> ! {
+    flush_traces();
+    std::process::exit(code)
+}
+
+pub(crate) fn flush_traces() {
+    #[cfg(feature = "otel")]
+    if let Some(provider) = OTLP_PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("Failed to export the remaining spans: {}", err);
+        }
+    }
+}
+
+// The generator is only made once the arguments select the benchmark
+pub(crate) fn run_cli<G: RecordGenerator>(generator: impl FnOnce(&Args) -> G) {
+    run_command(generator);
+    flush_traces();
+}
+
+pub(crate) fn run_command<G: RecordGenerator>(generator: impl FnOnce(&Args) -> G) {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(EXIT_CONFIG_ERROR);
+        }
+    };
+    if let Err(err) = install_tracing(&args) {
+        eprintln!("{}", err);
+        exit(EXIT_CONFIG_ERROR);
+    }
+    HUMAN_OUTPUT.store(args.format == OutputFormat::Table, Ordering::Relaxed);
+    match &args.command {
+        Some(Command::Stats { index }) => {
+            if let Err(err) = print_index_stats(index, args.format) {
+                eprintln!("Failed to read index {}: {}", index.display(), err);
+                exit(EXIT_RUNTIME_ERROR);
+            }
+            return;
+        }
+        Some(Command::Query { index, filter, filter_file, filter_format, explain_only }) => {
+            let source = match (filter, filter_file) {
+                (Some(name), _) => Some(FilterSource::Saved(name)),
+                (None, Some(path)) => Some(FilterSource::File(path, *filter_format)),
+                (None, None) => None,
+            };
+            if let Err(err) = query_saved_index(index, source, *explain_only, args.format) {
+                eprintln!("Failed to query index {}: {}", index.display(), err);
+                exit(EXIT_RUNTIME_ERROR);
+            }
+            return;
+        }
+        Some(Command::ImportFilter { index, name, file, filter_format }) => {
+            let imported = import_filter(file, *filter_format)
+                .and_then(|bitmap| FilterStore::for_index(index).save(name, &bitmap).map(|_| bitmap.len()));
+            match imported {
+                Ok(docs) => report!("Imported {} docs from {} as filter {:?}", format_count(docs), file.display(), name),
+                Err(err) => {
+                    eprintln!("Failed to import filter {}: {}", file.display(), err);
+                    exit(EXIT_RUNTIME_ERROR);
+                }
+            }
+            return;
+        }
+        Some(Command::Inspect { index, node, depth, leaf_limit, dot, html }) => {
+            let output = if *html { InspectOutput::Html } else if *dot { InspectOutput::Dot } else { InspectOutput::Dump };
+            if let Err(err) = inspect_index(index, *node, *depth, *leaf_limit, output, args.format) {
+                eprintln!("Failed to inspect index {}: {}", index.display(), err);
+                exit(EXIT_RUNTIME_ERROR);
+            }
+            return;
+        }
+        Some(Command::Repair { index, output }) => {
+            if let Err(err) = repair_index(index, output, args.format) {
+                eprintln!("Failed to repair index {}: {}", index.display(), err);
+                exit(EXIT_RUNTIME_ERROR);
+            }
+            return;
+        }
+        Some(Command::Calibrate { index, profile, runs }) => {
+            if let Err(err) = calibrate_planner(index, profile, *runs, args.format) {
+                eprintln!("Failed to calibrate against index {}: {}", index.display(), err);
+                exit(EXIT_RUNTIME_ERROR);
+            }
+            return;
+        }
+        Some(Command::Fuzz { .. } | Command::Adversarial { .. } | Command::Lookups { .. } | Command::Kernel { .. })
+        | None => {}
+    }
+    let verifier = Verifier::new(Tolerance::from_args(&args), args.strict);
+
+    if let Some(Command::Lookups { docs, lookups, sparsity, pgm_epsilon }) = &args.command {
+        verifier.set_case(0, format!("{} lookups --docs {} --lookups {} --sparsity {} --pgm-epsilon {}",
+                                     env!("CARGO_PKG_NAME"), docs, lookups, sparsity, pgm_epsilon));
+        report!("Doc id to position lookups: {} docs, {} ascending lookups", format_count(*docs as u64),
+                 format_count(*lookups as u64));
+        let reports = bench_position_lookups(*docs, *lookups, *sparsity, *pgm_epsilon, args.iterations, &verifier);
+        report!("\n  {:<14} {:<8} {:>12} {:>12}", "layout", "ids", "ns/lookup", "memory");
+        for lookup in &reports {
+            report!("  {:<14} {:<8} {:>12.2} {:>12}", lookup.layout, lookup.id_space, lookup.ns_per_lookup,
+                    format_bytes(lookup.memory_bytes));
+        }
+        if args.format == OutputFormat::Json {
+            let report = serde_json::json!({
+                "docs": docs,
+                "lookups": lookups,
+                "sparsity": sparsity,
+                "layouts": reports,
+                "mismatches": *verifier.mismatches.borrow(),
+            });
+            println!("{}", serde_json::to_string_pretty(&report).expect("lookup report serializes"));
+        }
+        exit_on_mismatches(&verifier);
+        return;
+    }
+
+    if let Some(Command::Kernel { docs, positions }) = &args.command {
+        verifier.set_case(0, format!("{} -l {} kernel --docs {} --positions {}",
+                                     env!("CARGO_PKG_NAME"), args.leaf_size, docs, positions));
+        report!("Leaf aggregation kernel: {} of {} positions (leaf size {}, current batch size {})",
+                 format_count(*positions as u64), format_count(*docs as u64), args.leaf_size, POSITION_BATCH_SIZE);
+        let reports = bench_position_kernel(*docs, *positions, args.leaf_size, args.iterations, &verifier)
+            .unwrap_or_else(|err| {
+                eprintln!("Kernel benchmark failed: {}", err);
+                exit(EXIT_RUNTIME_ERROR);
+            });
+        report!("\n  {:<8} {:>8} {:>14}", "order", "batch", "ns/position");
+        for kernel in &reports {
+            report!("  {:<8} {:>8} {:>14.2}", kernel.order, kernel.batch_size, kernel.ns_per_position);
+        }
+        for order in ["sorted", "random"] {
+            let fastest = reports.iter().filter(|kernel| kernel.order == order)
+                .min_by(|a, b| a.ns_per_position.total_cmp(&b.ns_per_position));
+            if let Some(fastest) = fastest {
+                report!("Fastest batch size over {} positions: {}", order, fastest.batch_size);
+            }
+        }
+        if args.format == OutputFormat::Json {
+            let report = serde_json::json!({
+                "docs": docs,
+                "positions": positions,
+                "leaf_size": args.leaf_size,
+                "batch_size": POSITION_BATCH_SIZE,
+                "kernels": reports,
+                "mismatches": *verifier.mismatches.borrow(),
+            });
+            println!("{}", serde_json::to_string_pretty(&report).expect("kernel report serializes"));
+        }
+        exit_on_mismatches(&verifier);
+        return;
+    }
+
+    if let Some(Command::Adversarial { docs, single_doc_queries }) = &args.command {
+        verifier.set_case(0, format!("{} -l {} adversarial --docs {} --single-doc-queries {}",
+                                     env!("CARGO_PKG_NAME"), args.leaf_size, docs, single_doc_queries));
+        report!("Adversarial scenarios over {} docs (leaf size {})", format_count(*docs as u64), args.leaf_size);
+        let scenarios = run_adversarial(*docs, *single_doc_queries, args.leaf_size, args.iterations, &verifier)
+            .unwrap_or_else(|err| {
+                eprintln!("Adversarial scenario failed: {}", err);
+                exit(EXIT_RUNTIME_ERROR);
+            });
+        report!("\n  {:<42} {:>14} {:>14}", "scenario", "build", "per query");
+        for scenario in &scenarios {
+            let build = scenario.build.map_or("-".to_string(), |time| format!("{:?}", time));
+            report!("  {:<42} {:>14} {:>14}", scenario.name, build, format!("{:?}", scenario.query));
+        }
+        if args.format == OutputFormat::Json {
+            let report = serde_json::json!({
+                "docs": docs,
+                "leaf_size": args.leaf_size,
+                "scenarios": scenarios,
+                "mismatches": *verifier.mismatches.borrow(),
+            });
+            println!("{}", serde_json::to_string_pretty(&report).expect("adversarial report serializes"));
+        }
+        exit_on_mismatches(&verifier);
+        return;
+    }
+
+    if let Some(Command::Fuzz { cases, seed, max_docs, fixture_dir, replay }) = &args.command {
+        if let Some(path) = replay {
+            let case = FuzzCase::load(path).unwrap_or_else(|err| {
+                eprintln!("Failed to read fuzz case {}: {}", path.display(), err);
+                exit(EXIT_CONFIG_ERROR);
+            });
+            verifier.set_case(case.seed, format!("{} fuzz --replay {}", env!("CARGO_PKG_NAME"), path.display()));
+            let passed = case.check(&verifier);
+            report!("Case {} ({} docs): {}", case.seed, case.values.len(), if passed { "passed" } else { "failed" });
+            exit_on_mismatches(&verifier);
+            return;
+        }
+        let seed = seed.unwrap_or_else(rand::random);
+        let failed = run_fuzz(*cases, seed, *max_docs, fixture_dir, &verifier);
+        if args.format == OutputFormat::Json {
+            let report = serde_json::json!({
+                "cases": cases,
+                "seed": seed,
+                "failed_cases": failed,
+                "mismatches": *verifier.mismatches.borrow(),
+            });
+            println!("{}", serde_json::to_string_pretty(&report).expect("fuzz report serializes"));
+        }
+        exit_on_mismatches(&verifier);
+        return;
+    }
+
+    report!("AIT Benchmark");
+    report!("=============");
+    report!("Configuration:");
+    if let Some(path) = &args.config {
+        report!("- Config file: {}", path.display());
+    }
+    report!("- Number of documents: {}", format_count(args.num_docs as u64));
+    report!("- Filter percentage: {}%", args.filter_percentage);
+    report!("- Distinct levels/regions/hosts/users/tags: {}/{}/{}/{}/{}",
+             args.levels, args.regions, args.hosts, args.users, args.tags);
+    if let Some(hours) = args.time_span_hours {
+        report!("- Time span: {} hours, daily swing {}, bursts of {} docs x{} (p={})",
+                 hours, args.diurnal_amplitude, args.burst_docs, args.burst_multiplier, args.burst_probability);
+    }
+    if let Some(exponent) = args.zipf_exponent {
+        report!("- Filter skew: Zipf exponent {} over {}s", exponent, args.hot_entity.name());
+    }
+    report!("- Leaf size: {}", args.leaf_size);
+    report!("- Sampled index: one aggregation every {} leaves", args.sample_every);
+    report!("- Store doc ids: {}", !args.no_doc_ids);
+    report!("- Position map: {}", !args.no_position_map);
+    report!("- Value runs: {}", args.value_runs);
+    report!("- Node digests: {}", args.digests);
+    report!("- Node histograms: {}", args.histograms);
+    report!("- Node moments: {}", args.extended_stats);
+    report!("- Node log sums: {} (non-positive: {})", args.geometric_mean, args.non_positive);
+    report!("- Distinct entity sketches: {}", args.distinct);
+    report!("- Weight column: {}", args.weights);
+    if let Some(interval) = args.histogram_interval {
+        report!("- Histogram interval: {}", interval);
+    }
+    if let Some(interval) = args.date_histogram {
+        report!("- Date histogram interval: {}", interval);
+    }
+    report!("- Keep doc-order values: {}", args.doc_values);
+    report!("- Iterations: {}", args.iterations);
+    report!("- Query mix (global/filtered/range): {}/{}/{}",
+             args.query_mix.global, args.query_mix.filtered, args.query_mix.range);
+    report!("- Tolerance: abs {}, rel {:e}", args.abs_tolerance, args.rel_tolerance);
+    report!();
+    
+    if let Err(err) = run_benchmark(&args, &verifier, generator(&args)) {
+        eprintln!("{}", err);
+        exit(EXIT_CONFIG_ERROR);
+    }
+    exit_on_mismatches(&verifier);
+}
+
+// Summarise any mismatches on stderr and exit with the verification failure code
+pub(crate) fn exit_on_mismatches(verifier: &Verifier) {
+    let mismatches = verifier.mismatches.borrow();
+    if !mismatches.is_empty() {
+        eprintln!("\nVerification failed ({} mismatched statistics):", mismatches.len());
+        for mismatch in mismatches.iter() {
+            eprintln!("  {}", mismatch);
+        }
+        exit(EXIT_VERIFICATION_FAILED);
+    }
+}
@@ -0,0 +1,371 @@
+use crate::filter::TermColumn;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use rand::Rng;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// A source of synthetic records for the benchmark and ingestion pipelines.
+// Implementations pick the record schema and the value distributions; the
+// benchmark only sees the fields `columns` reads out of each record.
+pub trait RecordGenerator {
+    type Record;
+    // Provenance recorded in index metadata
+    const SOURCE: &'static str;
+    // Names of the fields behind RecordColumns::value, ::secondary and ::term
+    const VALUE_FIELD: &'static str;
+    const SECONDARY_FIELD: &'static str;
+    const TERM_FIELD: &'static str;
+
+    // The record for `doc_id`. Records are generated in doc id order, and all
+    // randomness must come from `rng` so a seed reproduces the data.
+    fn generate<R: Rng + ?Sized>(&mut self, doc_id: usize, rng: &mut R) -> Self::Record;
+    fn columns<'r>(&self, record: &'r Self::Record) -> RecordColumns<'r>;
+}
+
+// The fields of one record the benchmark indexes and filters on
+#[derive(Debug, Clone, Copy)]
+pub struct RecordColumns<'a> {
+    // The field the tree is built over
+    pub value: f64,
+    // A second numeric field, for conjunctions and derived expressions
+    pub secondary: f64,
+    // Milliseconds since the epoch, for time-bounded queries
+    pub timestamp_ms: i64,
+    // Categorical field for group-bys
+    pub term: &'a str,
+    // The user, host or other source the doc comes from, for skewed filters
+    pub entity: &'a str,
+}
+
+// Columns extracted from generated records, indexed by doc id
+pub(crate) struct GeneratedColumns {
+    pub(crate) values: Vec<f64>,
+    pub(crate) secondary: Vec<f64>,
+    pub(crate) timestamps: Vec<i64>,
+    pub(crate) terms: TermColumn,
+    pub(crate) entities: TermColumn,
+}
+
+impl GeneratedColumns {
+    pub(crate) fn extract<G: RecordGenerator>(generator: &G, records: &[G::Record]) -> Self {
+        let mut values = Vec::with_capacity(records.len());
+        let mut secondary = Vec::with_capacity(records.len());
+        let mut timestamps = Vec::with_capacity(records.len());
+        let mut terms = Vec::with_capacity(records.len());
+        let mut entities = Vec::with_capacity(records.len());
+        for record in records {
+            let columns = generator.columns(record);
+            values.push(columns.value);
+            secondary.push(columns.secondary);
+            timestamps.push(columns.timestamp_ms);
+            terms.push(columns.term);
+            entities.push(columns.entity);
+        }
+        GeneratedColumns {
+            values,
+            secondary,
+            timestamps,
+            terms: TermColumn::from_values(terms),
+            entities: TermColumn::from_values(entities),
+        }
+    }
+}
+
+// Data structures for log records
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub doc_id: i64,
+    pub timestamp: String,
+    pub level: String,
+    pub message: String,
+    pub source: LogSource,
+    pub user: User,
+    pub payload_size: u32,
+    pub tags: Vec<String>,
+    pub answers: Vec<Answer>,
+    pub processed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogSource {
+    pub ip: String,
+    pub host: String,
+    pub region: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub session_id: String,
+    pub metrics: UserMetrics,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserMetrics {
+    pub login_time_ms: u32,
+    pub clicks: u32,
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Answer {
+    pub nx_domain: bool,
+    pub response_time_ms: u32,
+}
+
+// Distinct values of each categorical log record field. Each is drawn
+// uniformly, so a low cardinality gives large dictionary buckets and a high
+// one a long tail of small groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cardinalities {
+    pub levels: usize,
+    pub regions: usize,
+    pub hosts: usize,
+    pub users: usize,
+    // Tags drawn from; each record carries one to seven of them
+    pub tags: usize,
+}
+
+impl Default for Cardinalities {
+    fn default() -> Self {
+        Cardinalities {
+            levels: 5,
+            regions: 5,
+            hosts: 20,
+            users: 49_000,
+            tags: 49,
+        }
+    }
+}
+
+// Load that varies over time: docs arrive at a steady rate, and payload
+// sizes and response times follow a daily cycle with occasional bursts on
+// top, instead of being independent of the timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimePattern {
+    // Milliseconds between consecutive docs, which are timestamped in doc id order
+    pub doc_interval_ms: f64,
+    // Swing of the daily cycle as a fraction of the mean, from 0 to 1: values
+    // are scaled by 1 - amplitude at midnight UTC and 1 + amplitude at noon
+    pub diurnal_amplitude: f64,
+    // Chance that a burst starts at any doc outside one
+    pub burst_probability: f64,
+    // Docs a burst lasts, and how much it scales values
+    pub burst_docs: usize,
+    pub burst_multiplier: f64,
+}
+
+impl TimePattern {
+    pub(crate) const DAY_MS: f64 = 86_400_000.0;
+
+    // Scale of the daily cycle at `timestamp_ms`
+    pub(crate) fn diurnal_factor(&self, timestamp_ms: i64) -> f64 {
+        let phase = timestamp_ms.rem_euclid(Self::DAY_MS as i64) as f64 / Self::DAY_MS;
+        1.0 - self.diurnal_amplitude * (phase * std::f64::consts::TAU).cos()
+    }
+}
+
+// Which log record field identifies the source of a doc
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogEntity {
+    User,
+    Host,
+}
+
+impl LogEntity {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            LogEntity::User => "user",
+            LogEntity::Host => "host",
+        }
+    }
+}
+
+// The benchmark's default schema: log records with uniformly random fields,
+// timestamped within a minute of `base_time`. payload_size is the indexed
+// value, login_time_ms the secondary field and level the term. With a time
+// pattern, timestamps run forward from `base_time` and payload sizes and
+// response times follow the pattern.
+pub struct LogRecordGenerator {
+    pub(crate) base_time: DateTime<Utc>,
+    pub(crate) cardinalities: Cardinalities,
+    pub(crate) levels: Vec<String>,
+    pub(crate) regions: Vec<String>,
+    pub(crate) hosts: Vec<String>,
+    pub(crate) time_pattern: Option<TimePattern>,
+    pub(crate) entity: LogEntity,
+    // Docs left in the current burst
+    pub(crate) burst_remaining: usize,
+}
+
+impl LogRecordGenerator {
+    // Names of the first levels and regions; any beyond these are numbered
+    pub const LEVELS: [&'static str; 5] = ["info", "warn", "error", "debug", "trace"];
+    pub const REGIONS: [&'static str; 5] = ["us-east-1", "eu-west-1", "eu-west-2", "ap-south-1", "us-west-2"];
+
+    // Every cardinality is raised to at least one
+    pub fn new(base_time: DateTime<Utc>, cardinalities: Cardinalities) -> Self {
+        let cardinalities = Cardinalities {
+            levels: cardinalities.levels.max(1),
+            regions: cardinalities.regions.max(1),
+            hosts: cardinalities.hosts.max(1),
+            users: cardinalities.users.max(1),
+            tags: cardinalities.tags.max(1),
+        };
+        let named = |names: &[&str], count: usize, prefix: &str| -> Vec<String> {
+            (0..count)
+                .map(|i| names.get(i).map_or_else(|| format!("{}-{}", prefix, i + 1), |name| name.to_string()))
+                .collect()
+        };
+        LogRecordGenerator {
+            base_time,
+            cardinalities,
+            levels: named(&Self::LEVELS, cardinalities.levels, "level"),
+            regions: named(&Self::REGIONS, cardinalities.regions, "region"),
+            hosts: (1..=cardinalities.hosts).map(|n| format!("server-{}.region.local", n)).collect(),
+            time_pattern: None,
+            entity: LogEntity::User,
+            burst_remaining: 0,
+        }
+    }
+
+    // Generate time-correlated values; amplitude and burst probability are
+    // clamped to [0, 1]
+    pub fn with_time_pattern(mut self, pattern: TimePattern) -> Self {
+        self.time_pattern = Some(TimePattern {
+            doc_interval_ms: pattern.doc_interval_ms.max(0.0),
+            diurnal_amplitude: pattern.diurnal_amplitude.clamp(0.0, 1.0),
+            burst_probability: pattern.burst_probability.clamp(0.0, 1.0),
+            burst_multiplier: pattern.burst_multiplier.max(0.0),
+            ..pattern
+        });
+        self
+    }
+
+    // The field RecordColumns::entity reads; users by default
+    pub fn with_entity(mut self, entity: LogEntity) -> Self {
+        self.entity = entity;
+        self
+    }
+
+    // Timestamp of `doc_id` and the factor its load-dependent values are
+    // scaled by; a factor of one without a time pattern
+    pub(crate) fn time_and_load<R: Rng + ?Sized>(&mut self, doc_id: usize, rng: &mut R) -> (DateTime<Utc>, f64) {
+        let Some(pattern) = self.time_pattern else {
+            let offset_ms = rng.gen_range(-30000..30000);
+            return (self.base_time + chrono::Duration::milliseconds(offset_ms), 1.0);
+        };
+        let offset_ms = (doc_id as f64 * pattern.doc_interval_ms) as i64;
+        let timestamp = self.base_time + chrono::Duration::milliseconds(offset_ms);
+        if self.burst_remaining == 0 && rng.gen_bool(pattern.burst_probability) {
+            self.burst_remaining = pattern.burst_docs;
+        }
+        let burst = if self.burst_remaining > 0 {
+            self.burst_remaining -= 1;
+            pattern.burst_multiplier
+        } else {
+            1.0
+        };
+        (timestamp, pattern.diurnal_factor(timestamp.timestamp_millis()) * burst)
+    }
+}
+
+impl RecordGenerator for LogRecordGenerator {
+    type Record = LogRecord;
+    const SOURCE: &'static str = "generated log records";
+    const VALUE_FIELD: &'static str = "payload_size";
+    const SECONDARY_FIELD: &'static str = "login_time_ms";
+    const TERM_FIELD: &'static str = "level";
+
+    fn generate<R: Rng + ?Sized>(&mut self, doc_id: usize, rng: &mut R) -> LogRecord {
+        let (timestamp, load) = self.time_and_load(doc_id, rng);
+        let scale = |value: u32| (value as f64 * load).round() as u32;
+        let answers_len = rng.gen_range(0..=3);
+        let answers = (0..answers_len)
+            .map(|_| Answer {
+                nx_domain: rng.gen_bool(0.3),
+                response_time_ms: scale(rng.gen_range(5..150)),
+            })
+            .collect::<Vec<_>>();
+        LogRecord {
+            doc_id: doc_id as i64,
+            timestamp: timestamp.to_rfc3339(),
+            level: self.levels[rng.gen_range(0..self.levels.len())].clone(),
+            message: format!("Log message {} for record {}", Uuid::new_v4(), doc_id),
+            source: LogSource {
+                ip: format!("10.0.{}.{}", rng.gen_range(1..255), rng.gen_range(1..255)),
+                host: self.hosts[rng.gen_range(0..self.hosts.len())].clone(),
+                region: self.regions[rng.gen_range(0..self.regions.len())].clone(),
+            },
+            user: User {
+                id: format!("user_{}", 1000 + rng.gen_range(0..self.cardinalities.users)),
+                session_id: Uuid::new_v4().to_string(),
+                metrics: UserMetrics {
+                    login_time_ms: rng.gen_range(10..1500),
+                    clicks: rng.gen_range(0..100),
+                    active: rng.gen_bool(0.75),
+                },
+            },
+            payload_size: scale(rng.gen_range(50..20_480)),
+            // Generate fewer unique tags for better dictionary encoding demo
+            tags: (0..rng.gen_range(1..8))
+                .map(|_| format!("tag_{}", 1 + rng.gen_range(0..self.cardinalities.tags)))
+                .collect::<Vec<_>>(),
+            answers,
+            processed: rng.gen_bool(0.9),
+        }
+    }
+
+    fn columns<'r>(&self, record: &'r LogRecord) -> RecordColumns<'r> {
+        RecordColumns {
+            value: record.payload_size as f64,
+            secondary: record.user.metrics.login_time_ms as f64,
+            timestamp_ms: DateTime::parse_from_rfc3339(&record.timestamp)
+                .expect("generated timestamps are RFC 3339")
+                .timestamp_millis(),
+            term: &record.level,
+            entity: match self.entity {
+                LogEntity::User => &record.user.id,
+                LogEntity::Host => &record.source.host,
+            },
+        }
+    }
+}
+
+// Doc-id filter selecting about `target` docs, skewed towards hot entities:
+// each doc is picked with a chance proportional to 1 / rank^exponent of its
+// entity, ranked by dictionary code, and capped at certainty. The hottest
+// entities' docs are taken almost entirely and the tail contributes a few,
+// as in a "top talker" investigation. Filters drawn with the same entities
+// overlap heavily on the hot ones.
+pub(crate) fn zipf_filter(entities: &TermColumn, target: usize, exponent: f64, rng: &mut impl Rng) -> RoaringBitmap {
+    let weights: Vec<f64> = (1..=entities.counts.len()).map(|rank| (rank as f64).powf(-exponent)).collect();
+    // Expected docs picked when an entity with weight w picks each doc with chance min(1, scale * w)
+    let expected = |scale: f64| -> f64 {
+        weights
+            .iter()
+            .zip(&entities.counts)
+            .map(|(&weight, &count)| (scale * weight).min(1.0) * count as f64)
+            .sum()
+    };
+    // The scale picking every doc, then bisect down to the target
+    let (mut lo, mut hi) = (0.0, weights.last().map_or(1.0, |&weight| 1.0 / weight));
+    for _ in 0..64 {
+        let mid = (lo + hi) / 2.0;
+        if expected(mid) < target as f64 {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let chances: Vec<f64> = weights.iter().map(|&weight| (hi * weight).min(1.0)).collect();
+    let mut bitmap = RoaringBitmap::new();
+    for (doc_id, &code) in entities.codes.iter().enumerate() {
+        if rng.gen_bool(chances[code as usize]) {
+            bitmap.insert(doc_id as u32);
+        }
+    }
+    bitmap
+}
@@ -0,0 +1,364 @@
+use crate::heap::{self, HeapComponent};
+use crate::tree::*;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Doc ids a filtered query selects. Roaring bitmaps are the default input;
+// a sorted id list skips container decoding, which can win for small random
+// sets.
+pub trait DocFilter {
+    fn cardinality(&self) -> u64;
+    fn ids(&self) -> impl Iterator<Item = u32> + '_;
+}
+
+impl DocFilter for RoaringBitmap {
+    fn cardinality(&self) -> u64 {
+        self.len()
+    }
+
+    fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.iter()
+    }
+}
+
+// Ascending, deduplicated doc ids
+#[derive(Debug, Clone)]
+pub struct SortedIds(pub(crate) Vec<u32>);
+
+impl SortedIds {
+    pub fn new(mut ids: Vec<u32>) -> Self {
+        ids.sort_unstable();
+        ids.dedup();
+        SortedIds(ids)
+    }
+}
+
+impl DocFilter for SortedIds {
+    fn cardinality(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+// Dictionary-encoded categorical field in doc order: codes[doc_id] indexes terms
+#[derive(Debug, Clone)]
+pub struct TermColumn {
+    pub(crate) terms: Vec<String>,
+    pub(crate) codes: Vec<u32>,
+    // Dictionary statistics: docs per term code
+    pub(crate) counts: Vec<u32>,
+}
+
+impl TermColumn {
+    pub fn from_values<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let _heap = heap::enter(HeapComponent::Dictionaries);
+        let mut dictionary: HashMap<&str, u32> = HashMap::new();
+        let mut terms = Vec::new();
+        let codes: Vec<u32> = values
+            .into_iter()
+            .map(|value| {
+                *dictionary.entry(value).or_insert_with(|| {
+                    terms.push(value.to_string());
+                    terms.len() as u32 - 1
+                })
+            })
+            .collect();
+        let mut counts = vec![0; terms.len()];
+        for &code in &codes {
+            counts[code as usize] += 1;
+        }
+        TermColumn { terms, codes, counts }
+    }
+
+    pub(crate) fn code(&self, doc_id: u32) -> Option<usize> {
+        self.codes.get(doc_id as usize).map(|&code| code as usize)
+    }
+
+    pub(crate) fn term_code(&self, term: &str) -> Option<usize> {
+        self.terms.iter().position(|candidate| candidate == term)
+    }
+
+    // Docs holding a term, from the dictionary statistics alone
+    pub fn term_count(&self, term: &str) -> u64 {
+        self.term_code(term).map_or(0, |code| self.counts[code] as u64)
+    }
+
+    pub fn doc_ids_with(&self, term: &str) -> RoaringBitmap {
+        let Some(code) = self.term_code(term) else {
+            return RoaringBitmap::new();
+        };
+        let code = code as u32;
+        RoaringBitmap::from_sorted_iter(
+            (0..self.codes.len() as u32).filter(|&doc_id| self.codes[doc_id as usize] == code),
+        )
+        .expect("doc ids are ascending")
+    }
+
+    // One bucket per term with docs, for the terms with codes in `codes`
+    pub(crate) fn buckets(&self, codes: std::ops::Range<usize>, aggregations: &[NodeAggregations]) -> Vec<TermBucket> {
+        codes.zip(aggregations).filter_map(|(code, aggregations)| self.bucket(code, aggregations)).collect()
+    }
+
+    // Bucket of one term, None when it has no docs
+    pub(crate) fn bucket(&self, code: usize, aggregations: &NodeAggregations) -> Option<TermBucket> {
+        (aggregations.count > 0).then(|| TermBucket {
+            term: self.terms[code].clone(),
+            count: aggregations.count,
+            min: aggregations.min_value,
+            max: aggregations.max_value,
+            sum: aggregations.sum,
+            avg: aggregations.avg().unwrap_or_default(),
+        })
+    }
+}
+
+// Count and stats of the numeric field for one term, as a chart bar and its tooltip
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TermBucket {
+    pub term: String,
+    pub count: u32,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub avg: f64,
+}
+
+// Order in which AggregationIndexTree::terms_stats_stream yields buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TermOrder {
+    // Ascending by term
+    Term,
+    // Most docs first, ties by term, as terms_stats sorts them
+    DocCount,
+}
+
+// Mapping between caller doc ids and the ids assigned by
+// build_value_ordered_tree(), where the doc at position i has id i
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub(crate) struct ValueOrderMapping {
+    // Caller id of each assigned id
+    pub(crate) external: Vec<u32>,
+    pub(crate) internal: HashMap<u32, u32>,
+}
+
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+impl ValueOrderMapping {
+    pub(crate) fn internal_id(&self, external: u32) -> Option<u32> {
+        self.internal.get(&external).copied()
+    }
+
+    pub(crate) fn external_id(&self, internal: u32) -> Option<u32> {
+        self.external.get(internal as usize).copied()
+    }
+
+    // Translate a bitmap of caller ids, dropping ids the tree doesn't hold
+    pub(crate) fn translate(&self, external: &RoaringBitmap) -> RoaringBitmap {
+        let mut internal: Vec<u32> = external.iter().filter_map(|id| self.internal_id(id)).collect();
+        internal.sort_unstable();
+        RoaringBitmap::from_sorted_iter(internal).expect("translated ids are sorted")
+    }
+}
+
+// FNV-1a over the serialized bitmap, identifying repeated filters
+pub(crate) fn bitmap_fingerprint(bitmap: &RoaringBitmap) -> u64 {
+    let mut bytes = Vec::with_capacity(bitmap.serialized_size());
+    bitmap.serialize_into(&mut bytes).expect("serializing into a vec doesn't fail");
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// Translated filters keyed by the fingerprint of the caller-id bitmap, so
+// repeated filters, such as a dashboard re-running its queries, skip the
+// per-id translation. Entries keep the caller bitmap to rule out fingerprint
+// collisions; the least recently used one is evicted beyond `capacity`.
+pub struct TranslationCache {
+    pub(crate) capacity: usize,
+    pub(crate) entries: Mutex<HashMap<u64, CachedTranslation>>,
+    // Ticks on every lookup, stamping entries as they are used
+    pub(crate) clock: AtomicU64,
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+}
+
+pub(crate) struct CachedTranslation {
+    pub(crate) external: RoaringBitmap,
+    pub(crate) internal: Arc<RoaringBitmap>,
+    pub(crate) last_used: u64,
+}
+
+impl TranslationCache {
+    pub fn new(capacity: usize) -> Self {
+        TranslationCache {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // The translation of `external`, from the cache or computed by `translate`
+    // and cached. Translation runs outside the lock.
+    pub fn get_or_translate(&self, external: &RoaringBitmap, translate: impl FnOnce(&RoaringBitmap) -> RoaringBitmap)
+        -> Arc<RoaringBitmap> {
+        let fingerprint = bitmap_fingerprint(external);
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&fingerprint) {
+            if entry.external == *external {
+                entry.last_used = now;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Arc::clone(&entry.internal);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let internal = heap::scoped(HeapComponent::Caches, || Arc::new(translate(external)));
+        if self.capacity > 0 {
+            let _heap = heap::enter(HeapComponent::Caches);
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= self.capacity && !entries.contains_key(&fingerprint) {
+                let oldest = entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(&key, _)| key);
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            entries.insert(fingerprint, CachedTranslation {
+                external: external.clone(),
+                internal: Arc::clone(&internal),
+                last_used: now,
+            });
+        }
+        internal
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    // Lookups so far; entries used from now on are stamped at least this
+    pub fn clock(&self) -> u64 {
+        self.clock.load(Ordering::Relaxed)
+    }
+
+    // Drop the entries last used before lookup `tick`, returning how many
+    pub fn evict_unused_since(&self, tick: u64) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| entry.last_used >= tick);
+        before - entries.len()
+    }
+}
+
+// Doc ids allocated in time order: docs are grouped into fixed-width time
+// partitions, each partition takes the next block of ids, and ids within a
+// partition follow timestamp order. A time range then maps to one contiguous
+// id range, which doc-ordered columns answer from whole zones, and expiring a
+// partition drops a contiguous block of ids.
+#[derive(Debug, Clone)]
+pub struct TimePartitionedIds {
+    pub(crate) partition_ms: i64,
+    // In time order, each holding the ids allocated to its docs
+    pub(crate) partitions: Vec<TimePartition>,
+    // Timestamp of each allocated id
+    pub(crate) timestamps: Vec<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TimePartition {
+    pub(crate) start_ms: i64,
+    pub(crate) ids: std::ops::Range<u32>,
+}
+
+impl TimePartitionedIds {
+    // Allocate ids for docs with the given timestamps. Also returns the id
+    // allocated to each input doc, for remapping columns and filters.
+    pub fn allocate(doc_timestamps: &[i64], partition_ms: i64) -> (Self, Vec<u32>) {
+        let partition_ms = partition_ms.max(1);
+        let mut order: Vec<u32> = (0..doc_timestamps.len() as u32).collect();
+        order.sort_by_key(|&doc| doc_timestamps[doc as usize]);
+
+        let mut partitions: Vec<TimePartition> = Vec::new();
+        let mut timestamps = Vec::with_capacity(order.len());
+        let mut allocated = vec![0u32; order.len()];
+        for (id, &doc) in order.iter().enumerate() {
+            let timestamp = doc_timestamps[doc as usize];
+            let start_ms = timestamp.div_euclid(partition_ms) * partition_ms;
+            let id = id as u32;
+            match partitions.last_mut() {
+                Some(partition) if partition.start_ms == start_ms => partition.ids.end = id + 1,
+                _ => partitions.push(TimePartition { start_ms, ids: id..id + 1 }),
+            }
+            timestamps.push(timestamp);
+            allocated[doc as usize] = id;
+        }
+        (TimePartitionedIds { partition_ms, partitions, timestamps }, allocated)
+    }
+
+    // Ids of the docs with timestamps in [from, to]. Only the partitions at
+    // either end of the range are searched; the result is a single id range.
+    pub fn ids_in_time_range(&self, from: i64, to: i64) -> RoaringBitmap {
+        let first = self.partitions.partition_point(|partition| partition.start_ms + self.partition_ms <= from);
+        let last = self.partitions.partition_point(|partition| partition.start_ms <= to);
+        let mut ids = RoaringBitmap::new();
+        if first < last {
+            let lower = &self.partitions[first].ids;
+            let upper = &self.partitions[last - 1].ids;
+            let start = lower.start
+                + self.timestamps[lower.start as usize..lower.end as usize].partition_point(|&t| t < from) as u32;
+            let end = upper.start
+                + self.timestamps[upper.start as usize..upper.end as usize].partition_point(|&t| t <= to) as u32;
+            if start < end {
+                ids.insert_range(start..end);
+            }
+        }
+        ids
+    }
+
+    // Ids in the partitions that ended at or before `cutoff_ms`, whose docs
+    // have all outlived a TTL expiring at the cutoff. Returns the partition
+    // count and the ids, a prefix of the id space.
+    pub fn expired_ids(&self, cutoff_ms: i64) -> (usize, RoaringBitmap) {
+        let expired = self.partitions.partition_point(|partition| partition.start_ms + self.partition_ms <= cutoff_ms);
+        let mut ids = RoaringBitmap::new();
+        if let Some(partition) = expired.checked_sub(1).map(|idx| &self.partitions[idx]) {
+            ids.insert_range(0..partition.ids.end);
+        }
+        (expired, ids)
+    }
+}
+
+// Summarize the container composition of a filter bitmap. The bitmap's shape
+// (sparse arrays, dense bitsets, runs) decides which query strategy wins.
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) fn describe_bitmap(bitmap: &RoaringBitmap) -> String {
+    let stats = bitmap.statistics();
+    let density = match (stats.min_value, stats.max_value) {
+        (Some(min), Some(max)) => stats.cardinality as f64 / (max - min + 1) as f64,
+        _ => 0.0,
+    };
+    format!(
+        "{} ids in {} containers ({} array / {} bitset / {} run), {} bytes serialized, density {:.4}",
+        stats.cardinality,
+        stats.n_containers,
+        stats.n_array_containers,
+        stats.n_bitset_containers,
+        stats.n_run_containers,
+        bitmap.serialized_size(),
+        density,
+    )
+}
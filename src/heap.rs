@@ -0,0 +1,275 @@
+// Heap accounting per tree component, with the `heap-profile` feature.
+// ProfilingAllocator wraps the system allocator and charges each allocation to
+// the component the allocating thread has entered; frees and reallocations are
+// charged to the component that made the allocation, so live bytes stay
+// attributed however long the memory is kept. Without the feature, or with it
+// but without the allocator installed, `enter` does nothing and `profile`
+// returns None.
+//
+// Scopes are per thread: work handed to the rayon pool is charged to Other
+// unless the closure enters a component itself.
+
+use serde::Serialize;
+
+// Where heap memory goes. Other holds everything allocated outside a scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeapComponent {
+    Other,
+    // The node array
+    Nodes,
+    // Leaf doc ids and values
+    Leaves,
+    // Doc id and position maps
+    Maps,
+    // Term dictionaries and value runs
+    Dictionaries,
+    // Filter translation caches
+    Caches,
+    // Per-node digests, histograms and moments
+    Sketches,
+    // Doc-order values and attached secondary columns
+    Columns,
+}
+
+impl HeapComponent {
+    pub const ALL: [HeapComponent; 8] = [
+        HeapComponent::Other,
+        HeapComponent::Nodes,
+        HeapComponent::Leaves,
+        HeapComponent::Maps,
+        HeapComponent::Dictionaries,
+        HeapComponent::Caches,
+        HeapComponent::Sketches,
+        HeapComponent::Columns,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeapComponent::Other => "other",
+            HeapComponent::Nodes => "nodes",
+            HeapComponent::Leaves => "leaves",
+            HeapComponent::Maps => "maps",
+            HeapComponent::Dictionaries => "dictionaries",
+            HeapComponent::Caches => "caches",
+            HeapComponent::Sketches => "sketches",
+            HeapComponent::Columns => "columns",
+        }
+    }
+}
+
+// Measured heap use of one component
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ComponentUsage {
+    // Bytes allocated and not yet freed
+    pub live_bytes: usize,
+    // Highest live_bytes seen since the process started
+    pub peak_bytes: usize,
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+}
+
+// Heap use of every component at one point in time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeapProfile {
+    pub components: Vec<(HeapComponent, ComponentUsage)>,
+}
+
+impl HeapProfile {
+    pub fn get(&self, component: HeapComponent) -> ComponentUsage {
+        self.components.iter().find(|(c, _)| *c == component).map_or_else(ComponentUsage::default, |(_, usage)| *usage)
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.components.iter().map(|(_, usage)| usage.live_bytes).sum()
+    }
+
+    // What happened between `earlier` and this profile: live bytes gained
+    // (freed bytes don't go below zero) and allocations made. Peaks are kept
+    // from this profile.
+    pub fn since(&self, earlier: &HeapProfile) -> HeapProfile {
+        let components = self.components.iter().map(|&(component, usage)| {
+            let before = earlier.get(component);
+            (component, ComponentUsage {
+                live_bytes: usage.live_bytes.saturating_sub(before.live_bytes),
+                peak_bytes: usage.peak_bytes,
+                allocations: usage.allocations - before.allocations,
+                allocated_bytes: usage.allocated_bytes - before.allocated_bytes,
+            })
+        }).collect();
+        HeapProfile { components }
+    }
+}
+
+// Charges the current thread's allocations to a component until dropped,
+// then restores the previous one
+#[must_use = "the component is only entered while the scope is alive"]
+pub struct HeapScope {
+    #[cfg(feature = "heap-profile")]
+    previous: u8,
+}
+
+impl Drop for HeapScope {
+    fn drop(&mut self) {
+        #[cfg(feature = "heap-profile")]
+        profiling::set_current(self.previous);
+    }
+}
+
+// Enter `component` for the rest of the calling scope:
+//
+//     let _heap = heap::enter(HeapComponent::Leaves);
+#[inline]
+pub fn enter(component: HeapComponent) -> HeapScope {
+    #[cfg(feature = "heap-profile")]
+    {
+        HeapScope { previous: profiling::set_current(component as u8) }
+    }
+    #[cfg(not(feature = "heap-profile"))]
+    {
+        let _ = component;
+        HeapScope {}
+    }
+}
+
+// Run `f` with `component` entered
+#[inline]
+pub fn scoped<T>(component: HeapComponent, f: impl FnOnce() -> T) -> T {
+    let _scope = enter(component);
+    f()
+}
+
+// Current heap use per component, None unless ProfilingAllocator is the
+// global allocator
+pub fn profile() -> Option<HeapProfile> {
+    #[cfg(feature = "heap-profile")]
+    {
+        profiling::snapshot()
+    }
+    #[cfg(not(feature = "heap-profile"))]
+    {
+        None
+    }
+}
+
+#[cfg(feature = "heap-profile")]
+pub use profiling::ProfilingAllocator;
+
+#[cfg(feature = "heap-profile")]
+mod profiling {
+    use super::{ComponentUsage, HeapComponent, HeapProfile};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+    const COMPONENTS: usize = HeapComponent::ALL.len();
+
+    struct Counters {
+        live: [AtomicUsize; COMPONENTS],
+        peak: [AtomicUsize; COMPONENTS],
+        allocations: [AtomicU64; COMPONENTS],
+        allocated: [AtomicU64; COMPONENTS],
+    }
+
+    static COUNTERS: Counters = Counters {
+        live: [const { AtomicUsize::new(0) }; COMPONENTS],
+        peak: [const { AtomicUsize::new(0) }; COMPONENTS],
+        allocations: [const { AtomicU64::new(0) }; COMPONENTS],
+        allocated: [const { AtomicU64::new(0) }; COMPONENTS],
+    };
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+    thread_local! {
+        // Const-initialized without a destructor, so reading it never allocates
+        static CURRENT: Cell<u8> = const { Cell::new(0) };
+    }
+
+    pub(super) fn set_current(component: u8) -> u8 {
+        CURRENT.try_with(|current| current.replace(component)).unwrap_or(0)
+    }
+
+    pub(super) fn snapshot() -> Option<HeapProfile> {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            return None;
+        }
+        let components = HeapComponent::ALL.iter().enumerate().map(|(idx, &component)| {
+            (component, ComponentUsage {
+                live_bytes: COUNTERS.live[idx].load(Ordering::Relaxed),
+                peak_bytes: COUNTERS.peak[idx].load(Ordering::Relaxed),
+                allocations: COUNTERS.allocations[idx].load(Ordering::Relaxed),
+                allocated_bytes: COUNTERS.allocated[idx].load(Ordering::Relaxed),
+            })
+        }).collect();
+        Some(HeapProfile { components })
+    }
+
+    // The system allocator with every allocation prefixed by a header holding
+    // the component it is charged to. The header is as wide as the alignment
+    // (at least a word), so it costs RSS that the profile doesn't count.
+    // Install it in a binary with
+    //
+    //     #[global_allocator]
+    //     static ALLOCATOR: ait::heap::ProfilingAllocator = ait::heap::ProfilingAllocator;
+    pub struct ProfilingAllocator;
+
+    #[inline]
+    fn header(layout: Layout) -> usize {
+        layout.align().max(std::mem::size_of::<usize>())
+    }
+
+    #[inline]
+    fn charge(component: u8, bytes: usize) {
+        let idx = component as usize;
+        let live = COUNTERS.live[idx].fetch_add(bytes, Ordering::Relaxed) + bytes;
+        COUNTERS.peak[idx].fetch_max(live, Ordering::Relaxed);
+        COUNTERS.allocations[idx].fetch_add(1, Ordering::Relaxed);
+        COUNTERS.allocated[idx].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn release(component: u8, bytes: usize) {
+        COUNTERS.live[component as usize].fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    unsafe impl GlobalAlloc for ProfilingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let header = header(layout);
+            let Ok(outer) = Layout::from_size_align(layout.size() + header, layout.align()) else {
+                return std::ptr::null_mut();
+            };
+            let raw = System.alloc(outer);
+            if raw.is_null() {
+                return raw;
+            }
+            INSTALLED.store(true, Ordering::Relaxed);
+            let component = CURRENT.try_with(Cell::get).unwrap_or(0);
+            *raw = component;
+            charge(component, layout.size());
+            raw.add(header)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let header = header(layout);
+            let raw = ptr.sub(header);
+            release(*raw, layout.size());
+            System.dealloc(raw, Layout::from_size_align_unchecked(layout.size() + header, layout.align()));
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let header = header(layout);
+            let raw = ptr.sub(header);
+            let component = *raw;
+            let outer = Layout::from_size_align_unchecked(layout.size() + header, layout.align());
+            let Some(new_outer) = new_size.checked_add(header) else {
+                return std::ptr::null_mut();
+            };
+            let new_raw = System.realloc(raw, outer, new_outer);
+            if new_raw.is_null() {
+                return new_raw;
+            }
+            release(component, layout.size());
+            charge(component, new_size);
+            new_raw.add(header)
+        }
+    }
+}
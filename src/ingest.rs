@@ -0,0 +1,787 @@
+use crate::filter::{TimePartitionedIds, TranslationCache};
+#[cfg(feature = "persist")]
+use crate::persist::*;
+use crate::tree::*;
+#[cfg(feature = "bench")]
+use clap::ValueEnum;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "persist")]
+use std::fs::{self, File};
+#[cfg(feature = "persist")]
+use std::io::{self, Write};
+#[cfg(feature = "persist")]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg_attr(feature = "bench", derive(ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeKind {
+    // Merge `fanout` segments of similar size at once: each doc is rewritten
+    // about once per tier, but up to `fanout` segments per tier stay queryable
+    Tiered,
+    // Keep segments in levels `fanout` times apart in size, merging a segment
+    // into the next level as soon as it grows too close to it: few segments
+    // to query, at the cost of rewriting docs more often
+    Leveled,
+}
+
+impl MergeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MergeKind::Tiered => "tiered",
+            MergeKind::Leveled => "leveled",
+        }
+    }
+}
+
+// When a segmented index merges segments on its own
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MergePolicy {
+    pub kind: MergeKind,
+    pub fanout: usize,
+    // Segments at least this large are left alone
+    pub max_segment_docs: usize,
+    // Above this many segments the smallest are merged regardless of kind
+    pub max_segments: usize,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy {
+            kind: MergeKind::Tiered,
+            fanout: 10,
+            max_segment_docs: 4_000_000,
+            max_segments: 32,
+        }
+    }
+}
+
+impl MergePolicy {
+    // Indices of segments to merge next, or None when the segments are in
+    // shape. `sizes` are doc counts in segment order.
+    pub(crate) fn pick(&self, sizes: &[usize]) -> Option<Vec<usize>> {
+        let mut open: Vec<usize> = (0..sizes.len()).filter(|&i| sizes[i] < self.max_segment_docs).collect();
+        open.sort_by_key(|&i| sizes[i]);
+
+        if sizes.len() > self.max_segments && open.len() >= 2 {
+            return Some(open[..2].to_vec());
+        }
+        match self.kind {
+            MergeKind::Tiered => {
+                // Tier t holds segments with fewer than fanout^(t+1) docs
+                let tier = |size: usize| size.max(1).ilog(self.fanout);
+                open.chunk_by(|&a, &b| tier(sizes[a]) == tier(sizes[b]))
+                    .find(|same_tier| same_tier.len() >= self.fanout)
+                    .map(|same_tier| same_tier[..self.fanout].to_vec())
+            }
+            MergeKind::Leveled => {
+                // Merge the two smallest once they are within a level of each other
+                (open.len() >= 2 && sizes[open[0]] * self.fanout > sizes[open[1]]).then(|| open[..2].to_vec())
+            }
+        }
+    }
+}
+
+// Merge work done by a segmented index's policy
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SegmentMergeStats {
+    pub merges: u64,
+    // Docs rewritten by merges; divided by docs ingested, the write amplification
+    pub docs_merged: u64,
+    #[serde(rename = "time_ns", serialize_with = "serialize_nanos")]
+    pub time: Duration,
+}
+
+pub(crate) fn serialize_nanos<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_nanos() as u64)
+}
+
+// An AIT split into immutable segments so docs can be added without
+// rebuilding the whole tree. Single docs collect in a write buffer that is
+// built into a segment when full; batches become segments directly. The
+// merge policy decides when segments are merged; merge_segments merges all
+// of them. Doc ids must not repeat across inserts, or merging fails with
+// DuplicateDocId.
+//
+// Costs, for n docs in total and s segments:
+// - insert: amortized O(log b) per doc for buffer limit b, paid by sorting
+//   the buffer when it is flushed
+// - ingest_batch: O(m) for a batch of m docs already sorted by value,
+//   O(m log m) otherwise, plus any merges the policy triggers
+// - merge_segments: O(n log s), merging neighbouring segments pairwise
+// - queries: one query per segment plus a scan of the buffer
+pub struct SegmentedIndex {
+    pub(crate) options: BuildOptions,
+    pub(crate) policy: MergePolicy,
+    pub(crate) segments: Vec<AggregationIndexTree>,
+    // Docs inserted one at a time and not yet in a segment, in insertion order
+    pub(crate) buffer: Vec<(u32, f64)>,
+    pub(crate) buffer_limit: usize,
+    pub(crate) merge_stats: SegmentMergeStats,
+    // Apply the merge policy on every new segment; off when a background
+    // compaction task applies it instead
+    pub(crate) inline_merges: bool,
+}
+
+impl SegmentedIndex {
+    pub fn new(options: BuildOptions, buffer_limit: usize, policy: MergePolicy) -> Self {
+        SegmentedIndex {
+            options,
+            policy,
+            segments: Vec::new(),
+            buffer: Vec::new(),
+            buffer_limit: buffer_limit.max(1),
+            merge_stats: SegmentMergeStats::default(),
+            inline_merges: true,
+        }
+    }
+
+    // Leave merging to a background compaction task, keeping ingestion cheap
+    pub fn defer_merges(mut self) -> Self {
+        self.inline_merges = false;
+        self
+    }
+
+    pub fn insert(&mut self, doc_id: u32, value: f64) -> Result<(), AitError> {
+        self.buffer.push((doc_id, value));
+        if self.buffer.len() >= self.buffer_limit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Build a batch straight into a new segment, skipping the sort when the
+    // pairs already arrive in value order, then apply the merge policy
+    pub fn ingest_batch(&mut self, pairs: Vec<(u32, f64)>) -> Result<(), AitError> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        self.segments.push(build_aggregation_index_tree(pairs, &self.options)?);
+        if self.inline_merges {
+            self.apply_merge_policy()?;
+        }
+        Ok(())
+    }
+
+    // Turn the write buffer into a segment
+    pub fn flush(&mut self) -> Result<(), AitError> {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.ingest_batch(buffer)
+    }
+
+    pub(crate) fn apply_merge_policy(&mut self) -> Result<(), AitError> {
+        while self.merge_step()? {}
+        Ok(())
+    }
+
+    // Merge the next group the policy picks, returning whether there was one
+    pub(crate) fn merge_step(&mut self) -> Result<bool, AitError> {
+        let sizes: Vec<usize> = self.segments.iter().map(|segment| segment.position_count()).collect();
+        let Some(mut picked) = self.policy.pick(&sizes) else {
+            return Ok(false);
+        };
+        // Remove from the back so the remaining indices stay valid
+        picked.sort_unstable_by(|a, b| b.cmp(a));
+        let group = picked.into_iter().map(|i| self.segments.swap_remove(i)).collect();
+        let merged = self.merge_group(group)?;
+        self.segments.push(merged);
+        Ok(true)
+    }
+
+    // Merge segments pairwise into one, recording the work in the merge stats
+    pub(crate) fn merge_group(&mut self, mut group: Vec<AggregationIndexTree>) -> Result<AggregationIndexTree, AitError> {
+        let start = Instant::now();
+        while group.len() > 1 {
+            let mut merged = Vec::with_capacity(group.len().div_ceil(2));
+            let mut segments = group.into_iter();
+            while let Some(first) = segments.next() {
+                match segments.next() {
+                    Some(second) => {
+                        self.merge_stats.merges += 1;
+                        self.merge_stats.docs_merged += (first.position_count() + second.position_count()) as u64;
+                        merged.push(first.merge(&second)?);
+                    }
+                    None => merged.push(first),
+                }
+            }
+            group = merged;
+        }
+        self.merge_stats.time += start.elapsed();
+        Ok(group.pop().expect("merge group is not empty"))
+    }
+
+    // Merge every segment into one, flushing the buffer first
+    pub fn merge_segments(&mut self) -> Result<(), AitError> {
+        self.flush()?;
+        if self.segments.len() > 1 {
+            let segments = std::mem::take(&mut self.segments);
+            let merged = self.merge_group(segments)?;
+            self.segments.push(merged);
+        }
+        Ok(())
+    }
+
+    pub fn merge_stats(&self) -> SegmentMergeStats {
+        self.merge_stats
+    }
+
+    // Drop the given docs, rebuilding only the segments that hold any of them.
+    // Returns the number of docs removed. Segments need doc ids; on an error
+    // the index is left as it was.
+    pub fn remove_docs(&mut self, deleted: &RoaringBitmap) -> Result<usize, AitError> {
+        if self.segments.iter().any(|segment| !segment.has_doc_ids) {
+            return Err(AitError::DocIdsNotStored);
+        }
+        // Rebuild the touched segments first, so a failed build changes nothing
+        let mut rebuilt = Vec::new();
+        let mut removed = 0;
+        for (idx, segment) in self.segments.iter().enumerate() {
+            let touched = segment.doc_id_range.is_some_and(|(min, max)| deleted.range_cardinality(min..=max) > 0);
+            if !touched {
+                continue;
+            }
+            // Positions are in value order, so the survivors need no sort
+            let survivors: Vec<(u32, f64)> = (0..segment.position_count())
+                .map(|pos| (segment.get_doc_id_at_position(pos), segment.get_value_at_position(pos)))
+                .filter(|&(doc_id, _)| !deleted.contains(doc_id))
+                .collect();
+            removed += segment.position_count() - survivors.len();
+            let replacement = if survivors.is_empty() {
+                None
+            } else {
+                Some(build_aggregation_index_tree(survivors, &self.options)?)
+            };
+            rebuilt.push((idx, replacement));
+        }
+
+        let buffered = self.buffer.len();
+        self.buffer.retain(|&(doc_id, _)| !deleted.contains(doc_id));
+        removed += buffered - self.buffer.len();
+        let mut rebuilt = rebuilt.into_iter().peekable();
+        let segments = std::mem::take(&mut self.segments);
+        for (idx, segment) in segments.into_iter().enumerate() {
+            match rebuilt.next_if(|(rebuilt_idx, _)| *rebuilt_idx == idx) {
+                Some((_, replacement)) => self.segments.extend(replacement),
+                None => self.segments.push(segment),
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn get_global_aggregations(&self) -> NodeAggregations {
+        let mut result = self
+            .segments
+            .iter()
+            .fold(NodeAggregations::empty(), |acc, segment| acc + segment.get_global_aggregations());
+        for &(_, value) in &self.buffer {
+            result.add_value(value);
+        }
+        result
+    }
+
+    pub fn query_with_bitmap(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        let mut result = NodeAggregations::empty();
+        for segment in &self.segments {
+            result += segment.query_with_bitmap(bitmap)?;
+        }
+        for &(doc_id, value) in &self.buffer {
+            if bitmap.contains(doc_id) {
+                result.add_value(value);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn query_value_range(&self, min: f64, max: f64) -> NodeAggregations {
+        let mut result = self
+            .segments
+            .iter()
+            .fold(NodeAggregations::empty(), |acc, segment| acc + segment.query_value_range(min, max));
+        for &(_, value) in &self.buffer {
+            if value >= min && value <= max {
+                result.add_value(value);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(feature = "persist")]
+pub(crate) const SEGMENT_MANIFEST: &str = "MANIFEST.json";
+
+// The committed state of a persisted segmented index: the segment
+// directories that make it up. Replaced atomically on every persist.
+#[cfg(feature = "persist")]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SegmentManifest {
+    pub(crate) generation: u64,
+    pub(crate) segments: Vec<String>,
+}
+
+// Write `bytes` to `path` through a temporary file and a rename, so readers
+// see either the old contents or the new ones
+#[cfg(feature = "persist")]
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+// Make a rename or create within the parent directory durable
+#[cfg(feature = "persist")]
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+// Persistence for segmented indexes. Each segment is saved in the split
+// layout under a directory named after its checksum, so unchanged segments
+// are not rewritten. A persist writes new segments to temporary directories,
+// renames them into place, then swaps the manifest; only then are segments
+// the manifest no longer lists removed. A crash at any point leaves the old
+// or the new manifest, and each lists complete segments only.
+#[cfg(feature = "persist")]
+impl SegmentedIndex {
+    pub fn persist(&mut self, dir: &Path) -> io::Result<()> {
+        self.flush().map_err(io::Error::other)?;
+        fs::create_dir_all(dir)?;
+
+        let mut names = Vec::with_capacity(self.segments.len());
+        for segment in &self.segments {
+            let name = format!("seg-{:016x}", segment.checksum());
+            let path = dir.join(&name);
+            if !path.exists() {
+                let tmp = dir.join(format!("{}.tmp", name));
+                if tmp.exists() {
+                    fs::remove_dir_all(&tmp)?;
+                }
+                segment.save(&tmp)?;
+                for entry in fs::read_dir(&tmp)? {
+                    File::open(entry?.path())?.sync_all()?;
+                }
+                File::open(&tmp)?.sync_all()?;
+                fs::rename(&tmp, &path)?;
+            }
+            names.push(name);
+        }
+        sync_parent(&dir.join(SEGMENT_MANIFEST))?;
+
+        let previous = Self::read_manifest(dir)?;
+        let manifest = SegmentManifest {
+            generation: previous.map_or(0, |manifest| manifest.generation + 1),
+            segments: names,
+        };
+        write_atomically(&dir.join(SEGMENT_MANIFEST), &serde_json::to_vec_pretty(&manifest)?)?;
+        Self::remove_unreferenced(dir, &manifest)
+    }
+
+    // Open a directory written by persist, discarding anything an interrupted
+    // persist left behind. A directory without a manifest holds an empty index.
+    pub fn open(dir: &Path, options: BuildOptions, buffer_limit: usize, policy: MergePolicy) -> io::Result<Self> {
+        let mut index = SegmentedIndex::new(options, buffer_limit, policy);
+        let Some(manifest) = Self::read_manifest(dir)? else {
+            return Ok(index);
+        };
+        for name in &manifest.segments {
+            index.segments.push(AggregationIndexTree::load(&dir.join(name))?);
+        }
+        Self::remove_unreferenced(dir, &manifest)?;
+        Ok(index)
+    }
+
+    pub(crate) fn read_manifest(dir: &Path) -> io::Result<Option<SegmentManifest>> {
+        match fs::read(dir.join(SEGMENT_MANIFEST)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Remove segment directories the manifest doesn't list and temporary
+    // files; other files in the directory are left alone
+    pub(crate) fn remove_unreferenced(dir: &Path, manifest: &SegmentManifest) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let stale_segment = name.starts_with("seg-") && !manifest.segments.contains(&name);
+            if !stale_segment && !name.ends_with(".tmp") {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Read-only view of a persisted segmented index for query processes running
+// beside the writer. Segments are memory-mapped, and the view never modifies
+// the directory. refresh picks up whatever manifest the writer has committed
+// since, keeping the mappings of segments that are still listed.
+#[cfg(feature = "persist")]
+pub struct SegmentReader {
+    pub(crate) dir: PathBuf,
+    pub(crate) generation: Option<u64>,
+    pub(crate) segments: Vec<(String, Arc<MappedSegment>)>,
+}
+
+#[cfg(feature = "persist")]
+impl SegmentReader {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let mut reader = SegmentReader {
+            dir: dir.to_path_buf(),
+            generation: None,
+            segments: Vec::new(),
+        };
+        reader.refresh()?;
+        Ok(reader)
+    }
+
+    // Switch to the latest committed manifest, returning whether it changed
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        const MAX_ATTEMPTS: usize = 8;
+        let mut attempt = 1;
+        loop {
+            let manifest = SegmentedIndex::read_manifest(&self.dir)?;
+            let generation = manifest.as_ref().map(|manifest| manifest.generation);
+            if generation == self.generation {
+                return Ok(false);
+            }
+            let names = manifest.map_or_else(Vec::new, |manifest| manifest.segments);
+            match self.map_segments(names) {
+                Ok(segments) => {
+                    self.segments = segments;
+                    self.generation = generation;
+                    return Ok(true);
+                }
+                // The writer committed a newer manifest and removed one of
+                // this manifest's segments before it was mapped
+                Err(err) if err.kind() == io::ErrorKind::NotFound && attempt < MAX_ATTEMPTS => attempt += 1,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub(crate) fn map_segments(&self, names: Vec<String>) -> io::Result<Vec<(String, Arc<MappedSegment>)>> {
+        names
+            .into_iter()
+            .map(|name| {
+                let segment = match self.segments.iter().find(|(mapped, _)| *mapped == name) {
+                    Some((_, segment)) => Arc::clone(segment),
+                    None => Arc::new(MappedSegment::open(&self.dir.join(&name))?),
+                };
+                Ok((name, segment))
+            })
+            .collect()
+    }
+
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn get_global_aggregations(&self) -> NodeAggregations {
+        self.segments
+            .iter()
+            .fold(NodeAggregations::empty(), |acc, (_, segment)| acc + segment.get_global_aggregations())
+    }
+
+    pub fn query_with_bitmap(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        let mut result = NodeAggregations::empty();
+        for (_, segment) in &self.segments {
+            result += segment.query_with_bitmap(bitmap)?;
+        }
+        Ok(result)
+    }
+
+    pub fn query_value_range(&self, min: f64, max: f64) -> NodeAggregations {
+        self.segments
+            .iter()
+            .fold(NodeAggregations::empty(), |acc, (_, segment)| acc + segment.query_value_range(min, max))
+    }
+}
+
+// Periodic upkeep run on a background thread by the maintenance scheduler
+pub trait MaintenanceTask: Send {
+    fn name(&self) -> &'static str;
+    fn interval(&self) -> Duration;
+    // Do whatever work is due; Ok(true) if there was any
+    fn run(&mut self) -> Result<bool, String>;
+}
+
+// Turns docs sitting in a segmented index's write buffer into a segment, so
+// a slow trickle of inserts doesn't stay in the scanned buffer indefinitely
+pub struct FlushTask {
+    pub index: Arc<RwLock<SegmentedIndex>>,
+    pub interval: Duration,
+}
+
+impl MaintenanceTask for FlushTask {
+    fn name(&self) -> &'static str {
+        "flush"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn run(&mut self) -> Result<bool, String> {
+        let mut index = self.index.write().expect("segmented index lock poisoned");
+        if index.buffer.is_empty() {
+            return Ok(false);
+        }
+        index.flush().map(|()| true).map_err(|err| err.to_string())
+    }
+}
+
+// Applies the merge policy to a segmented index that defers its merges.
+// Each merge takes the write lock on its own, so queries wait for at most
+// one merge rather than the whole pass.
+pub struct CompactionTask {
+    pub index: Arc<RwLock<SegmentedIndex>>,
+    pub interval: Duration,
+}
+
+impl MaintenanceTask for CompactionTask {
+    fn name(&self) -> &'static str {
+        "compaction"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn run(&mut self) -> Result<bool, String> {
+        let mut merged = false;
+        loop {
+            let mut index = self.index.write().expect("segmented index lock poisoned");
+            if !index.merge_step().map_err(|err| err.to_string())? {
+                return Ok(merged);
+            }
+            merged = true;
+        }
+    }
+}
+
+// Removes the docs of time partitions that ended more than `ttl` ago from a
+// segmented index whose doc ids were allocated by `ids`. Expired partitions
+// are a prefix of the id space, so each run only removes the ones that have
+// expired since the last.
+pub struct ExpiryTask {
+    pub(crate) index: Arc<RwLock<SegmentedIndex>>,
+    pub(crate) ids: Arc<TimePartitionedIds>,
+    pub(crate) ttl: Duration,
+    pub(crate) interval: Duration,
+    // Current time in epoch milliseconds, the timestamps' clock
+    pub(crate) now_ms: Box<dyn Fn() -> i64 + Send>,
+    pub(crate) expired_partitions: usize,
+}
+
+impl ExpiryTask {
+    pub fn new(index: Arc<RwLock<SegmentedIndex>>, ids: Arc<TimePartitionedIds>, ttl: Duration, interval: Duration,
+               now_ms: Box<dyn Fn() -> i64 + Send>) -> Self {
+        ExpiryTask { index, ids, ttl, interval, now_ms, expired_partitions: 0 }
+    }
+}
+
+impl MaintenanceTask for ExpiryTask {
+    fn name(&self) -> &'static str {
+        "expiry"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn run(&mut self) -> Result<bool, String> {
+        let cutoff_ms = (self.now_ms)().saturating_sub(self.ttl.as_millis() as i64);
+        let (expired, mut ids) = self.ids.expired_ids(cutoff_ms);
+        if expired <= self.expired_partitions {
+            return Ok(false);
+        }
+        if let Some(done) = self.expired_partitions.checked_sub(1).map(|idx| &self.ids.partitions[idx]) {
+            ids.remove_range(0..done.ids.end);
+        }
+        let mut index = self.index.write().expect("segmented index lock poisoned");
+        index.remove_docs(&ids).map_err(|err| err.to_string())?;
+        self.expired_partitions = expired;
+        Ok(true)
+    }
+}
+
+// Evicts translation cache entries that went unused since the previous run,
+// so filters a dashboard stopped asking for don't hold memory until enough
+// new ones push them out
+pub struct CacheEvictionTask {
+    pub(crate) cache: Arc<TranslationCache>,
+    pub(crate) interval: Duration,
+    // Cache clock at the previous run
+    pub(crate) mark: u64,
+}
+
+impl CacheEvictionTask {
+    // The first run evicts nothing; it marks where unused time starts
+    pub fn new(cache: Arc<TranslationCache>, interval: Duration) -> Self {
+        CacheEvictionTask { cache, interval, mark: 0 }
+    }
+}
+
+impl MaintenanceTask for CacheEvictionTask {
+    fn name(&self) -> &'static str {
+        "cache-eviction"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn run(&mut self) -> Result<bool, String> {
+        let evicted = self.cache.evict_unused_since(self.mark);
+        self.mark = self.cache.clock();
+        Ok(evicted > 0)
+    }
+}
+
+// Per-task counters reported by the scheduler
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskStats {
+    pub name: &'static str,
+    pub runs: u64,
+    // Runs that found work to do
+    pub busy_runs: u64,
+    pub errors: u64,
+    pub last_error: Option<String>,
+    #[serde(rename = "busy_time_ns", serialize_with = "serialize_nanos")]
+    pub busy_time: Duration,
+}
+
+#[derive(Default)]
+pub(crate) struct SchedulerState {
+    pub(crate) paused: bool,
+    pub(crate) stopping: bool,
+}
+
+pub(crate) struct SchedulerShared {
+    pub(crate) state: Mutex<SchedulerState>,
+    pub(crate) wake: Condvar,
+    pub(crate) stats: Mutex<Vec<TaskStats>>,
+}
+
+// Runs maintenance tasks on their own background threads, each on its own
+// interval. Pausing lets in-flight runs finish but starts no new ones.
+pub struct MaintenanceScheduler {
+    pub(crate) shared: Arc<SchedulerShared>,
+    pub(crate) threads: Vec<JoinHandle<()>>,
+}
+
+impl MaintenanceScheduler {
+    pub fn start(tasks: Vec<Box<dyn MaintenanceTask>>) -> Self {
+        let shared = Arc::new(SchedulerShared {
+            state: Mutex::new(SchedulerState::default()),
+            wake: Condvar::new(),
+            stats: Mutex::new(tasks.iter().map(|task| TaskStats { name: task.name(), ..TaskStats::default() }).collect()),
+        });
+        let threads = tasks
+            .into_iter()
+            .enumerate()
+            .map(|(slot, task)| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || Self::run_task(&shared, slot, task))
+            })
+            .collect();
+        MaintenanceScheduler { shared, threads }
+    }
+
+    pub(crate) fn run_task(shared: &SchedulerShared, slot: usize, mut task: Box<dyn MaintenanceTask>) {
+        loop {
+            let state = shared.state.lock().expect("scheduler state lock poisoned");
+            let (state, _) = shared
+                .wake
+                .wait_timeout_while(state, task.interval(), |state| !state.stopping)
+                .expect("scheduler state lock poisoned");
+            let state = shared
+                .wake
+                .wait_while(state, |state| state.paused && !state.stopping)
+                .expect("scheduler state lock poisoned");
+            if state.stopping {
+                return;
+            }
+            drop(state);
+
+            let start = Instant::now();
+            let outcome = task.run();
+            let elapsed = start.elapsed();
+            let mut stats = shared.stats.lock().expect("scheduler stats lock poisoned");
+            let stats = &mut stats[slot];
+            stats.runs += 1;
+            match outcome {
+                Ok(true) => {
+                    stats.busy_runs += 1;
+                    stats.busy_time += elapsed;
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    stats.errors += 1;
+                    stats.last_error = Some(err);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.shared.state.lock().expect("scheduler state lock poisoned").paused = paused;
+        self.shared.wake.notify_all();
+    }
+
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    pub fn resume(&self) {
+        self.set_paused(false);
+    }
+
+    pub fn stats(&self) -> Vec<TaskStats> {
+        self.shared.stats.lock().expect("scheduler stats lock poisoned").clone()
+    }
+
+    pub(crate) fn stop(&self) {
+        self.shared.state.lock().expect("scheduler state lock poisoned").stopping = true;
+        self.shared.wake.notify_all();
+    }
+
+    // Stop every task after its current run and wait for the threads to exit
+    pub fn shutdown(mut self) -> Vec<TaskStats> {
+        self.stop();
+        for handle in self.threads.drain(..) {
+            let _ = handle.join();
+        }
+        self.stats()
+    }
+}
+
+impl Drop for MaintenanceScheduler {
+    // Threads of a scheduler dropped without shutdown exit after their current run
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
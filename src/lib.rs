@@ -0,0 +1,186 @@
+// Aggregation Index Tree: a value-sorted tree of pre-aggregated nodes that
+// answers min/max/sum/count over doc-id filters and value ranges.
+//
+// - tree: the tree itself, its build and the ways trees are combined
+// - query: bitmap and value-range queries, the planner, and alternative indexes
+// - sketch: mergeable distribution summaries kept per node
+// - filter: doc-id filters, term columns and doc-id remapping
+// - heap: heap accounting per tree component, for the benchmark's breakdown
+// - ingest: segmented ingestion, merge policies and background maintenance
+// - persist: on-disk formats, checkpoints and row export
+// - otel: OpenTelemetry export of the build and query spans
+// - bench: the benchmark, fuzzer and index tools behind the `ait_benchmark` binary
+// - datagen: synthetic records for the benchmark, behind the RecordGenerator trait
+//
+// Items re-exported from `prelude` are the stable surface; everything else is
+// crate-internal and may change between releases.
+//
+// Cargo features: `parallel` runs large queries and partitioned builds on the
+// rayon pool, `persist` adds the persist module and saving of segmented
+// indexes and planner profiles, and `bench` (the default) adds the benchmark
+// with its CLI and data generation. `heap-profile`, off by default, installs
+// heap::ProfilingAllocator in the benchmark binary to measure memory per tree
+// component. `otel`, also off by default, adds the otel module and the
+// benchmark's --otlp-endpoint. With none of them the crate depends only on
+// roaring, memuse, serde and tracing.
+
+// Crate-internal items that only the benchmark drives (planner profiles,
+// build checkpoints, value-ordered ids and the like) allow dead code when it
+// is off, item by item, so anything else left unused still warns.
+
+use crate::tree::Aggregator;
+
+// Whether human-readable progress and results are printed. Only the table
+// format prints them; JSON output is written once at the end of a command.
+#[cfg(feature = "bench")]
+pub(crate) static HUMAN_OUTPUT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
+
+#[cfg(feature = "bench")]
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if $crate::HUMAN_OUTPUT.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Format a count with thousands separators, e.g. 10,000,000
+pub(crate) fn format_count(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+// Format a count to two significant digits for previews, e.g. 1.2M
+pub(crate) fn format_approx_count(n: u64) -> String {
+    const UNITS: [(f64, &str); 3] = [(1e9, "B"), (1e6, "M"), (1e3, "K")];
+    for (scale, unit) in UNITS {
+        let value = n as f64 / scale;
+        if value >= 1.0 {
+            return if value < 10.0 { format!("{:.1}{}", value, unit) } else { format!("{:.0}{}", value, unit) };
+        }
+    }
+    n.to_string()
+}
+
+// Format a byte count in the largest unit that keeps it at or above one
+pub(crate) fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["bytes", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+// Aggregate each item and combine the partial results, each merge starting
+// from the aggregator's empty state. Items are spread over the rayon pool with
+// the `parallel` feature and run in order on the calling thread without it.
+#[cfg(feature = "parallel")]
+pub(crate) fn aggregate_each<A, I, F>(aggregator: &A, items: I, aggregate: F) -> A::State
+where
+    A: Aggregator,
+    I: rayon::iter::IntoParallelIterator,
+    F: Fn(I::Item) -> A::State + Sync + Send,
+{
+    use rayon::prelude::*;
+    items.into_par_iter().map(aggregate).reduce(|| aggregator.init(), |mut a, b| {
+        aggregator.merge(&mut a, b);
+        a
+    })
+}
+
+#[cfg(not(feature = "parallel"))]
+pub(crate) fn aggregate_each<A, I, F>(aggregator: &A, items: I, aggregate: F) -> A::State
+where
+    A: Aggregator,
+    I: IntoIterator,
+    F: Fn(I::Item) -> A::State,
+{
+    items.into_iter().map(aggregate).fold(aggregator.init(), |mut a, b| {
+        aggregator.merge(&mut a, b);
+        a
+    })
+}
+
+// Map each item, keeping results in item order; parallel as aggregate_each
+#[cfg(all(feature = "persist", feature = "parallel"))]
+pub(crate) fn map_each<I, F, R>(items: I, map: F) -> Vec<R>
+where
+    I: rayon::iter::IntoParallelIterator,
+    I::Iter: rayon::iter::IndexedParallelIterator,
+    F: Fn(I::Item) -> R + Sync + Send,
+    R: Send,
+{
+    use rayon::prelude::*;
+    items.into_par_iter().map(map).collect()
+}
+
+#[cfg(all(feature = "persist", not(feature = "parallel")))]
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) fn map_each<I, F, R>(items: I, map: F) -> Vec<R>
+where
+    I: IntoIterator,
+    F: Fn(I::Item) -> R,
+{
+    items.into_iter().map(map).collect()
+}
+
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(feature = "bench")]
+pub mod datagen;
+pub mod filter;
+pub mod heap;
+pub mod ingest;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "persist")]
+pub mod persist;
+pub mod query;
+pub(crate) mod sketch;
+pub mod tree;
+
+// The supported API, for `use ait::prelude::*`
+pub mod prelude {
+    #[cfg(feature = "bench")]
+    pub use crate::datagen::{Cardinalities, LogEntity, LogRecordGenerator, RecordColumns, RecordGenerator, TimePattern};
+    pub use crate::filter::{DocFilter, SortedIds, TermBucket, TermColumn, TermOrder, TimePartitionedIds, TranslationCache};
+    pub use crate::heap::{ComponentUsage, HeapComponent, HeapProfile};
+    #[cfg(feature = "persist")]
+    pub use crate::ingest::SegmentReader;
+    pub use crate::ingest::{
+        CacheEvictionTask, CompactionTask, ExpiryTask, FlushTask, MaintenanceScheduler, MaintenanceTask, MergeKind,
+        MergePolicy, SegmentMergeStats, SegmentedIndex, TaskStats,
+    };
+    #[cfg(feature = "otel")]
+    pub use crate::otel::{otlp_provider, tracing_layer};
+    #[cfg(feature = "persist")]
+    pub use crate::persist::{import_filter, ExportFormat, FilterFormat, FilterStore, MappedSegment};
+    pub use crate::query::{
+        AggIndex, ConjunctionPlan, CostEstimate, CountEstimate, DerivedMetric, FenwickSums, FieldIndex, FieldIndexSet,
+        FieldQueryMix, FieldRepresentation, LatencySummary, Metric, PlanStep, PlannerThresholds, Predicate,
+        QueryStrategy, SampledIndex, StepMethod, TermBucketStream, TimeSegmentTree, WaveletMatrix, ZoneMapColumn,
+    };
+    pub use crate::sketch::{LogBucket, LogHistogram};
+    pub use crate::tree::{
+        build_aggregation_index_tree, AggregationIndexTree, Aggregator, AitBuilder, AitError, ArenaStats, BuildOptions,
+        DateBucket, DateInterval, ExtendedStats, GeometricMean, IdRemap, IntervalBucket, LevelStats, LogSum, Moments,
+        NodeAggregations, NonPositive, StructureBlock, SubtreeNode, Summary, TreeStructure, ValueCount, WeightedSums,
+    };
+}
+
+#[cfg(all(test, feature = "bench"))]
+mod tests;
@@ -0,0 +1,47 @@
+// OpenTelemetry export of the index's tracing spans. The index only emits
+// `tracing` spans; an embedding service adds `tracing_layer` to its
+// subscriber so they join its distributed traces, and the benchmark exports
+// them with --otlp-endpoint. Spans, all at info level, with their fields
+// recorded as attributes of the same name:
+//
+// - ait.build: rows (input docs), leaf_size
+// - ait.query.bitmap: cardinality (ids in the filter), strategy (the
+//   QueryStrategy name run, e.g. "complement")
+// - ait.query.range: min, max, cardinality (docs matched) and nodes_visited
+//   (nodes aggregated or descended into), both recorded as the query returns
+// - ait.save: nodes
+// - ait.load: split (whether the path is a split-layout directory)
+//
+// Queries nest under whatever span is current, so a request span covering
+// several index calls shows each one's strategy and cost.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+// Instrumentation scope of the spans, and the service name of otlp_provider
+pub const TRACER_NAME: &str = "ait";
+
+// A tracing layer turning the index's spans into OpenTelemetry spans of
+// `provider`'s tracer
+pub fn tracing_layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, SdkTracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(TRACER_NAME))
+}
+
+// Provider sending spans in batches to the OTLP/HTTP traces endpoint of a
+// collector, e.g. http://localhost:4318/v1/traces. Batches go out from a
+// background thread; shut the provider down before exit to flush the last one.
+pub fn otlp_provider(endpoint: &str) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder().with_http().with_endpoint(endpoint).build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name(TRACER_NAME).build())
+        .with_batch_exporter(exporter)
+        .build())
+}
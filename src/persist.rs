@@ -0,0 +1,1508 @@
+use crate::{filter::*, query::*, tree::*};
+use chrono::{DateTime, Utc};
+#[cfg(feature = "bench")]
+use clap::ValueEnum;
+use memmap2::Mmap;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// On-disk index formats. Older versions are migrated on read so persisted
+// indexes survive crate upgrades.
+//
+// v1: single file; magic, version, node count, nodes (leaves always carry doc ids)
+// v2: single file; magic, version, metadata JSON block, doc-id flag, node count, nodes
+// v3: directory; a small JSON file with the metadata, the node skeleton, and
+//     value/doc-id slabs in position order that leaves reference by offset.
+//     Slabs can be mapped independently or live on a different storage tier.
+// v4: v3 with each node's sum of squares after its count; v3 indexes get them
+//     recomputed from the leaf values on read
+pub(crate) const INDEX_MAGIC: &[u8; 4] = b"AITX";
+pub(crate) const INDEX_FORMAT_VERSION: u32 = 4;
+// Oldest split-layout version still read
+pub(crate) const MIN_SPLIT_FORMAT_VERSION: u32 = 3;
+
+pub(crate) const INDEX_META_FILE: &str = "meta.json";
+pub(crate) const INDEX_NODES_FILE: &str = "nodes.bin";
+pub(crate) const INDEX_VALUES_SLAB: &str = "values.slab";
+pub(crate) const INDEX_DOC_IDS_SLAB: &str = "doc_ids.slab";
+
+// Term dictionary files: magic, version, doc count, term count, then per term
+// its UTF-8 length and bytes and its posting bitmap in the portable Roaring
+// format, prefixed by its byte length
+pub(crate) const TERMS_MAGIC: &[u8; 4] = b"AITD";
+pub(crate) const TERMS_FORMAT_VERSION: u32 = 1;
+
+// A saved FieldIndexSet: a JSON manifest naming the fields, a split-layout
+// index directory per tree field, a doc-order value file per zone-map field,
+// and a dictionary file per term field. Files are named by position in the
+// manifest, so field names need not be valid file names.
+pub(crate) const FIELD_SET_MANIFEST_FILE: &str = "fields.json";
+// Where the benchmark saves its field set inside a saved index directory
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) const INDEX_FIELD_SET_DIR: &str = "fields";
+pub(crate) const FIELD_SET_FORMAT_VERSION: u32 = 1;
+
+// Named filter bitmaps saved with an index, one portable Roaring file each
+pub(crate) const INDEX_FILTERS_DIR: &str = "filters";
+pub(crate) const FILTER_EXTENSION: &str = "roaring";
+
+pub(crate) const NODE_TAG_INTERNAL: u8 = 0;
+pub(crate) const NODE_TAG_LEAF: u8 = 1;
+
+// Contents of the metadata file of a split-layout index. Small enough to read
+// without touching the slabs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct IndexManifest {
+    pub(crate) format_version: u32,
+    pub(crate) metadata: IndexMetadata,
+    pub(crate) has_doc_ids: bool,
+    pub(crate) node_count: u64,
+    pub(crate) leaf_count: u64,
+    pub(crate) global: NodeAggregations,
+}
+
+impl IndexManifest {
+    pub(crate) fn read(dir: &Path) -> io::Result<Self> {
+        let manifest: IndexManifest = serde_json::from_slice(&fs::read(dir.join(INDEX_META_FILE))?)?;
+        if !(MIN_SPLIT_FORMAT_VERSION..=INDEX_FORMAT_VERSION).contains(&manifest.format_version) {
+            return Err(invalid_data(&format!(
+                "unsupported index format version {} (this build reads up to {})",
+                manifest.format_version, INDEX_FORMAT_VERSION
+            )));
+        }
+        Ok(manifest)
+    }
+
+    // Whether the nodes file stores sums of squares
+    pub(crate) fn has_sum_squares(&self) -> bool {
+        self.format_version >= 4
+    }
+}
+
+impl AggregationIndexTree {
+    // Write the index to `dir` in the split layout, creating the directory if needed.
+    // Files are written under temporary names and renamed over an existing
+    // index's, so a save never truncates files that readers may have mapped.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let _span = tracing::info_span!("ait.save", nodes = self.nodes.len()).entered();
+        fs::create_dir_all(dir)?;
+        let staged = |name: &str| dir.join(format!("{}.tmp", name));
+
+        // Slabs hold leaf contents in position order; record where each leaf starts.
+        // Positions come from the tree, so a damaged position map saves intact.
+        let mut leaf_starts = HashMap::with_capacity(self.leaf_count);
+        let mut values = BufWriter::new(File::create(staged(INDEX_VALUES_SLAB))?);
+        let mut doc_ids = if self.has_doc_ids {
+            Some(BufWriter::new(File::create(staged(INDEX_DOC_IDS_SLAB))?))
+        } else {
+            None
+        };
+        for (position, &(node_idx, offset)) in self.traverse_positions().iter().enumerate() {
+            if offset == 0 {
+                leaf_starts.insert(node_idx, position as u64);
+            }
+            if let AggregationTreeNode::Leaf { doc_ids: leaf_doc_ids, values: leaf_values, .. } = &self.nodes[node_idx] {
+                values.write_all(&leaf_values[offset].to_le_bytes())?;
+                if let Some(doc_ids) = &mut doc_ids {
+                    doc_ids.write_all(&leaf_doc_ids[offset].to_le_bytes())?;
+                }
+            }
+        }
+        values.into_inner()?.sync_all()?;
+        if let Some(doc_ids) = doc_ids {
+            doc_ids.into_inner()?.sync_all()?;
+        }
+
+        let mut nodes = BufWriter::new(File::create(staged(INDEX_NODES_FILE))?);
+        nodes.write_all(&(self.nodes.len() as u64).to_le_bytes())?;
+        for (node_idx, node) in self.nodes.iter().enumerate() {
+            match node {
+                AggregationTreeNode::Internal { split_value, left, right, aggregations } => {
+                    nodes.write_all(&[NODE_TAG_INTERNAL])?;
+                    nodes.write_all(&split_value.to_le_bytes())?;
+                    nodes.write_all(&(*left as u64).to_le_bytes())?;
+                    nodes.write_all(&(*right as u64).to_le_bytes())?;
+                    write_aggregations(&mut nodes, aggregations)?;
+                }
+                AggregationTreeNode::Leaf { values, aggregations, .. } => {
+                    nodes.write_all(&[NODE_TAG_LEAF])?;
+                    nodes.write_all(&leaf_starts.get(&node_idx).copied().unwrap_or(0).to_le_bytes())?;
+                    nodes.write_all(&(values.len() as u64).to_le_bytes())?;
+                    write_aggregations(&mut nodes, aggregations)?;
+                }
+            }
+        }
+        nodes.into_inner()?.sync_all()?;
+        let mut meta = File::create(staged(INDEX_META_FILE))?;
+        meta.write_all(&serde_json::to_vec_pretty(&self.manifest())?)?;
+        meta.sync_all()?;
+
+        // Drop the old metadata before swapping files in, so a crash mid-swap
+        // leaves no manifest rather than one over mismatched slabs. The new
+        // metadata file goes last so a complete one implies complete slabs.
+        match fs::remove_file(dir.join(INDEX_META_FILE)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        fs::rename(staged(INDEX_VALUES_SLAB), dir.join(INDEX_VALUES_SLAB))?;
+        if self.has_doc_ids {
+            fs::rename(staged(INDEX_DOC_IDS_SLAB), dir.join(INDEX_DOC_IDS_SLAB))?;
+        } else if dir.join(INDEX_DOC_IDS_SLAB).exists() {
+            fs::remove_file(dir.join(INDEX_DOC_IDS_SLAB))?;
+        }
+        fs::rename(staged(INDEX_NODES_FILE), dir.join(INDEX_NODES_FILE))?;
+        fs::rename(staged(INDEX_META_FILE), dir.join(INDEX_META_FILE))
+    }
+
+    pub(crate) fn manifest(&self) -> IndexManifest {
+        IndexManifest {
+            format_version: INDEX_FORMAT_VERSION,
+            metadata: self.metadata.clone(),
+            has_doc_ids: self.has_doc_ids,
+            node_count: self.nodes.len() as u64,
+            leaf_count: self.leaf_count as u64,
+            global: self.get_global_aggregations(),
+        }
+    }
+
+    // Load an index from a split-layout directory or a legacy single file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let _span = tracing::info_span!("ait.load", split = path.is_dir()).entered();
+        if path.is_dir() {
+            Self::load_split(path)
+        } else {
+            Self::load_single_file(path)
+        }
+    }
+
+    pub(crate) fn load_split(dir: &Path) -> io::Result<Self> {
+        let manifest = IndexManifest::read(dir)?;
+        // The slabs become the tree's leaf arenas, one chunk per column
+        let values: Arc<[f64]> = fs::read(dir.join(INDEX_VALUES_SLAB))?
+            .chunks_exact(8)
+            .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let doc_ids: Arc<[u32]> = if manifest.has_doc_ids {
+            fs::read(dir.join(INDEX_DOC_IDS_SLAB))?
+                .chunks_exact(4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect()
+        } else {
+            Arc::new([])
+        };
+
+        let mut nodes = Vec::new();
+        for node in read_split_nodes(dir, manifest.has_sum_squares())? {
+            nodes.push(match node {
+                SplitNode::Internal { split_value, left, right, aggregations } => {
+                    AggregationTreeNode::Internal { split_value, left, right, aggregations }
+                }
+                SplitNode::Leaf { start, len, aggregations } => {
+                    let end = start.checked_add(len).filter(|&end| end <= values.len())
+                        .ok_or_else(|| invalid_data("leaf outside the value slab"))?;
+                    let doc_ids = if manifest.has_doc_ids {
+                        if end > doc_ids.len() {
+                            return Err(invalid_data("leaf outside the doc id slab"));
+                        }
+                        LeafSlice::new(&doc_ids, start, len)
+                    } else {
+                        LeafSlice::new(&doc_ids, 0, 0)
+                    };
+                    AggregationTreeNode::Leaf {
+                        doc_ids,
+                        values: LeafSlice::new(&values, start, len),
+                        aggregations,
+                    }
+                }
+            });
+        }
+
+        let has_sum_squares = manifest.has_sum_squares();
+        let mut tree = Self::from_nodes(nodes, manifest.has_doc_ids, manifest.metadata)?;
+        if !has_sum_squares {
+            tree.fill_sum_squares();
+        }
+        Ok(tree)
+    }
+
+    // Single-file formats written by older versions
+    pub(crate) fn load_single_file(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(invalid_data("not an AIT index file"));
+        }
+
+        match read_u32(&mut reader)? {
+            1 => {
+                let available = file_len - reader.stream_position()?;
+                let nodes = read_nodes(&mut reader, true, available)?;
+                let metadata = migrate_v1_metadata(&nodes);
+                let mut tree = Self::from_nodes(nodes, true, metadata)?;
+                tree.fill_sum_squares();
+                Ok(tree)
+            }
+            2 => {
+                let metadata_len = read_u64(&mut reader)?;
+                if metadata_len > file_len - reader.stream_position()? {
+                    return Err(invalid_data("metadata length exceeds the index file"));
+                }
+                let mut metadata = vec![0u8; metadata_len as usize];
+                reader.read_exact(&mut metadata)?;
+                let metadata: IndexMetadata = serde_json::from_slice(&metadata)?;
+                let has_doc_ids = read_u8(&mut reader)? != 0;
+                let available = file_len - reader.stream_position()?;
+                let nodes = read_nodes(&mut reader, has_doc_ids, available)?;
+                let mut tree = Self::from_nodes(nodes, has_doc_ids, metadata)?;
+                tree.fill_sum_squares();
+                Ok(tree)
+            }
+            version => Err(invalid_data(&format!(
+                "unsupported single-file index format version {}",
+                version
+            ))),
+        }
+    }
+
+    // Recompute every node's sum of squares from the leaf values, for formats
+    // that don't store them. Runs after from_nodes has checked the tree shape.
+    pub(crate) fn fill_sum_squares(&mut self) {
+        fn fill(nodes: &mut [AggregationTreeNode], node_idx: usize) -> f64 {
+            let sum_squares = match nodes[node_idx] {
+                AggregationTreeNode::Internal { left, right, .. } => fill(nodes, left) + fill(nodes, right),
+                AggregationTreeNode::Leaf { ref values, .. } => values.iter().map(|value| value * value).sum(),
+            };
+            match &mut nodes[node_idx] {
+                AggregationTreeNode::Internal { aggregations, .. } | AggregationTreeNode::Leaf { aggregations, .. } => {
+                    aggregations.sum_squares = sum_squares;
+                }
+            }
+            sum_squares
+        }
+        if !self.nodes.is_empty() {
+            fill(&mut self.nodes, 0);
+        }
+    }
+
+    // Rebuild the derived lookup structures for a set of loaded nodes
+    pub(crate) fn from_nodes(nodes: Vec<AggregationTreeNode>, has_doc_ids: bool, metadata: IndexMetadata) -> io::Result<Self> {
+        // Every node but the root is some node's child exactly once
+        let mut is_child = vec![false; nodes.len()];
+        for node in &nodes {
+            if let AggregationTreeNode::Internal { left, right, .. } = *node {
+                if left >= nodes.len() || right >= nodes.len() {
+                    return Err(invalid_data("child index out of range"));
+                }
+                for child in [left, right] {
+                    if child == 0 || std::mem::replace(&mut is_child[child], true) {
+                        return Err(invalid_data("node is linked more than once"));
+                    }
+                }
+            }
+        }
+        if tree_height(&nodes) > MAX_TREE_HEIGHT {
+            return Err(invalid_data(&format!("tree is deeper than {} levels", MAX_TREE_HEIGHT)));
+        }
+
+        let len = nodes.first().map_or(0, |root| root.aggregations().count as usize);
+        let leaf_values: usize = nodes
+            .iter()
+            .map(|node| match node {
+                AggregationTreeNode::Leaf { values, .. } => values.len(),
+                AggregationTreeNode::Internal { .. } => 0,
+            })
+            .sum();
+        if leaf_values != len {
+            return Err(invalid_data("leaf values don't match the root count"));
+        }
+
+        let mut position_map = vec![(0, 0); len];
+        if !nodes.is_empty() {
+            build_position_map(&nodes, 0, &mut position_map, 0);
+        }
+
+        let mut doc_id_map = HashMap::with_capacity(if has_doc_ids { len } else { 0 });
+        if has_doc_ids {
+            for (position, &(node_idx, offset)) in position_map.iter().enumerate() {
+                if let AggregationTreeNode::Leaf { doc_ids, .. } = &nodes[node_idx] {
+                    doc_id_map.insert(doc_ids[offset], position);
+                }
+            }
+        }
+        let doc_id_range = doc_id_map
+            .keys()
+            .min()
+            .zip(doc_id_map.keys().max())
+            .map(|(&min, &max)| (min, max));
+
+        let leaf_count = nodes
+            .iter()
+            .filter(|node| matches!(node, AggregationTreeNode::Leaf { .. }))
+            .count();
+
+        let mut tree = AggregationIndexTree {
+            nodes,
+            has_doc_ids,
+            doc_id_range,
+            leaf_count,
+            planner: metadata.config.planner,
+            metadata,
+            doc_id_map,
+            doc_values: None,
+            value_runs: None,
+            node_digests: Vec::new(),
+            node_histograms: Vec::new(),
+            node_moments: Vec::new(),
+            node_log_sums: Vec::new(),
+            distinct: None,
+            weights: None,
+            timestamps: None,
+            position_map,
+            position_fallbacks: FallbackCounter::default(),
+            query_latencies: QueryLatencies::default(),
+        };
+        if tree.metadata.statistics.is_none() {
+            tree.metadata.statistics = tree.compute_statistics();
+        }
+        tree.doc_values = tree.collect_doc_values();
+        tree.value_runs = tree.collect_value_runs();
+        tree.collect_node_sketches();
+        if !tree.metadata.config.position_map {
+            tree.position_map = Vec::new();
+        }
+        Ok(tree)
+    }
+}
+
+// A node as stored in the nodes file of the split layout: leaves refer to a
+// position range of the slabs instead of holding their contents
+pub(crate) enum SplitNode {
+    Internal {
+        split_value: f64,
+        left: usize,
+        right: usize,
+        aggregations: NodeAggregations,
+    },
+    Leaf {
+        start: usize,
+        len: usize,
+        aggregations: NodeAggregations,
+    },
+}
+
+impl SplitNode {
+    pub(crate) fn aggregations(&self) -> NodeAggregations {
+        match self {
+            SplitNode::Internal { aggregations, .. } | SplitNode::Leaf { aggregations, .. } => *aggregations,
+        }
+    }
+}
+
+pub(crate) fn read_split_nodes(dir: &Path, with_sum_squares: bool) -> io::Result<Vec<SplitNode>> {
+    let file = File::open(dir.join(INDEX_NODES_FILE))?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    // Every node takes at least a tag, two u64s and its aggregations, so a
+    // count the file can't hold is corrupt; checked before allocating for it
+    let count = read_u64(&mut reader)?;
+    let min_node_bytes = 1 + 16 + aggregations_bytes(with_sum_squares);
+    if count > (file_len - 8) / min_node_bytes {
+        return Err(invalid_data("node count exceeds the nodes file"));
+    }
+    let mut nodes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let node = match read_u8(&mut reader)? {
+            NODE_TAG_INTERNAL => SplitNode::Internal {
+                split_value: read_f64(&mut reader)?,
+                left: read_u64(&mut reader)? as usize,
+                right: read_u64(&mut reader)? as usize,
+                aggregations: read_aggregations(&mut reader, with_sum_squares)?,
+            },
+            NODE_TAG_LEAF => SplitNode::Leaf {
+                start: read_u64(&mut reader)? as usize,
+                len: read_u64(&mut reader)? as usize,
+                aggregations: read_aggregations(&mut reader, with_sum_squares)?,
+            },
+            tag => return Err(invalid_data(&format!("unknown node tag {}", tag))),
+        };
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+// Map a file read-only. Index files are never modified once written: savers
+// write new files and rename them into place, and the segment store renames
+// whole directories into place and unlinks old ones, all of which leave
+// existing mappings intact.
+pub(crate) fn map_file(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // SAFETY: see above; nothing truncates or rewrites a mapped index file
+    unsafe { Mmap::map(&file) }
+}
+
+// A split-layout index served straight from its files. Nodes are read into
+// memory, but the slabs stay memory-mapped, so processes serving the same
+// index share one copy of the leaf contents in the page cache. Without a
+// doc-id map, filtered queries scan the doc-id slab.
+pub struct MappedSegment {
+    pub(crate) nodes: Vec<SplitNode>,
+    // Half-open slab position range covered by each node
+    pub(crate) spans: Vec<(usize, usize)>,
+    pub(crate) values: Mmap,
+    pub(crate) doc_ids: Option<Mmap>,
+}
+
+impl MappedSegment {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let manifest = IndexManifest::read(dir)?;
+        let nodes = read_split_nodes(dir, manifest.has_sum_squares())?;
+        let values = map_file(&dir.join(INDEX_VALUES_SLAB))?;
+        let doc_ids = if manifest.has_doc_ids {
+            Some(map_file(&dir.join(INDEX_DOC_IDS_SLAB))?)
+        } else {
+            None
+        };
+
+        let len = values.len() / 8;
+        if doc_ids.as_ref().is_some_and(|doc_ids| doc_ids.len() / 4 != len) {
+            return Err(invalid_data("doc id slab doesn't match the value slab"));
+        }
+        let mut spans = vec![(0, 0); nodes.len()];
+        if !nodes.is_empty() {
+            Self::compute_spans(&nodes, 0, &mut spans, nodes.len())?;
+        }
+        if spans.first().is_some_and(|&(start, end)| start != 0 || end != len) {
+            return Err(invalid_data("leaves don't cover the value slab"));
+        }
+        let mut segment = MappedSegment { nodes, spans, values, doc_ids };
+        if !manifest.has_sum_squares() && !segment.nodes.is_empty() {
+            segment.fill_sum_squares(0);
+        }
+        Ok(segment)
+    }
+
+    // Recompute the sums of squares of `node_idx` and its descendants from the
+    // value slab, for indexes written before format v4
+    pub(crate) fn fill_sum_squares(&mut self, node_idx: usize) -> f64 {
+        let sum_squares = match self.nodes[node_idx] {
+            SplitNode::Internal { left, right, .. } => self.fill_sum_squares(left) + self.fill_sum_squares(right),
+            SplitNode::Leaf { .. } => {
+                let (start, end) = self.spans[node_idx];
+                (start..end).map(|position| self.value_at(position) * self.value_at(position)).sum()
+            }
+        };
+        match &mut self.nodes[node_idx] {
+            SplitNode::Internal { aggregations, .. } | SplitNode::Leaf { aggregations, .. } => {
+                aggregations.sum_squares = sum_squares;
+            }
+        }
+        sum_squares
+    }
+
+    // Fill in the span of `node_idx` and its descendants. `depth_left` bounds
+    // the recursion so a corrupt file with a cycle can't overflow the stack.
+    pub(crate) fn compute_spans(nodes: &[SplitNode], node_idx: usize, spans: &mut [(usize, usize)],
+                     depth_left: usize) -> io::Result<(usize, usize)> {
+        if node_idx >= nodes.len() || depth_left == 0 {
+            return Err(invalid_data("malformed node tree"));
+        }
+        let span = match nodes[node_idx] {
+            SplitNode::Internal { left, right, .. } => {
+                let (start, _) = Self::compute_spans(nodes, left, spans, depth_left - 1)?;
+                let (_, end) = Self::compute_spans(nodes, right, spans, depth_left - 1)?;
+                (start, end)
+            }
+            SplitNode::Leaf { start, len, .. } => (start, start + len),
+        };
+        spans[node_idx] = span;
+        Ok(span)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.values.len() / 8
+    }
+
+    pub(crate) fn value_at(&self, position: usize) -> f64 {
+        f64::from_le_bytes(self.values[position * 8..position * 8 + 8].try_into().unwrap())
+    }
+
+    pub fn get_global_aggregations(&self) -> NodeAggregations {
+        self.nodes.first().map_or(NodeAggregations::empty(), SplitNode::aggregations)
+    }
+
+    pub fn query_with_bitmap(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        let doc_ids = self.doc_ids.as_ref().ok_or(AitError::DocIdsNotStored)?;
+        let mut result = NodeAggregations::empty();
+        for (position, bytes) in doc_ids.chunks_exact(4).enumerate() {
+            if bitmap.contains(u32::from_le_bytes(bytes.try_into().unwrap())) {
+                result.add_value(self.value_at(position));
+            }
+        }
+        Ok(result)
+    }
+
+    // Values are in slab order, so [min, max] is a position range answered
+    // from the node aggregations wherever a node lies entirely inside it
+    pub fn query_value_range(&self, min: f64, max: f64) -> NodeAggregations {
+        let mut result = NodeAggregations::empty();
+        if self.nodes.is_empty() || min > max {
+            return result;
+        }
+        let first_position = |predicate: &dyn Fn(f64) -> bool| {
+            let (mut low, mut high) = (0, self.len());
+            while low < high {
+                let mid = low + (high - low) / 2;
+                if predicate(self.value_at(mid)) {
+                    high = mid;
+                } else {
+                    low = mid + 1;
+                }
+            }
+            low
+        };
+        let start = first_position(&|value| value >= min);
+        let end = first_position(&|value| value > max);
+        if start < end {
+            self.aggregate_positions(&mut result, 0, start, end);
+        }
+        result
+    }
+
+    pub(crate) fn aggregate_positions(&self, result: &mut NodeAggregations, node_idx: usize, start: usize, end: usize) {
+        let (node_start, node_end) = self.spans[node_idx];
+        if node_end <= start || node_start >= end {
+            return;
+        }
+        if start <= node_start && node_end <= end {
+            *result += self.nodes[node_idx].aggregations();
+            return;
+        }
+        match self.nodes[node_idx] {
+            SplitNode::Internal { left, right, .. } => {
+                self.aggregate_positions(result, left, start, end);
+                self.aggregate_positions(result, right, start, end);
+            }
+            SplitNode::Leaf { .. } => {
+                for position in start.max(node_start)..end.min(node_end) {
+                    result.add_value(self.value_at(position));
+                }
+            }
+        }
+    }
+}
+
+// v1 files carry no metadata; recover what the nodes themselves tell us
+pub(crate) fn migrate_v1_metadata(nodes: &[AggregationTreeNode]) -> IndexMetadata {
+    let leaf_size = nodes
+        .iter()
+        .filter_map(|node| match node {
+            AggregationTreeNode::Leaf { values, .. } => Some(values.len()),
+            AggregationTreeNode::Internal { .. } => None,
+        })
+        .max()
+        .unwrap_or(BuildOptions::default().leaf_size);
+
+    IndexMetadata {
+        source: "unknown (migrated from format v1)".to_string(),
+        field: "unknown".to_string(),
+        row_count: nodes.first().map_or(0, |root| root.aggregations().count as u64),
+        built_at: String::new(),
+        crate_version: String::new(),
+        config: BuildOptions {
+            leaf_size,
+            ..BuildOptions::default()
+        },
+        statistics: None,
+        ids_in_value_order: false,
+    }
+}
+
+// Nodes of a single-file index, from a reader with `available` bytes left
+pub(crate) fn read_nodes(reader: &mut impl Read, has_doc_ids: bool, available: u64) -> io::Result<Vec<AggregationTreeNode>> {
+    // The smallest node is an empty leaf: a tag, its length and aggregations
+    let count = read_u64(reader)?;
+    if count > available.saturating_sub(8) / (1 + 8 + aggregations_bytes(false)) {
+        return Err(invalid_data("node count exceeds the index file"));
+    }
+    let mut nodes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let node = match read_u8(reader)? {
+            NODE_TAG_INTERNAL => AggregationTreeNode::Internal {
+                split_value: read_f64(reader)?,
+                left: read_u64(reader)? as usize,
+                right: read_u64(reader)? as usize,
+                aggregations: read_aggregations(reader, false)?,
+            },
+            NODE_TAG_LEAF => {
+                let len = read_u64(reader)? as usize;
+                let doc_ids = if has_doc_ids {
+                    (0..len).map(|_| read_u32(reader)).collect::<io::Result<Vec<_>>>()?
+                } else {
+                    Vec::new()
+                };
+                let values = (0..len).map(|_| read_f64(reader)).collect::<io::Result<Vec<_>>>()?;
+                AggregationTreeNode::Leaf {
+                    doc_ids: doc_ids.into(),
+                    values: values.into(),
+                    aggregations: read_aggregations(reader, false)?,
+                }
+            }
+            tag => return Err(invalid_data(&format!("unknown node tag {}", tag))),
+        };
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+pub(crate) fn write_aggregations(writer: &mut impl Write, aggregations: &NodeAggregations) -> io::Result<()> {
+    writer.write_all(&aggregations.min_value.to_le_bytes())?;
+    writer.write_all(&aggregations.max_value.to_le_bytes())?;
+    writer.write_all(&aggregations.sum.to_le_bytes())?;
+    writer.write_all(&aggregations.count.to_le_bytes())?;
+    writer.write_all(&aggregations.sum_squares.to_le_bytes())
+}
+
+// Encoded size of a node's aggregations
+pub(crate) fn aggregations_bytes(with_sum_squares: bool) -> u64 {
+    if with_sum_squares { 3 * 8 + 4 + 8 } else { 3 * 8 + 4 }
+}
+
+// Formats before v4 stop after the count; their sums of squares read as zero
+// until fill_sum_squares recomputes them
+pub(crate) fn read_aggregations(reader: &mut impl Read, with_sum_squares: bool) -> io::Result<NodeAggregations> {
+    Ok(NodeAggregations {
+        min_value: read_f64(reader)?,
+        max_value: read_f64(reader)?,
+        sum: read_f64(reader)?,
+        count: read_u32(reader)?,
+        sum_squares: if with_sum_squares { read_f64(reader)? } else { 0.0 },
+    })
+}
+
+pub(crate) fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn read_f64(reader: &mut impl Read) -> io::Result<f64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl TermColumn {
+    // Write the dictionary and each term's posting bitmap to `path`
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut postings = vec![RoaringBitmap::new(); self.terms.len()];
+        for (doc_id, &code) in self.codes.iter().enumerate() {
+            postings[code as usize].insert(doc_id as u32);
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(TERMS_MAGIC)?;
+        writer.write_all(&TERMS_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.codes.len() as u64).to_le_bytes())?;
+        writer.write_all(&(self.terms.len() as u64).to_le_bytes())?;
+        for (term, posting) in self.terms.iter().zip(&postings) {
+            writer.write_all(&(term.len() as u32).to_le_bytes())?;
+            writer.write_all(term.as_bytes())?;
+            writer.write_all(&(posting.serialized_size() as u64).to_le_bytes())?;
+            posting.serialize_into(&mut writer)?;
+        }
+        writer.flush()
+    }
+
+    // Read a dictionary file; every doc must hold exactly one term
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != TERMS_MAGIC {
+            return Err(invalid_data("not a term dictionary file"));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != TERMS_FORMAT_VERSION {
+            return Err(invalid_data(&format!("unsupported term dictionary version {}", version)));
+        }
+        let docs = read_u64(&mut reader)? as usize;
+        let term_count = read_u64(&mut reader)? as usize;
+        let mut terms = Vec::with_capacity(term_count);
+        let mut counts = Vec::with_capacity(term_count);
+        let mut codes = vec![u32::MAX; docs];
+        for code in 0..term_count as u32 {
+            let mut term = vec![0u8; read_u32(&mut reader)? as usize];
+            reader.read_exact(&mut term)?;
+            terms.push(String::from_utf8(term).map_err(|_| invalid_data("term is not UTF-8"))?);
+            let len = read_u64(&mut reader)?;
+            let posting = RoaringBitmap::deserialize_from((&mut reader).take(len))?;
+            for doc_id in &posting {
+                match codes.get_mut(doc_id as usize) {
+                    Some(slot) if *slot == u32::MAX => *slot = code,
+                    _ => return Err(invalid_data(&format!("doc {} is out of range or holds two terms", doc_id))),
+                }
+            }
+            counts.push(posting.len() as u32);
+        }
+        if let Some(doc_id) = codes.iter().position(|&code| code == u32::MAX) {
+            return Err(invalid_data(&format!("doc {} holds no term", doc_id)));
+        }
+        Ok(TermColumn { terms, codes, counts })
+    }
+}
+
+// Filter bitmaps saved under an index directory by name, so filters that are
+// expensive to compute (or come from another system) are computed once and
+// reused across sessions. Names are limited to ASCII letters, digits, '-' and
+// '_' so they map to file names as-is. Each file is written to a temporary
+// name and renamed into place, so readers see the old or the new bitmap.
+pub struct FilterStore {
+    pub(crate) dir: PathBuf,
+}
+
+impl FilterStore {
+    // The filters of the index in `index_dir`
+    pub fn for_index(index_dir: &Path) -> Self {
+        FilterStore { dir: index_dir.join(INDEX_FILTERS_DIR) }
+    }
+
+    pub fn save(&self, name: &str, bitmap: &RoaringBitmap) -> io::Result<()> {
+        let path = self.path(name)?;
+        fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        bitmap.serialize_into(&mut writer)?;
+        writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(&tmp, &path)
+    }
+
+    pub fn load(&self, name: &str) -> io::Result<RoaringBitmap> {
+        let path = self.path(name)?;
+        let file = File::open(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::new(io::ErrorKind::NotFound, format!("no saved filter {:?}", name)),
+            _ => err,
+        })?;
+        RoaringBitmap::deserialize_from(BufReader::new(file))
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(name)?)
+    }
+
+    // Names of the saved filters, sorted; none when nothing was saved
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == FILTER_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub(crate) fn path(&self, name: &str) -> io::Result<PathBuf> {
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("filter name {:?} must be ASCII letters, digits, '-' or '_'", name)));
+        }
+        Ok(self.dir.join(format!("{}.{}", name, FILTER_EXTENSION)))
+    }
+}
+
+// Filter bitmaps produced outside this index, e.g. exported from a search
+// engine or a database, in the doc-id space of the index
+#[cfg_attr(feature = "bench", derive(ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilterFormat {
+    // RoaringBitmap portable serialization, as the Java, C and Go libraries write it
+    Roaring,
+    // Decimal doc ids separated by whitespace, in any order
+    Ids,
+    // Arrow IPC stream with a single boolean column; row i selects doc i
+    ArrowBool,
+}
+
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+impl FilterFormat {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            FilterFormat::Roaring => "roaring",
+            FilterFormat::Ids => "ids",
+            FilterFormat::ArrowBool => "arrow-bool",
+        }
+    }
+}
+
+// Read a filter bitmap written by another system
+pub fn import_filter(path: &Path, format: FilterFormat) -> io::Result<RoaringBitmap> {
+    match format {
+        FilterFormat::Roaring => RoaringBitmap::deserialize_from(BufReader::new(File::open(path)?)),
+        FilterFormat::Ids => {
+            let text = fs::read_to_string(path)?;
+            let mut ids = Vec::new();
+            for token in text.split_ascii_whitespace() {
+                ids.push(token.parse::<u32>().map_err(|_| invalid_data(&format!("{:?} is not a doc id", token)))?);
+            }
+            // Exports are usually sorted already, which makes this cheap
+            if !ids.is_sorted() {
+                ids.sort_unstable();
+            }
+            ids.dedup();
+            Ok(RoaringBitmap::from_sorted_iter(ids).expect("ids are sorted and unique"))
+        }
+        FilterFormat::ArrowBool => read_arrow_bool_stream(&mut BufReader::new(File::open(path)?)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FieldSetManifest {
+    pub(crate) format_version: u32,
+    pub(crate) fields: Vec<FieldEntry>,
+    pub(crate) terms: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FieldEntry {
+    pub(crate) name: String,
+    // "tree" or "zone_map"
+    pub(crate) kind: String,
+}
+
+impl FieldIndexSet {
+    // Write every field and term dictionary to `dir`, creating it if needed.
+    // The manifest goes last so a complete one implies complete field files.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let mut names: Vec<&String> = self.fields.keys().collect();
+        names.sort();
+        let mut fields = Vec::with_capacity(names.len());
+        for (idx, name) in names.into_iter().enumerate() {
+            let kind = match &self.fields[name] {
+                FieldIndex::Tree(tree) => {
+                    tree.save(&dir.join(format!("field-{}", idx)))?;
+                    "tree"
+                }
+                FieldIndex::ZoneMap(column) => {
+                    let mut writer = BufWriter::new(File::create(dir.join(format!("field-{}.values", idx)))?);
+                    for value in &column.values {
+                        writer.write_all(&value.to_le_bytes())?;
+                    }
+                    writer.flush()?;
+                    "zone_map"
+                }
+            };
+            fields.push(FieldEntry { name: name.clone(), kind: kind.to_string() });
+        }
+        let mut terms: Vec<String> = self.terms.keys().cloned().collect();
+        terms.sort();
+        for (idx, name) in terms.iter().enumerate() {
+            self.terms[name].save(&dir.join(format!("terms-{}.dict", idx)))?;
+        }
+        let manifest = FieldSetManifest { format_version: FIELD_SET_FORMAT_VERSION, fields, terms };
+        fs::write(dir.join(FIELD_SET_MANIFEST_FILE), serde_json::to_vec_pretty(&manifest)?)
+    }
+
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let manifest = FieldSetManifest::read(dir)?;
+        let mut set = FieldIndexSet::default();
+        for (idx, entry) in manifest.fields.iter().enumerate() {
+            let index = match entry.kind.as_str() {
+                "tree" => FieldIndex::Tree(Box::new(AggregationIndexTree::load(&dir.join(format!("field-{}", idx)))?)),
+                "zone_map" => {
+                    let values = fs::read(dir.join(format!("field-{}.values", idx)))?
+                        .chunks_exact(8)
+                        .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+                        .collect();
+                    FieldIndex::ZoneMap(ZoneMapColumn::new(values))
+                }
+                kind => return Err(invalid_data(&format!("unknown kind {:?} of field {:?}", kind, entry.name))),
+            };
+            set.fields.insert(entry.name.clone(), index);
+        }
+        for (idx, name) in manifest.terms.iter().enumerate() {
+            set.add_terms(name, TermColumn::load(&dir.join(format!("terms-{}.dict", idx)))?);
+        }
+        Ok(set)
+    }
+}
+
+impl FieldSetManifest {
+    pub(crate) fn read(dir: &Path) -> io::Result<Self> {
+        let manifest: FieldSetManifest = serde_json::from_slice(&fs::read(dir.join(FIELD_SET_MANIFEST_FILE))?)?;
+        if manifest.format_version > FIELD_SET_FORMAT_VERSION {
+            return Err(invalid_data(&format!("unsupported field set version {}", manifest.format_version)));
+        }
+        Ok(manifest)
+    }
+}
+
+// Resumable partitioned build state. Each finished partition is saved under
+// `dir` followed by a marker holding a checksum of its input. On restart,
+// partitions whose marker matches the input and whose saved contents hash to
+// the same checksum are loaded instead of rebuilt.
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) struct BuildCheckpoint {
+    pub(crate) dir: PathBuf,
+}
+
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PartitionMarker {
+    pub(crate) rows: u64,
+    pub(crate) checksum: u64,
+}
+
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+impl BuildCheckpoint {
+    pub(crate) fn partition_dir(&self, idx: usize) -> PathBuf {
+        self.dir.join(format!("part-{:05}", idx))
+    }
+
+    pub(crate) fn marker_path(&self, idx: usize) -> PathBuf {
+        self.dir.join(format!("part-{:05}.done", idx))
+    }
+
+    // Load partition `idx` if it was completed for exactly this input
+    pub(crate) fn resume(&self, idx: usize, input: &[(u32, f64)], options: &BuildOptions) -> Option<AggregationIndexTree> {
+        let marker: PartitionMarker = serde_json::from_slice(&fs::read(self.marker_path(idx)).ok()?).ok()?;
+        let expected = PartitionMarker {
+            rows: input.len() as u64,
+            checksum: entries_checksum(input.iter().map(|&(doc_id, value)| {
+                (if options.store_doc_ids { doc_id } else { 0 }, value)
+            })),
+        };
+        if marker != expected {
+            return None;
+        }
+
+        let tree = AggregationIndexTree::load(&self.partition_dir(idx)).ok()?;
+        (tree.has_doc_ids == options.store_doc_ids && tree.checksum() == marker.checksum).then_some(tree)
+    }
+
+    // Persist a finished partition; the marker is written last so a partial
+    // save is never resumed
+    pub(crate) fn commit(&self, idx: usize, tree: &AggregationIndexTree) -> io::Result<()> {
+        tree.save(&self.partition_dir(idx))?;
+        let marker = PartitionMarker {
+            rows: tree.position_count() as u64,
+            checksum: tree.checksum(),
+        };
+        fs::write(self.marker_path(idx), serde_json::to_vec(&marker)?)
+    }
+
+    // Build every partition, loading intact ones from earlier runs. Returns the
+    // trees in partition order and how many were resumed.
+    pub(crate) fn build_partitions(&self, chunks: &[&[(u32, f64)]], options: &BuildOptions)
+        -> io::Result<(Vec<AggregationIndexTree>, usize)> {
+        fs::create_dir_all(&self.dir)?;
+        let results: Vec<io::Result<(AggregationIndexTree, bool)>> = crate::map_each(0..chunks.len(), |idx| {
+            let chunk = chunks[idx];
+            if let Some(tree) = self.resume(idx, chunk, options) {
+                return Ok((tree, true));
+            }
+            let tree = build_aggregation_index_tree(chunk.to_vec(), options)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            self.commit(idx, &tree)?;
+            Ok((tree, false))
+        });
+
+        let mut trees = Vec::with_capacity(results.len());
+        let mut resumed = 0;
+        for result in results {
+            let (tree, was_resumed) = result?;
+            resumed += was_resumed as usize;
+            trees.push(tree);
+        }
+        Ok((trees, resumed))
+    }
+}
+
+// FNV-1a over (doc_id, value) pairs in order
+pub(crate) fn entries_checksum(entries: impl Iterator<Item = (u32, f64)>) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for (doc_id, value) in entries {
+        for byte in doc_id.to_le_bytes().into_iter().chain(value.to_le_bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg_attr(feature = "bench", derive(ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    Csv,
+    // Arrow IPC streaming format
+    Arrow,
+}
+
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+impl ExportFormat {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Arrow => "arrow",
+        }
+    }
+}
+
+impl AggregationIndexTree {
+    // Write the (doc_id, value[, timestamp]) rows of the docs in `bitmap` to
+    // `path`, in doc-id order. `timestamps` is a doc-ordered column of epoch
+    // milliseconds. Rows are streamed in batches rather than collected.
+    // Returns the number of rows written.
+    pub fn export(&self, bitmap: &RoaringBitmap, format: ExportFormat, path: &Path, timestamps: Option<&[i64]>)
+        -> io::Result<u64> {
+        if !self.has_doc_ids {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, AitError::DocIdsNotStored));
+        }
+        let rows = bitmap.iter().filter_map(|doc_id| {
+            let value = self.get_value_at_position(*self.doc_id_map.get(&doc_id)?);
+            let timestamp = timestamps.map(|timestamps| timestamps.get(doc_id as usize).copied().unwrap_or(0));
+            Some((doc_id, value, timestamp))
+        });
+        let mut writer = BufWriter::new(File::create(path)?);
+        let written = match format {
+            ExportFormat::Csv => write_csv_rows(&mut writer, rows, timestamps.is_some())?,
+            ExportFormat::Arrow => write_arrow_stream(&mut writer, rows, timestamps.is_some())?,
+        };
+        writer.flush()?;
+        Ok(written)
+    }
+}
+
+pub(crate) type ExportRow = (u32, f64, Option<i64>);
+
+// Timestamps are written as RFC 3339
+pub(crate) fn write_csv_rows(writer: &mut impl Write, rows: impl Iterator<Item = ExportRow>, with_timestamps: bool)
+    -> io::Result<u64> {
+    writeln!(writer, "{}", if with_timestamps { "doc_id,value,timestamp" } else { "doc_id,value" })?;
+    let mut written = 0;
+    for (doc_id, value, timestamp) in rows {
+        match timestamp.and_then(DateTime::<Utc>::from_timestamp_millis) {
+            Some(timestamp) => writeln!(writer, "{},{},{}", doc_id, value, timestamp.to_rfc3339())?,
+            None => writeln!(writer, "{},{}", doc_id, value)?,
+        }
+        written += 1;
+    }
+    Ok(written)
+}
+
+// Rows per Arrow record batch
+pub(crate) const ARROW_BATCH_ROWS: usize = 65_536;
+
+// Arrow IPC stream: a schema message, one record batch message per
+// ARROW_BATCH_ROWS rows, and the end-of-stream marker. Columns are doc_id
+// (uint32), value (float64) and timestamp (timestamp[ms]), none nullable.
+pub(crate) fn write_arrow_stream(writer: &mut impl Write, rows: impl Iterator<Item = ExportRow>, with_timestamps: bool)
+    -> io::Result<u64> {
+    let field = |name: &str, type_id: u8, type_table: Vec<(u16, FlatValue)>| {
+        FlatValue::Table(vec![
+            (0, FlatValue::String(name.to_string())),
+            (1, FlatValue::Bool(false)),
+            (2, FlatValue::U8(type_id)),
+            (3, FlatValue::Table(type_table)),
+            (5, FlatValue::TableVector(Vec::new())),
+        ])
+    };
+    // Type union members: Int = 2, FloatingPoint = 3, Timestamp = 10
+    let mut fields = vec![
+        field("doc_id", 2, vec![(0, FlatValue::I32(32)), (1, FlatValue::Bool(false))]),
+        field("value", 3, vec![(0, FlatValue::I16(2))]),
+    ];
+    if with_timestamps {
+        fields.push(field("timestamp", 10, vec![(0, FlatValue::I16(1))]));
+    }
+    // Message header union members: Schema = 1, RecordBatch = 3
+    let schema = FlatValue::Table(vec![(0, FlatValue::I16(0)), (1, FlatValue::TableVector(fields))]);
+    write_arrow_message(writer, 1, schema, &[])?;
+
+    let mut written = 0;
+    let mut rows = rows.peekable();
+    let mut doc_ids = Vec::with_capacity(ARROW_BATCH_ROWS);
+    let mut values = Vec::with_capacity(ARROW_BATCH_ROWS);
+    let mut times = Vec::with_capacity(ARROW_BATCH_ROWS);
+    while rows.peek().is_some() {
+        doc_ids.clear();
+        values.clear();
+        times.clear();
+        for (doc_id, value, timestamp) in rows.by_ref().take(ARROW_BATCH_ROWS) {
+            doc_ids.extend_from_slice(&doc_id.to_le_bytes());
+            values.extend_from_slice(&value.to_le_bytes());
+            if let Some(timestamp) = timestamp {
+                times.extend_from_slice(&timestamp.to_le_bytes());
+            }
+        }
+        let len = values.len() / 8;
+        let mut columns = vec![&doc_ids, &values];
+        if with_timestamps {
+            columns.push(&times);
+        }
+
+        // Each column has an empty validity buffer and its data buffer, at
+        // 8-byte aligned offsets into the body
+        let mut body = Vec::new();
+        let mut nodes = Vec::new();
+        let mut buffers = Vec::new();
+        for column in columns {
+            nodes.extend_from_slice(&(len as i64).to_le_bytes());
+            nodes.extend_from_slice(&0i64.to_le_bytes());
+            for bytes in [&[][..], &column[..]] {
+                buffers.extend_from_slice(&(body.len() as i64).to_le_bytes());
+                buffers.extend_from_slice(&(bytes.len() as i64).to_le_bytes());
+                body.extend_from_slice(bytes);
+                body.resize(body.len().next_multiple_of(8), 0);
+            }
+        }
+        let batch = FlatValue::Table(vec![
+            (0, FlatValue::I64(len as i64)),
+            (1, FlatValue::StructVector { align: 8, count: (nodes.len() / 16) as u32, bytes: nodes }),
+            (2, FlatValue::StructVector { align: 8, count: (buffers.len() / 16) as u32, bytes: buffers }),
+        ]);
+        write_arrow_message(writer, 3, batch, &body)?;
+        written += len as u64;
+    }
+    // End-of-stream: continuation marker and a zero metadata length
+    writer.write_all(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0])?;
+    Ok(written)
+}
+
+// Encapsulated IPC message: continuation marker, metadata length, the
+// flatbuffer Message padded to 8 bytes, then the body
+pub(crate) fn write_arrow_message(writer: &mut impl Write, header_type: u8, header: FlatValue, body: &[u8]) -> io::Result<()> {
+    let message = FlatValue::Table(vec![
+        // MetadataVersion V5
+        (0, FlatValue::I16(4)),
+        (1, FlatValue::U8(header_type)),
+        (2, header),
+        (3, FlatValue::I64(body.len() as i64)),
+    ]);
+    let mut metadata = message.finish();
+    metadata.resize(metadata.len().next_multiple_of(8), 0);
+    writer.write_all(&[0xff, 0xff, 0xff, 0xff])?;
+    writer.write_all(&(metadata.len() as i32).to_le_bytes())?;
+    writer.write_all(&metadata)?;
+    writer.write_all(body)
+}
+
+// Just enough of the flatbuffers encoding for Arrow IPC metadata. Objects are
+// laid out front to back: a table's vtable sits just before it and the
+// objects it refers to follow it, so every offset points forward.
+pub(crate) enum FlatValue {
+    U8(u8),
+    Bool(bool),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    // Fields as (field id, value); union members take two ids, type then value
+    Table(Vec<(u16, FlatValue)>),
+    String(String),
+    TableVector(Vec<FlatValue>),
+    StructVector { align: usize, count: u32, bytes: Vec<u8> },
+}
+
+impl FlatValue {
+    // Encode with this table as the root
+    pub(crate) fn finish(self) -> Vec<u8> {
+        let mut buf = vec![0u8; 4];
+        let root = self.write(&mut buf);
+        patch_offset(&mut buf, 0, root);
+        buf
+    }
+
+    // Bytes the value takes inline in a table; objects are referred to by offset
+    pub(crate) fn inline_size(&self) -> usize {
+        match self {
+            FlatValue::U8(_) | FlatValue::Bool(_) => 1,
+            FlatValue::I16(_) => 2,
+            FlatValue::I64(_) => 8,
+            _ => 4,
+        }
+    }
+
+    // Append the value as an object and return its position
+    pub(crate) fn write(self, buf: &mut Vec<u8>) -> usize {
+        match self {
+            FlatValue::Table(mut fields) => {
+                // Widest fields first keeps them aligned without padding
+                fields.sort_by_key(|(_, value)| std::cmp::Reverse(value.inline_size()));
+                let mut layout = Vec::with_capacity(fields.len());
+                let mut size = 4usize;
+                for (id, value) in fields {
+                    let width = value.inline_size();
+                    size = size.next_multiple_of(width);
+                    layout.push((id, size, value));
+                    size += width;
+                }
+                let slots = layout.iter().map(|&(id, _, _)| id as usize + 1).max().unwrap_or(0);
+                let mut vtable = vec![0u16; 2 + slots];
+                vtable[0] = (4 + 2 * slots) as u16;
+                vtable[1] = size as u16;
+                for &(id, offset, _) in &layout {
+                    vtable[2 + id as usize] = offset as u16;
+                }
+
+                buf.resize(buf.len().next_multiple_of(2), 0);
+                let vtable_pos = buf.len();
+                for entry in vtable {
+                    buf.extend_from_slice(&entry.to_le_bytes());
+                }
+                buf.resize(buf.len().next_multiple_of(8), 0);
+                let table_pos = buf.len();
+                buf.resize(table_pos + size, 0);
+                buf[table_pos..table_pos + 4].copy_from_slice(&((table_pos - vtable_pos) as i32).to_le_bytes());
+
+                let mut children = Vec::new();
+                for (_, offset, value) in layout {
+                    let at = table_pos + offset;
+                    match value {
+                        FlatValue::U8(v) => buf[at] = v,
+                        FlatValue::Bool(v) => buf[at] = v as u8,
+                        FlatValue::I16(v) => buf[at..at + 2].copy_from_slice(&v.to_le_bytes()),
+                        FlatValue::I32(v) => buf[at..at + 4].copy_from_slice(&v.to_le_bytes()),
+                        FlatValue::I64(v) => buf[at..at + 8].copy_from_slice(&v.to_le_bytes()),
+                        object => children.push((at, object)),
+                    }
+                }
+                for (at, child) in children {
+                    let pos = child.write(buf);
+                    patch_offset(buf, at, pos);
+                }
+                table_pos
+            }
+            FlatValue::String(text) => {
+                buf.resize(buf.len().next_multiple_of(4), 0);
+                let pos = buf.len();
+                buf.extend_from_slice(&(text.len() as u32).to_le_bytes());
+                buf.extend_from_slice(text.as_bytes());
+                buf.push(0);
+                pos
+            }
+            FlatValue::TableVector(tables) => {
+                buf.resize(buf.len().next_multiple_of(4), 0);
+                let pos = buf.len();
+                buf.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+                buf.resize(pos + 4 + 4 * tables.len(), 0);
+                for (i, table) in tables.into_iter().enumerate() {
+                    let table_pos = table.write(buf);
+                    patch_offset(buf, pos + 4 + 4 * i, table_pos);
+                }
+                pos
+            }
+            FlatValue::StructVector { align, count, bytes } => {
+                // Elements follow the length and must be aligned themselves
+                while !(buf.len() + 4).is_multiple_of(align) {
+                    buf.push(0);
+                }
+                let pos = buf.len();
+                buf.extend_from_slice(&count.to_le_bytes());
+                buf.extend_from_slice(&bytes);
+                pos
+            }
+            scalar => unreachable!("scalar {} bytes wide written as an object", scalar.inline_size()),
+        }
+    }
+}
+
+// Store at `at` the forward offset to the object at `target`
+pub(crate) fn patch_offset(buf: &mut [u8], at: usize, target: usize) {
+    buf[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+// Selected rows of an Arrow IPC stream holding one boolean column. Row
+// numbers continue across record batches; null rows are not selected.
+// Compressed bodies and dictionary batches are rejected.
+pub(crate) fn read_arrow_bool_stream(reader: &mut impl Read) -> io::Result<RoaringBitmap> {
+    let mut bitmap = RoaringBitmap::new();
+    let mut row_offset = 0u64;
+    let mut seen_schema = false;
+    loop {
+        // Streams written before Arrow 0.15 have no continuation marker
+        let mut len = match read_u32(reader) {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+        if len == 0xffff_ffff {
+            len = read_u32(reader)?;
+        }
+        if len == 0 {
+            break;
+        }
+        let mut metadata = vec![0u8; len as usize];
+        reader.read_exact(&mut metadata)?;
+        let message = FlatTable::root(&metadata)?;
+        let body_len = message.i64(3)?.unwrap_or(0);
+        let mut body = vec![0u8; usize::try_from(body_len).map_err(|_| invalid_data("negative body length"))?];
+        reader.read_exact(&mut body)?;
+        let header = message.table(2)?.ok_or_else(|| invalid_data("message has no header"))?;
+        match message.u8(1)?.unwrap_or(0) {
+            // Schema: exactly one field, of type Bool (union member 6)
+            1 => {
+                let fields = header.tables(1)?;
+                if fields.len() != 1 || fields[0].u8(2)? != Some(6) {
+                    return Err(invalid_data("filter stream must hold a single boolean column"));
+                }
+                seen_schema = true;
+            }
+            // RecordBatch: buffers are the column's validity then its values
+            3 => {
+                if !seen_schema {
+                    return Err(invalid_data("record batch before schema"));
+                }
+                if header.field(3)?.is_some() {
+                    return Err(invalid_data("compressed record batches are not supported"));
+                }
+                let rows = header.i64(0)?.unwrap_or(0) as u64;
+                let buffers = header.structs(2, 16)?;
+                if buffers.len() < 2 {
+                    return Err(invalid_data("boolean column needs two buffers"));
+                }
+                let slice = |buffer: &[u8]| -> io::Result<&[u8]> {
+                    let offset = i64::from_le_bytes(buffer[0..8].try_into().unwrap()) as usize;
+                    let len = i64::from_le_bytes(buffer[8..16].try_into().unwrap()) as usize;
+                    body.get(offset..offset.saturating_add(len)).ok_or_else(|| invalid_data("buffer outside the body"))
+                };
+                let validity = slice(buffers[0])?;
+                let values = slice(buffers[1])?;
+                if (values.len() as u64) * 8 < rows || (!validity.is_empty() && (validity.len() as u64) * 8 < rows) {
+                    return Err(invalid_data("boolean buffer shorter than the batch"));
+                }
+                // Bits are least significant first; whole zero bytes are skipped
+                for (byte_idx, &byte) in values.iter().enumerate() {
+                    let mut bits = byte & validity.get(byte_idx).copied().unwrap_or(0xff);
+                    while bits != 0 {
+                        let row = byte_idx as u64 * 8 + bits.trailing_zeros() as u64;
+                        if row >= rows {
+                            break;
+                        }
+                        let doc_id = u32::try_from(row_offset + row).map_err(|_| invalid_data("row beyond the doc-id range"))?;
+                        bitmap.insert(doc_id);
+                        bits &= bits - 1;
+                    }
+                }
+                row_offset += rows;
+            }
+            other => return Err(invalid_data(&format!("unsupported Arrow message type {}", other))),
+        }
+    }
+    if !seen_schema {
+        return Err(invalid_data("Arrow stream has no schema"));
+    }
+    Ok(bitmap)
+}
+
+// Read side of the flatbuffers encoding above, for tables in Arrow IPC
+// metadata. Absent fields come back as None; every offset is bounds checked.
+#[derive(Clone, Copy)]
+pub(crate) struct FlatTable<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FlatTable<'a> {
+    pub(crate) fn root(buf: &'a [u8]) -> io::Result<Self> {
+        let pos = flat_u32(buf, 0)? as usize;
+        Ok(FlatTable { buf, pos })
+    }
+
+    // Position of the field's inline data
+    pub(crate) fn field(&self, id: u16) -> io::Result<Option<usize>> {
+        let soffset = flat_u32(self.buf, self.pos)? as i32 as i64;
+        let vtable = usize::try_from(self.pos as i64 - soffset).map_err(|_| invalid_data("bad flatbuffer vtable"))?;
+        let vtable_len = flat_u16(self.buf, vtable)? as usize;
+        let entry = 4 + 2 * id as usize;
+        if entry + 2 > vtable_len {
+            return Ok(None);
+        }
+        let offset = flat_u16(self.buf, vtable + entry)? as usize;
+        Ok((offset != 0).then_some(self.pos + offset))
+    }
+
+    pub(crate) fn u8(&self, id: u16) -> io::Result<Option<u8>> {
+        self.field(id)?.map(|at| flat_bytes(self.buf, at, 1).map(|bytes| bytes[0])).transpose()
+    }
+
+    pub(crate) fn i64(&self, id: u16) -> io::Result<Option<i64>> {
+        self.field(id)?.map(|at| flat_bytes(self.buf, at, 8).map(|bytes| i64::from_le_bytes(bytes.try_into().unwrap())))
+            .transpose()
+    }
+
+    pub(crate) fn table(&self, id: u16) -> io::Result<Option<FlatTable<'a>>> {
+        match self.field(id)? {
+            Some(at) => Ok(Some(FlatTable { buf: self.buf, pos: at + flat_u32(self.buf, at)? as usize })),
+            None => Ok(None),
+        }
+    }
+
+    // Vector of tables; empty when absent
+    pub(crate) fn tables(&self, id: u16) -> io::Result<Vec<FlatTable<'a>>> {
+        let Some((start, count)) = self.vector(id)? else { return Ok(Vec::new()) };
+        (0..count).map(|idx| {
+            let at = start + 4 * idx;
+            Ok(FlatTable { buf: self.buf, pos: at + flat_u32(self.buf, at)? as usize })
+        }).collect()
+    }
+
+    // Vector of `size`-byte structs; empty when absent
+    pub(crate) fn structs(&self, id: u16, size: usize) -> io::Result<Vec<&'a [u8]>> {
+        let Some((start, count)) = self.vector(id)? else { return Ok(Vec::new()) };
+        let bytes = flat_bytes(self.buf, start, count.saturating_mul(size))?;
+        Ok(bytes.chunks_exact(size).collect())
+    }
+
+    // Start and element count of a vector field
+    fn vector(&self, id: u16) -> io::Result<Option<(usize, usize)>> {
+        let Some(at) = self.field(id)? else { return Ok(None) };
+        let vector = at + flat_u32(self.buf, at)? as usize;
+        Ok(Some((vector + 4, flat_u32(self.buf, vector)? as usize)))
+    }
+}
+
+fn flat_bytes(buf: &[u8], at: usize, len: usize) -> io::Result<&[u8]> {
+    buf.get(at..at.saturating_add(len)).ok_or_else(|| invalid_data("truncated flatbuffer"))
+}
+
+fn flat_u16(buf: &[u8], at: usize) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(flat_bytes(buf, at, 2)?.try_into().unwrap()))
+}
+
+fn flat_u32(buf: &[u8], at: usize) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(flat_bytes(buf, at, 4)?.try_into().unwrap()))
+}
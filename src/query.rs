@@ -0,0 +1,3576 @@
+use crate::heap::{self, HeapComponent};
+use crate::{filter::*, sketch::*, tree::*};
+use crate::{format_approx_count, format_count};
+use memuse::DynamicUsage;
+#[cfg(feature = "bench")]
+use rand::Rng;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(feature = "persist")]
+use std::{fs, io, path::Path};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Upper bounds of the selectivity bands query latencies are kept for
+pub(crate) const SELECTIVITY_BANDS: [f64; 6] = [0.001, 0.01, 0.1, 0.5, 0.9, 1.0];
+// Latency buckets: under 1µs, then [2^(k-1), 2^k) µs, the last one open-ended
+pub(crate) const LATENCY_BUCKETS: usize = 24;
+// Positions the direct strategies hand to process_position_batch at a time.
+// The `kernel` benchmark finds no difference beyond run-to-run noise from 16
+// to 8192 (about 35-45ns per sorted position, 2-3x that unsorted): the cost is
+// the position lookup, and the batch only sets how often the partial result
+// is folded back.
+pub(crate) const POSITION_BATCH_SIZE: usize = 1024;
+
+// Latency histograms of bitmap queries, one per strategy and selectivity band
+// (the bitmap's share of the tree's docs). Shared by concurrent queries like
+// FallbackCounter; clones start from the current counts.
+#[derive(Debug)]
+pub(crate) struct QueryLatencies {
+    pub(crate) buckets: Vec<AtomicU64>,
+}
+
+impl Default for QueryLatencies {
+    fn default() -> Self {
+        let cells = QueryStrategy::ALL.len() * SELECTIVITY_BANDS.len() * LATENCY_BUCKETS;
+        QueryLatencies { buckets: (0..cells).map(|_| AtomicU64::new(0)).collect() }
+    }
+}
+
+impl Clone for QueryLatencies {
+    fn clone(&self) -> Self {
+        let buckets = self.buckets.iter().map(|count| AtomicU64::new(count.load(Ordering::Relaxed))).collect();
+        QueryLatencies { buckets }
+    }
+}
+
+impl QueryLatencies {
+    pub(crate) fn record(&self, strategy: QueryStrategy, selectivity: f64, elapsed: Duration) {
+        let band = SELECTIVITY_BANDS
+            .iter()
+            .position(|&upper| selectivity < upper)
+            .unwrap_or(SELECTIVITY_BANDS.len() - 1);
+        let micros = elapsed.as_micros();
+        let bucket = ((u128::BITS - micros.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[Self::cell(strategy, band) + bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cell(strategy: QueryStrategy, band: usize) -> usize {
+        let strategy = QueryStrategy::ALL.iter().position(|&s| s == strategy).expect("strategy is listed in ALL");
+        (strategy * SELECTIVITY_BANDS.len() + band) * LATENCY_BUCKETS
+    }
+
+    // Counts and quantiles of every histogram that saw a query
+    pub(crate) fn summaries(&self) -> Vec<LatencySummary> {
+        let mut summaries = Vec::new();
+        for &strategy in QueryStrategy::ALL.iter() {
+            for band in 0..SELECTIVITY_BANDS.len() {
+                let cell = Self::cell(strategy, band);
+                let counts: Vec<u64> = self.buckets[cell..cell + LATENCY_BUCKETS]
+                    .iter()
+                    .map(|count| count.load(Ordering::Relaxed))
+                    .collect();
+                let queries: u64 = counts.iter().sum();
+                if queries == 0 {
+                    continue;
+                }
+                // Upper bound of the bucket holding the q-quantile
+                let quantile = |q: f64| {
+                    let rank = ((queries as f64 * q).ceil() as u64).max(1);
+                    let mut seen = 0;
+                    let bucket = counts.iter().position(|&count| {
+                        seen += count;
+                        seen >= rank
+                    });
+                    1u64 << bucket.unwrap_or(LATENCY_BUCKETS - 1)
+                };
+                summaries.push(LatencySummary {
+                    strategy,
+                    min_selectivity: if band == 0 { 0.0 } else { SELECTIVITY_BANDS[band - 1] },
+                    max_selectivity: SELECTIVITY_BANDS[band],
+                    queries,
+                    p50_us: quantile(0.5),
+                    p99_us: quantile(0.99),
+                });
+            }
+        }
+        summaries
+    }
+}
+
+// Query latencies for one strategy and selectivity band. Quantiles are the
+// upper bounds of their histogram buckets, so they are within 2x.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencySummary {
+    pub strategy: QueryStrategy,
+    pub min_selectivity: f64,
+    pub max_selectivity: f64,
+    pub queries: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+}
+
+impl std::fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {}-{}% selected: {} queries, p50 <= {} us, p99 <= {} us", self.strategy.name(),
+               self.min_selectivity * 100.0, self.max_selectivity * 100.0, format_count(self.queries),
+               format_count(self.p50_us), format_count(self.p99_us))
+    }
+}
+
+// Part of a position range handed out by AggregationIndexTree::visit_range
+pub(crate) enum RangePart<'t> {
+    // A node the range covers entirely
+    Covered(usize),
+    // Leaf values at these positions
+    Values(&'t [f64], std::ops::Range<usize>),
+}
+
+// A single statistic requested by a query. Min, max and count can usually be
+// answered without visiting every matching value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Min,
+    Max,
+    Sum,
+    Count,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 4] = [Metric::Min, Metric::Max, Metric::Sum, Metric::Count];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Min => "min",
+            Metric::Max => "max",
+            Metric::Sum => "sum",
+            Metric::Count => "count",
+        }
+    }
+
+    // Extract this metric from full aggregations
+    pub fn of(&self, aggregations: &NodeAggregations) -> f64 {
+        match self {
+            Metric::Min => aggregations.min_value,
+            Metric::Max => aggregations.max_value,
+            Metric::Sum => aggregations.sum,
+            Metric::Count => aggregations.count as f64,
+        }
+    }
+
+    // This metric over the docs the aggregations cover: None when there are
+    // none, except for the count, which is then 0
+    pub fn of_matching(&self, aggregations: &NodeAggregations) -> Option<f64> {
+        (aggregations.count > 0 || *self == Metric::Count).then(|| self.of(aggregations))
+    }
+}
+
+// Execution strategies for bitmap queries. The planner picks one per query;
+// each can also be run directly to measure it in isolation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueryStrategy {
+    // Position lookups on a single thread
+    Sequential,
+    // Position lookups split across the rayon pool
+    Parallel,
+    // Aggregate the excluded docs and subtract from the global aggregations
+    Complement,
+    // Answer runs of consecutive positions from pre-aggregated nodes
+    RunRange,
+    // Read the selected ids straight from the kept doc-order values; resolves
+    // positions like Sequential on trees that don't keep them
+    ColumnarScan,
+}
+
+impl QueryStrategy {
+    pub const ALL: [QueryStrategy; 5] = [
+        QueryStrategy::Sequential,
+        QueryStrategy::Parallel,
+        QueryStrategy::Complement,
+        QueryStrategy::RunRange,
+        QueryStrategy::ColumnarScan,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueryStrategy::Sequential => "sequential",
+            QueryStrategy::Parallel => "parallel",
+            QueryStrategy::Complement => "complement",
+            QueryStrategy::RunRange => "run-range",
+            QueryStrategy::ColumnarScan => "columnar-scan",
+        }
+    }
+}
+
+// Cardinality cut-offs the planner uses to choose a strategy
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PlannerThresholds {
+    // Bitmaps with fewer ids than this are resolved on a single thread
+    pub parallel_min_len: u64,
+    // Bitmaps covering more than this fraction of the docs use the complement
+    pub complement_min_fraction: f64,
+    // Bitmaps with at least this many ids use the columnar scan, on trees that
+    // keep doc-order values
+    #[serde(default = "PlannerThresholds::default_columnar_min_len")]
+    pub columnar_min_len: u64,
+}
+
+impl PlannerThresholds {
+    pub(crate) fn default_columnar_min_len() -> u64 {
+        1_000
+    }
+}
+
+impl Default for PlannerThresholds {
+    fn default() -> Self {
+        PlannerThresholds {
+            parallel_min_len: 10_000,
+            complement_min_fraction: 0.8,
+            columnar_min_len: PlannerThresholds::default_columnar_min_len(),
+        }
+    }
+}
+
+// One executed bitmap query: its selectivity and shape, the strategy that ran
+// it and how long it took
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct PlannerObservation {
+    pub(crate) cardinality: u64,
+    pub(crate) total_count: u32,
+    pub(crate) strategy: QueryStrategy,
+    pub(crate) latency_ns: u64,
+}
+
+// Planner thresholds together with the observations they were fitted from.
+// Persisted as JSON so each machine/workload keeps its own tuning.
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct PlannerProfile {
+    pub(crate) thresholds: PlannerThresholds,
+    pub(crate) observations: Vec<PlannerObservation>,
+}
+
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+impl PlannerProfile {
+    // Oldest observations are dropped beyond this many
+    pub(crate) const MAX_OBSERVATIONS: usize = 1_000;
+
+    // Load a profile, starting from the defaults when the file doesn't exist yet
+    #[cfg(feature = "persist")]
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::from),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(PlannerProfile::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[cfg(feature = "persist")]
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    pub(crate) fn record(&mut self, bitmap: &RoaringBitmap, total_count: u32, strategy: QueryStrategy, latency: Duration) {
+        self.observations.push(PlannerObservation {
+            cardinality: bitmap.len(),
+            total_count,
+            strategy,
+            latency_ns: latency.as_nanos() as u64,
+        });
+        if self.observations.len() > Self::MAX_OBSERVATIONS {
+            let excess = self.observations.len() - Self::MAX_OBSERVATIONS;
+            self.observations.drain(..excess);
+        }
+    }
+
+    // Refit both thresholds from queries measured with more than one strategy.
+    // A threshold only moves when that reduces the number of observed queries
+    // the planner would have routed to the slower strategy.
+    pub(crate) fn refit(&mut self) {
+        // Mean latency per strategy for each distinct (cardinality, total) query
+        let mut queries: HashMap<(u64, u32), HashMap<QueryStrategy, (u64, u64)>> = HashMap::new();
+        for obs in &self.observations {
+            let entry = queries
+                .entry((obs.cardinality, obs.total_count))
+                .or_default()
+                .entry(obs.strategy)
+                .or_insert((0, 0));
+            entry.0 += obs.latency_ns;
+            entry.1 += 1;
+        }
+
+        let mut parallel_points = Vec::new();
+        let mut complement_points = Vec::new();
+        let mut columnar_points = Vec::new();
+        for (&(cardinality, total_count), latencies) in &queries {
+            let mean = |strategy| latencies.get(&strategy).map(|&(sum, n)| sum / n);
+            let sequential = mean(QueryStrategy::Sequential);
+            let parallel = mean(QueryStrategy::Parallel);
+            if let (Some(sequential), Some(parallel)) = (sequential, parallel) {
+                parallel_points.push((cardinality as f64, parallel < sequential));
+            }
+            let best_lookup = sequential.into_iter().chain(parallel).min();
+            if let (Some(complement), Some(best_lookup)) = (mean(QueryStrategy::Complement), best_lookup) {
+                let fraction = cardinality as f64 / total_count.max(1) as f64;
+                complement_points.push((fraction, complement < best_lookup));
+            }
+            // The planner tries the columnar scan before every other strategy
+            let best_other = [QueryStrategy::Sequential, QueryStrategy::Parallel, QueryStrategy::Complement]
+                .into_iter()
+                .filter_map(mean)
+                .min();
+            if let (Some(columnar), Some(best_other)) = (mean(QueryStrategy::ColumnarScan), best_other) {
+                columnar_points.push((cardinality as f64, columnar < best_other));
+            }
+        }
+
+        let thresholds = &mut self.thresholds;
+        thresholds.parallel_min_len =
+            fit_threshold(&mut parallel_points, thresholds.parallel_min_len as f64).round() as u64;
+        thresholds.complement_min_fraction =
+            fit_threshold(&mut complement_points, thresholds.complement_min_fraction).min(1.0);
+        thresholds.columnar_min_len =
+            fit_threshold(&mut columnar_points, thresholds.columnar_min_len as f64).round() as u64;
+    }
+}
+
+// Pick the cut-off between points labelled false (below) and true (above)
+// that misclassifies the fewest, preferring the one closest to the current value.
+// Candidates are the current value and midpoints between neighbouring points.
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) fn fit_threshold(points: &mut [(f64, bool)], current: f64) -> f64 {
+    if points.is_empty() {
+        return current;
+    }
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut candidates = vec![current, points[0].0 / 2.0, points[points.len() - 1].0 * 2.0];
+    candidates.extend(points.windows(2).map(|pair| (pair[0].0 + pair[1].0) / 2.0));
+
+    let errors = |threshold: f64| points.iter().filter(|&&(x, above)| (x >= threshold) != above).count();
+    candidates
+        .into_iter()
+        .map(|threshold| (errors(threshold), (threshold - current).abs(), threshold))
+        .min_by(|a, b| (a.0, a.1).partial_cmp(&(b.0, b.1)).unwrap_or(std::cmp::Ordering::Equal))
+        .map_or(current, |(_, _, threshold)| threshold)
+}
+
+// Estimated work for a bitmap query, produced before execution
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CostEstimate {
+    // Strategy the planner would pick; None when the answer needs no lookups
+    // (empty or full bitmaps, or a tree without doc ids)
+    pub strategy: Option<QueryStrategy>,
+    // Doc ids the strategy resolves to positions
+    pub expected_positions: u64,
+    // Leaves expected to be read while aggregating those positions
+    pub leaves_touched: u64,
+}
+
+impl std::fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.strategy {
+            Some(strategy) => write!(f, "{} strategy, {} positions, ~{} leaves",
+                                     strategy.name(), self.expected_positions, self.leaves_touched),
+            None => write!(f, "no lookups needed"),
+        }
+    }
+}
+
+// Memory usage tracking
+impl DynamicUsage for SampledIndex {
+    fn dynamic_usage(&self) -> usize {
+        (self.sorted_values.capacity() + self.doc_values.capacity()) * std::mem::size_of::<f64>()
+            + self.blocks.capacity() * std::mem::size_of::<NodeAggregations>()
+    }
+
+    fn dynamic_usage_bounds(&self) -> (usize, Option<usize>) {
+        let usage = self.dynamic_usage();
+        (usage, Some(usage))
+    }
+}
+
+impl DynamicUsage for ZoneMapColumn {
+    fn dynamic_usage(&self) -> usize {
+        self.values.capacity() * std::mem::size_of::<f64>()
+            + self.zones.capacity() * std::mem::size_of::<NodeAggregations>()
+            + self.statistics.as_ref().map_or(0, |statistics| {
+                statistics.buckets.capacity() * std::mem::size_of::<HistogramBucket>()
+            })
+    }
+
+    fn dynamic_usage_bounds(&self) -> (usize, Option<usize>) {
+        let usage = self.dynamic_usage();
+        (usage, Some(usage))
+    }
+}
+
+// Query functions for AIT
+impl AggregationIndexTree {
+    // Record where the indexed values came from
+    pub(crate) fn with_provenance(mut self, source: &str, field: &str) -> Self {
+        self.metadata.source = source.to_string();
+        self.metadata.field = field.to_string();
+        self
+    }
+
+    // Doc-order values gathered from the leaves, when the config asks for them
+    pub(crate) fn collect_doc_values(&self) -> Option<DocValues> {
+        let range = self.doc_id_range.filter(|_| self.metadata.config.keep_doc_values)?;
+        let pairs = (0..self.position_count()).map(|pos| (self.get_doc_id_at_position(pos), self.get_value_at_position(pos)));
+        DocValues::new(pairs, range, self.position_count())
+    }
+
+    // Runs of repeated values gathered from the leaves, when the config asks for them
+    pub(crate) fn collect_value_runs(&self) -> Option<ValueRuns> {
+        self.metadata.config.value_runs
+            .then(|| ValueRuns::from_sorted((0..self.position_count()).map(|pos| self.get_value_at_position(pos))))
+    }
+
+    // Value statistics from the tree's positions, which are in value order
+    pub(crate) fn compute_statistics(&self) -> Option<FieldStatistics> {
+        let len = self.position_count();
+        FieldStatistics::from_sorted(len, (0..len).map(|pos| self.get_value_at_position(pos)))
+    }
+
+    pub fn get_global_aggregations(&self) -> NodeAggregations {
+        if self.nodes.is_empty() {
+            return NodeAggregations::empty();
+        }
+        
+        self.nodes[0].aggregations()
+    }
+    
+    pub fn query_with_bitmap(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+
+        if self.nodes.is_empty() {
+            return Ok(NodeAggregations::empty());
+        }
+        
+        // Get global aggregations count
+        let global_aggs = self.get_global_aggregations();
+        
+        // If bitmap is empty, or holds no ids this tree stores, return empty result
+        if bitmap.is_empty() || !self.may_contain_any(bitmap) {
+            return Ok(NodeAggregations::empty());
+        }
+        
+        // If bitmap includes all documents, return global aggregations
+        if self.covered_by(bitmap) {
+            return Ok(global_aggs);
+        }
+        
+        let strategy = self.choose_strategy(bitmap, global_aggs.count);
+        self.query_with_strategy(bitmap, strategy)
+    }
+
+    // Run a user-defined aggregation over the docs in `bitmap`, planned like
+    // query_with_bitmap. Every strategy runs generically except the built-in
+    // complement subtraction, which gives way to reading the included ranges.
+    pub fn aggregate<A: Aggregator>(&self, aggregator: &A, bitmap: &RoaringBitmap) -> Result<A::Output, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        if self.nodes.is_empty() || bitmap.is_empty() || !self.may_contain_any(bitmap) {
+            return Ok(aggregator.finalize(aggregator.init()));
+        }
+        if self.covered_by(bitmap) {
+            let mut result = aggregator.init();
+            self.accumulate_range(aggregator, &mut result, 0, 0, self.position_count() - 1);
+            return Ok(aggregator.finalize(result));
+        }
+        let strategy = self.choose_strategy(bitmap, self.get_global_aggregations().count);
+        self.aggregate_with_strategy(aggregator, bitmap, strategy)
+    }
+
+    // Run a user-defined aggregation with a fixed strategy, bypassing the planner
+    pub fn aggregate_with_strategy<A: Aggregator>(&self, aggregator: &A, bitmap: &RoaringBitmap,
+                                                  strategy: QueryStrategy) -> Result<A::Output, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        if self.nodes.is_empty() {
+            return Ok(aggregator.finalize(aggregator.init()));
+        }
+        let state = match strategy {
+            QueryStrategy::Sequential => self.sequential_state(aggregator, bitmap),
+            QueryStrategy::Parallel => self.parallel_state(aggregator, bitmap),
+            QueryStrategy::Complement => self.complement_state(aggregator, bitmap),
+            QueryStrategy::RunRange => self.run_range_state(aggregator, bitmap),
+            QueryStrategy::ColumnarScan => match &self.doc_values {
+                Some(doc_values) => doc_values.aggregate_with(aggregator, bitmap),
+                None => self.sequential_state(aggregator, bitmap),
+            },
+        };
+        Ok(aggregator.finalize(state))
+    }
+
+    // Filtered query over any doc filter with sequential lookups. Unlike
+    // query_with_bitmap there is no planner: every selected id is resolved.
+    pub fn query_with_filter(&self, filter: &impl DocFilter) -> Result<NodeAggregations, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        Ok(self.direct_query_sequential(filter))
+    }
+
+    // Whether the bitmap holds every id in the tree's doc-id range, and so every
+    // doc the tree stores. A tree's ids need not start at 0 (segments, merged
+    // partitions), so comparing the bitmap's cardinality to the count isn't enough.
+    pub(crate) fn covered_by(&self, bitmap: &RoaringBitmap) -> bool {
+        self.doc_id_range
+            .is_some_and(|(min, max)| bitmap.range_cardinality(min..=max) == (max - min) as u64 + 1)
+    }
+
+    // Cheap membership pre-check against the tree's doc-id range. A bitmap with
+    // no ids inside the range can't match, so the doc_id map isn't consulted.
+    pub(crate) fn may_contain_any(&self, bitmap: &RoaringBitmap) -> bool {
+        self.doc_id_range
+            .is_some_and(|(min, max)| bitmap.range_cardinality(min..=max) > 0)
+    }
+
+    // Estimate the work a bitmap query will do from the bitmap cardinality and the
+    // tree shape, without resolving any doc ids. Callers can use this to reject
+    // or route expensive queries before running them.
+    pub fn estimate_cost(&self, bitmap: &RoaringBitmap) -> CostEstimate {
+        let total = self.get_global_aggregations().count;
+        let selected = bitmap.len().min(total as u64);
+        if !self.has_doc_ids || selected == 0 || self.covered_by(bitmap) || !self.may_contain_any(bitmap) {
+            return CostEstimate {
+                strategy: None,
+                expected_positions: 0,
+                leaves_touched: 0,
+            };
+        }
+
+        let strategy = self.choose_strategy(bitmap, total);
+        let expected_positions = match strategy {
+            QueryStrategy::Complement => total as u64 - selected,
+            _ => selected,
+        };
+
+        // Expected distinct leaves hit by positions spread uniformly over the
+        // leaves; a columnar scan reads none
+        let leaves = self.leaf_count.max(1) as f64;
+        let miss_probability = (1.0 - 1.0 / leaves).powf(expected_positions as f64);
+        let leaves_touched = match strategy {
+            QueryStrategy::ColumnarScan => 0,
+            _ => (leaves * (1.0 - miss_probability)).round() as u64,
+        };
+
+        CostEstimate {
+            strategy: Some(strategy),
+            expected_positions,
+            leaves_touched,
+        }
+    }
+
+    // Planner: pick the execution strategy for a non-empty, partial bitmap
+    pub(crate) fn choose_strategy(&self, bitmap: &RoaringBitmap, total_count: u32) -> QueryStrategy {
+        let thresholds = &self.planner;
+        if self.doc_values.is_some() && bitmap.len() >= thresholds.columnar_min_len {
+            // Scanning the selected ids skips the doc_id -> position lookups,
+            // which costs less than the complement's lookups even for dense bitmaps
+            QueryStrategy::ColumnarScan
+        } else if bitmap.len() as f64 > total_count as f64 * thresholds.complement_min_fraction {
+            // If bitmap covers most of the docs, use complement approach
+            QueryStrategy::Complement
+        } else if self.metadata.ids_in_value_order {
+            // Id runs are position runs, answered from pre-aggregated nodes
+            QueryStrategy::RunRange
+        } else if bitmap.len() < thresholds.parallel_min_len {
+            // For very small bitmaps, use single-threaded processing
+            QueryStrategy::Sequential
+        } else {
+            // For larger bitmaps, use parallel processing
+            QueryStrategy::Parallel
+        }
+    }
+
+    // Run a bitmap query with a fixed strategy, bypassing the planner. Every
+    // strategy returns the same result; only the cost differs.
+    pub fn query_with_strategy(&self, bitmap: &RoaringBitmap, strategy: QueryStrategy)
+        -> Result<NodeAggregations, AitError> {
+        let _span = tracing::info_span!("ait.query.bitmap", cardinality = bitmap.len(), strategy = strategy.name())
+            .entered();
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+
+        let start = Instant::now();
+        let result = match strategy {
+            QueryStrategy::Sequential => self.direct_query_sequential(bitmap),
+            QueryStrategy::Parallel => self.direct_query_parallel(bitmap),
+            QueryStrategy::Complement => self.complement_query(bitmap),
+            QueryStrategy::RunRange => self.run_range_query(bitmap),
+            QueryStrategy::ColumnarScan => match &self.doc_values {
+                Some(doc_values) => doc_values.aggregate(bitmap),
+                None => self.direct_query_sequential(bitmap),
+            },
+        };
+        let total = self.get_global_aggregations().count.max(1);
+        self.query_latencies.record(strategy, bitmap.len() as f64 / total as f64, start.elapsed());
+        Ok(result)
+    }
+
+    // Complement strategy: aggregate the excluded docs and subtract them from the
+    // global aggregations. Min and max cannot be subtracted, but since positions
+    // are in value order they are the values at the first and last positions
+    // that are not excluded.
+    pub(crate) fn complement_query(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        let global_aggs = self.get_global_aggregations();
+        let total = global_aggs.count as usize;
+
+        // Calculate complement of the bitmap over the tree's doc id range
+        let mut complement = RoaringBitmap::new();
+        if let Some((min, max)) = self.doc_id_range {
+            complement.insert_range(min..=max);
+        }
+        complement -= bitmap;
+
+        // If complement is empty, return global aggregations (safeguard)
+        if complement.is_empty() {
+            return global_aggs;
+        }
+
+        // Get aggregations for excluded docs
+        let excluded_positions = self.sorted_positions(&complement);
+        if excluded_positions.len() == total {
+            return NodeAggregations::empty();
+        }
+        let mut excluded_aggs = NodeAggregations::empty();
+        for chunk in excluded_positions.chunks(POSITION_BATCH_SIZE) {
+            self.process_position_batch(&mut excluded_aggs, chunk);
+        }
+
+        // First and last positions that survive the exclusion
+        let first_included = excluded_positions
+            .iter()
+            .enumerate()
+            .find(|&(i, &pos)| pos != i)
+            .map_or(excluded_positions.len(), |(i, _)| i);
+        let mut last_included = total - 1;
+        for &pos in excluded_positions.iter().rev() {
+            if pos != last_included {
+                break;
+            }
+            last_included -= 1;
+        }
+
+        // Subtract from global
+        NodeAggregations {
+            min_value: self.get_value_at_position(first_included),
+            max_value: self.get_value_at_position(last_included),
+            sum: global_aggs.sum - excluded_aggs.sum,
+            count: global_aggs.count - excluded_aggs.count,
+            sum_squares: global_aggs.sum_squares - excluded_aggs.sum_squares,
+        }
+    }
+
+    // Run-based strategy: coalesce sorted positions into runs of consecutive
+    // positions. Long runs are answered from pre-aggregated nodes, short ones
+    // by direct lookup.
+    pub(crate) fn run_range_query(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        self.run_range_state(&Summary, bitmap)
+    }
+
+    pub(crate) fn run_range_state<A: Aggregator>(&self, aggregator: &A, bitmap: &RoaringBitmap) -> A::State {
+        self.sorted_runs_state(aggregator, &self.sorted_positions(bitmap))
+    }
+
+    // Aggregate ascending positions, answering runs of consecutive positions
+    // from pre-aggregated nodes and looking up the rest one by one
+    pub(crate) fn sorted_runs_state<A: Aggregator>(&self, aggregator: &A, positions: &[usize]) -> A::State {
+        const MIN_RUN_LENGTH: usize = 64;
+        let mut result = aggregator.init();
+
+        let mut run_start = 0;
+        while run_start < positions.len() {
+            let mut run_end = run_start + 1;
+            while run_end < positions.len() && positions[run_end] == positions[run_end - 1] + 1 {
+                run_end += 1;
+            }
+
+            let run = &positions[run_start..run_end];
+            if run.len() >= MIN_RUN_LENGTH {
+                self.accumulate_range(aggregator, &mut result, 0, run[0], run[run.len() - 1]);
+            } else {
+                self.accumulate_positions(aggregator, &mut result, run);
+            }
+            run_start = run_end;
+        }
+
+        result
+    }
+
+    // Complement strategy for any aggregator: the included positions are the
+    // gaps between the excluded ones, each read as a range. complement_query
+    // subtracts instead, which only the built-in aggregations support.
+    pub(crate) fn complement_state<A: Aggregator>(&self, aggregator: &A, bitmap: &RoaringBitmap) -> A::State {
+        let total = self.position_count();
+        let mut complement = RoaringBitmap::new();
+        if let Some((min, max)) = self.doc_id_range {
+            complement.insert_range(min..=max);
+        }
+        complement -= bitmap;
+
+        let mut result = aggregator.init();
+        let mut next = 0;
+        for &pos in self.sorted_positions(&complement).iter().chain(std::iter::once(&total)) {
+            if pos > next {
+                self.accumulate_range(aggregator, &mut result, 0, next, pos - 1);
+            }
+            next = pos + 1;
+        }
+        result
+    }
+
+    // Accumulate positions start..=end, relative to the subtree at node_idx.
+    // Fully covered subtrees come from their precomputed aggregations when
+    // the aggregator can use them; otherwise leaf values are read in order.
+    pub(crate) fn accumulate_range<A: Aggregator>(&self, aggregator: &A, result: &mut A::State, node_idx: usize,
+                                                  start: usize, end: usize) {
+        let node = &self.nodes[node_idx];
+        if start == 0 && end + 1 >= self.node_count(node_idx) {
+            if let Some(state) = aggregator.node_state(&node.aggregations()) {
+                aggregator.merge(result, state);
+                return;
+            }
+        }
+        match node {
+            AggregationTreeNode::Leaf { values, .. } => {
+                for &value in &values[start..=end] {
+                    aggregator.accumulate(result, value);
+                }
+            }
+            AggregationTreeNode::Internal { left, right, .. } => {
+                let left_size = self.node_count(*left);
+                if start < left_size {
+                    self.accumulate_range(aggregator, result, *left, start, end.min(left_size - 1));
+                }
+                if end >= left_size {
+                    self.accumulate_range(aggregator, result, *right, start.saturating_sub(left_size), end - left_size);
+                }
+            }
+        }
+    }
+
+    // Translate doc ids into tree positions, sorted for better cache locality
+    pub(crate) fn sorted_positions(&self, filter: &impl DocFilter) -> Vec<usize> {
+        if self.metadata.ids_in_value_order {
+            let len = self.position_count();
+            let mut positions: Vec<usize> = filter.ids().map(|doc_id| doc_id as usize).filter(|&pos| pos < len).collect();
+            positions.sort_unstable();
+            return positions;
+        }
+        let mut positions = Vec::with_capacity(filter.cardinality() as usize);
+        for doc_id in filter.ids() {
+            // Look up the position in the sorted array
+            if let Some(&pos) = self.doc_id_map.get(&doc_id) {
+                positions.push(pos);
+            }
+        }
+        positions.sort_unstable();
+        positions
+    }
+    
+    // Sequential processing for small bitmaps
+    pub(crate) fn direct_query_sequential(&self, filter: &impl DocFilter) -> NodeAggregations {
+        self.sequential_state(&Summary, filter)
+    }
+
+    pub(crate) fn sequential_state<A: Aggregator>(&self, aggregator: &A, filter: &impl DocFilter) -> A::State {
+        let mut result = aggregator.init();
+        
+        // Collect all positions first, sorted to reduce cache misses
+        let positions = self.sorted_positions(filter);
+        
+        // Process positions in batches
+        for chunk in positions.chunks(POSITION_BATCH_SIZE) {
+            self.accumulate_positions(aggregator, &mut result, chunk);
+        }
+        
+        result
+    }
+    
+    // Parallel processing for large bitmaps
+    pub(crate) fn direct_query_parallel(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        self.parallel_state(&Summary, bitmap)
+    }
+
+    pub(crate) fn parallel_state<A: Aggregator>(&self, aggregator: &A, bitmap: &RoaringBitmap) -> A::State {
+        // Collect all positions first
+        let mut sorted_positions: Vec<usize> = bitmap.iter()
+            .filter_map(|doc_id| self.doc_id_map.get(&doc_id).copied())
+            .collect();
+        
+        // No positions found
+        if sorted_positions.is_empty() {
+            return aggregator.init();
+        }
+        
+        // Sort positions for better cache locality
+        // If need more performance, we could use parallel sort
+        sorted_positions.sort_unstable();
+        
+        // Split into chunks for parallel processing and combine the partial
+        // results inside the pool, starting every merge from the empty identity
+        const CHUNK_SIZE: usize = 50_000;
+        let chunks: Vec<&[usize]> = sorted_positions.chunks(CHUNK_SIZE).collect();
+        crate::aggregate_each(aggregator, chunks, |chunk| {
+            let mut local_result = aggregator.init();
+            
+            // Process chunk in batches for better cache performance
+            for batch in chunk.chunks(POSITION_BATCH_SIZE) {
+                self.accumulate_positions(aggregator, &mut local_result, batch);
+            }
+            
+            local_result
+        })
+    }
+    
+    // Batch process positions for better cache utilization
+    #[inline]
+    pub(crate) fn process_position_batch(&self, result: &mut NodeAggregations, positions: &[usize]) {
+        self.accumulate_positions(&Summary, result, positions);
+    }
+
+    #[inline]
+    pub(crate) fn accumulate_positions<A: Aggregator>(&self, aggregator: &A, result: &mut A::State,
+                                                      positions: &[usize]) {
+        // For small batches, use direct processing
+        if positions.len() < 32 {
+            for &pos in positions {
+                aggregator.accumulate(result, self.get_value_at_position(pos));
+            }
+            return;
+        }
+        
+        // For larger batches, accumulate into a local copy kept in registers
+        let mut batch_result = aggregator.init();
+        for &pos in positions {
+            aggregator.accumulate(&mut batch_result, self.get_value_at_position(pos));
+        }
+        
+        // Update the final result
+        aggregator.merge(result, batch_result);
+    }
+    
+    // Recursive range query that tries to use pre-aggregated nodes when possible.
+    // Returns the number of nodes visited, for tracing.
+    pub(crate) fn recursive_range_query(&self, result: &mut NodeAggregations, node_idx: usize, 
+                            start_pos: usize, end_pos: usize) -> usize {
+        let mut visited = 1;
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Internal { left, right, aggregations, .. } => {
+                // Determine the positions covered by the left child
+                let left_size = self.node_count(*left);
+                
+                // Calculate range overlap with left and right children
+                let left_start = 0;
+                let left_end = left_size - 1;
+                let right_start = left_size;
+                let right_end = right_start + self.node_count(*right) - 1;
+                
+                // Check if the range fully covers this node
+                if start_pos <= left_start && end_pos >= right_end {
+                    // Use pre-calculated aggregations for this node
+                    *result += *aggregations;
+                    return visited;
+                }
+                
+                // Check if range overlaps with left child
+                if start_pos <= left_end && end_pos >= left_start {
+                    let overlap_start = start_pos.max(left_start);
+                    let overlap_end = end_pos.min(left_end);
+                    
+                    // If range fully contains left child, use pre-calculated aggregations
+                    if overlap_start == left_start && overlap_end == left_end {
+                        *result += self.nodes[*left].aggregations();
+                        visited += 1;
+                    } else {
+                        // Otherwise recurse into left child
+                        visited += self.recursive_range_query(result, *left, overlap_start, overlap_end);
+                    }
+                }
+                
+                // Check if range overlaps with right child
+                if start_pos <= right_end && end_pos >= right_start {
+                    let overlap_start = start_pos.max(right_start);
+                    let overlap_end = end_pos.min(right_end);
+                    
+                    // If range fully contains right child, use pre-calculated aggregations
+                    if overlap_start == right_start && overlap_end == right_end {
+                        *result += self.nodes[*right].aggregations();
+                        visited += 1;
+                    } else {
+                        // Otherwise recurse into right child with adjusted positions
+                        visited += self.recursive_range_query(result, *right, 
+                            overlap_start - right_start, overlap_end - right_start);
+                    }
+                }
+            },
+            AggregationTreeNode::Leaf { values, .. } => {
+                // Process the leaf node directly
+                for &value in &values[start_pos..=end_pos.min(values.len() - 1)] {
+                    result.add_value(value);
+                }
+            }
+        }
+        visited
+    }
+    
+    // Aggregate all values within [min, max] by translating the bounds into a
+    // position range and answering it from pre-aggregated nodes
+    pub fn query_value_range(&self, min: f64, max: f64) -> NodeAggregations {
+        let span = tracing::info_span!("ait.query.range", min, max,
+                                       cardinality = tracing::field::Empty,
+                                       nodes_visited = tracing::field::Empty).entered();
+        let Some((start_pos, end_pos)) = self.value_range_positions(min, max) else {
+            span.record("cardinality", 0);
+            span.record("nodes_visited", 0);
+            return NodeAggregations::empty();
+        };
+
+        let mut result = NodeAggregations::empty();
+        let visited = self.recursive_range_query(&mut result, 0, start_pos, end_pos - 1);
+        span.record("cardinality", end_pos - start_pos);
+        span.record("nodes_visited", visited);
+        result
+    }
+
+    // Half-open position range [start, end) holding the values within [min, max],
+    // or None if no value falls in the range
+    pub(crate) fn value_range_positions(&self, min: f64, max: f64) -> Option<(usize, usize)> {
+        if self.nodes.is_empty() || min > max {
+            return None;
+        }
+
+        let start_pos = self.count_below(0, min, false);
+        let end_pos = self.count_below(0, max, true);
+        (start_pos < end_pos).then_some((start_pos, end_pos))
+    }
+
+    // t-digests of the internal nodes covering at least MIN_DIGEST_WEIGHT
+    // values, when the config asks for them. Built bottom-up: a node's digest
+    // compresses its children's centroids, or their values below the cut-off.
+    pub(crate) fn collect_node_digests(&self) -> Vec<Option<TDigest>> {
+        if !self.metadata.config.digests || self.nodes.is_empty() {
+            return Vec::new();
+        }
+        let mut digests = vec![None; self.nodes.len()];
+        self.fill_node_digest(0, &mut digests);
+        digests
+    }
+
+    // Centroids of `node_idx` in value order: its digest's, or one per value
+    fn fill_node_digest(&self, node_idx: usize, digests: &mut [Option<TDigest>]) -> Vec<Centroid> {
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Leaf { values, .. } => {
+                values.iter().map(|&mean| Centroid { mean, weight: 1.0 }).collect()
+            }
+            AggregationTreeNode::Internal { left, right, aggregations, .. } => {
+                let mut centroids = self.fill_node_digest(*left, digests);
+                centroids.extend(self.fill_node_digest(*right, digests));
+                if (aggregations.count as f64) < MIN_DIGEST_WEIGHT {
+                    return centroids;
+                }
+                let digest = TDigest::from_sorted(DIGEST_COMPRESSION, centroids, aggregations.min_value,
+                                                  aggregations.max_value);
+                let centroids = digest.centroids.clone();
+                digests[node_idx] = Some(digest);
+                centroids
+            }
+        }
+    }
+
+    // Approximate percentiles (0-100) of the docs the bitmap selects, or of all
+    // docs. The selected positions are coalesced into runs; nodes a run covers
+    // contribute their digest, the rest their values, and the merged centroids
+    // are compressed into one digest. Exact min and max anchor the tails.
+    // Without BuildOptions::digests every selected value is merged. Bitmaps
+    // still resolve every position first, and exact percentiles select by rank
+    // in position order, so this is not faster than percentiles on this tree;
+    // the benchmark's --digests comparison tracks the gap.
+    pub fn approx_percentiles(&self, ps: &[f64], bitmap: Option<&RoaringBitmap>)
+        -> Result<Vec<Option<f64>>, AitError> {
+        let runs = self.position_runs(bitmap)?;
+        let (Some(first), Some(last)) = (runs.first(), runs.last()) else {
+            return Ok(vec![None; ps.len()]);
+        };
+        let (first, last) = (first.start, last.end - 1);
+        let mut centroids = Vec::new();
+        for run in runs {
+            self.visit_range(0, 0, run, &mut |part| match part {
+                RangePart::Covered(node_idx) => match self.node_digests.get(node_idx) {
+                    Some(Some(digest)) => {
+                        centroids.extend_from_slice(&digest.centroids);
+                        true
+                    }
+                    _ => false,
+                },
+                RangePart::Values(values, _) => {
+                    centroids.extend(values.iter().map(|&mean| Centroid { mean, weight: 1.0 }));
+                    true
+                }
+            });
+        }
+        // Runs are in position order and so in value order: the centroids are sorted
+        let digest = TDigest::from_sorted(DIGEST_COMPRESSION, centroids, self.get_value_at_position(first),
+                                          self.get_value_at_position(last));
+        Ok(ps.iter().map(|&p| digest.quantile(p / 100.0)).collect())
+    }
+
+    // Positions the bitmap selects as ascending runs of consecutive positions;
+    // one run of every position without a filter, none for an empty tree
+    fn position_runs(&self, bitmap: Option<&RoaringBitmap>) -> Result<Vec<std::ops::Range<usize>>, AitError> {
+        let Some(bitmap) = bitmap.filter(|bitmap| !self.covered_by(bitmap)) else {
+            let len = self.position_count();
+            return Ok(if len > 0 { std::iter::once(0..len).collect() } else { Vec::new() });
+        };
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let positions = self.sorted_positions(bitmap);
+        let mut runs = Vec::new();
+        let mut run_start = 0;
+        for i in 1..=positions.len() {
+            if i == positions.len() || positions[i] != positions[i - 1] + 1 {
+                runs.push(positions[run_start]..positions[i - 1] + 1);
+                run_start = i;
+            }
+        }
+        Ok(runs)
+    }
+
+    // Hand the positions `range` under `node_idx`, which starts at
+    // `node_start`, to `visit` in position order. Each node the range covers
+    // is offered as Covered, and `visit` returns whether a per-node summary
+    // accounted for it; the values of the other nodes, and of covered nodes
+    // without a summary, arrive as Values. Values' return is ignored.
+    pub(crate) fn visit_range<'t>(&'t self, node_idx: usize, node_start: usize, range: std::ops::Range<usize>,
+                                  visit: &mut impl FnMut(RangePart<'t>) -> bool) {
+        let node_end = node_start + self.node_count(node_idx);
+        if range.end <= node_start || range.start >= node_end {
+            return;
+        }
+        if range.start <= node_start && node_end <= range.end && visit(RangePart::Covered(node_idx)) {
+            return;
+        }
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Internal { left, right, .. } => {
+                let right_start = node_start + self.node_count(*left);
+                self.visit_range(*left, node_start, range.clone(), visit);
+                self.visit_range(*right, right_start, range, visit);
+            }
+            AggregationTreeNode::Leaf { values, .. } => {
+                let (from, to) = (range.start.max(node_start), range.end.min(node_end));
+                visit(RangePart::Values(&values[from - node_start..to - node_start], from..to));
+            }
+        }
+    }
+
+    // visit_range over the runs of positions the bitmap selects, or all positions
+    pub(crate) fn visit_runs<'t>(&'t self, bitmap: Option<&RoaringBitmap>,
+                                 visit: &mut impl FnMut(RangePart<'t>) -> bool) -> Result<(), AitError> {
+        for run in self.position_runs(bitmap)? {
+            self.visit_range(0, 0, run, visit);
+        }
+        Ok(())
+    }
+
+    // Per-node summaries, built bottom-up: a leaf's from `leaf` given its
+    // values and their positions, an internal node's by adding its children's
+    pub(crate) fn fill_node_sums<S>(&self, leaf: &impl Fn(&[f64], std::ops::Range<usize>) -> S) -> Vec<S>
+    where
+        S: Copy + Default + std::ops::AddAssign,
+    {
+        let mut sums = vec![S::default(); self.nodes.len()];
+        if !self.nodes.is_empty() {
+            self.fill_node_sum(0, 0, leaf, &mut sums);
+        }
+        sums
+    }
+
+    fn fill_node_sum<S>(&self, node_idx: usize, node_start: usize, leaf: &impl Fn(&[f64], std::ops::Range<usize>) -> S,
+                        sums: &mut [S]) -> S
+    where
+        S: Copy + Default + std::ops::AddAssign,
+    {
+        let sum = match &self.nodes[node_idx] {
+            AggregationTreeNode::Leaf { values, .. } => leaf(values, node_start..node_start + values.len()),
+            AggregationTreeNode::Internal { left, right, .. } => {
+                let right_start = node_start + self.node_count(*left);
+                let mut sum = self.fill_node_sum(*left, node_start, leaf, sums);
+                sum += self.fill_node_sum(*right, right_start, leaf, sums);
+                sum
+            }
+        };
+        sums[node_idx] = sum;
+        sum
+    }
+
+    // Log-bucketed histograms of the internal nodes covering at least
+    // MIN_HISTOGRAM_COUNT values, when the config asks for them. Built
+    // bottom-up, each appending its right child's buckets to its left's.
+    pub(crate) fn collect_node_histograms(&self) -> Vec<Option<LogHistogram>> {
+        if !self.metadata.config.histograms || self.nodes.is_empty() {
+            return Vec::new();
+        }
+        let mut histograms = vec![None; self.nodes.len()];
+        self.fill_node_histogram(0, &mut histograms);
+        histograms
+    }
+
+    fn fill_node_histogram(&self, node_idx: usize, histograms: &mut [Option<LogHistogram>]) -> LogHistogram {
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Leaf { values, .. } => LogHistogram::from_sorted(values),
+            AggregationTreeNode::Internal { left, right, aggregations, .. } => {
+                let mut histogram = self.fill_node_histogram(*left, histograms);
+                histogram.append(&self.fill_node_histogram(*right, histograms));
+                if aggregations.count >= MIN_HISTOGRAM_COUNT {
+                    histograms[node_idx] = Some(histogram.clone());
+                }
+                histogram
+            }
+        }
+    }
+
+    // Log-bucketed histogram of the docs the bitmap selects, or of all docs,
+    // for p99/p999-style percentiles and histogram output. As with
+    // approx_percentiles, nodes a run of selected positions covers contribute
+    // their histogram and the rest their values; without
+    // BuildOptions::histograms every selected value is added.
+    pub fn query_histogram(&self, bitmap: Option<&RoaringBitmap>) -> Result<LogHistogram, AitError> {
+        let mut histogram = LogHistogram::new();
+        self.visit_runs(bitmap, &mut |part| match part {
+            RangePart::Covered(node_idx) => match self.node_histograms.get(node_idx) {
+                Some(Some(node_histogram)) => {
+                    histogram.append(node_histogram);
+                    true
+                }
+                _ => false,
+            },
+            RangePart::Values(values, _) => {
+                histogram.extend_sorted(values);
+                true
+            }
+        })?;
+        Ok(histogram)
+    }
+
+    // Fill the per-node digests, histograms and moments the config asks for
+    pub(crate) fn collect_node_sketches(&mut self) {
+        let _heap = heap::enter(HeapComponent::Sketches);
+        self.node_digests = self.collect_node_digests();
+        self.node_histograms = self.collect_node_histograms();
+        self.node_moments = self.collect_node_moments();
+        self.node_log_sums = self.collect_node_log_sums();
+    }
+
+    // Central moments of every node, when the config asks for them. Built
+    // bottom-up, each internal node merging its children's.
+    pub(crate) fn collect_node_moments(&self) -> Vec<Moments> {
+        if !self.metadata.config.extended_stats || self.nodes.is_empty() {
+            return Vec::new();
+        }
+        self.fill_node_sums(&|values, _| {
+            let mut moments = Moments::default();
+            values.iter().for_each(|&value| moments.add_value(value));
+            moments
+        })
+    }
+
+    // Count, mean and central moments of the docs the bitmap selects, or of
+    // all docs, for skewness and kurtosis alongside the standard deviation. As
+    // with query_histogram, nodes a run of selected positions covers
+    // contribute their moments and the rest their values; without
+    // BuildOptions::extended_stats every selected value is added.
+    pub fn extended_stats(&self, bitmap: Option<&RoaringBitmap>) -> Result<Moments, AitError> {
+        let mut moments = Moments::default();
+        self.visit_runs(bitmap, &mut |part| match part {
+            RangePart::Covered(node_idx) => self.node_moments.get(node_idx).map(|node| moments += *node).is_some(),
+            RangePart::Values(values, _) => {
+                values.iter().for_each(|&value| moments.add_value(value));
+                true
+            }
+        })?;
+        Ok(moments)
+    }
+
+    // Sums of logarithms of every node, when the config asks for them
+    pub(crate) fn collect_node_log_sums(&self) -> Vec<LogSum> {
+        if !self.metadata.config.geometric_mean || self.nodes.is_empty() {
+            return Vec::new();
+        }
+        let non_positive = self.metadata.config.non_positive;
+        self.fill_node_sums(&|values, _| {
+            let mut log_sum = LogSum::default();
+            values.iter().for_each(|&value| log_sum.add_value(value, non_positive));
+            log_sum
+        })
+    }
+
+    // Sum of logarithms of the docs the bitmap selects, or of all docs, for
+    // their geometric mean and product. Zeros and negatives are handled as
+    // BuildOptions::non_positive says. Covered nodes contribute their sums
+    // with BuildOptions::geometric_mean; without it every selected value is
+    // read.
+    pub fn geometric_mean(&self, bitmap: Option<&RoaringBitmap>) -> Result<LogSum, AitError> {
+        let non_positive = self.metadata.config.non_positive;
+        let mut log_sum = LogSum::default();
+        self.visit_runs(bitmap, &mut |part| match part {
+            RangePart::Covered(node_idx) => self.node_log_sums.get(node_idx).map(|node| log_sum += *node).is_some(),
+            RangePart::Values(values, _) => {
+                values.iter().for_each(|&value| log_sum.add_value(value, non_positive));
+                true
+            }
+        })?;
+        Ok(log_sum)
+    }
+
+    // Attach a secondary id column, indexed by doc id, for approx_distinct.
+    // The ids are stored in position order, and internal nodes covering at
+    // least MIN_DISTINCT_SKETCH_COUNT docs get a HyperLogLog sketch of theirs.
+    // The column is not persisted; attach it again after loading.
+    pub fn attach_distinct_column(&mut self, ids_by_doc: &[u64]) -> Result<(), AitError> {
+        let _heap = heap::enter(HeapComponent::Columns);
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let mut ids = Vec::with_capacity(self.position_count());
+        let mut missing = None;
+        if !self.nodes.is_empty() {
+            self.visit_leaves(0, false, &mut |doc_ids, _| {
+                for &doc_id in doc_ids {
+                    match ids_by_doc.get(doc_id as usize) {
+                        Some(&id) => ids.push(id),
+                        None => missing = Some(doc_id),
+                    }
+                }
+                missing.is_none()
+            });
+        }
+        if let Some(doc_id) = missing {
+            return Err(AitError::MissingDistinctId(doc_id));
+        }
+        let mut node_sketches = vec![None; self.nodes.len()];
+        if !self.nodes.is_empty() {
+            self.fill_distinct_sketch(0, 0, &ids, &mut node_sketches);
+        }
+        self.distinct = Some(DistinctColumn { ids, node_sketches });
+        Ok(())
+    }
+
+    // Sketch of the ids under `node_idx`, which starts at `node_start`, or None
+    // below MIN_DISTINCT_SKETCH_COUNT docs. A sketched node merges its
+    // children's sketches, hashing the ids of a child too small for one.
+    fn fill_distinct_sketch(&self, node_idx: usize, node_start: usize, ids: &[u64],
+                            sketches: &mut [Option<HyperLogLog>]) -> Option<HyperLogLog> {
+        let AggregationTreeNode::Internal { left, right, aggregations, .. } = &self.nodes[node_idx] else {
+            return None;
+        };
+        let right_start = node_start + self.node_count(*left);
+        let left_sketch = self.fill_distinct_sketch(*left, node_start, ids, sketches);
+        let right_sketch = self.fill_distinct_sketch(*right, right_start, ids, sketches);
+        if aggregations.count < MIN_DISTINCT_SKETCH_COUNT {
+            return None;
+        }
+        let mut sketch = HyperLogLog::new();
+        let node_end = node_start + aggregations.count as usize;
+        for (child, range) in [(left_sketch, node_start..right_start), (right_sketch, right_start..node_end)] {
+            match child {
+                Some(child) => sketch.merge(&child),
+                None => ids[range].iter().for_each(|&id| sketch.add(id)),
+            }
+        }
+        sketches[node_idx] = Some(sketch.clone());
+        Some(sketch)
+    }
+
+    // Approximate number of distinct secondary ids among the docs the bitmap
+    // selects, or all docs, to report next to the numeric aggregations. Nodes
+    // a run of selected positions covers contribute their sketch, the rest
+    // hash their ids.
+    pub fn approx_distinct(&self, bitmap: Option<&RoaringBitmap>) -> Result<u64, AitError> {
+        let distinct = self.distinct.as_ref().ok_or(AitError::NoDistinctColumn)?;
+        let mut sketch = HyperLogLog::new();
+        self.visit_runs(bitmap, &mut |part| match part {
+            RangePart::Covered(node_idx) => {
+                distinct.node_sketches[node_idx].as_ref().map(|node| sketch.merge(node)).is_some()
+            }
+            RangePart::Values(_, positions) => {
+                distinct.ids[positions].iter().for_each(|&id| sketch.add(id));
+                true
+            }
+        })?;
+        Ok(sketch.estimate())
+    }
+
+    // Attach a weight column, indexed by doc id, for weighted_aggregations.
+    // Weights are stored in position order next to the leaf values, and every
+    // node gets the weighted sums of its docs. The column is not persisted;
+    // attach it again after loading.
+    pub fn attach_weight_column(&mut self, weights_by_doc: &[f64]) -> Result<(), AitError> {
+        let _heap = heap::enter(HeapComponent::Columns);
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let mut weights = Vec::with_capacity(self.position_count());
+        let mut missing = None;
+        if !self.nodes.is_empty() {
+            self.visit_leaves(0, false, &mut |doc_ids, _| {
+                for &doc_id in doc_ids {
+                    match weights_by_doc.get(doc_id as usize) {
+                        Some(&weight) => weights.push(weight),
+                        None => missing = Some(doc_id),
+                    }
+                }
+                missing.is_none()
+            });
+        }
+        if let Some(doc_id) = missing {
+            return Err(AitError::MissingWeight(doc_id));
+        }
+        let node_sums = self.fill_node_sums(&|values, positions| {
+            let mut sums = WeightedSums::default();
+            for (&value, &weight) in values.iter().zip(&weights[positions]) {
+                sums.add_weighted(value, weight);
+            }
+            sums
+        });
+        self.weights = Some(WeightColumn { weights, node_sums });
+        Ok(())
+    }
+
+    // Attach epoch-millisecond timestamps, indexed by doc id, for
+    // date_histogram. Stored in position order for filtered histograms, and
+    // with the values in time order so unfiltered buckets are range queries.
+    // Every stored doc id must have an entry.
+    pub fn attach_timestamp_column(&mut self, timestamps_by_doc: &[i64]) -> Result<(), AitError> {
+        let _heap = heap::enter(HeapComponent::Columns);
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let mut timestamps = Vec::with_capacity(self.position_count());
+        let mut values = Vec::with_capacity(self.position_count());
+        let mut missing = None;
+        if !self.nodes.is_empty() {
+            self.visit_leaves(0, false, &mut |doc_ids, leaf_values| {
+                for (&doc_id, &value) in doc_ids.iter().zip(leaf_values) {
+                    match timestamps_by_doc.get(doc_id as usize) {
+                        Some(&timestamp) => {
+                            timestamps.push(timestamp);
+                            values.push(value);
+                        }
+                        None => missing = Some(doc_id),
+                    }
+                }
+                missing.is_none()
+            });
+        }
+        if let Some(doc_id) = missing {
+            return Err(AitError::MissingTimestamp(doc_id));
+        }
+        let by_time = TimeSegmentTree::new(&timestamps, &values);
+        self.timestamps = Some(TimestampColumn { timestamps, by_time });
+        Ok(())
+    }
+
+    // Weighted sum and weight total of the docs the bitmap selects, or all
+    // docs, planned like query_with_bitmap
+    pub fn weighted_aggregations(&self, bitmap: Option<&RoaringBitmap>) -> Result<WeightedSums, AitError> {
+        let column = self.weights.as_ref().ok_or(AitError::NoWeightColumn)?;
+        let Some(bitmap) = bitmap.filter(|bitmap| !self.covered_by(bitmap)) else {
+            return Ok(column.node_sums.first().copied().unwrap_or_default());
+        };
+        if bitmap.is_empty() || !self.may_contain_any(bitmap) {
+            return Ok(WeightedSums::default());
+        }
+        let strategy = self.choose_strategy(bitmap, self.get_global_aggregations().count);
+        self.weighted_aggregations_with_strategy(bitmap, strategy)
+    }
+
+    // Weighted aggregations with a fixed strategy, bypassing the planner. There
+    // are no doc-order weights, so the columnar scan resolves positions instead.
+    pub fn weighted_aggregations_with_strategy(&self, bitmap: &RoaringBitmap, strategy: QueryStrategy)
+        -> Result<WeightedSums, AitError> {
+        let column = self.weights.as_ref().ok_or(AitError::NoWeightColumn)?;
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        if self.nodes.is_empty() {
+            return Ok(WeightedSums::default());
+        }
+        let weighted = |positions: &[usize]| {
+            let mut sums = WeightedSums::default();
+            for &pos in positions {
+                sums.add_weighted(self.get_value_at_position(pos), column.weights[pos]);
+            }
+            sums
+        };
+        Ok(match strategy {
+            QueryStrategy::Sequential | QueryStrategy::ColumnarScan => weighted(&self.sorted_positions(bitmap)),
+            QueryStrategy::Parallel => {
+                const CHUNK_SIZE: usize = 50_000;
+                let positions = self.sorted_positions(bitmap);
+                crate::aggregate_each(&UnitWeights, positions.chunks(CHUNK_SIZE).collect::<Vec<_>>(), weighted)
+            }
+            QueryStrategy::Complement => {
+                let mut complement = RoaringBitmap::new();
+                if let Some((min, max)) = self.doc_id_range {
+                    complement.insert_range(min..=max);
+                }
+                complement -= bitmap;
+                let mut sums = column.node_sums[0];
+                sums -= weighted(&self.sorted_positions(&complement));
+                sums
+            }
+            QueryStrategy::RunRange => {
+                let mut sums = WeightedSums::default();
+                self.visit_runs(Some(bitmap), &mut |part| match part {
+                    RangePart::Covered(node_idx) => {
+                        sums += column.node_sums[node_idx];
+                        true
+                    }
+                    RangePart::Values(values, positions) => {
+                        for (&value, &weight) in values.iter().zip(&column.weights[positions]) {
+                            sums.add_weighted(value, weight);
+                        }
+                        true
+                    }
+                })?;
+                sums
+            }
+        })
+    }
+
+    // Fixed-width buckets of the docs the bitmap selects, or all docs: bucket k
+    // holds values in [k * interval, (k + 1) * interval) and is keyed by its
+    // lower bound. Only buckets holding docs are returned, ascending. Values
+    // are in position order, so each bucket is a position range found from
+    // the split values; without a filter it is answered from pre-aggregated
+    // nodes, and with one the selected positions are sliced at the bounds.
+    pub fn histogram(&self, interval: f64, bitmap: Option<&RoaringBitmap>) -> Result<Vec<IntervalBucket>, AitError> {
+        if !(interval.is_finite() && interval > 0.0) {
+            return Err(AitError::InvalidInterval(interval));
+        }
+        let positions = match bitmap.filter(|bitmap| !self.covered_by(bitmap)) {
+            Some(_) if !self.has_doc_ids => return Err(AitError::DocIdsNotStored),
+            Some(bitmap) => Some(self.sorted_positions(bitmap)),
+            None => None,
+        };
+        let len = positions.as_ref().map_or(self.position_count(), Vec::len);
+        let mut buckets = Vec::new();
+        let mut next = 0;
+        while next < len {
+            let first_pos = positions.as_ref().map_or(next, |positions| positions[next]);
+            let value = self.get_value_at_position(first_pos);
+            let mut index = (value / interval).floor();
+            // Rounding can put a value on its bucket's upper bound
+            if (index + 1.0) * interval <= value {
+                index += 1.0;
+            }
+            let end_pos = self.count_below(0, (index + 1.0) * interval, false).max(first_pos + 1);
+            let (end, aggregations) = match &positions {
+                Some(positions) => {
+                    let end = next + positions[next..].partition_point(|&pos| pos < end_pos);
+                    (end, self.sorted_runs_state(&Summary, &positions[next..end]))
+                }
+                None => {
+                    let mut aggregations = NodeAggregations::empty();
+                    self.recursive_range_query(&mut aggregations, 0, first_pos, end_pos - 1);
+                    (end_pos, aggregations)
+                }
+            };
+            buckets.push(IntervalBucket { key: index * interval, aggregations });
+            next = end;
+        }
+        Ok(buckets)
+    }
+
+    // Aggregations per date bucket of the docs in `bitmap` (or all docs), in
+    // time order, leaving out empty buckets. Needs a timestamp column; without
+    // a filter each bucket is a range query over the values in time order,
+    // with one the selected positions are grouped by their timestamps.
+    pub fn date_histogram(&self, interval: DateInterval, bitmap: Option<&RoaringBitmap>)
+        -> Result<Vec<DateBucket>, AitError> {
+        let column = self.timestamps.as_ref().ok_or(AitError::NoTimestampColumn)?;
+        if interval.millis() <= 0 {
+            return Err(AitError::InvalidInterval(interval.millis() as f64));
+        }
+        match bitmap.filter(|bitmap| !self.covered_by(bitmap)) {
+            Some(bitmap) => {
+                let mut buckets: BTreeMap<i64, NodeAggregations> = BTreeMap::new();
+                for pos in self.sorted_positions(bitmap) {
+                    let key_ms = interval.bucket_start(column.timestamps[pos]);
+                    buckets.entry(key_ms).or_default()
+                        .add_value(self.get_value_at_position(pos));
+                }
+                Ok(buckets.into_iter().map(|(key_ms, aggregations)| DateBucket { key_ms, aggregations }).collect())
+            }
+            None => {
+                let sorted = &column.by_time.timestamps;
+                let mut buckets = Vec::new();
+                let mut next = 0;
+                while next < sorted.len() {
+                    let key_ms = interval.bucket_start(sorted[next]);
+                    let last_ms = key_ms.saturating_add(interval.millis() - 1);
+                    buckets.push(DateBucket { key_ms, aggregations: column.by_time.query_time_range(key_ms, last_ms) });
+                    next += sorted[next..].partition_point(|&timestamp| timestamp <= last_ms);
+                }
+                Ok(buckets)
+            }
+        }
+    }
+
+    // Dump the subtree under `node_idx` for debugging: every node's
+    // aggregations and split value down to `max_depth` levels below it, and
+    // the first `leaf_limit` entries of each leaf reached. Splits that
+    // disagree with their children's bounds are flagged.
+    pub fn subtree(&self, node_idx: usize, max_depth: usize, leaf_limit: usize) -> Result<SubtreeNode, AitError> {
+        let start = self.node_start(node_idx)?;
+        Ok(self.dump_node(node_idx, start, max_depth, leaf_limit))
+    }
+
+    // First position under `node_idx`. It is only known by descending from
+    // the root; stitched trees don't keep subtrees contiguous, so search them all.
+    fn node_start(&self, node_idx: usize) -> Result<usize, AitError> {
+        if node_idx >= self.nodes.len() {
+            return Err(AitError::NoSuchNode(node_idx));
+        }
+        let mut stack = vec![(0, 0)];
+        while let Some((current, start)) = stack.pop() {
+            if current == node_idx {
+                return Ok(start);
+            }
+            if let AggregationTreeNode::Internal { left, right, .. } = &self.nodes[current] {
+                stack.push((*right, start + self.node_count(*left)));
+                stack.push((*left, start));
+            }
+        }
+        Err(AitError::NoSuchNode(node_idx))
+    }
+
+    // How leaf doc ids and values are stored: a fresh build or a split-format
+    // load keeps one chunk per column; stitched partitions keep theirs
+    pub fn arena_stats(&self) -> ArenaStats {
+        fn chunk_bytes<T>(chunk: &Arc<[T]>) -> usize {
+            std::mem::size_of_val::<[T]>(chunk)
+        }
+        let mut chunks: HashSet<*const u8> = HashSet::new();
+        let mut stats = ArenaStats::default();
+        for node in &self.nodes {
+            let AggregationTreeNode::Leaf { doc_ids, values, .. } = node else { continue };
+            stats.leaves += 1;
+            stats.referenced_bytes += std::mem::size_of_val::<[u32]>(doc_ids) + std::mem::size_of_val::<[f64]>(values);
+            for (ptr, bytes) in [(doc_ids.chunk().as_ptr().cast(), chunk_bytes(doc_ids.chunk())),
+                                 (values.chunk().as_ptr().cast(), chunk_bytes(values.chunk()))] {
+                if bytes > 0 && chunks.insert(ptr) {
+                    stats.chunks += 1;
+                    stats.allocated_bytes += bytes;
+                }
+            }
+        }
+        stats
+    }
+
+    // Per-level shape of the subtree under `node_idx`: node and leaf counts,
+    // docs per node, value spans and leaf fill, plus the nodes of the top
+    // `block_depth` levels for drawing. See TreeStructure::to_html.
+    pub fn structure(&self, node_idx: usize, block_depth: usize) -> Result<TreeStructure, AitError> {
+        let leaf_size = self.metadata.config.leaf_size.max(1);
+        let mut structure = TreeStructure { node: node_idx, leaf_size, levels: Vec::new(), leaf_fill: [0; 11],
+                                            blocks: Vec::new() };
+        let mut stack = vec![(node_idx, 0, self.node_start(node_idx)?)];
+        while let Some((current, depth, start)) = stack.pop() {
+            let node = &self.nodes[current];
+            let aggregations = node.aggregations();
+            let value_span = if aggregations.count > 0 { aggregations.max_value - aggregations.min_value } else { 0.0 };
+            if structure.levels.len() == depth {
+                structure.levels.push(LevelStats {
+                    depth,
+                    internal_nodes: 0,
+                    leaves: 0,
+                    min_docs: u32::MAX,
+                    max_docs: 0,
+                    min_value_span: f64::INFINITY,
+                    max_value_span: 0.0,
+                    constant_nodes: 0,
+                    leaf_values: 0,
+                });
+            }
+            let level = &mut structure.levels[depth];
+            level.min_docs = level.min_docs.min(aggregations.count);
+            level.max_docs = level.max_docs.max(aggregations.count);
+            level.min_value_span = level.min_value_span.min(value_span);
+            level.max_value_span = level.max_value_span.max(value_span);
+            if aggregations.count > 1 && value_span == 0.0 {
+                level.constant_nodes += 1;
+            }
+            match node {
+                AggregationTreeNode::Internal { left, right, .. } => {
+                    level.internal_nodes += 1;
+                    stack.push((*right, depth + 1, start + self.node_count(*left)));
+                    stack.push((*left, depth + 1, start));
+                }
+                AggregationTreeNode::Leaf { values, .. } => {
+                    level.leaves += 1;
+                    level.leaf_values += values.len();
+                    structure.leaf_fill[(values.len() * 10 / leaf_size).min(10)] += 1;
+                }
+            }
+            if depth < block_depth {
+                structure.blocks.push(StructureBlock {
+                    node: current,
+                    depth,
+                    start,
+                    count: aggregations.count,
+                    min_value: aggregations.min_value,
+                    max_value: aggregations.max_value,
+                    leaf: matches!(node, AggregationTreeNode::Leaf { .. }),
+                });
+            }
+        }
+        Ok(structure)
+    }
+
+    fn dump_node(&self, node_idx: usize, start: usize, depth: usize, leaf_limit: usize) -> SubtreeNode {
+        let node = &self.nodes[node_idx];
+        let mut dump = SubtreeNode {
+            node: node_idx,
+            start,
+            aggregations: node.aggregations(),
+            split_value: None,
+            split_mismatch: false,
+            children: Vec::new(),
+            values: Vec::new(),
+            doc_ids: Vec::new(),
+            omitted: 0,
+        };
+        match node {
+            AggregationTreeNode::Internal { split_value, left, right, .. } => {
+                let (left_aggs, right_aggs) = (self.nodes[*left].aggregations(), self.nodes[*right].aggregations());
+                dump.split_value = Some(*split_value);
+                dump.split_mismatch = (left_aggs.count > 0 && left_aggs.max_value > *split_value)
+                    || (right_aggs.count > 0 && right_aggs.min_value != *split_value);
+                if depth == 0 {
+                    dump.omitted = 2;
+                } else {
+                    let right_start = start + self.node_count(*left);
+                    dump.children.push(self.dump_node(*left, start, depth - 1, leaf_limit));
+                    dump.children.push(self.dump_node(*right, right_start, depth - 1, leaf_limit));
+                }
+            }
+            AggregationTreeNode::Leaf { doc_ids, values, .. } => {
+                dump.values = values.iter().take(leaf_limit).copied().collect();
+                dump.doc_ids = doc_ids.iter().take(leaf_limit).copied().collect();
+                dump.omitted = values.len().saturating_sub(leaf_limit);
+            }
+        }
+        dump
+    }
+
+    // Number of docs whose value equals `value`, from the recorded runs when
+    // the tree keeps them and otherwise from the bounds of its position run
+    pub fn count_eq(&self, value: f64) -> usize {
+        if let Some(count) = self.value_runs.as_ref().and_then(|runs| runs.count(value)) {
+            return count as usize;
+        }
+        self.value_range_positions(value, value).map_or(0, |(start_pos, end_pos)| end_pos - start_pos)
+    }
+
+    // The `top_n` most frequent values, most frequent first. Values held by a
+    // single doc are left out. Trees built without BuildOptions::value_runs
+    // scan every position to find the runs.
+    pub fn value_histogram(&self, top_n: usize) -> Vec<ValueCount> {
+        match &self.value_runs {
+            Some(runs) => runs.top(top_n),
+            None => ValueRuns::from_sorted((0..self.position_count()).map(|pos| self.get_value_at_position(pos)))
+                .top(top_n),
+        }
+    }
+
+    // Doc ids of the values within [min, max]. The values form a position
+    // range, so whole runs of each leaf's doc ids are copied.
+    pub fn doc_ids_in_value_range(&self, min: f64, max: f64) -> Result<RoaringBitmap, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let Some((start_pos, end_pos)) = self.value_range_positions(min, max) else {
+            return Ok(RoaringBitmap::new());
+        };
+
+        let mut ids = Vec::with_capacity(end_pos - start_pos);
+        self.extend_doc_ids(&mut ids, start_pos, end_pos);
+        ids.sort_unstable();
+        Ok(RoaringBitmap::from_sorted_iter(ids).expect("doc ids are unique and sorted"))
+    }
+
+    // Doc ids whose value is exactly `value`, e.g. one code of an integer-coded
+    // field. Equal values are a single position run found by binary search.
+    pub fn docs_with_value(&self, value: f64) -> Result<RoaringBitmap, AitError> {
+        self.doc_ids_in_value_range(value, value)
+    }
+
+    // Doc ids whose value is any of `values`. Each distinct value is one
+    // position run; the ids of all runs are sorted once at the end.
+    pub fn docs_with_value_in(&self, values: &[f64]) -> Result<RoaringBitmap, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let mut values: Vec<f64> = values.iter().copied().filter(|value| !value.is_nan()).collect();
+        values.sort_by(f64::total_cmp);
+        values.dedup_by(|a, b| a == b);
+
+        let mut ids = Vec::new();
+        for value in values {
+            if let Some((start_pos, end_pos)) = self.value_range_positions(value, value) {
+                self.extend_doc_ids(&mut ids, start_pos, end_pos);
+            }
+        }
+        ids.sort_unstable();
+        Ok(RoaringBitmap::from_sorted_iter(ids).expect("doc ids are unique and sorted"))
+    }
+
+    // Append the doc ids at positions [start_pos, end_pos), copying whole runs
+    // of each leaf's doc ids
+    fn extend_doc_ids(&self, ids: &mut Vec<u32>, start_pos: usize, end_pos: usize) {
+        let mut pos = start_pos;
+        while pos < end_pos {
+            let (node_idx, offset) = self.locate_position(pos);
+            let AggregationTreeNode::Leaf { doc_ids, .. } = &self.nodes[node_idx] else {
+                unreachable!("position lookup ended at an internal node");
+            };
+            let run = &doc_ids[offset..doc_ids.len().min(offset + end_pos - pos)];
+            ids.extend_from_slice(run);
+            pos += run.len();
+        }
+    }
+
+    // The k docs the bitmap selects with the highest values, as (doc id, value)
+    // from the highest down; equal values come in reverse position order
+    pub fn top_k(&self, k: usize, bitmap: &RoaringBitmap) -> Result<Vec<(u32, f64)>, AitError> {
+        self.extreme_k(k, bitmap, true)
+    }
+
+    // The k docs the bitmap selects with the lowest values, from the lowest up
+    pub fn bottom_k(&self, k: usize, bitmap: &RoaringBitmap) -> Result<Vec<(u32, f64)>, AitError> {
+        self.extreme_k(k, bitmap, false)
+    }
+
+    // Leaves are walked from the chosen end of the value order, testing each
+    // doc for membership, until k match. That reads about k * docs / |bitmap|
+    // positions; past |bitmap| it is cheaper to resolve every selected id to
+    // its position and take the first or last k.
+    fn extreme_k(&self, k: usize, bitmap: &RoaringBitmap, highest: bool) -> Result<Vec<(u32, f64)>, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        if k == 0 || self.nodes.is_empty() || bitmap.is_empty() {
+            return Ok(Vec::new());
+        }
+        let expected_scan = k as f64 * self.position_count() as f64 / bitmap.len() as f64;
+        if expected_scan > bitmap.len() as f64 {
+            let positions = self.sorted_positions(bitmap);
+            let doc = |&pos: &usize| (self.get_doc_id_at_position(pos), self.get_value_at_position(pos));
+            return Ok(if highest {
+                positions.iter().rev().take(k).map(doc).collect()
+            } else {
+                positions.iter().take(k).map(doc).collect()
+            });
+        }
+
+        let mut docs = Vec::with_capacity(k);
+        self.visit_leaves(0, highest, &mut |doc_ids, values| {
+            let mut take = |i: usize| {
+                if bitmap.contains(doc_ids[i]) {
+                    docs.push((doc_ids[i], values[i]));
+                }
+                docs.len() < k
+            };
+            if highest { (0..values.len()).rev().all(&mut take) } else { (0..values.len()).all(&mut take) }
+        });
+        Ok(docs)
+    }
+
+    // Visit the leaves under `node_idx` with their doc ids and values, in
+    // position order or reversed, until `visit` returns false. Returns whether
+    // every leaf was visited.
+    fn visit_leaves(&self, node_idx: usize, reverse: bool, visit: &mut impl FnMut(&[u32], &[f64]) -> bool) -> bool {
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Leaf { doc_ids, values, .. } => visit(doc_ids, values),
+            AggregationTreeNode::Internal { left, right, .. } => {
+                let (first, second) = if reverse { (*right, *left) } else { (*left, *right) };
+                self.visit_leaves(first, reverse, visit) && self.visit_leaves(second, reverse, visit)
+            }
+        }
+    }
+
+    // Count docs with values in [min, max] whose ids fall in `docs`. The value
+    // range is a position range, but doc ids within it are unordered, so each
+    // position's doc id is checked.
+    pub fn count_value_range_in_docs(&self, min: f64, max: f64, docs: std::ops::Range<u32>) -> Result<usize, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        Ok(self.value_range_positions(min, max).map_or(0, |(start_pos, end_pos)| {
+            (start_pos..end_pos)
+                .filter(|&pos| docs.contains(&self.get_doc_id_at_position(pos)))
+                .count()
+        }))
+    }
+
+    // Single-metric value range query. Positions are in value order, so count,
+    // min and max follow from the range bounds alone; only sum traverses nodes.
+    pub fn query_metric_value_range(&self, min: f64, max: f64, metric: Metric) -> Option<f64> {
+        let Some((start_pos, end_pos)) = self.value_range_positions(min, max) else {
+            return (metric == Metric::Count).then_some(0.0);
+        };
+
+        match metric {
+            Metric::Count => Some((end_pos - start_pos) as f64),
+            Metric::Min => Some(self.get_value_at_position(start_pos)),
+            Metric::Max => Some(self.get_value_at_position(end_pos - 1)),
+            Metric::Sum => {
+                let mut result = NodeAggregations::empty();
+                self.recursive_range_query(&mut result, 0, start_pos, end_pos - 1);
+                Some(result.sum)
+            }
+        }
+    }
+
+    // Single-metric bitmap query with early-exit rules:
+    // - a bitmap covering every doc is answered from the root aggregations
+    // - count only needs doc id membership, never values
+    // - min/max of a dense bitmap walks positions from the matching end of the
+    //   value order and stops at the first member, instead of resolving every id
+    pub fn query_metric_with_bitmap(&self, bitmap: &RoaringBitmap, metric: Metric) -> Result<Option<f64>, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+
+        let global_aggs = self.get_global_aggregations();
+        if bitmap.is_empty() || global_aggs.count == 0 || !self.may_contain_any(bitmap) {
+            return Ok((metric == Metric::Count).then_some(0.0));
+        }
+        if self.covered_by(bitmap) {
+            return Ok(Some(metric.of(&global_aggs)));
+        }
+
+        match metric {
+            Metric::Count => {
+                let count = bitmap.iter().filter(|doc_id| self.doc_id_map.contains_key(doc_id)).count();
+                Ok(Some(count as f64))
+            }
+            Metric::Min | Metric::Max => {
+                // A walk from one end expects total/len probes before the first
+                // hit, against len lookups for resolving every id
+                let total = global_aggs.count as u64;
+                if bitmap.len() * bitmap.len() > total {
+                    let first_match = if metric == Metric::Min {
+                        (0..total as usize).find(|&pos| bitmap.contains(self.get_doc_id_at_position(pos)))
+                    } else {
+                        (0..total as usize).rev().find(|&pos| bitmap.contains(self.get_doc_id_at_position(pos)))
+                    };
+                    Ok(first_match.map(|pos| self.get_value_at_position(pos)))
+                } else {
+                    let positions = self.sorted_positions(bitmap);
+                    let pos = if metric == Metric::Min { positions.first() } else { positions.last() };
+                    Ok(pos.map(|&pos| self.get_value_at_position(pos)))
+                }
+            }
+            Metric::Sum => {
+                let result = self.query_with_bitmap(bitmap)?;
+                Ok(Some(result.sum))
+            }
+        }
+    }
+
+    // Percentile (0-100) at which `value` sits among the docs the bitmap
+    // selects: the share of them below it, counting ties as half. The inverse
+    // of a quantile. None when the bitmap selects no docs.
+    pub fn percentile_rank(&self, bitmap: &RoaringBitmap, value: f64) -> Result<Option<f64>, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        if self.nodes.is_empty() || !self.may_contain_any(bitmap) {
+            return Ok(None);
+        }
+
+        // Positions are in value order, so the docs below and at the value
+        // are the ones before two position bounds
+        let below = self.count_below(0, value, false);
+        let at_most = self.count_below(0, value, true);
+        let (selected, selected_below, selected_at_most) = if self.covered_by(bitmap) {
+            (self.get_global_aggregations().count as usize, below, at_most)
+        } else {
+            let positions = self.sorted_positions(bitmap);
+            (positions.len(), positions.partition_point(|&pos| pos < below),
+             positions.partition_point(|&pos| pos < at_most))
+        };
+        if selected == 0 {
+            return Ok(None);
+        }
+        let ties = (selected_at_most - selected_below) as f64;
+        Ok(Some(100.0 * (selected_below as f64 + ties / 2.0) / selected as f64))
+    }
+
+    // Exact percentile (0-100) of the values of the docs the bitmap selects, or
+    // of all docs, interpolating linearly between the two closest ranks. None
+    // when no docs are selected.
+    pub fn percentile(&self, p: f64, bitmap: Option<&RoaringBitmap>) -> Result<Option<f64>, AitError> {
+        Ok(self.percentiles(&[p], bitmap)?[0])
+    }
+
+    // Several exact percentiles over one selection. Positions are in value
+    // order, so a rank is a position: unfiltered, each percentile is two
+    // lookups; filtered, the bitmap's positions are sorted once and indexed.
+    pub fn percentiles(&self, ps: &[f64], bitmap: Option<&RoaringBitmap>) -> Result<Vec<Option<f64>>, AitError> {
+        let filtered = bitmap.filter(|bitmap| !self.covered_by(bitmap));
+        if filtered.is_some() && !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let positions = filtered.map(|bitmap| self.sorted_positions(bitmap));
+        let len = positions.as_ref().map_or(self.position_count(), Vec::len);
+        let value_at_rank = |rank: usize| match &positions {
+            Some(positions) => self.get_value_at_position(positions[rank]),
+            None => self.get_value_at_position(rank),
+        };
+        Ok(ps
+            .iter()
+            .map(|&p| {
+                if len == 0 {
+                    return None;
+                }
+                let rank = p.clamp(0.0, 100.0) / 100.0 * (len - 1) as f64;
+                let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+                let low_value = value_at_rank(lower);
+                Some(if upper == lower { low_value } else {
+                    low_value + (value_at_rank(upper) - low_value) * (rank - lower as f64)
+                })
+            })
+            .collect())
+    }
+
+    // Aggregations of the values between two quantiles (each in [0, 1]) of the
+    // docs the bitmap selects, or of all docs: the interquartile range for
+    // 0.25..0.75, a 5% trimmed mean for 0.05..0.95. Of n docs in value order,
+    // ranks round(q_low * n) up to (not including) round(q_high * n) are kept.
+    // Unfiltered, that rank range is a position range answered from node
+    // aggregations; filtered, it is a slice of the bitmap's sorted positions.
+    pub fn aggregate_between_quantiles(&self, q_low: f64, q_high: f64, bitmap: Option<&RoaringBitmap>)
+        -> Result<NodeAggregations, AitError> {
+        let mut result = NodeAggregations::empty();
+        if self.nodes.is_empty() {
+            return Ok(result);
+        }
+        let rank_range = |n: usize| {
+            let rank = |q: f64| (q.clamp(0.0, 1.0) * n as f64).round() as usize;
+            rank(q_low)..rank(q_high)
+        };
+        match bitmap.filter(|bitmap| !self.covered_by(bitmap)) {
+            None => {
+                let ranks = rank_range(self.position_count());
+                if !ranks.is_empty() {
+                    self.recursive_range_query(&mut result, 0, ranks.start, ranks.end - 1);
+                }
+            }
+            Some(bitmap) => {
+                if !self.has_doc_ids {
+                    return Err(AitError::DocIdsNotStored);
+                }
+                let positions = self.sorted_positions(bitmap);
+                let ranks = rank_range(positions.len());
+                if let Some(positions) = positions.get(ranks) {
+                    for batch in positions.chunks(POSITION_BATCH_SIZE) {
+                        self.process_position_batch(&mut result, batch);
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // Count and stats of the values per term of a categorical field, for the
+    // docs the bitmap selects or all docs, most docs first. Without a memory
+    // limit this is a single pass. With one, the per-term state is kept under
+    // the limit by aggregating only a range of term codes per pass over the
+    // docs; a limit too small for a single term's state is an error.
+    pub fn terms_stats(&self, terms: &TermColumn, bitmap: Option<&RoaringBitmap>, memory_limit: Option<usize>)
+        -> Result<Vec<TermBucket>, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+
+        let term_count = terms.terms.len();
+        let per_term = std::mem::size_of::<NodeAggregations>();
+        let terms_per_pass = match memory_limit {
+            Some(limit) if limit < per_term => {
+                return Err(AitError::MemoryLimitExceeded { needed: per_term, limit });
+            }
+            Some(limit) => limit / per_term,
+            None => term_count.max(1),
+        };
+        let mut buckets = Vec::new();
+        for start in (0..term_count).step_by(terms_per_pass) {
+            let codes = start..(start + terms_per_pass).min(term_count);
+            buckets.extend(terms.buckets(codes.clone(), &self.term_aggregations(terms, bitmap, codes)?));
+        }
+        buckets.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.term.cmp(&b.term)));
+        Ok(buckets)
+    }
+
+    // Buckets per term like terms_stats, yielded a pass at a time in `order`
+    // rather than collected, so only one pass's per-term state is held: all
+    // terms without a memory limit, as many as fit under it with one. Term
+    // order takes a sorted copy of the term codes; doc-count order first counts
+    // the docs per term, from the dictionary statistics when unfiltered.
+    pub fn terms_stats_stream<'a>(&'a self, terms: &'a TermColumn, bitmap: Option<&'a RoaringBitmap>,
+                                  order: TermOrder, memory_limit: Option<usize>)
+        -> Result<TermBucketStream<'a>, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let per_term = std::mem::size_of::<NodeAggregations>();
+        let terms_per_pass = match memory_limit {
+            Some(limit) if limit < per_term => {
+                return Err(AitError::MemoryLimitExceeded { needed: per_term, limit });
+            }
+            Some(limit) => limit / per_term,
+            None => terms.terms.len().max(1),
+        };
+
+        let bitmap = bitmap.filter(|bitmap| !self.covered_by(bitmap));
+        let mut codes: Vec<u32> = (0..terms.terms.len() as u32).collect();
+        match order {
+            TermOrder::Term => codes.sort_unstable_by(|&a, &b| terms.terms[a as usize].cmp(&terms.terms[b as usize])),
+            TermOrder::DocCount => {
+                let counts = match bitmap {
+                    None => terms.counts.clone(),
+                    Some(bitmap) => {
+                        let mut counts = vec![0u32; terms.terms.len()];
+                        for doc_id in bitmap.iter().filter(|doc_id| self.doc_id_map.contains_key(doc_id)) {
+                            if let Some(code) = terms.code(doc_id) {
+                                counts[code] += 1;
+                            }
+                        }
+                        counts
+                    }
+                };
+                // Terms without docs yield no bucket; leave them out of the passes
+                codes.retain(|&code| counts[code as usize] > 0);
+                codes.sort_unstable_by(|&a, &b| {
+                    counts[b as usize].cmp(&counts[a as usize])
+                        .then_with(|| terms.terms[a as usize].cmp(&terms.terms[b as usize]))
+                });
+            }
+        }
+        // Output rank of each code, for placing a doc's term within a pass
+        let mut ranks = vec![u32::MAX; terms.terms.len()];
+        for (rank, &code) in codes.iter().enumerate() {
+            ranks[code as usize] = rank as u32;
+        }
+        Ok(TermBucketStream {
+            tree: self,
+            terms,
+            bitmap,
+            codes,
+            ranks,
+            terms_per_pass,
+            next: 0,
+            pending: Vec::new().into_iter(),
+        })
+    }
+
+    // Aggregations of the terms with codes in `codes`, indexed by code from the
+    // range start. Without a filter the leaves are walked once; with one, every
+    // selected id is resolved to its value.
+    pub(crate) fn term_aggregations(&self, terms: &TermColumn, bitmap: Option<&RoaringBitmap>, codes: std::ops::Range<usize>)
+        -> Result<Vec<NodeAggregations>, AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+        let slot = |code: usize| codes.contains(&code).then(|| code - codes.start);
+        Ok(self.term_slot_aggregations(terms, bitmap, codes.len(), slot))
+    }
+
+    // Aggregations into `slots` entries, adding each doc to the slot its term
+    // code maps to, if any
+    fn term_slot_aggregations(&self, terms: &TermColumn, bitmap: Option<&RoaringBitmap>, slots: usize,
+                              slot: impl Fn(usize) -> Option<usize>) -> Vec<NodeAggregations> {
+        let mut aggregations = vec![NodeAggregations::empty(); slots];
+        match bitmap.filter(|bitmap| !self.covered_by(bitmap)) {
+            None => {
+                for node in &self.nodes {
+                    if let AggregationTreeNode::Leaf { doc_ids, values, .. } = node {
+                        for (&doc_id, &value) in doc_ids.iter().zip(values) {
+                            if let Some(slot) = terms.code(doc_id).and_then(&slot) {
+                                aggregations[slot].add_value(value);
+                            }
+                        }
+                    }
+                }
+            }
+            Some(bitmap) => {
+                for doc_id in bitmap.iter() {
+                    let slot = terms.code(doc_id).and_then(&slot);
+                    if let (Some(&pos), Some(slot)) = (self.doc_id_map.get(&doc_id), slot) {
+                        aggregations[slot].add_value(self.get_value_at_position(pos));
+                    }
+                }
+            }
+        }
+        aggregations
+    }
+
+    // Doc id stored at a position in value order
+    pub(crate) fn get_doc_id_at_position(&self, pos: usize) -> u32 {
+        let (node_idx, offset) = self.locate_position(pos);
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Leaf { doc_ids, .. } => doc_ids[offset],
+            AggregationTreeNode::Internal { .. } => unreachable!("position lookup ended at an internal node"),
+        }
+    }
+
+    // Leaf and offset holding a position. Uses the position map when its entry
+    // points inside a leaf and, with doc ids stored, the doc there maps back to
+    // this position; otherwise the event is counted, logged once, and the
+    // position is found by descending the tree.
+    pub(crate) fn locate_position(&self, pos: usize) -> (usize, usize) {
+        if !self.metadata.config.position_map {
+            return self.find_position_recursive(0, pos);
+        }
+        if let Some(&(node_idx, offset)) = self.position_map.get(pos) {
+            if let Some(AggregationTreeNode::Leaf { doc_ids, values, .. }) = self.nodes.get(node_idx) {
+                // A stale entry can name a valid offset in the wrong leaf
+                let holds_pos = !self.has_doc_ids
+                    || doc_ids.get(offset).is_some_and(|doc_id| self.doc_id_map.get(doc_id) == Some(&pos));
+                if offset < values.len() && holds_pos {
+                    return (node_idx, offset);
+                }
+            }
+        }
+
+        if self.position_fallbacks.record() {
+            tracing::warn!(position = pos, "position map missing or invalid; falling back to tree traversal \
+                                            (run `repair` to rebuild it)");
+        }
+        self.find_position_recursive(0, pos)
+    }
+
+    // Latency counts and quantiles of the bitmap queries run so far, per
+    // strategy and selectivity band that saw any
+    pub fn query_latencies(&self) -> Vec<LatencySummary> {
+        self.query_latencies.summaries()
+    }
+
+    // Number of lookups that fell back to a tree traversal
+    #[cfg_attr(not(feature = "bench"), allow(dead_code))]
+    pub(crate) fn position_map_fallbacks(&self) -> u64 {
+        self.position_fallbacks.get()
+    }
+
+    // Count position map entries that disagree with the tree; none when the
+    // tree was built without one
+    #[cfg_attr(not(feature = "bench"), allow(dead_code))]
+    pub(crate) fn verify_position_map(&self) -> usize {
+        if !self.metadata.config.position_map {
+            return 0;
+        }
+        let len = self.get_global_aggregations().count as usize;
+        let mismatched = (0..len.min(self.position_map.len()))
+            .filter(|&pos| self.position_map[pos] != self.find_position_recursive(0, pos))
+            .count();
+        mismatched + len.abs_diff(self.position_map.len())
+    }
+
+    // Rebuild the position map from the tree and reset the fallback counter
+    pub(crate) fn rebuild_position_map(&mut self) {
+        self.position_map = self.traverse_positions();
+        self.metadata.config.position_map = true;
+        self.position_fallbacks = FallbackCounter::default();
+    }
+
+    // Positions in the tree, i.e. docs
+    pub(crate) fn position_count(&self) -> usize {
+        self.get_global_aggregations().count as usize
+    }
+
+    // Position map built by walking the tree, ignoring the stored one
+    pub(crate) fn traverse_positions(&self) -> Vec<(usize, usize)> {
+        let mut position_map = vec![(0, 0); self.position_count()];
+        if !self.nodes.is_empty() {
+            build_position_map(&self.nodes, 0, &mut position_map, 0);
+        }
+        position_map
+    }
+
+    // Number of values in the subtree below `target` (or at most `target` when
+    // `inclusive` is set), using split values to descend a single path
+    pub(crate) fn count_below(&self, node_idx: usize, target: f64, inclusive: bool) -> usize {
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Internal { split_value, left, right, .. } => {
+                // The split value is the smallest value of the right subtree
+                let right_has_matches = if inclusive {
+                    *split_value <= target
+                } else {
+                    *split_value < target
+                };
+
+                if right_has_matches {
+                    self.node_count(*left) + self.count_below(*right, target, inclusive)
+                } else {
+                    self.count_below(*left, target, inclusive)
+                }
+            },
+            AggregationTreeNode::Leaf { values, .. } => {
+                if inclusive {
+                    values.partition_point(|&v| v <= target)
+                } else {
+                    values.partition_point(|&v| v < target)
+                }
+            }
+        }
+    }
+
+    // Number of values stored under a node
+    pub(crate) fn node_count(&self, node_idx: usize) -> usize {
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Internal { aggregations, .. } => aggregations.count as usize,
+            AggregationTreeNode::Leaf { values, .. } => values.len(),
+        }
+    }
+    
+    // Helper method to find a value at a given position in the sorted array
+    #[inline(always)]
+    pub(crate) fn get_value_at_position(&self, pos: usize) -> f64 {
+        let (node_idx, offset) = self.locate_position(pos);
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Leaf { values, .. } => values[offset],
+            AggregationTreeNode::Internal { .. } => unreachable!("position lookup ended at an internal node"),
+        }
+    }
+
+    // Find the leaf and offset of a position by descending the tree by subtree counts
+    pub(crate) fn find_position_recursive(&self, node_idx: usize, global_pos: usize) -> (usize, usize) {
+        match &self.nodes[node_idx] {
+            AggregationTreeNode::Internal { left, right, .. } => {
+                // Get the count of elements in the left subtree
+                let left_count = self.node_count(*left);
+                
+                // Determine if the position is in the left or right subtree
+                if global_pos < left_count {
+                    // Position is in left subtree
+                    self.find_position_recursive(*left, global_pos)
+                } else {
+                    // Position is in right subtree, adjust the position relative to right subtree
+                    self.find_position_recursive(*right, global_pos - left_count)
+                }
+            },
+            AggregationTreeNode::Leaf { .. } => (node_idx, global_pos),
+        }
+    }
+}
+
+// Term buckets from AggregationIndexTree::terms_stats_stream. Each pass over
+// the docs aggregates the next run of terms in output order and is drained
+// before the next begins.
+pub struct TermBucketStream<'a> {
+    tree: &'a AggregationIndexTree,
+    terms: &'a TermColumn,
+    bitmap: Option<&'a RoaringBitmap>,
+    // Term codes in output order, and the position of each code in it
+    codes: Vec<u32>,
+    ranks: Vec<u32>,
+    terms_per_pass: usize,
+    // Start of the next pass in `codes`
+    next: usize,
+    pending: std::vec::IntoIter<TermBucket>,
+}
+
+impl Iterator for TermBucketStream<'_> {
+    type Item = TermBucket;
+
+    fn next(&mut self) -> Option<TermBucket> {
+        loop {
+            if let Some(bucket) = self.pending.next() {
+                return Some(bucket);
+            }
+            if self.next >= self.codes.len() {
+                return None;
+            }
+            let pass = self.next..(self.next + self.terms_per_pass).min(self.codes.len());
+            let slot = |code: usize| {
+                let rank = self.ranks[code] as usize;
+                pass.contains(&rank).then(|| rank - pass.start)
+            };
+            let aggregations = self.tree.term_slot_aggregations(self.terms, self.bitmap, pass.len(), slot);
+            self.pending = self.codes[pass.clone()]
+                .iter()
+                .zip(&aggregations)
+                .filter_map(|(&code, aggregations)| self.terms.bucket(code as usize, aggregations))
+                .collect::<Vec<_>>()
+                .into_iter();
+            self.next = pass.end;
+        }
+    }
+}
+
+// Doc-ordered values with per-zone aggregations. Zones fully covered by a
+// filter or value range are answered from their aggregations and zones that
+// can't match are skipped; only partially covered zones are scanned.
+#[derive(Debug, Clone)]
+pub struct ZoneMapColumn {
+    pub(crate) values: Vec<f64>,
+    pub(crate) zones: Vec<NodeAggregations>,
+    // Zones of unsorted values mostly span the whole domain, so range
+    // estimates come from a histogram over a sorted copy instead
+    pub(crate) statistics: Option<FieldStatistics>,
+}
+
+impl ZoneMapColumn {
+    pub(crate) const ZONE_SIZE: usize = 1024;
+
+    pub fn new(values: Vec<f64>) -> Self {
+        let zones = values
+            .chunks(Self::ZONE_SIZE)
+            .map(|zone| {
+                let mut aggregations = NodeAggregations::empty();
+                for &value in zone {
+                    aggregations.add_value(value);
+                }
+                aggregations
+            })
+            .collect();
+        let mut sorted = values.clone();
+        sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let statistics = FieldStatistics::from_sorted(sorted.len(), sorted);
+        ZoneMapColumn { values, zones, statistics }
+    }
+
+    pub(crate) fn zone_values(&self, zone_idx: usize) -> &[f64] {
+        let start = zone_idx * Self::ZONE_SIZE;
+        &self.values[start..(start + Self::ZONE_SIZE).min(self.values.len())]
+    }
+
+    pub fn get_global_aggregations(&self) -> NodeAggregations {
+        self.zones.iter().fold(NodeAggregations::empty(), |acc, &zone| acc + zone)
+    }
+
+    pub fn query_with_bitmap(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        crate::aggregate_each(&Summary, 0..self.zones.len(), |zone_idx| {
+            let start = (zone_idx * Self::ZONE_SIZE) as u32;
+            let zone = self.zone_values(zone_idx);
+            let ids = start..start + zone.len() as u32;
+            match bitmap.range_cardinality(ids.clone()) {
+                0 => NodeAggregations::empty(),
+                selected if selected == zone.len() as u64 => self.zones[zone_idx],
+                _ => {
+                    let mut result = NodeAggregations::empty();
+                    for doc_id in bitmap.range(ids) {
+                        result.add_value(zone[(doc_id - start) as usize]);
+                    }
+                    result
+                }
+            }
+        })
+    }
+
+    pub fn query_value_range(&self, min: f64, max: f64) -> NodeAggregations {
+        crate::aggregate_each(&Summary, 0..self.zones.len(), |zone_idx| {
+            let zone = &self.zones[zone_idx];
+            if zone.count == 0 || zone.max_value < min || zone.min_value > max {
+                NodeAggregations::empty()
+            } else if zone.min_value >= min && zone.max_value <= max {
+                *zone
+            } else {
+                let mut result = NodeAggregations::empty();
+                for &value in self.zone_values(zone_idx) {
+                    if value >= min && value <= max {
+                        result.add_value(value);
+                    }
+                }
+                result
+            }
+        })
+    }
+
+    // Estimated values within [min, max] from the column's histogram
+    pub fn estimate_value_range_count(&self, min: f64, max: f64) -> u64 {
+        self.statistics.as_ref().map_or(0, |statistics| statistics.estimate_range_count(min, max))
+    }
+
+    // Doc ids of the values within [min, max]. Zones entirely inside the range
+    // are added as id ranges without reading their values.
+    pub fn doc_ids_in_value_range(&self, min: f64, max: f64) -> RoaringBitmap {
+        let mut ids = RoaringBitmap::new();
+        for (zone_idx, zone) in self.zones.iter().enumerate() {
+            let start = (zone_idx * Self::ZONE_SIZE) as u32;
+            if zone.count == 0 || zone.max_value < min || zone.min_value > max {
+                continue;
+            } else if zone.min_value >= min && zone.max_value <= max {
+                ids.insert_range(start..start + zone.count);
+            } else {
+                for (offset, &value) in self.zone_values(zone_idx).iter().enumerate() {
+                    if value >= min && value <= max {
+                        ids.insert(start + offset as u32);
+                    }
+                }
+            }
+        }
+        ids
+    }
+}
+
+// Memory-constrained alternative to the AIT. Values are kept sorted with an
+// aggregation only for every `sample_every` leaves' worth of positions, and no
+// doc-id or position maps. Range queries use the samples for covered blocks
+// and scan the sorted values at the edges; filtered queries fall back to a
+// scan of the doc-ordered column.
+#[derive(Debug, Clone)]
+pub struct SampledIndex {
+    pub(crate) sorted_values: Vec<f64>,
+    pub(crate) doc_values: Vec<f64>,
+    pub(crate) block_size: usize,
+    pub(crate) blocks: Vec<NodeAggregations>,
+}
+
+impl SampledIndex {
+    pub fn new(doc_values: Vec<f64>, options: &BuildOptions) -> Self {
+        let mut sorted_values = doc_values.clone();
+        sorted_values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        let block_size = (options.leaf_size * options.sample_every).max(1);
+        let blocks = sorted_values
+            .chunks(block_size)
+            .map(|block| {
+                let mut aggregations = NodeAggregations::empty();
+                for &value in block {
+                    aggregations.add_value(value);
+                }
+                aggregations
+            })
+            .collect();
+
+        SampledIndex { sorted_values, doc_values, block_size, blocks }
+    }
+
+    pub fn get_global_aggregations(&self) -> NodeAggregations {
+        self.blocks.iter().fold(NodeAggregations::empty(), |acc, &block| acc + block)
+    }
+
+    pub(crate) fn scan_positions(&self, positions: std::ops::Range<usize>) -> NodeAggregations {
+        let mut result = NodeAggregations::empty();
+        for &value in &self.sorted_values[positions] {
+            result.add_value(value);
+        }
+        result
+    }
+
+    pub fn query_value_range(&self, min: f64, max: f64) -> NodeAggregations {
+        let start = self.sorted_values.partition_point(|&value| value < min);
+        let end = self.sorted_values.partition_point(|&value| value <= max);
+        if start >= end {
+            return NodeAggregations::empty();
+        }
+
+        // Blocks lying entirely inside [start, end) come from their samples
+        let first_block = start.div_ceil(self.block_size);
+        let last_block = end / self.block_size;
+        if first_block >= last_block {
+            return self.scan_positions(start..end);
+        }
+
+        let mut result = self.scan_positions(start..first_block * self.block_size);
+        for block in &self.blocks[first_block..last_block] {
+            result += *block;
+        }
+        result + self.scan_positions(last_block * self.block_size..end)
+    }
+
+    pub fn query_with_bitmap(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        let mut result = NodeAggregations::empty();
+        for doc_id in bitmap.range(0..self.doc_values.len() as u32) {
+            result.add_value(self.doc_values[doc_id as usize]);
+        }
+        result
+    }
+}
+
+// Binary indexed tree of sums over doc order, for update-heavy workloads:
+// O(log n) point updates and doc-id prefix sums. Counts over a doc-id range
+// are implied by its length; min/max aren't invertible and aren't kept.
+#[derive(Debug, Clone)]
+pub struct FenwickSums {
+    pub(crate) values: Vec<f64>,
+    // 1-based: tree[i] holds the sum of the lowbit(i) values ending at doc i - 1
+    pub(crate) tree: Vec<f64>,
+}
+
+impl FenwickSums {
+    // Linear-time build: each node pushes its partial sum to its parent
+    pub fn new(values: Vec<f64>) -> Self {
+        let mut tree = vec![0.0; values.len() + 1];
+        for i in 1..tree.len() {
+            tree[i] += values[i - 1];
+            let parent = i + (i & i.wrapping_neg());
+            if parent < tree.len() {
+                tree[parent] += tree[i];
+            }
+        }
+        FenwickSums { values, tree }
+    }
+
+    // Set a doc's value. The sums cover the docs given to new, so an id
+    // past the last of them is an error
+    pub fn update(&mut self, doc_id: u32, value: f64) -> Result<(), AitError> {
+        let Some(old) = self.values.get_mut(doc_id as usize) else {
+            return Err(AitError::NoSuchDoc(doc_id));
+        };
+        let delta = value - *old;
+        *old = value;
+
+        let mut i = doc_id as usize + 1;
+        while i < self.tree.len() {
+            self.tree[i] += delta;
+            i += i & i.wrapping_neg();
+        }
+        Ok(())
+    }
+
+    // Sum of the values of docs [0, end)
+    pub fn prefix_sum(&self, end: u32) -> f64 {
+        let mut sum = 0.0;
+        let mut i = (end as usize).min(self.values.len());
+        while i > 0 {
+            sum += self.tree[i];
+            i -= i & i.wrapping_neg();
+        }
+        sum
+    }
+
+    pub fn range_sum(&self, docs: std::ops::Range<u32>) -> f64 {
+        if docs.start >= docs.end {
+            return 0.0;
+        }
+        self.prefix_sum(docs.end) - self.prefix_sum(docs.start)
+    }
+}
+
+// Aggregation backends that can stand in for one another over the same doc
+// ids. Every backend answers sums and counts; `supports` says whether it also
+// keeps min and max. Metrics follow Metric::of_matching: None when no doc
+// matches, except for a count of 0.
+pub trait AggIndex {
+    fn name(&self) -> &'static str;
+
+    fn supports(&self, _metric: Metric) -> bool {
+        true
+    }
+
+    fn metric_with_bitmap(&self, bitmap: &RoaringBitmap, metric: Metric) -> Result<Option<f64>, AitError>;
+
+    // The metric over the docs with ids in `docs`
+    fn metric_in_doc_range(&self, docs: std::ops::Range<u32>, metric: Metric) -> Result<Option<f64>, AitError> {
+        let mut bitmap = RoaringBitmap::new();
+        bitmap.insert_range(docs);
+        self.metric_with_bitmap(&bitmap, metric)
+    }
+}
+
+impl AggIndex for AggregationIndexTree {
+    fn name(&self) -> &'static str {
+        "AIT"
+    }
+
+    fn metric_with_bitmap(&self, bitmap: &RoaringBitmap, metric: Metric) -> Result<Option<f64>, AitError> {
+        self.query_metric_with_bitmap(bitmap, metric)
+    }
+}
+
+impl AggIndex for SampledIndex {
+    fn name(&self) -> &'static str {
+        "Sampled index"
+    }
+
+    fn metric_with_bitmap(&self, bitmap: &RoaringBitmap, metric: Metric) -> Result<Option<f64>, AitError> {
+        Ok(metric.of_matching(&self.query_with_bitmap(bitmap)))
+    }
+}
+
+impl AggIndex for ZoneMapColumn {
+    fn name(&self) -> &'static str {
+        "Zone map"
+    }
+
+    fn metric_with_bitmap(&self, bitmap: &RoaringBitmap, metric: Metric) -> Result<Option<f64>, AitError> {
+        Ok(metric.of_matching(&self.query_with_bitmap(bitmap)))
+    }
+}
+
+impl AggIndex for FenwickSums {
+    fn name(&self) -> &'static str {
+        "Fenwick"
+    }
+
+    fn supports(&self, metric: Metric) -> bool {
+        matches!(metric, Metric::Sum | Metric::Count)
+    }
+
+    fn metric_with_bitmap(&self, bitmap: &RoaringBitmap, metric: Metric) -> Result<Option<f64>, AitError> {
+        if !self.supports(metric) {
+            return Err(AitError::UnsupportedMetric(metric.name()));
+        }
+        let mut aggregations = NodeAggregations::empty();
+        for doc_id in bitmap.range(0..self.values.len() as u32) {
+            aggregations.add_value(self.values[doc_id as usize]);
+        }
+        Ok(metric.of_matching(&aggregations))
+    }
+
+    // Sums come from two prefix sums, and counts from the range's length
+    fn metric_in_doc_range(&self, docs: std::ops::Range<u32>, metric: Metric) -> Result<Option<f64>, AitError> {
+        if !self.supports(metric) {
+            return Err(AitError::UnsupportedMetric(metric.name()));
+        }
+        let docs = docs.start.min(self.values.len() as u32)..docs.end.min(self.values.len() as u32);
+        let count = docs.end.saturating_sub(docs.start);
+        Ok(match metric {
+            Metric::Count => Some(count as f64),
+            _ => (count > 0).then(|| self.range_sum(docs)),
+        })
+    }
+}
+
+// Segment tree over docs in timestamp order, answering min/max/sum/count for
+// any time window in O(log n). Complements the value-ordered AIT for the many
+// log queries that are time-bounded rather than value-bounded.
+#[derive(Debug, Clone)]
+pub struct TimeSegmentTree {
+    // Milliseconds since the epoch, ascending
+    pub(crate) timestamps: Vec<i64>,
+    // Bottom-up layout: leaves at [n, 2n), node i aggregates nodes 2i and 2i + 1
+    pub(crate) nodes: Vec<NodeAggregations>,
+}
+
+impl TimeSegmentTree {
+    pub fn new(doc_timestamps: &[i64], doc_values: &[f64]) -> Self {
+        let mut pairs: Vec<(i64, f64)> = doc_timestamps.iter().copied().zip(doc_values.iter().copied()).collect();
+        pairs.sort_by_key(|&(timestamp, _)| timestamp);
+
+        let n = pairs.len();
+        let mut nodes = vec![NodeAggregations::empty(); 2 * n];
+        for (i, &(_, value)) in pairs.iter().enumerate() {
+            nodes[n + i].add_value(value);
+        }
+        for i in (1..n).rev() {
+            nodes[i] = nodes[2 * i] + nodes[2 * i + 1];
+        }
+
+        TimeSegmentTree {
+            timestamps: pairs.into_iter().map(|(timestamp, _)| timestamp).collect(),
+            nodes,
+        }
+    }
+
+    // Aggregate docs with timestamps in [from, to]
+    pub fn query_time_range(&self, from: i64, to: i64) -> NodeAggregations {
+        let n = self.timestamps.len();
+        let mut lo = self.timestamps.partition_point(|&timestamp| timestamp < from) + n;
+        let mut hi = self.timestamps.partition_point(|&timestamp| timestamp <= to) + n;
+
+        let mut result = NodeAggregations::empty();
+        while lo < hi {
+            if lo & 1 == 1 {
+                result += self.nodes[lo];
+                lo += 1;
+            }
+            if hi & 1 == 1 {
+                hi -= 1;
+                result += self.nodes[hi];
+            }
+            lo /= 2;
+            hi /= 2;
+        }
+        result
+    }
+
+    // A random window spanning `span` consecutive docs in time order
+    #[cfg(feature = "bench")]
+    pub(crate) fn pick_window(&self, span: usize, rng: &mut impl Rng) -> Option<(i64, i64)> {
+        if self.timestamps.is_empty() {
+            return None;
+        }
+        let span = span.clamp(1, self.timestamps.len());
+        let first = rng.gen_range(0..=self.timestamps.len() - span);
+        Some((self.timestamps[first], self.timestamps[first + span - 1]))
+    }
+}
+
+// Bit vector with constant-time rank: cumulative popcounts are kept per
+// 512-bit block and the remainder is counted within the block
+#[derive(Debug, Clone)]
+pub(crate) struct RankBitVec {
+    pub(crate) words: Vec<u64>,
+    // Ones before each block of BLOCK_WORDS words
+    pub(crate) block_ranks: Vec<u32>,
+}
+
+impl RankBitVec {
+    pub(crate) const BLOCK_WORDS: usize = 8;
+
+    pub(crate) fn new(bits: &[bool]) -> Self {
+        let mut words = vec![0u64; bits.len().div_ceil(64)];
+        for (i, _) in bits.iter().enumerate().filter(|(_, &bit)| bit) {
+            words[i / 64] |= 1 << (i % 64);
+        }
+
+        let mut block_ranks = Vec::with_capacity(words.len() / Self::BLOCK_WORDS + 1);
+        let mut ones = 0u32;
+        for block in words.chunks(Self::BLOCK_WORDS) {
+            block_ranks.push(ones);
+            ones += block.iter().map(|word| word.count_ones()).sum::<u32>();
+        }
+        block_ranks.push(ones);
+
+        RankBitVec { words, block_ranks }
+    }
+
+    // Number of ones in [0, i)
+    pub(crate) fn rank1(&self, i: usize) -> usize {
+        let word = i / 64;
+        let block = word / Self::BLOCK_WORDS;
+        let mut rank = self.block_ranks[block] as usize;
+        for &w in &self.words[block * Self::BLOCK_WORDS..word] {
+            rank += w.count_ones() as usize;
+        }
+        if !i.is_multiple_of(64) {
+            rank += (self.words[word] & ((1u64 << (i % 64)) - 1)).count_ones() as usize;
+        }
+        rank
+    }
+
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.words.capacity() * std::mem::size_of::<u64>() + self.block_ranks.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+// Experimental wavelet matrix over quantized values in doc order. Counts docs
+// with values in a range within a doc-id range in O(log sigma), which neither
+// the value-ordered AIT nor a plain column answers without a scan. Values are
+// bucketed into 2^BITS levels, so counts are exact only when distinct values
+// land in distinct buckets.
+#[derive(Debug, Clone)]
+pub struct WaveletMatrix {
+    // One bit vector per level, most significant bit first
+    pub(crate) levels: Vec<RankBitVec>,
+    // Number of zeros at each level
+    pub(crate) zeros: Vec<usize>,
+    pub(crate) len: usize,
+    pub(crate) min_value: f64,
+    pub(crate) step: f64,
+    // Whether every distinct value has a bucket of its own
+    pub(crate) exact: bool,
+    // The distinct values in order when exact, so query bounds falling
+    // inside a bucket can be placed on either side of its one value
+    pub(crate) distinct: Vec<f64>,
+}
+
+impl WaveletMatrix {
+    pub(crate) const BITS: u32 = 16;
+
+    pub fn new(values: &[f64]) -> Self {
+        let min_value = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max_value = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let buckets = (1u64 << Self::BITS) as f64;
+        let step = if values.is_empty() || max_value <= min_value {
+            1.0
+        } else {
+            (max_value - min_value) / (buckets - 1.0)
+        };
+
+        let mut matrix = WaveletMatrix {
+            levels: Vec::with_capacity(Self::BITS as usize),
+            zeros: Vec::with_capacity(Self::BITS as usize),
+            len: values.len(),
+            min_value,
+            step,
+            exact: true,
+            distinct: Vec::new(),
+        };
+        let mut sorted = values.to_vec();
+        sorted.sort_unstable_by(f64::total_cmp);
+        sorted.dedup();
+        matrix.exact = sorted.windows(2).all(|pair| matrix.quantize(pair[0]) != matrix.quantize(pair[1]));
+        if matrix.exact {
+            matrix.distinct = sorted;
+        }
+
+        // Each level stably partitions the symbols by its bit, zeros first
+        let mut symbols: Vec<u32> = values.iter().map(|&value| matrix.quantize(value)).collect();
+        for level in 0..Self::BITS {
+            let shift = Self::BITS - 1 - level;
+            let bits: Vec<bool> = symbols.iter().map(|&symbol| (symbol >> shift) & 1 == 1).collect();
+            let (zeros, ones): (Vec<u32>, Vec<u32>) = symbols.iter().partition(|&&symbol| (symbol >> shift) & 1 == 0);
+            matrix.zeros.push(zeros.len());
+            matrix.levels.push(RankBitVec::new(&bits));
+            symbols = zeros;
+            symbols.extend(ones);
+        }
+        matrix
+    }
+
+    pub(crate) fn quantize(&self, value: f64) -> u32 {
+        let max_symbol = (1u64 << Self::BITS) - 1;
+        ((value - self.min_value) / self.step).floor().clamp(0.0, max_symbol as f64) as u32
+    }
+
+    // Docs in `docs` whose symbol is below `symbol`
+    pub(crate) fn count_less(&self, docs: std::ops::Range<usize>, symbol: u64) -> usize {
+        if symbol >= 1 << Self::BITS {
+            return docs.len();
+        }
+
+        let (mut start, mut end) = (docs.start, docs.end);
+        let mut count = 0;
+        for (level, (bits, &zeros)) in self.levels.iter().zip(&self.zeros).enumerate() {
+            let (start_ones, end_ones) = (bits.rank1(start), bits.rank1(end));
+            if (symbol >> (Self::BITS as usize - 1 - level)) & 1 == 1 {
+                // Everything taking the zero branch here is smaller
+                count += (end - start) - (end_ones - start_ones);
+                start = zeros + start_ones;
+                end = zeros + end_ones;
+            } else {
+                start -= start_ones;
+                end -= end_ones;
+            }
+        }
+        count
+    }
+
+    // Docs with ids in `docs` and values in [min, max]
+    pub fn count_value_range_in_docs(&self, min: f64, max: f64, docs: std::ops::Range<u32>) -> usize {
+        let docs = (docs.start as usize).min(self.len)..(docs.end as usize).min(self.len);
+        if min > max || docs.is_empty() || max < self.min_value {
+            return 0;
+        }
+        let (lo, hi) = if self.exact {
+            let first = self.distinct.partition_point(|&value| value < min);
+            let end = self.distinct.partition_point(|&value| value <= max);
+            if first >= end {
+                return 0;
+            }
+            (self.quantize(self.distinct[first]) as u64, self.quantize(self.distinct[end - 1]) as u64 + 1)
+        } else {
+            // Whole buckets: values sharing a bucket with a bound are counted
+            let lo = if min <= self.min_value { 0 } else { self.quantize(min) as u64 };
+            (lo, self.quantize(max) as u64 + 1)
+        };
+        self.count_less(docs.clone(), hi) - self.count_less(docs, lo)
+    }
+
+    pub fn dynamic_usage(&self) -> usize {
+        self.levels.iter().map(RankBitVec::dynamic_usage).sum::<usize>()
+            + self.zeros.capacity() * std::mem::size_of::<usize>()
+            + self.distinct.capacity() * std::mem::size_of::<f64>()
+    }
+}
+
+// Relative weights of the query kinds a field is expected to serve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldQueryMix {
+    pub global: u64,
+    pub filtered: u64,
+    pub range: u64,
+}
+
+impl FieldQueryMix {
+    pub(crate) fn range_share(&self) -> f64 {
+        let total = self.global + self.filtered + self.range;
+        if total == 0 {
+            0.0
+        } else {
+            self.range as f64 / total as f64
+        }
+    }
+}
+
+impl std::str::FromStr for FieldQueryMix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|weight| weight.trim().parse::<u64>().map_err(|err| format!("invalid weight {:?}: {}", weight, err)))
+            .collect::<Result<Vec<_>, _>>()?;
+        match weights[..] {
+            [global, filtered, range] => Ok(FieldQueryMix { global, filtered, range }),
+            _ => Err(format!("expected global,filtered,range weights, got {:?}", s)),
+        }
+    }
+}
+
+// How a field in a FieldIndexSet is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldRepresentation {
+    Tree,
+    ZoneMap,
+}
+
+impl FieldRepresentation {
+    // Fields with fewer rows are cheap to scan and never get a tree
+    pub(crate) const TREE_MIN_ROWS: usize = 100_000;
+    // Share of range queries from which the tree's memory overhead pays off
+    pub(crate) const TREE_MIN_RANGE_SHARE: f64 = 0.1;
+
+    pub fn choose(mix: &FieldQueryMix, rows: usize) -> Self {
+        if rows >= Self::TREE_MIN_ROWS && mix.range_share() >= Self::TREE_MIN_RANGE_SHARE {
+            FieldRepresentation::Tree
+        } else {
+            FieldRepresentation::ZoneMap
+        }
+    }
+}
+
+// A field indexed with whichever representation suits its workload
+pub enum FieldIndex {
+    Tree(Box<AggregationIndexTree>),
+    ZoneMap(ZoneMapColumn),
+}
+
+impl FieldIndex {
+    pub fn representation(&self) -> FieldRepresentation {
+        match self {
+            FieldIndex::Tree(_) => FieldRepresentation::Tree,
+            FieldIndex::ZoneMap(_) => FieldRepresentation::ZoneMap,
+        }
+    }
+
+    pub fn global(&self) -> NodeAggregations {
+        match self {
+            FieldIndex::Tree(ait) => ait.get_global_aggregations(),
+            FieldIndex::ZoneMap(column) => column.get_global_aggregations(),
+        }
+    }
+
+    pub fn filtered(&self, bitmap: &RoaringBitmap) -> Result<NodeAggregations, AitError> {
+        match self {
+            FieldIndex::Tree(ait) => ait.query_with_bitmap(bitmap),
+            FieldIndex::ZoneMap(column) => Ok(column.query_with_bitmap(bitmap)),
+        }
+    }
+
+    pub fn range(&self, min: f64, max: f64) -> NodeAggregations {
+        match self {
+            FieldIndex::Tree(ait) => ait.query_value_range(min, max),
+            FieldIndex::ZoneMap(column) => column.query_value_range(min, max),
+        }
+    }
+
+    // Docs with values in [min, max]: exact for a tree, whose positions give
+    // the count, and estimated from the column's histogram for a zone map
+    pub fn estimate_range_count(&self, min: f64, max: f64) -> CountEstimate {
+        match self {
+            FieldIndex::Tree(ait) => CountEstimate {
+                docs: ait.query_metric_value_range(min, max, Metric::Count).unwrap_or(0.0) as u64,
+                exact: true,
+            },
+            FieldIndex::ZoneMap(column) => CountEstimate {
+                docs: column.estimate_value_range_count(min, max),
+                exact: false,
+            },
+        }
+    }
+
+    pub fn value_of(&self, doc_id: u32) -> Result<Option<f64>, AitError> {
+        match self {
+            FieldIndex::Tree(ait) => {
+                if !ait.has_doc_ids {
+                    return Err(AitError::DocIdsNotStored);
+                }
+                Ok(ait.doc_id_map.get(&doc_id).map(|&pos| ait.get_value_at_position(pos)))
+            }
+            FieldIndex::ZoneMap(column) => Ok(column.values.get(doc_id as usize).copied()),
+        }
+    }
+
+    pub fn range_doc_ids(&self, min: f64, max: f64) -> Result<RoaringBitmap, AitError> {
+        match self {
+            FieldIndex::Tree(ait) => ait.doc_ids_in_value_range(min, max),
+            FieldIndex::ZoneMap(column) => Ok(column.doc_ids_in_value_range(min, max)),
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            FieldIndex::Tree(ait) => ait.dynamic_usage(),
+            FieldIndex::ZoneMap(column) => column.dynamic_usage(),
+        }
+    }
+}
+
+// Per-field indexes, each field choosing between a full AIT and a zone-mapped
+// column so rarely range-queried fields don't pay the tree's memory overhead
+#[derive(Default)]
+pub struct FieldIndexSet {
+    pub(crate) fields: HashMap<String, FieldIndex>,
+    // Categorical fields, usable in term predicates
+    pub(crate) terms: HashMap<String, TermColumn>,
+}
+
+impl FieldIndexSet {
+    // Index a field given its values in doc-id order and its expected query mix
+    pub fn add_field(&mut self, name: &str, doc_values: &[f64], mix: &FieldQueryMix, options: &BuildOptions)
+        -> Result<(), AitError> {
+        let index = match FieldRepresentation::choose(mix, doc_values.len()) {
+            FieldRepresentation::Tree => {
+                let mut values: Vec<(u32, f64)> = doc_values
+                    .iter()
+                    .enumerate()
+                    .map(|(doc_id, &value)| (doc_id as u32, value))
+                    .collect();
+                values.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+                FieldIndex::Tree(Box::new(
+                    build_aggregation_index_tree(values, options)?.with_provenance("field index set", name),
+                ))
+            }
+            FieldRepresentation::ZoneMap => FieldIndex::ZoneMap(ZoneMapColumn::new(doc_values.to_vec())),
+        };
+        self.fields.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    pub fn add_terms(&mut self, name: &str, column: TermColumn) {
+        self.terms.insert(name.to_string(), column);
+    }
+
+    pub fn field(&self, name: &str) -> Option<&FieldIndex> {
+        self.fields.get(name)
+    }
+
+    pub fn term_field(&self, name: &str) -> Result<&TermColumn, String> {
+        self.terms.get(name).ok_or_else(|| format!("unknown term field {:?}", name))
+    }
+}
+
+// A filter on one field of a FieldIndexSet
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Range { field: String, min: f64, max: f64 },
+    Term { field: String, term: String },
+}
+
+impl std::fmt::Display for Predicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Predicate::Range { field, min, max } => write!(f, "{} in [{}, {}]", field, min, max),
+            Predicate::Term { field, term } => write!(f, "{} = {:?}", field, term),
+        }
+    }
+}
+
+// How one predicate of a conjunction was applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepMethod {
+    // Materialize the predicate's docs and intersect them into the result
+    Intersect,
+    // Check the predicate on each remaining candidate instead
+    Probe,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlanStep {
+    pub predicate: Predicate,
+    pub estimate: CountEstimate,
+    // Method and docs remaining after the step, once executed
+    pub executed: Option<(StepMethod, u64)>,
+}
+
+// Evaluation order of a conjunction: most selective predicate first, so the
+// candidate set shrinks before the more expensive predicates run
+#[derive(Debug, Clone)]
+pub struct ConjunctionPlan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl std::fmt::Display for ConjunctionPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "conjunction of {} predicates, most selective first:", self.steps.len())?;
+        for (i, step) in self.steps.iter().enumerate() {
+            write!(f, "\n    {}. {}: {}", i + 1, step.predicate, step.estimate)?;
+            match step.executed {
+                Some((StepMethod::Intersect, remaining)) => write!(f, ", intersect -> {}", format_count(remaining))?,
+                Some((StepMethod::Probe, remaining)) => write!(f, ", probe -> {}", format_count(remaining))?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Docs a predicate is expected to match, computed without running it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountEstimate {
+    pub docs: u64,
+    // Whether `docs` is the true count rather than an approximation
+    pub exact: bool,
+}
+
+impl std::fmt::Display for CountEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.exact {
+            write!(f, "{} docs", format_count(self.docs))
+        } else {
+            write!(f, "~{} docs", format_approx_count(self.docs))
+        }
+    }
+}
+
+impl FieldIndexSet {
+    // Docs a predicate matches, for planning, admission control and previews.
+    // Trees count the positions between the range bounds and term fields read
+    // their dictionary counts, both exact; zone maps interpolate within their
+    // equi-depth histogram buckets. Nothing is materialized.
+    pub fn estimate_count(&self, predicate: &Predicate) -> Result<CountEstimate, String> {
+        match predicate {
+            Predicate::Range { field, min, max } => {
+                let index = self.field(field).ok_or_else(|| format!("unknown field {:?}", field))?;
+                Ok(index.estimate_range_count(*min, *max))
+            }
+            Predicate::Term { field, term } => {
+                Ok(CountEstimate { docs: self.term_field(field)?.term_count(term), exact: true })
+            }
+        }
+    }
+
+    // Order the predicates by estimated matches, ascending
+    pub fn plan_conjunction(&self, predicates: &[Predicate]) -> Result<ConjunctionPlan, String> {
+        let mut steps = predicates
+            .iter()
+            .map(|predicate| {
+                Ok(PlanStep {
+                    predicate: predicate.clone(),
+                    estimate: self.estimate_count(predicate)?,
+                    executed: None,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        steps.sort_by_key(|step| step.estimate.docs);
+        Ok(ConjunctionPlan { steps })
+    }
+
+    // Docs matching every predicate, with the executed plan. Once checking
+    // each candidate costs less than materializing the predicate's estimated
+    // matches, the remaining candidates are probed instead.
+    pub fn query_conjunction(&self, predicates: &[Predicate]) -> Result<(RoaringBitmap, ConjunctionPlan), String> {
+        let mut plan = self.plan_conjunction(predicates)?;
+        let mut matched: Option<RoaringBitmap> = None;
+        for step in &mut plan.steps {
+            let (method, docs) = match matched {
+                Some(candidates) if candidates.len() * self.probe_cost(&step.predicate) < step.estimate.docs => {
+                    (StepMethod::Probe, self.probe(&step.predicate, &candidates)?)
+                }
+                Some(mut candidates) => {
+                    candidates &= self.predicate_doc_ids(&step.predicate)?;
+                    (StepMethod::Intersect, candidates)
+                }
+                None => (StepMethod::Intersect, self.predicate_doc_ids(&step.predicate)?),
+            };
+            step.executed = Some((method, docs.len()));
+            matched = Some(docs);
+            if matched.as_ref().is_some_and(RoaringBitmap::is_empty) {
+                break;
+            }
+        }
+        let matched = matched.ok_or("a conjunction needs at least one predicate")?;
+        Ok((matched, plan))
+    }
+
+    // Docs matching every (field, min, max) range predicate
+    pub fn query_multi_range(&self, predicates: &[(&str, f64, f64)]) -> Result<RoaringBitmap, String> {
+        let predicates: Vec<Predicate> = predicates
+            .iter()
+            .map(|&(field, min, max)| Predicate::Range { field: field.to_string(), min, max })
+            .collect();
+        Ok(self.query_conjunction(&predicates)?.0)
+    }
+
+    pub(crate) fn predicate_doc_ids(&self, predicate: &Predicate) -> Result<RoaringBitmap, String> {
+        match predicate {
+            Predicate::Range { field, min, max } => {
+                let index = self.field(field).ok_or_else(|| format!("unknown field {:?}", field))?;
+                index.range_doc_ids(*min, *max).map_err(|err| err.to_string())
+            }
+            Predicate::Term { field, term } => Ok(self.term_field(field)?.doc_ids_with(term)),
+        }
+    }
+
+    // Cost of checking one doc against a predicate, relative to materializing
+    // one matching doc. A tree resolves the doc through its doc-id map and
+    // position lookup, which measures about 16 times a sequential leaf copy;
+    // columns are indexed by doc id directly.
+    pub(crate) fn probe_cost(&self, predicate: &Predicate) -> u64 {
+        match predicate {
+            Predicate::Range { field, .. } => match self.field(field) {
+                Some(FieldIndex::Tree(_)) => 16,
+                _ => 1,
+            },
+            Predicate::Term { .. } => 1,
+        }
+    }
+
+    // The candidates that match a predicate, checked one doc at a time
+    pub(crate) fn probe(&self, predicate: &Predicate, candidates: &RoaringBitmap) -> Result<RoaringBitmap, String> {
+        let mut matched = RoaringBitmap::new();
+        match predicate {
+            Predicate::Range { field, min, max } => {
+                let index = self.field(field).ok_or_else(|| format!("unknown field {:?}", field))?;
+                for doc_id in candidates {
+                    let value = index.value_of(doc_id).map_err(|err| err.to_string())?;
+                    if value.is_some_and(|value| value >= *min && value <= *max) {
+                        matched.push(doc_id);
+                    }
+                }
+            }
+            Predicate::Term { field, term } => {
+                let column = self.term_field(field)?;
+                if let Some(code) = column.term_code(term) {
+                    matched.extend(candidates.iter().filter(|&doc_id| column.code(doc_id) == Some(code)));
+                }
+            }
+        }
+        Ok(matched)
+    }
+}
+
+impl FieldIndexSet {
+    // Evaluate derived metrics over all docs, or the docs a bitmap selects.
+    // Each referenced field is aggregated once however many metrics use it.
+    pub fn evaluate(&self, metrics: &[DerivedMetric], bitmap: Option<&RoaringBitmap>) -> Result<Vec<Option<f64>>, String> {
+        let cache: RefCell<HashMap<String, NodeAggregations>> = RefCell::new(HashMap::new());
+        let aggregate = |name: Option<&str>| -> Result<NodeAggregations, String> {
+            // Every field holds every doc, so any field answers the doc count
+            let Some(name) = name.or_else(|| self.fields.keys().min().map(String::as_str)) else {
+                return Ok(NodeAggregations::empty());
+            };
+            if let Some(&aggregations) = cache.borrow().get(name) {
+                return Ok(aggregations);
+            }
+            let index = self.field(name).ok_or_else(|| format!("unknown field {:?}", name))?;
+            let aggregations = match bitmap {
+                Some(bitmap) => index.filtered(bitmap).map_err(|err| err.to_string())?,
+                None => index.global(),
+            };
+            cache.borrow_mut().insert(name.to_string(), aggregations);
+            Ok(aggregations)
+        };
+        metrics.iter().map(|metric| metric.expr.eval(&aggregate)).collect()
+    }
+}
+
+// Arithmetic over query aggregates, evaluated after the aggregation so clients
+// receive final metrics
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum DerivedExpr {
+    Number(f64),
+    // Number of docs the query matched
+    DocCount,
+    Aggregate(Metric, String),
+    Avg(String),
+    Neg(Box<DerivedExpr>),
+    Binary(Box<DerivedExpr>, char, Box<DerivedExpr>),
+}
+
+impl DerivedExpr {
+    // None where the result is undefined: division by zero, or min, max or avg
+    // over no docs. `aggregate` resolves a field's aggregations, or those of
+    // any field for None.
+    pub(crate) fn eval(&self, aggregate: &dyn Fn(Option<&str>) -> Result<NodeAggregations, String>)
+        -> Result<Option<f64>, String> {
+        Ok(match self {
+            DerivedExpr::Number(value) => Some(*value),
+            DerivedExpr::DocCount => Some(aggregate(None)?.count as f64),
+            DerivedExpr::Aggregate(metric, field) => {
+                let aggregations = aggregate(Some(field))?;
+                let defined = aggregations.count > 0 || matches!(metric, Metric::Sum | Metric::Count);
+                defined.then(|| metric.of(&aggregations))
+            }
+            DerivedExpr::Avg(field) => {
+                aggregate(Some(field))?.avg()
+            }
+            DerivedExpr::Neg(expr) => expr.eval(aggregate)?.map(|value| -value),
+            DerivedExpr::Binary(left, op, right) => {
+                let (Some(left), Some(right)) = (left.eval(aggregate)?, right.eval(aggregate)?) else {
+                    return Ok(None);
+                };
+                match op {
+                    '+' => Some(left + right),
+                    '-' => Some(left - right),
+                    '*' => Some(left * right),
+                    _ => (right != 0.0).then(|| left / right),
+                }
+            }
+        })
+    }
+}
+
+// A derived metric as written on the command line, with its parsed expression
+#[derive(Debug, Clone)]
+pub struct DerivedMetric {
+    pub(crate) text: String,
+    pub(crate) expr: DerivedExpr,
+}
+
+impl DerivedMetric {
+    // The expression as written, trimmed
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl std::str::FromStr for DerivedMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = ExprParser { tokens: tokenize_expr(s)?, pos: 0 };
+        let expr = parser.expr()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {} in {:?}", token, s));
+        }
+        Ok(DerivedMetric { text: s.trim().to_string(), expr })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExprToken {
+    Number(f64),
+    Ident(String),
+    Symbol(char),
+}
+
+impl std::fmt::Display for ExprToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExprToken::Number(value) => write!(f, "{}", value),
+            ExprToken::Ident(name) => write!(f, "{:?}", name),
+            ExprToken::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+pub(crate) fn tokenize_expr(s: &str) -> Result<Vec<ExprToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if "+-*/()".contains(c) {
+            tokens.push(ExprToken::Symbol(c));
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek().filter(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let number = &s[start..end];
+            tokens.push(ExprToken::Number(number.parse().map_err(|_| format!("invalid number {:?}", number))?));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek().filter(|(_, c)| c.is_alphanumeric() || *c == '_' || *c == '.') {
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(ExprToken::Ident(s[start..end].to_string()));
+        } else {
+            return Err(format!("unexpected character {:?} in {:?}", c, s));
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent over: expr = term (('+' | '-') term)*,
+// term = factor (('*' | '/') factor)*, and factor = number | '-' factor |
+// '(' expr ')' | count | name '(' field ')'
+pub(crate) struct ExprParser {
+    pub(crate) tokens: Vec<ExprToken>,
+    pub(crate) pos: usize,
+}
+
+impl ExprParser {
+    pub(crate) fn next(&mut self) -> Option<ExprToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    pub(crate) fn eat(&mut self, symbol: char) -> bool {
+        let found = self.tokens.get(self.pos) == Some(&ExprToken::Symbol(symbol));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    pub(crate) fn expect(&mut self, symbol: char) -> Result<(), String> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", symbol))
+        }
+    }
+
+    pub(crate) fn binary(&mut self, ops: &str, operand: fn(&mut Self) -> Result<DerivedExpr, String>)
+        -> Result<DerivedExpr, String> {
+        let mut expr = operand(self)?;
+        while let Some(op) = ops.chars().find(|&op| self.eat(op)) {
+            expr = DerivedExpr::Binary(Box::new(expr), op, Box::new(operand(self)?));
+        }
+        Ok(expr)
+    }
+
+    pub(crate) fn expr(&mut self) -> Result<DerivedExpr, String> {
+        self.binary("+-", |parser| parser.binary("*/", Self::factor))
+    }
+
+    pub(crate) fn factor(&mut self) -> Result<DerivedExpr, String> {
+        match self.next() {
+            Some(ExprToken::Number(value)) => Ok(DerivedExpr::Number(value)),
+            Some(ExprToken::Symbol('-')) => Ok(DerivedExpr::Neg(Box::new(self.factor()?))),
+            Some(ExprToken::Symbol('(')) => {
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(ExprToken::Ident(name)) if !self.eat('(') => match name.as_str() {
+                "count" => Ok(DerivedExpr::DocCount),
+                _ => Err(format!("expected '(' after {:?}", name)),
+            },
+            Some(ExprToken::Ident(name)) => {
+                let field = match self.next() {
+                    Some(ExprToken::Ident(field)) => field,
+                    Some(ExprToken::Symbol(')')) if name == "count" => return Ok(DerivedExpr::DocCount),
+                    _ => return Err(format!("expected a field name in {}()", name)),
+                };
+                self.expect(')')?;
+                match name.as_str() {
+                    "avg" => Ok(DerivedExpr::Avg(field)),
+                    _ => Metric::ALL
+                        .into_iter()
+                        .find(|metric| metric.name() == name)
+                        .map(|metric| DerivedExpr::Aggregate(metric, field))
+                        .ok_or_else(|| format!("unknown aggregate {:?}", name)),
+                }
+            }
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+// Aggregate the values addressed by a selection vector, using AVX2 gathers when available.
+// A selection with an index past the end of `values` takes the checked scalar path,
+// which panics on it.
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) fn aggregate_selection(values: &[f64], selection: &[u32]) -> NodeAggregations {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && values.len() <= i32::MAX as usize
+            && selection.iter().all(|&doc_id| (doc_id as usize) < values.len()) {
+            // SAFETY: AVX2 support was checked above, and every index was just checked to be
+            // in bounds for `values`, whose length fits in i32
+            return unsafe { aggregate_selection_avx2(values, selection) };
+        }
+    }
+    aggregate_selection_scalar(values, selection)
+}
+
+// Portable fallback: four independent accumulator lanes so the compiler can vectorize
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) fn aggregate_selection_scalar(values: &[f64], selection: &[u32]) -> NodeAggregations {
+    let mut mins = [f64::MAX; 4];
+    let mut maxs = [f64::MIN; 4];
+    let mut sums = [0.0; 4];
+    let mut squares = [0.0; 4];
+
+    let chunks = selection.chunks_exact(4);
+    let remainder = chunks.remainder();
+    for chunk in chunks {
+        for (lane, &doc_id) in chunk.iter().enumerate() {
+            let value = values[doc_id as usize];
+            mins[lane] = mins[lane].min(value);
+            maxs[lane] = maxs[lane].max(value);
+            sums[lane] += value;
+            squares[lane] += value * value;
+        }
+    }
+
+    let mut result = NodeAggregations {
+        min_value: mins.iter().fold(f64::MAX, |a, &b| a.min(b)),
+        max_value: maxs.iter().fold(f64::MIN, |a, &b| a.max(b)),
+        sum: sums.iter().sum(),
+        count: (selection.len() - remainder.len()) as u32,
+        sum_squares: squares.iter().sum(),
+    };
+    for &doc_id in remainder {
+        result.add_value(values[doc_id as usize]);
+    }
+    result
+}
+
+// # Safety
+// The CPU must support AVX2, `values.len()` must fit in i32, and every index in
+// `selection` must be in bounds for `values`.
+#[cfg(target_arch = "x86_64")]
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+#[target_feature(enable = "avx2")]
+pub(crate) unsafe fn aggregate_selection_avx2(values: &[f64], selection: &[u32]) -> NodeAggregations {
+    use std::arch::x86_64::*;
+
+    let mut min_v = _mm256_set1_pd(f64::MAX);
+    let mut max_v = _mm256_set1_pd(f64::MIN);
+    let mut sum_v = _mm256_setzero_pd();
+    let mut squares_v = _mm256_setzero_pd();
+
+    let chunks = selection.chunks_exact(4);
+    let remainder = chunks.remainder();
+    for chunk in chunks {
+        // Gather four values addressed by 32-bit doc ids (scale = 8 bytes per f64)
+        let indices = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+        let gathered = _mm256_i32gather_pd::<8>(values.as_ptr(), indices);
+        min_v = _mm256_min_pd(min_v, gathered);
+        max_v = _mm256_max_pd(max_v, gathered);
+        sum_v = _mm256_add_pd(sum_v, gathered);
+        squares_v = _mm256_add_pd(squares_v, _mm256_mul_pd(gathered, gathered));
+    }
+
+    let mut mins = [0.0; 4];
+    let mut maxs = [0.0; 4];
+    let mut sums = [0.0; 4];
+    let mut squares = [0.0; 4];
+    _mm256_storeu_pd(mins.as_mut_ptr(), min_v);
+    _mm256_storeu_pd(maxs.as_mut_ptr(), max_v);
+    _mm256_storeu_pd(sums.as_mut_ptr(), sum_v);
+    _mm256_storeu_pd(squares.as_mut_ptr(), squares_v);
+
+    let mut result = NodeAggregations {
+        min_value: mins.iter().fold(f64::MAX, |a, &b| a.min(b)),
+        max_value: maxs.iter().fold(f64::MIN, |a, &b| a.max(b)),
+        sum: sums.iter().sum(),
+        count: (selection.len() - remainder.len()) as u32,
+        sum_squares: squares.iter().sum(),
+    };
+    for &doc_id in remainder {
+        result.add_value(*values.get_unchecked(doc_id as usize));
+    }
+    result
+}
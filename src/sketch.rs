@@ -0,0 +1,298 @@
+// Mergeable summaries of value distributions, attached to tree nodes so
+// approximate statistics over many nodes combine summaries instead of values
+
+use serde::{Deserialize, Serialize};
+
+// Compression of node digests: about this many centroids at most
+pub(crate) const DIGEST_COMPRESSION: f64 = 100.0;
+// Smallest node given a digest. Below this its values take little more room
+// than the centroids would, and are merged directly instead.
+pub(crate) const MIN_DIGEST_WEIGHT: f64 = 16.0 * DIGEST_COMPRESSION;
+
+// A weighted point of a t-digest: the mean of the values merged into it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Centroid {
+    pub(crate) mean: f64,
+    pub(crate) weight: f64,
+}
+
+// Merging t-digest (Dunning): centroids in ascending order of mean, small
+// near both tails and large in the middle, so extreme quantiles stay
+// accurate. Digests over adjacent value ranges merge by concatenating their
+// centroids and compressing again.
+#[derive(Debug, Clone)]
+pub(crate) struct TDigest {
+    pub(crate) compression: f64,
+    pub(crate) centroids: Vec<Centroid>,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+}
+
+impl TDigest {
+    // From centroids already in ascending order of mean, within [min, max]
+    pub(crate) fn from_sorted(compression: f64, centroids: Vec<Centroid>, min: f64, max: f64) -> Self {
+        let mut digest = TDigest { compression, centroids, min, max };
+        digest.compress();
+        digest
+    }
+
+    pub(crate) fn weight(&self) -> f64 {
+        self.centroids.iter().map(|centroid| centroid.weight).sum()
+    }
+
+    // Scale function k1: centroids may span one unit of k, which is narrow in
+    // q near 0 and 1
+    fn k(&self, q: f64) -> f64 {
+        self.compression / (2.0 * std::f64::consts::PI) * (2.0 * q.clamp(0.0, 1.0) - 1.0).asin()
+    }
+
+    fn k_inverse(&self, k: f64) -> f64 {
+        let angle = (2.0 * std::f64::consts::PI * k / self.compression).clamp(-std::f64::consts::FRAC_PI_2,
+                                                                               std::f64::consts::FRAC_PI_2);
+        (angle.sin() + 1.0) / 2.0
+    }
+
+    // Merge neighbouring centroids while each stays within one unit of k
+    pub(crate) fn compress(&mut self) {
+        let total = self.weight();
+        if self.centroids.len() < 2 || total == 0.0 {
+            return;
+        }
+        let mut merged = Vec::with_capacity(self.compression as usize);
+        let mut current = self.centroids[0];
+        let mut weight_before = 0.0;
+        let mut limit = total * self.k_inverse(self.k(0.0) + 1.0);
+        for &centroid in &self.centroids[1..] {
+            if weight_before + current.weight + centroid.weight <= limit {
+                let weight = current.weight + centroid.weight;
+                current.mean += (centroid.mean - current.mean) * centroid.weight / weight;
+                current.weight = weight;
+            } else {
+                weight_before += current.weight;
+                merged.push(current);
+                limit = total * self.k_inverse(self.k(weight_before / total) + 1.0);
+                current = centroid;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    // Approximate value at quantile `q` in [0, 1], interpolating between
+    // centroid midpoints and towards the exact min and max at the ends. None
+    // for an empty digest.
+    pub(crate) fn quantile(&self, q: f64) -> Option<f64> {
+        let first = self.centroids.first()?;
+        if self.centroids.len() == 1 {
+            return Some(first.mean);
+        }
+        let target = q.clamp(0.0, 1.0) * self.weight();
+        let (mut previous_mean, mut previous_mid) = (self.min, 0.0);
+        let mut cumulative = 0.0;
+        for centroid in &self.centroids {
+            let mid = cumulative + centroid.weight / 2.0;
+            if target < mid {
+                let fraction = (target - previous_mid) / (mid - previous_mid);
+                return Some(previous_mean + (centroid.mean - previous_mean) * fraction);
+            }
+            previous_mean = centroid.mean;
+            previous_mid = mid;
+            cumulative += centroid.weight;
+        }
+        let fraction = ((target - previous_mid) / (cumulative - previous_mid)).clamp(0.0, 1.0);
+        Some(previous_mean + (self.max - previous_mean) * fraction)
+    }
+
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.centroids.capacity() * std::mem::size_of::<Centroid>()
+    }
+}
+
+// Mantissa bits kept in a histogram bucket key: each power of two splits into
+// 128 buckets, so a bucket's width is under 0.8% of the values in it
+const HISTOGRAM_SUB_BUCKET_BITS: u32 = 7;
+const HISTOGRAM_SHIFT: u32 = 52 - HISTOGRAM_SUB_BUCKET_BITS;
+// Smallest node given a histogram. Below this its values are added directly.
+pub(crate) const MIN_HISTOGRAM_COUNT: u32 = 1024;
+
+// Log-bucketed histogram in the manner of HDR histograms, for latency-style
+// fields where relative precision matters at every magnitude. A bucket key is
+// the top bits of a value's float representation, which order like the values
+// themselves; negative values mirror positive ones below zero. Buckets are
+// sparse and in ascending order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogHistogram {
+    pub(crate) buckets: Vec<(i32, u32)>,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+}
+
+// One non-empty bucket: `count` values in [lower, upper)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LogBucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u32,
+}
+
+impl Default for LogHistogram {
+    fn default() -> Self {
+        LogHistogram::new()
+    }
+}
+
+impl LogHistogram {
+    pub(crate) fn new() -> Self {
+        LogHistogram { buckets: Vec::new(), min: f64::MAX, max: f64::MIN }
+    }
+
+    // From values in ascending order
+    pub(crate) fn from_sorted(values: &[f64]) -> Self {
+        let mut histogram = LogHistogram::new();
+        histogram.extend_sorted(values);
+        histogram
+    }
+
+    // Add values in ascending order, none below the values already added
+    pub(crate) fn extend_sorted(&mut self, values: &[f64]) {
+        for &value in values {
+            let key = bucket_key(value);
+            match self.buckets.last_mut() {
+                Some((last, count)) if *last == key => *count += 1,
+                _ => self.buckets.push((key, 1)),
+            }
+        }
+        if let (Some(&min), Some(&max)) = (values.first(), values.last()) {
+            self.min = self.min.min(min);
+            self.max = self.max.max(max);
+        }
+    }
+
+    // Add the buckets of a histogram over values none below ours. Tree nodes
+    // and position runs come in value order, so histograms only ever append.
+    pub(crate) fn append(&mut self, other: &LogHistogram) {
+        let Some((&(first, first_count), rest)) = other.buckets.split_first() else {
+            return;
+        };
+        debug_assert!(self.buckets.last().is_none_or(|&(last, _)| last <= first));
+        match self.buckets.last_mut() {
+            Some((last, count)) if *last == first => *count += first_count,
+            _ => self.buckets.push((first, first_count)),
+        }
+        self.buckets.extend_from_slice(rest);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    // Number of values added
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|&(_, count)| count as u64).sum()
+    }
+
+    // Value at percentile `p` (0-100): the midpoint of the bucket holding the
+    // value of rank ceil(p / 100 * count), within the exact min and max. None
+    // when empty.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((p.clamp(0.0, 100.0) / 100.0 * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for &(key, count) in &self.buckets {
+            seen += count as u64;
+            if seen >= rank {
+                let (lower, upper) = bucket_bounds(key);
+                return Some((lower + (upper - lower) / 2.0).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    // Non-empty buckets in ascending order
+    pub fn buckets(&self) -> Vec<LogBucket> {
+        self.buckets
+            .iter()
+            .map(|&(key, count)| {
+                let (lower, upper) = bucket_bounds(key);
+                LogBucket { lower, upper, count }
+            })
+            .collect()
+    }
+
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.buckets.capacity() * std::mem::size_of::<(i32, u32)>()
+    }
+}
+
+fn bucket_key(value: f64) -> i32 {
+    let magnitude = (value.abs().to_bits() >> HISTOGRAM_SHIFT) as i32;
+    if value < 0.0 { -magnitude - 1 } else { magnitude }
+}
+
+// Range [lower, upper) of the values with a bucket key
+fn bucket_bounds(key: i32) -> (f64, f64) {
+    let magnitude = if key < 0 { -(key + 1) } else { key } as u64;
+    let (low, high) = (f64::from_bits(magnitude << HISTOGRAM_SHIFT), f64::from_bits((magnitude + 1) << HISTOGRAM_SHIFT));
+    if key < 0 { (-high, -low) } else { (low, high) }
+}
+
+// Register index bits of distinct-count sketches: 4096 one-byte registers,
+// for a standard error of about 1.6%
+const HLL_PRECISION: u32 = 12;
+// Smallest node given a distinct-count sketch. Below this its ids are hashed
+// directly, which is cheaper than merging 4 KiB of registers.
+pub(crate) const MIN_DISTINCT_SKETCH_COUNT: u32 = 4096;
+
+// HyperLogLog distinct-count sketch over 64-bit ids. Sketches of any two sets
+// merge into the sketch of their union by taking register maxima.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HyperLogLog {
+    pub(crate) registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub(crate) fn new() -> Self {
+        HyperLogLog { registers: vec![0; 1 << HLL_PRECISION] }
+    }
+
+    pub(crate) fn add(&mut self, id: u64) {
+        let hash = mix64(id);
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+        // Leading zeros of the remaining bits, plus one; the sentinel bit caps it
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    pub(crate) fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(other);
+        }
+    }
+
+    // Estimated distinct ids added, with linear counting while registers are
+    // still empty. 64-bit hashes need no large-range correction.
+    pub(crate) fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let harmonic: f64 = self.registers.iter().map(|&rank| (-(rank as f64)).exp2()).sum();
+        let raw = alpha * m * m / harmonic;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.registers.capacity()
+    }
+}
+
+// SplitMix64 finalizer: spreads sequential ids over all 64 bits
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
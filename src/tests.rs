@@ -0,0 +1,2943 @@
+use crate::{bench::*, filter::*, ingest::*, persist::*, query::*, sketch::*, tree::*};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use roaring::RoaringBitmap;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const DOCS: u32 = 100_000;
+
+// Tree over DOCS docs whose values are their doc ids
+fn planner_tree(planner: PlannerThresholds) -> AggregationIndexTree {
+    let values = (0..DOCS).map(|doc_id| (doc_id, doc_id as f64)).collect();
+    build_aggregation_index_tree(values, &BuildOptions { planner, ..BuildOptions::default() }).unwrap()
+}
+
+fn random_bitmap(seed: u64, fraction: f64) -> RoaringBitmap {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..DOCS).filter(|_| rng.gen_bool(fraction)).collect()
+}
+
+// Bitmaps of each shape the planner distinguishes, with stable names
+fn planner_shapes() -> Vec<(&'static str, RoaringBitmap)> {
+    let runs: RoaringBitmap = (0..DOCS).filter(|doc_id| (doc_id / 1000) % 4 == 0).collect();
+    vec![
+        ("empty", RoaringBitmap::new()),
+        ("full", (0..DOCS).collect()),
+        ("outside doc-id range", (DOCS..DOCS + 1000).collect()),
+        ("single id", std::iter::once(DOCS / 2).collect()),
+        ("random sparse 0.1%", random_bitmap(1, 0.001)),
+        ("random 5%", random_bitmap(2, 0.05)),
+        ("random 30%", random_bitmap(3, 0.3)),
+        ("prefix 79%", (0..DOCS * 79 / 100).collect()),
+        ("prefix 81%", (0..DOCS * 81 / 100).collect()),
+        ("random 95%", random_bitmap(4, 0.95)),
+        ("runs of 1000 every 4000", runs),
+    ]
+}
+
+// One line per shape: the planner's cost estimate, which includes the strategy.
+// The snapshots below change only when a planner heuristic is meant to.
+fn planner_snapshot(ait: &AggregationIndexTree) -> String {
+    planner_shapes()
+        .iter()
+        .map(|(name, bitmap)| format!("{}: {} ids, {}\n", name, bitmap.len(), ait.estimate_cost(bitmap)))
+        .collect()
+}
+
+#[test]
+fn planner_snapshot_default_thresholds() {
+    let ait = planner_tree(PlannerThresholds::default());
+    assert_eq!(planner_snapshot(&ait), "empty: 0 ids, no lookups needed\n\
+               full: 100000 ids, no lookups needed\n\
+               outside doc-id range: 1000 ids, no lookups needed\n\
+               single id: 1 ids, sequential strategy, 1 positions, ~1 leaves\n\
+               random sparse 0.1%: 84 ids, sequential strategy, 84 positions, ~82 leaves\n\
+               random 5%: 5033 ids, sequential strategy, 5033 positions, ~1873 leaves\n\
+               random 30%: 30298 ids, parallel strategy, 30298 positions, ~2048 leaves\n\
+               prefix 79%: 79000 ids, parallel strategy, 79000 positions, ~2048 leaves\n\
+               prefix 81%: 81000 ids, complement strategy, 19000 positions, ~2048 leaves\n\
+               random 95%: 94943 ids, complement strategy, 5057 positions, ~1875 leaves\n\
+               runs of 1000 every 4000: 25000 ids, parallel strategy, 25000 positions, ~2048 leaves\n");
+}
+
+#[test]
+fn planner_snapshot_custom_thresholds() {
+    let ait = planner_tree(PlannerThresholds {
+        parallel_min_len: 100,
+        complement_min_fraction: 0.5,
+        ..PlannerThresholds::default()
+    });
+    assert_eq!(planner_snapshot(&ait), "empty: 0 ids, no lookups needed\n\
+               full: 100000 ids, no lookups needed\n\
+               outside doc-id range: 1000 ids, no lookups needed\n\
+               single id: 1 ids, sequential strategy, 1 positions, ~1 leaves\n\
+               random sparse 0.1%: 84 ids, sequential strategy, 84 positions, ~82 leaves\n\
+               random 5%: 5033 ids, parallel strategy, 5033 positions, ~1873 leaves\n\
+               random 30%: 30298 ids, parallel strategy, 30298 positions, ~2048 leaves\n\
+               prefix 79%: 79000 ids, complement strategy, 21000 positions, ~2048 leaves\n\
+               prefix 81%: 81000 ids, complement strategy, 19000 positions, ~2048 leaves\n\
+               random 95%: 94943 ids, complement strategy, 5057 positions, ~1875 leaves\n\
+               runs of 1000 every 4000: 25000 ids, parallel strategy, 25000 positions, ~2048 leaves\n");
+}
+
+// The benchmark's default tolerance: abs 0.001, rel 1e-9
+const TOLERANCE: Tolerance = Tolerance { absolute: 0.001, relative: 1e-9 };
+
+// Sums of large values differ by rounding depending on the order they're
+// added in, by more than a fixed 0.001 but a tiny fraction of the sum
+#[test]
+fn tolerance_accepts_rounding_in_large_sums() {
+    let values: Vec<f64> = (0..1_000_000u64).map(|i| ((i * 7919) % 1_000_003) as f64 * 13_700.37).collect();
+    let forward: f64 = values.iter().sum();
+    let backward: f64 = values.iter().rev().sum();
+    assert!((forward - backward).abs() >= 0.001, "{} vs {}", forward, backward);
+    assert!(TOLERANCE.approx_eq(forward, backward));
+    assert!(TOLERANCE.approx_eq(-forward, -backward));
+    // One unit in the last place of 1e13 is about 0.002
+    assert!(TOLERANCE.approx_eq(1e13, 1e13_f64.next_up()));
+    assert!(!TOLERANCE.approx_eq(1e13, 1e13 + 1e5));
+}
+
+#[test]
+fn tolerance_boundaries() {
+    // Near zero only the absolute bound applies
+    assert!(TOLERANCE.approx_eq(0.0, 0.0) && TOLERANCE.approx_eq(0.0, -0.0));
+    assert!(TOLERANCE.approx_eq(0.0, 0.0009) && TOLERANCE.approx_eq(-0.0009, 0.0));
+    assert!(!TOLERANCE.approx_eq(0.0, 0.002));
+    // Signs matter: equal magnitudes of opposite sign are far apart
+    assert!(!TOLERANCE.approx_eq(-5.0, 5.0));
+    assert!(TOLERANCE.approx_eq(-1e12, -1e12 - 0.5));
+    assert!(!TOLERANCE.approx_eq(-1e12, -1e12 - 5e3));
+    // Zero tolerance is exact comparison
+    let exact = Tolerance { absolute: 0.0, relative: 0.0 };
+    assert!(exact.approx_eq(1e13, 1e13) && exact.approx_eq(0.0, -0.0));
+    assert!(!exact.approx_eq(1e13, 1e13_f64.next_up()) && !exact.approx_eq(0.0, f64::MIN_POSITIVE));
+    // A relative bound alone accepts nothing but zero next to zero
+    let relative = Tolerance { absolute: 0.0, relative: 1e-9 };
+    assert!(relative.approx_eq(1e13, 1e13_f64.next_up()) && !relative.approx_eq(0.0, 1e-300));
+}
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ait-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+const CRASH_BATCH: u32 = 500;
+
+// Batch `i` of the crash tests: docs i*CRASH_BATCH.. with values equal to their ids
+fn crash_batch(i: u32) -> Vec<(u32, f64)> {
+    (i * CRASH_BATCH..(i + 1) * CRASH_BATCH).map(|doc_id| (doc_id, doc_id as f64)).collect()
+}
+
+fn crash_policy() -> MergePolicy {
+    MergePolicy { fanout: 2, ..MergePolicy::default() }
+}
+
+// A reopened index must hold exactly the first k batches for some k
+fn assert_batch_prefix(global: NodeAggregations) {
+    assert_eq!(global.count % CRASH_BATCH, 0, "partial batch recovered");
+    let docs = global.count as f64;
+    assert_eq!(global.sum, docs * (docs - 1.0) / 2.0, "recovered docs aren't a prefix of the batches");
+}
+
+#[test]
+fn segment_store_discards_leftovers() {
+    let dir = test_dir("segment-leftovers");
+    let mut index = SegmentedIndex::new(BuildOptions::default(), 1000, crash_policy());
+    for i in 0..5 {
+        index.ingest_batch(crash_batch(i)).unwrap();
+    }
+    index.persist(&dir).unwrap();
+
+    // What a persist killed part-way through leaves behind
+    fs::create_dir_all(dir.join("seg-00000000deadbeef.tmp")).unwrap();
+    fs::create_dir_all(dir.join("seg-00000000cafef00d")).unwrap();
+    fs::write(dir.join("MANIFEST.tmp"), b"{\"generation\": 7, \"seg").unwrap();
+
+    let reopened = SegmentedIndex::open(&dir, BuildOptions::default(), 1000, crash_policy()).unwrap();
+    assert_eq!(reopened.get_global_aggregations().count, 5 * CRASH_BATCH);
+    assert_batch_prefix(reopened.get_global_aggregations());
+    let mut names: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    let mut expected: Vec<String> = SegmentedIndex::read_manifest(&dir).unwrap().unwrap().segments;
+    expected.push(SEGMENT_MANIFEST.to_string());
+    expected.sort();
+    assert_eq!(names, expected);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn merge_policies_pick_groups() {
+    let tiered = MergePolicy { kind: MergeKind::Tiered, fanout: 3, max_segment_docs: 10_000, max_segments: 8 };
+    // Tiers below 3, 9, 27, ...: three segments of tier 2 are due, the tier 1 pair isn't
+    assert_eq!(tiered.pick(&[5, 12, 4, 20, 15]), Some(vec![1, 4, 3]));
+    assert_eq!(tiered.pick(&[5, 12, 4, 20]), None);
+    // Segments at the size cap are never picked
+    assert_eq!(tiered.pick(&[10_000, 10_000, 10_000]), None);
+    // Too many segments: the two smallest open ones merge whatever their tiers
+    assert_eq!(tiered.pick(&[1, 30, 300, 3000, 9000, 10_000, 10_000, 10_000, 2]), Some(vec![0, 8]));
+
+    let leveled = MergePolicy { kind: MergeKind::Leveled, ..tiered };
+    // The two smallest merge once the smaller is within a factor of the fanout
+    assert_eq!(leveled.pick(&[100, 40, 250]), Some(vec![1, 0]));
+    assert_eq!(leveled.pick(&[100, 30, 250]), None);
+    assert_eq!(leveled.pick(&[100, 10_000, 9000]), None);
+    assert_eq!(leveled.pick(&[7]), None);
+}
+
+// Under either policy kind a segmented index answers like a scan after
+// every batch, ends each batch with no merge due, and counts the docs its
+// merges rewrote. Merging everything leaves one segment with the same answers.
+#[test]
+fn merge_policies_preserve_aggregations() {
+    let mut rng = StdRng::seed_from_u64(468);
+    let mut ids: Vec<u32> = (0..DOCS / 2).collect();
+    for i in (1..ids.len()).rev() {
+        ids.swap(i, rng.gen_range(0..=i));
+    }
+    let pairs: Vec<(u32, f64)> = ids.into_iter().map(|doc_id| (doc_id, rng.gen_range(-500..500) as f64)).collect();
+    let mut batches = Vec::new();
+    let mut rest = &pairs[..];
+    while !rest.is_empty() {
+        let (batch, tail) = rest.split_at(rng.gen_range(1..3000).min(rest.len()));
+        batches.push(batch);
+        rest = tail;
+    }
+    let bitmap = random_bitmap(9, 0.3);
+
+    for kind in [MergeKind::Tiered, MergeKind::Leveled] {
+        let policy = MergePolicy { kind, fanout: 3, max_segment_docs: 20_000, max_segments: 12 };
+        let mut index = SegmentedIndex::new(BuildOptions::default(), 1000, policy);
+        let mut ingested = Vec::new();
+        let check = |index: &SegmentedIndex, ingested: &[(u32, f64)], label: &str| {
+            let scan = |keep: &dyn Fn(u32, f64) -> bool| {
+                let mut expected = NodeAggregations::empty();
+                for &(doc_id, value) in ingested {
+                    if keep(doc_id, value) {
+                        expected.add_value(value);
+                    }
+                }
+                (expected.count, expected.sum, expected.min_value, expected.max_value)
+            };
+            let summary = |actual: NodeAggregations| (actual.count, actual.sum, actual.min_value, actual.max_value);
+            assert_eq!(summary(index.get_global_aggregations()), scan(&|_, _| true), "{} global", label);
+            assert_eq!(summary(index.query_value_range(-100.0, 250.0)),
+                       scan(&|_, value| (-100.0..=250.0).contains(&value)), "{} range", label);
+            assert_eq!(summary(index.query_with_bitmap(&bitmap).unwrap()), scan(&|doc_id, _| bitmap.contains(doc_id)),
+                       "{} bitmap", label);
+        };
+        for (i, batch) in batches.iter().enumerate() {
+            index.ingest_batch(batch.to_vec()).unwrap();
+            ingested.extend_from_slice(batch);
+            let sizes: Vec<usize> = index.segments.iter().map(|segment| segment.position_count()).collect();
+            assert_eq!(policy.pick(&sizes), None, "{} batch {}: merge left due in {:?}", kind.name(), i, sizes);
+            if i % 10 == 0 {
+                check(&index, &ingested, &format!("{} batch {}", kind.name(), i));
+            }
+        }
+        check(&index, &ingested, kind.name());
+        let stats = index.merge_stats();
+        assert!(stats.merges > 0 && stats.docs_merged >= pairs.len() as u64, "{}: {:?}", kind.name(), stats);
+
+        index.merge_segments().unwrap();
+        assert_eq!(index.segment_count(), 1);
+        check(&index, &ingested, &format!("{} merged", kind.name()));
+    }
+}
+
+// Docs inserted while flush and compaction run in the background are
+// queryable throughout, in insertion order, and once the scheduler has
+// caught up the buffer is empty and no merge is due. A paused scheduler
+// starts no runs.
+#[test]
+fn maintenance_keeps_segments_queryable() {
+    let policy = MergePolicy { fanout: 3, max_segments: 8, ..MergePolicy::default() };
+    let index = Arc::new(RwLock::new(SegmentedIndex::new(BuildOptions::default(), 500, policy).defer_merges()));
+    let scheduler = MaintenanceScheduler::start(vec![
+        Box::new(FlushTask { index: Arc::clone(&index), interval: Duration::from_millis(5) }),
+        Box::new(CompactionTask { index: Arc::clone(&index), interval: Duration::from_millis(2) }),
+    ]);
+    // Values equal doc ids, inserted in id order, so every state is a prefix
+    let assert_prefix = |index: &SegmentedIndex| {
+        let global = index.get_global_aggregations();
+        let docs = global.count as f64;
+        assert_eq!(global.sum, docs * (docs - 1.0) / 2.0, "{} docs aren't a prefix", global.count);
+        let half = global.count / 2;
+        assert_eq!(index.query_value_range(0.0, half as f64 - 0.5).count, half);
+    };
+    // Chunks aren't a multiple of the buffer limit, so some leave docs for the flush task
+    const CHUNK: u32 = 1750;
+    for chunk in 0..DOCS / 2 / CHUNK {
+        if chunk == DOCS / 4 / CHUNK {
+            // Runs in flight when paused finish, but no task starts another
+            scheduler.pause();
+            let runs: Vec<u64> = scheduler.stats().iter().map(|stats| stats.runs).collect();
+            sleep(Duration::from_millis(50));
+            for (stats, runs) in scheduler.stats().iter().zip(runs) {
+                assert!(stats.runs <= runs + 1, "{} ran while paused", stats.name);
+            }
+            scheduler.resume();
+        }
+        let mut writer = index.write().unwrap();
+        for doc_id in chunk * CHUNK..(chunk + 1) * CHUNK {
+            writer.insert(doc_id, doc_id as f64).unwrap();
+        }
+        drop(writer);
+        assert_prefix(&index.read().unwrap());
+    }
+    // Whether the flush task found the chunks' leftovers depends on timing,
+    // so end on a partial buffer that only it can empty: waiting for an empty
+    // buffer below then waits for a flush that finds work
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !index.read().unwrap().buffer.is_empty() {
+        assert!(Instant::now() < deadline, "flush task never emptied the buffer");
+        sleep(Duration::from_millis(5));
+    }
+    const TAIL: u32 = 250;
+    let inserted = DOCS / 2 / CHUNK * CHUNK;
+    let mut writer = index.write().unwrap();
+    for doc_id in inserted..inserted + TAIL {
+        writer.insert(doc_id, doc_id as f64).unwrap();
+    }
+    assert_eq!(writer.buffer.len() as u32, TAIL);
+    drop(writer);
+
+    loop {
+        let index = index.read().unwrap();
+        let sizes: Vec<usize> = index.segments.iter().map(|segment| segment.position_count()).collect();
+        if index.buffer.is_empty() && policy.pick(&sizes).is_none() {
+            break;
+        }
+        assert!(Instant::now() < deadline, "maintenance didn't catch up: {:?}", sizes);
+        drop(index);
+        sleep(Duration::from_millis(5));
+    }
+    let stats = scheduler.shutdown();
+    assert!(stats.iter().all(|task| task.busy_runs > 0 && task.errors == 0), "{:?}", stats);
+    let index = index.read().unwrap();
+    assert_eq!(index.get_global_aggregations().count, inserted + TAIL);
+    assert_prefix(&index);
+    assert!(index.segment_count() <= policy.max_segments);
+}
+
+// Child process for the segment store tests: ingests and persists
+// batches forever, merging on every other batch, until it is killed. Does
+// nothing unless AIT_CRASH_DIR is set.
+#[test]
+fn segment_store_crash_child() {
+    let Ok(dir) = std::env::var("AIT_CRASH_DIR") else {
+        return;
+    };
+    let dir = PathBuf::from(dir);
+    let mut index = SegmentedIndex::open(&dir, BuildOptions::default(), 1000, crash_policy()).unwrap();
+    let start = index.get_global_aggregations().count / CRASH_BATCH;
+    for i in start..start + 100_000 {
+        index.ingest_batch(crash_batch(i)).unwrap();
+        index.persist(&dir).unwrap();
+    }
+}
+
+#[test]
+fn segment_store_survives_kill() {
+    let dir = test_dir("segment-crash");
+    let mut rng = StdRng::seed_from_u64(470);
+    let mut recovered = 0;
+    for _ in 0..8 {
+        let mut child = spawn_crash_child(&dir);
+        sleep(Duration::from_millis(rng.gen_range(20..200)));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Reopening after each kill also resumes from the recovered state
+        let index = SegmentedIndex::open(&dir, BuildOptions::default(), 1000, crash_policy()).unwrap();
+        assert_batch_prefix(index.get_global_aggregations());
+        let count = index.get_global_aggregations().count;
+        assert!(count >= recovered, "persisted batches were lost");
+        recovered = count;
+    }
+    assert!(recovered > 0, "the child never persisted a batch");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn spawn_crash_child(dir: &Path) -> std::process::Child {
+    std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["tests::segment_store_crash_child", "--exact", "--nocapture"])
+        .env("AIT_CRASH_DIR", dir)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+// A reader in this process follows a writer in another: every generation
+// it sees is a complete prefix of the writer's batches
+#[test]
+fn segment_reader_follows_writer() {
+    let dir = test_dir("segment-reader");
+    fs::create_dir_all(&dir).unwrap();
+    let mut writer = spawn_crash_child(&dir);
+    let mut reader = SegmentReader::open(&dir).unwrap();
+    let mut generations = 0;
+    let mut seen = 0;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < deadline {
+        if reader.refresh().unwrap() {
+            generations += 1;
+        }
+        let global = reader.get_global_aggregations();
+        assert_batch_prefix(global);
+        assert!(global.count >= seen, "reader went back to an older generation");
+        seen = global.count;
+        let upper = (seen / 2) as f64;
+        assert_eq!(reader.query_value_range(0.0, upper).count, seen.min(seen / 2 + 1));
+        let mut bitmap = RoaringBitmap::new();
+        bitmap.insert_range(0..seen / 2);
+        assert_eq!(reader.query_with_bitmap(&bitmap).unwrap().count, seen / 2);
+    }
+    writer.kill().unwrap();
+    writer.wait().unwrap();
+    assert!(generations > 1, "the reader never saw the writer commit");
+    drop(reader);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// The snapshot's strategies are the ones the query path actually runs, and
+// every strategy agrees with a scan
+#[test]
+fn planner_choices_match_query_results() {
+    let ait = planner_tree(PlannerThresholds::default());
+    let reference = ColumnarStorage { values: (0..DOCS).map(|doc_id| doc_id as f64).collect() };
+    for (name, bitmap) in planner_shapes() {
+        let expected = reference.query_with_bitmap_naive(&bitmap);
+        let actual = ait.query_with_bitmap(&bitmap).unwrap();
+        assert_eq!((actual.count, actual.sum), (expected.count, expected.sum), "{}", name);
+        for &strategy in QueryStrategy::ALL.iter() {
+            let actual = ait.query_with_strategy(&bitmap, strategy).unwrap();
+            assert_eq!((actual.count, actual.sum), (expected.count, expected.sum), "{} with {}", name, strategy.name());
+        }
+    }
+}
+
+// A tree whose doc ids start past zero, as a segment's or a merged
+// partition's do, filters like a scan over its docs. Bitmaps with as many ids
+// as the tree has docs aren't necessarily covering, and the complement is
+// taken over the tree's id range.
+#[test]
+fn offset_doc_ids_filter_like_a_scan() {
+    const OFFSET: u32 = 3 * DOCS;
+    let pairs: Vec<(u32, f64)> = (OFFSET..OFFSET + DOCS)
+        .map(|doc_id| (doc_id, (doc_id * 7919 % 1000) as f64))
+        .collect();
+    let ait = build_aggregation_index_tree(pairs.clone(), &BuildOptions::default()).unwrap();
+    let mut rng = StdRng::seed_from_u64(467);
+    let bitmaps: Vec<(&str, RoaringBitmap)> = vec![
+        ("whole range", (OFFSET..OFFSET + DOCS).collect()),
+        ("whole range and beyond", (0..OFFSET + 2 * DOCS).collect()),
+        ("as many ids as docs, half below the range", (OFFSET - DOCS / 2..OFFSET + DOCS / 2).collect()),
+        ("random 95% of the range", (OFFSET..OFFSET + DOCS).filter(|_| rng.gen_bool(0.95)).collect()),
+        ("random 5% of the range", (OFFSET..OFFSET + DOCS).filter(|_| rng.gen_bool(0.05)).collect()),
+        ("below the range", (0..OFFSET).collect()),
+    ];
+    for (name, bitmap) in bitmaps {
+        let mut expected = NodeAggregations::empty();
+        for &(doc_id, value) in &pairs {
+            if bitmap.contains(doc_id) {
+                expected.add_value(value);
+            }
+        }
+        let actual = ait.query_with_bitmap(&bitmap).unwrap();
+        assert_eq!((actual.count, actual.sum), (expected.count, expected.sum), "{}", name);
+        for &strategy in QueryStrategy::ALL.iter() {
+            let actual = ait.query_with_strategy(&bitmap, strategy).unwrap();
+            assert_eq!((actual.count, actual.sum), (expected.count, expected.sum), "{} with {}", name, strategy.name());
+        }
+        for metric in Metric::ALL {
+            let expected = (expected.count > 0 || metric == Metric::Count).then(|| metric.of(&expected));
+            assert_eq!(ait.query_metric_with_bitmap(&bitmap, metric).unwrap(), expected, "{} {}", name, metric.name());
+        }
+    }
+}
+
+// A bitmap holding every doc id answers from the root, single metrics
+// included, and so does one that also holds ids the tree doesn't have
+#[test]
+fn bitmap_covering_every_doc_id_matches_global() {
+    let ait = planner_tree(PlannerThresholds::default());
+    let global = ait.get_global_aggregations();
+    for bitmap in [(0..DOCS).collect::<RoaringBitmap>(), (0..2 * DOCS).collect()] {
+        assert!(ait.covered_by(&bitmap));
+        let actual = ait.query_with_bitmap(&bitmap).unwrap();
+        assert_eq!((actual.count, actual.sum, actual.min_value, actual.max_value),
+                   (global.count, global.sum, global.min_value, global.max_value));
+        for metric in Metric::ALL {
+            assert_eq!(ait.query_metric_with_bitmap(&bitmap, metric).unwrap(), Some(metric.of(&global)),
+                       "{}", metric.name());
+        }
+    }
+    // One id short of the whole range isn't covering
+    let mut bitmap: RoaringBitmap = (0..2 * DOCS).collect();
+    bitmap.remove(DOCS / 2);
+    assert!(!ait.covered_by(&bitmap));
+    let expected_sum = global.sum - (DOCS / 2) as f64;
+    assert_eq!(ait.query_metric_with_bitmap(&bitmap, Metric::Count).unwrap(), Some((DOCS - 1) as f64));
+    assert_eq!(ait.query_metric_with_bitmap(&bitmap, Metric::Sum).unwrap(), Some(expected_sum));
+    assert_eq!(ait.query_with_bitmap(&bitmap).unwrap().sum, expected_sum);
+}
+
+// Query results keep one JSON schema: empty aggregations have null bounds,
+// and manifests written with the old f64 sentinels still read back
+#[test]
+fn query_result_json_schema() {
+    let mut aggregations = NodeAggregations::empty();
+    assert_eq!(serde_json::to_string(&aggregations).unwrap(),
+               r#"{"min_value":null,"max_value":null,"sum":0.0,"count":0,"sum_squares":0.0,"avg":null,"std_dev":null}"#);
+    let legacy = format!(r#"{{"min_value":{:e},"max_value":{:e},"sum":0.0,"count":0}}"#, f64::MAX, f64::MIN);
+    let read: NodeAggregations = serde_json::from_str(&legacy).unwrap();
+    assert_eq!((read.min_value, read.max_value), (f64::MAX, f64::MIN));
+
+    aggregations.add_value(2.5);
+    aggregations.add_value(-1.0);
+    let json = serde_json::to_string(&aggregations).unwrap();
+    assert_eq!(json, r#"{"min_value":-1.0,"max_value":2.5,"sum":1.5,"count":2,"sum_squares":7.25,"avg":0.75,"std_dev":1.75}"#);
+    let read: NodeAggregations = serde_json::from_str(&json).unwrap();
+    assert_eq!((read.min_value, read.max_value, read.sum, read.count), (-1.0, 2.5, 1.5, 2));
+    assert_eq!((read.avg(), NodeAggregations::empty().avg()), (Some(0.75), None));
+
+    let cost = planner_tree(PlannerThresholds::default()).estimate_cost(&random_bitmap(7, 0.1));
+    assert_eq!(serde_json::from_str::<CostEstimate>(&serde_json::to_string(&cost).unwrap()).unwrap(), cost);
+    let estimate = CountEstimate { docs: 42, exact: false };
+    assert_eq!(serde_json::to_string(&estimate).unwrap(), r#"{"docs":42,"exact":false}"#);
+}
+
+// A repeated doc id is reported instead of silently keeping one position
+#[test]
+fn build_rejects_duplicate_doc_ids() {
+    let values = vec![(1, 1.0), (7, 2.0), (3, 3.0), (7, 4.0), (5, 5.0)];
+    let options = BuildOptions { leaf_size: 2, ..BuildOptions::default() };
+    let err = build_aggregation_index_tree(values.clone(), &options).err();
+    assert!(matches!(err, Some(AitError::DuplicateDocId(7))), "{:?}", err);
+    assert!(matches!(build_value_ordered_tree(values.clone(), &options).err(), Some(AitError::DuplicateDocId(7))));
+
+    // Without doc ids there is no map for the duplicate to corrupt
+    let ait = build_aggregation_index_tree(values, &BuildOptions { store_doc_ids: false, ..options }).unwrap();
+    assert_eq!(ait.get_global_aggregations().count, 5);
+}
+
+// Unsorted input is sorted before the build; NaN can't be placed
+#[test]
+fn build_sorts_unsorted_input() {
+    let values: Vec<(u32, f64)> = (0..1000).map(|doc_id| (doc_id, (999 - doc_id) as f64)).collect();
+    let options = BuildOptions { leaf_size: 8, ..BuildOptions::default() };
+    let ait = build_aggregation_index_tree(values.clone(), &options).unwrap();
+    let range = ait.query_value_range(100.0, 199.0);
+    assert_eq!((range.count, range.min_value, range.max_value), (100, 100.0, 199.0));
+    let mut bitmap = RoaringBitmap::new();
+    bitmap.insert_range(0..10);
+    assert_eq!(ait.query_with_bitmap(&bitmap).unwrap().sum, (990..1000).sum::<u32>() as f64);
+
+    let mut with_nan = values;
+    with_nan[500].1 = f64::NAN;
+    let err = build_aggregation_index_tree(with_nan, &options).err();
+    assert!(matches!(err, Some(AitError::NanValue(500))), "{:?}", err);
+    // Input that is trivially sorted is checked too
+    for values in [vec![(7, f64::NAN)], vec![(7, f64::NAN), (8, f64::NAN)]] {
+        let err = build_aggregation_index_tree(values, &options).err();
+        assert!(matches!(err, Some(AitError::NanValue(7))), "{:?}", err);
+    }
+}
+
+// Dropping the position map changes how positions are found, not the answers,
+// including after a save and load
+#[test]
+fn builder_without_position_map_matches() {
+    let values: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id, (doc_id % 977) as f64)).collect();
+    let with_map = AitBuilder::new().leaf_size(32).build(values.clone()).unwrap();
+    let without_map = AitBuilder::new().leaf_size(32).position_map(false).parallel(true).build(values).unwrap();
+    assert!(!without_map.metadata.config.position_map);
+    let dir = test_dir("builder_without_position_map");
+    without_map.save(&dir).unwrap();
+    let loaded = AggregationIndexTree::load(&dir).unwrap();
+    assert!(!loaded.metadata.config.position_map);
+    for (name, bitmap) in planner_shapes() {
+        let expected = with_map.query_with_bitmap(&bitmap).unwrap();
+        for ait in [&without_map, &loaded] {
+            let actual = ait.query_with_bitmap(&bitmap).unwrap();
+            assert_eq!((actual.count, actual.sum, actual.min_value, actual.max_value),
+                       (expected.count, expected.sum, expected.min_value, expected.max_value), "{}", name);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Equal values form one run of positions even across leaves; counts agree with
+// or without recorded runs, and survive a save and load
+#[test]
+fn value_runs_count_repeated_values() {
+    let values: Vec<(u32, f64)> = (0..1000).map(|doc_id| (doc_id, (doc_id % 7 * doc_id % 3) as f64)).collect();
+    let with_runs = AitBuilder::new().leaf_size(4).value_runs(true).build(values.clone()).unwrap();
+    let without_runs = AitBuilder::new().leaf_size(4).build(values.clone()).unwrap();
+    let dir = test_dir("value_runs");
+    with_runs.save(&dir).unwrap();
+    let loaded = AggregationIndexTree::load(&dir).unwrap();
+    assert!(loaded.value_runs.is_some() && without_runs.value_runs.is_none());
+
+    let mut expected: Vec<ValueCount> = Vec::new();
+    for value in (0..13).map(f64::from) {
+        let count = values.iter().filter(|&&(_, v)| v == value).count();
+        for ait in [&with_runs, &without_runs, &loaded] {
+            assert_eq!(ait.count_eq(value), count, "{}", value);
+        }
+        if count > 1 {
+            expected.push(ValueCount { value, count: count as u32 });
+        }
+    }
+    expected.sort_by_key(|bucket| std::cmp::Reverse(bucket.count));
+    for ait in [&with_runs, &without_runs, &loaded] {
+        assert_eq!(ait.value_histogram(3), expected[..3]);
+    }
+    assert_eq!(with_runs.count_eq(0.5), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Set membership unions the position runs of each distinct value; duplicates,
+// absent values and NaN in the set are harmless
+#[test]
+fn docs_with_value_in_matches_scan() {
+    let values: Vec<(u32, f64)> = (0..5000).map(|doc_id| (doc_id * 3, (doc_id % 50) as f64)).collect();
+    let ait = AitBuilder::new().leaf_size(8).build(values.clone()).unwrap();
+    let set = [7.0, 3.0, 7.0, 49.0, 50.5, f64::NAN];
+    let expected: RoaringBitmap =
+        values.iter().filter(|(_, value)| set.contains(value)).map(|&(doc_id, _)| doc_id).collect();
+    assert_eq!(ait.docs_with_value_in(&set).unwrap(), expected);
+    assert_eq!(ait.docs_with_value(3.0).unwrap().len(), 100);
+    assert!(ait.docs_with_value_in(&[]).unwrap().is_empty());
+
+    let value_only = AitBuilder::new().store_doc_ids(false).build(values).unwrap();
+    assert!(matches!(value_only.docs_with_value_in(&set), Err(AitError::DocIdsNotStored)));
+}
+
+// Sums of squares combine through every bitmap strategy, including the
+// complement's subtraction, and are written with the index
+#[test]
+fn variance_matches_scan_across_strategies() {
+    let values: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id, ((doc_id * 7919) % 1000) as f64 / 8.0)).collect();
+    let ait = AitBuilder::new().leaf_size(16).build(values.clone()).unwrap();
+    let bitmap = random_bitmap(11, 0.6);
+    let mut expected = NodeAggregations::empty();
+    for doc_id in &bitmap {
+        expected.add_value(values[doc_id as usize].1);
+    }
+    let expected = expected.variance().unwrap();
+    for strategy in QueryStrategy::ALL {
+        let variance = ait.query_with_strategy(&bitmap, strategy).unwrap().variance().unwrap();
+        assert!((variance - expected).abs() <= expected * 1e-9, "{:?}: {} vs {}", strategy, variance, expected);
+    }
+
+    let dir = test_dir("variance");
+    ait.save(&dir).unwrap();
+    let loaded = AggregationIndexTree::load(&dir).unwrap();
+    assert_eq!(loaded.get_global_aggregations().sum_squares, ait.get_global_aggregations().sum_squares);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Percentiles interpolate between ranks of the selected docs, and quantile
+// ranges keep the ranks between them, filtered or not
+#[test]
+fn percentiles_and_quantile_ranges() {
+    // Doc i holds value (i * 37) % 100, so every value 0..100 occurs once
+    let values: Vec<(u32, f64)> = (0..100).map(|doc_id| (doc_id, ((doc_id * 37) % 100) as f64)).collect();
+    let ait = AitBuilder::new().leaf_size(4).build(values.clone()).unwrap();
+    assert_eq!(ait.percentiles(&[0.0, 50.0, 99.0, 100.0], None).unwrap(),
+               vec![Some(0.0), Some(49.5), Some(98.01), Some(99.0)]);
+    let iqr = ait.aggregate_between_quantiles(0.25, 0.75, None).unwrap();
+    assert_eq!((iqr.count, iqr.min_value, iqr.max_value), (50, 25.0, 74.0));
+
+    // Docs holding even values
+    let bitmap: RoaringBitmap = values.iter().filter(|(_, value)| value % 2.0 == 0.0).map(|&(doc_id, _)| doc_id).collect();
+    assert_eq!(ait.percentile(50.0, Some(&bitmap)).unwrap(), Some(49.0));
+    let trimmed = ait.aggregate_between_quantiles(0.1, 0.9, Some(&bitmap)).unwrap();
+    assert_eq!((trimmed.count, trimmed.min_value, trimmed.max_value), (40, 10.0, 88.0));
+    assert_eq!(ait.percentile(50.0, Some(&RoaringBitmap::new())).unwrap(), None);
+}
+
+#[test]
+fn approx_percentiles_track_exact() {
+    // 20000 distinct values, enough for node digests above the leaves
+    let values: Vec<(u32, f64)> = (0..20_000).map(|doc_id| (doc_id, ((doc_id * 7919) % 20_000) as f64)).collect();
+    let ait = AitBuilder::new().digests(true).build(values.clone()).unwrap();
+    assert!(ait.node_digests.iter().any(Option::is_some));
+    let ps = [1.0, 50.0, 99.0];
+    let close = |approx: Vec<Option<f64>>, exact: Vec<Option<f64>>| {
+        for (approx, exact) in approx.iter().zip(&exact) {
+            assert!((approx.unwrap() - exact.unwrap()).abs() <= 20.0, "{:?} vs {:?}", approx, exact);
+        }
+    };
+    close(ait.approx_percentiles(&ps, None).unwrap(), ait.percentiles(&ps, None).unwrap());
+
+    // Docs holding values below 15000: one run covering whole nodes
+    let bitmap: RoaringBitmap = values.iter().filter(|(_, value)| *value < 15_000.0).map(|&(doc_id, _)| doc_id).collect();
+    close(ait.approx_percentiles(&ps, Some(&bitmap)).unwrap(), ait.percentiles(&ps, Some(&bitmap)).unwrap());
+    assert_eq!(ait.approx_percentiles(&ps, Some(&RoaringBitmap::new())).unwrap(), vec![None; 3]);
+}
+
+#[test]
+fn streamed_terms_stats_match_collected() {
+    // 5000 terms, doc i holding term i % 5000 (squared, so counts differ)
+    let labels: Vec<String> = (0..DOCS).map(|doc_id| format!("t{}", (doc_id % 5000).pow(2) % 5000)).collect();
+    let terms = TermColumn::from_values(labels.iter().map(String::as_str));
+    let ait = planner_tree(PlannerThresholds::default());
+    let bitmap = random_bitmap(7, 0.3);
+    // Room for 64 terms' state per pass
+    let limit = Some(64 * std::mem::size_of::<NodeAggregations>());
+    for filter in [None, Some(&bitmap)] {
+        let collected = ait.terms_stats(&terms, filter, None).unwrap();
+        let streamed: Vec<TermBucket> = ait.terms_stats_stream(&terms, filter, TermOrder::DocCount, limit).unwrap().collect();
+        let key = |bucket: &TermBucket| (bucket.term.clone(), bucket.count, bucket.sum);
+        assert_eq!(streamed.iter().map(key).collect::<Vec<_>>(), collected.iter().map(key).collect::<Vec<_>>());
+
+        let by_term: Vec<TermBucket> = ait.terms_stats_stream(&terms, filter, TermOrder::Term, limit).unwrap().collect();
+        assert_eq!(by_term.len(), collected.len());
+        assert!(by_term.windows(2).all(|pair| pair[0].term < pair[1].term));
+    }
+}
+
+#[test]
+fn histograms_merge_like_values() {
+    // Long-tailed latencies, plus a few negative values to mirror below zero
+    let mut rng = StdRng::seed_from_u64(11);
+    let values: Vec<(u32, f64)> =
+        (0..DOCS).map(|doc_id| (doc_id, (1.0 / (1.0 - rng.gen::<f64>()) - 1.5) * 10.0)).collect();
+    let with = AitBuilder::new().histograms(true).build(values.clone()).unwrap();
+    let without = AitBuilder::new().build(values.clone()).unwrap();
+    assert!(with.node_histograms.iter().any(Option::is_some));
+
+    let bitmap = random_bitmap(5, 0.4);
+    for filter in [None, Some(&bitmap)] {
+        // Node histograms hold the same buckets as adding every value
+        let histogram = with.query_histogram(filter).unwrap();
+        assert_eq!(histogram, without.query_histogram(filter).unwrap());
+        assert_eq!(histogram.count(), filter.map_or(DOCS as u64, |bitmap| bitmap.len()));
+        let exact = with.percentiles(&[1.0, 50.0, 99.0, 99.9], filter).unwrap();
+        for (p, exact) in [1.0, 50.0, 99.0, 99.9].into_iter().zip(exact) {
+            let (approx, exact) = (histogram.percentile(p).unwrap(), exact.unwrap());
+            assert!((approx - exact).abs() <= exact.abs() * 0.01 + 0.01, "p{}: {} vs {}", p, approx, exact);
+        }
+    }
+}
+
+#[test]
+fn field_set_round_trips_with_term_dictionaries() {
+    let dir = test_dir("field-set");
+    let values: Vec<f64> = (0..DOCS).map(|doc_id| ((doc_id * 7) % 1000) as f64).collect();
+    let levels = ["info", "warn", "error", "débug"];
+    let terms = TermColumn::from_values((0..DOCS).map(|doc_id| levels[(doc_id % 7 % 4) as usize]));
+    let mut set = FieldIndexSet::default();
+    // Range-heavy fields get a tree, the rest a zone map
+    let ranges = FieldQueryMix { global: 0, filtered: 0, range: 1 };
+    let globals = FieldQueryMix { global: 1, filtered: 0, range: 0 };
+    set.add_field("size", &values, &ranges, &BuildOptions::default()).unwrap();
+    set.add_field("latency", &values, &globals, &BuildOptions::default()).unwrap();
+    set.add_terms("level", terms.clone());
+    set.save(&dir).unwrap();
+
+    let reopened = FieldIndexSet::load(&dir).unwrap();
+    let level = reopened.term_field("level").unwrap();
+    assert_eq!((&level.terms, &level.codes, &level.counts), (&terms.terms, &terms.codes, &terms.counts));
+    let predicates = [
+        Predicate::Term { field: "level".to_string(), term: "débug".to_string() },
+        Predicate::Range { field: "size".to_string(), min: 100.0, max: 400.0 },
+        Predicate::Range { field: "latency".to_string(), min: 300.0, max: 900.0 },
+    ];
+    assert!(matches!(reopened.field("size"), Some(FieldIndex::Tree(_))));
+    assert!(matches!(reopened.field("latency"), Some(FieldIndex::ZoneMap(_))));
+    assert_eq!(reopened.query_conjunction(&predicates).unwrap().0, set.query_conjunction(&predicates).unwrap().0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn saved_filters_reload_by_name() {
+    let dir = test_dir("saved-filters");
+    let ait = planner_tree(PlannerThresholds::default());
+    ait.save(&dir).unwrap();
+    let filters = FilterStore::for_index(&dir);
+    assert_eq!(filters.names().unwrap(), Vec::<String>::new());
+
+    let bitmap = random_bitmap(3, 0.2);
+    filters.save("hot_users", &bitmap).unwrap();
+    filters.save("empty", &RoaringBitmap::new()).unwrap();
+    assert_eq!(filters.names().unwrap(), vec!["empty", "hot_users"]);
+    let reloaded = FilterStore::for_index(&dir).load("hot_users").unwrap();
+    assert_eq!(reloaded, bitmap);
+    let reopened = AggregationIndexTree::load(&dir).unwrap();
+    assert_eq!(reopened.query_with_bitmap(&reloaded).unwrap().sum, ait.query_with_bitmap(&bitmap).unwrap().sum);
+
+    assert_eq!(filters.save("../escape", &bitmap).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(filters.load("missing").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    filters.remove("empty").unwrap();
+    assert_eq!(filters.names().unwrap(), vec!["hot_users"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn imported_filters_match_in_every_format() {
+    let dir = test_dir("imported-filters");
+    fs::create_dir_all(&dir).unwrap();
+    let bitmap = random_bitmap(5, 0.1);
+
+    let roaring = dir.join("filter.roaring");
+    bitmap.serialize_into(fs::File::create(&roaring).unwrap()).unwrap();
+    assert_eq!(import_filter(&roaring, FilterFormat::Roaring).unwrap(), bitmap);
+
+    // Out of order, with a duplicate, across lines
+    let mut ids: Vec<String> = bitmap.iter().rev().map(|doc_id| doc_id.to_string()).collect();
+    ids.push(ids[0].clone());
+    let text = dir.join("filter.txt");
+    fs::write(&text, ids.chunks(7).map(|line| line.join(" ")).collect::<Vec<_>>().join("\n")).unwrap();
+    assert_eq!(import_filter(&text, FilterFormat::Ids).unwrap(), bitmap);
+    fs::write(&text, "1 2 x").unwrap();
+    assert_eq!(import_filter(&text, FilterFormat::Ids).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+    // Two record batches; the second marks every third row null, which drops it
+    let mut stream = Vec::new();
+    let field = FlatValue::Table(vec![(0, FlatValue::String("keep".to_string())), (1, FlatValue::Bool(true)),
+                                      (2, FlatValue::U8(6)), (3, FlatValue::Table(Vec::new()))]);
+    let schema = FlatValue::Table(vec![(1, FlatValue::TableVector(vec![field]))]);
+    write_arrow_message(&mut stream, 1, schema, &[]).unwrap();
+    let half = DOCS / 2;
+    let mut expected = RoaringBitmap::new();
+    for (first, nulls) in [(0, false), (half, true)] {
+        let mut values = vec![0u8; (half as usize).div_ceil(8)];
+        let mut validity = vec![0u8; values.len()];
+        for row in 0..half {
+            let valid = !nulls || row % 3 != 0;
+            if valid {
+                validity[row as usize / 8] |= 1 << (row % 8);
+            }
+            if bitmap.contains(first + row) {
+                values[row as usize / 8] |= 1 << (row % 8);
+                if valid {
+                    expected.insert(first + row);
+                }
+            }
+        }
+        let validity = if nulls { validity } else { Vec::new() };
+        let mut body = Vec::new();
+        let mut buffers = Vec::new();
+        for bytes in [&validity, &values] {
+            buffers.extend_from_slice(&(body.len() as i64).to_le_bytes());
+            buffers.extend_from_slice(&(bytes.len() as i64).to_le_bytes());
+            body.extend_from_slice(bytes);
+            body.resize(body.len().next_multiple_of(8), 0);
+        }
+        let nodes: Vec<u8> = [half as i64, 0].iter().flat_map(|n| n.to_le_bytes()).collect();
+        let batch = FlatValue::Table(vec![
+            (0, FlatValue::I64(half as i64)),
+            (1, FlatValue::StructVector { align: 8, count: 1, bytes: nodes }),
+            (2, FlatValue::StructVector { align: 8, count: 2, bytes: buffers }),
+        ]);
+        write_arrow_message(&mut stream, 3, batch, &body).unwrap();
+    }
+    stream.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+    let arrow = dir.join("filter.arrows");
+    fs::write(&arrow, &stream).unwrap();
+    assert_eq!(import_filter(&arrow, FilterFormat::ArrowBool).unwrap(), expected);
+
+    // Our own value exports are not boolean columns
+    let ait = planner_tree(PlannerThresholds::default());
+    ait.export(&bitmap, ExportFormat::Arrow, &arrow, None).unwrap();
+    assert_eq!(import_filter(&arrow, FilterFormat::ArrowBool).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Harmonic mean of value + 1, which no precomputed node aggregation gives
+struct HarmonicMean;
+
+impl Aggregator for HarmonicMean {
+    type State = (f64, u64);
+    type Output = Option<f64>;
+
+    fn init(&self) -> (f64, u64) {
+        (0.0, 0)
+    }
+
+    fn accumulate(&self, state: &mut (f64, u64), value: f64) {
+        state.0 += 1.0 / (value + 1.0);
+        state.1 += 1;
+    }
+
+    fn merge(&self, state: &mut (f64, u64), other: (f64, u64)) {
+        state.0 += other.0;
+        state.1 += other.1;
+    }
+
+    fn finalize(&self, (reciprocals, count): (f64, u64)) -> Option<f64> {
+        (count > 0).then(|| count as f64 / reciprocals)
+    }
+}
+
+#[test]
+fn custom_aggregators_run_on_every_strategy() {
+    let ait = planner_tree(PlannerThresholds::default());
+    for (name, bitmap) in planner_shapes() {
+        // Values equal doc ids; ids past the tree select nothing
+        let selected = bitmap.range_cardinality(0..DOCS);
+        let reciprocals: f64 = bitmap.range(0..DOCS).map(|doc_id| 1.0 / (doc_id as f64 + 1.0)).sum();
+        let expected = (selected > 0).then(|| selected as f64 / reciprocals);
+        let summary = ait.query_with_bitmap(&bitmap).unwrap();
+        let planned = ait.aggregate(&HarmonicMean, &bitmap).unwrap();
+        assert_eq!(planned.is_some(), expected.is_some(), "{}", name);
+        for strategy in QueryStrategy::ALL {
+            let harmonic = ait.aggregate_with_strategy(&HarmonicMean, &bitmap, strategy).unwrap();
+            if let (Some(harmonic), Some(expected)) = (harmonic, expected) {
+                assert!((harmonic - expected).abs() <= expected * 1e-9, "{} {:?}: {} vs {}",
+                        name, strategy, harmonic, expected);
+            }
+            let generic = ait.aggregate_with_strategy(&Summary, &bitmap, strategy).unwrap();
+            assert_eq!((generic.count, generic.sum), (summary.count, summary.sum), "{} {:?}", name, strategy);
+        }
+    }
+}
+
+#[test]
+fn weighted_aggregations_match_on_every_strategy() {
+    let weight = |doc_id: u32| (doc_id % 7) as f64 + 0.5;
+    let weights: Vec<f64> = (0..DOCS).map(weight).collect();
+    let values = (0..DOCS).map(|doc_id| (doc_id, doc_id as f64)).collect();
+    let mut ait = AitBuilder::new().weights(weights.clone()).build(values).unwrap();
+    for (name, bitmap) in planner_shapes() {
+        // Values equal doc ids; ids past the tree select nothing
+        let mut expected = WeightedSums::default();
+        for doc_id in bitmap.range(0..DOCS) {
+            expected.add_weighted(doc_id as f64, weight(doc_id));
+        }
+        let close = |actual: WeightedSums| {
+            (actual.weight - expected.weight).abs() <= 1e-9 * expected.weight.max(1.0)
+                && (actual.weighted_sum - expected.weighted_sum).abs() <= 1e-9 * expected.weighted_sum.max(1.0)
+        };
+        assert!(close(ait.weighted_aggregations(Some(&bitmap)).unwrap()), "{}", name);
+        for strategy in QueryStrategy::ALL {
+            let actual = ait.weighted_aggregations_with_strategy(&bitmap, strategy).unwrap();
+            assert!(close(actual), "{} {:?}: {:?} vs {:?}", name, strategy, actual, expected);
+        }
+    }
+    let all = ait.weighted_aggregations(None).unwrap();
+    let total: f64 = weights.iter().sum();
+    assert!((all.weighted_avg().unwrap() - all.weighted_sum / total).abs() < 1e-9);
+
+    assert!(matches!(ait.attach_weight_column(&weights[..10]), Err(AitError::MissingWeight(_))));
+    let unweighted = planner_tree(PlannerThresholds::default());
+    assert!(matches!(unweighted.weighted_aggregations(None), Err(AitError::NoWeightColumn)));
+}
+
+#[test]
+fn fixed_width_histogram_matches_bucketed_scan() {
+    // Values equal doc ids; ids past the tree select nothing
+    let ait = planner_tree(PlannerThresholds::default());
+    let expected = |ids: &mut dyn Iterator<Item = u32>, interval: f64| {
+        let mut buckets: Vec<(f64, u32, f64, f64, f64)> = Vec::new();
+        for value in ids.filter(|&doc_id| doc_id < DOCS).map(f64::from) {
+            let key = (value / interval).floor() * interval;
+            match buckets.last_mut() {
+                Some(bucket) if bucket.0 == key => {
+                    bucket.1 += 1;
+                    bucket.2 += value;
+                    bucket.4 = value;
+                }
+                _ => buckets.push((key, 1, value, value, value)),
+            }
+        }
+        buckets
+    };
+    let summarize = |buckets: Vec<IntervalBucket>| -> Vec<(f64, u32, f64, f64, f64)> {
+        buckets.iter().map(|bucket| {
+            let aggregations = &bucket.aggregations;
+            (bucket.key, aggregations.count, aggregations.sum, aggregations.min_value, aggregations.max_value)
+        }).collect()
+    };
+    for interval in [1000.0, 7.5, 1e9] {
+        assert_eq!(summarize(ait.histogram(interval, None).unwrap()), expected(&mut (0..DOCS), interval));
+        for (name, bitmap) in planner_shapes() {
+            let buckets = ait.histogram(interval, Some(&bitmap)).unwrap();
+            assert_eq!(summarize(buckets), expected(&mut bitmap.iter(), interval), "{} {}", name, interval);
+        }
+    }
+    assert_eq!(ait.histogram(0.0, None).unwrap_err(), AitError::InvalidInterval(0.0));
+}
+
+#[test]
+fn date_histogram_matches_bucketed_scan() {
+    let values: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id, ((doc_id * 7919) % DOCS) as f64)).collect();
+    // One doc every 37s, starting 40-odd days before the epoch
+    let timestamps: Vec<i64> = (0..DOCS as i64).map(|doc_id| doc_id * 37_000 - 3_600_000_000).collect();
+    let ait = AitBuilder::new().timestamps(timestamps.clone()).build(values.clone()).unwrap();
+    let expected = |ids: &mut dyn Iterator<Item = u32>, interval: DateInterval| {
+        let mut buckets: std::collections::BTreeMap<i64, (u32, f64, f64, f64)> = Default::default();
+        for doc_id in ids.filter(|&doc_id| doc_id < DOCS) {
+            let value = values[doc_id as usize].1;
+            let bucket = buckets.entry(interval.bucket_start(timestamps[doc_id as usize]))
+                .or_insert((0, 0.0, f64::INFINITY, f64::NEG_INFINITY));
+            *bucket = (bucket.0 + 1, bucket.1 + value, bucket.2.min(value), bucket.3.max(value));
+        }
+        buckets.into_iter().collect::<Vec<_>>()
+    };
+    let summarize = |buckets: Vec<DateBucket>| -> Vec<(i64, (u32, f64, f64, f64))> {
+        buckets.iter().map(|bucket| {
+            let aggregations = &bucket.aggregations;
+            (bucket.key_ms, (aggregations.count, aggregations.sum, aggregations.min_value, aggregations.max_value))
+        }).collect()
+    };
+    for interval in [DateInterval::Hour, DateInterval::Day, DateInterval::Week, DateInterval::Millis(90_000)] {
+        assert_eq!(summarize(ait.date_histogram(interval, None).unwrap()), expected(&mut (0..DOCS), interval));
+        for (name, bitmap) in planner_shapes() {
+            let buckets = ait.date_histogram(interval, Some(&bitmap)).unwrap();
+            assert_eq!(summarize(buckets), expected(&mut bitmap.iter(), interval), "{} {}", name, interval);
+        }
+    }
+
+    // Weeks start on Monday 1970-01-05, four days after the epoch
+    let day = 86_400_000;
+    assert_eq!(DateInterval::Week.bucket_start(4 * day + 1), 4 * day);
+    assert_eq!(DateInterval::Week.bucket_start(4 * day - 1), -3 * day);
+    assert_eq!("day".parse::<DateInterval>(), Ok(DateInterval::Day));
+    assert_eq!("90000ms".parse::<DateInterval>(), Ok(DateInterval::Millis(90_000)));
+    assert!("0".parse::<DateInterval>().is_err());
+    let without = planner_tree(PlannerThresholds::default());
+    assert_eq!(without.date_histogram(DateInterval::Day, None).unwrap_err(), AitError::NoTimestampColumn);
+}
+
+#[test]
+fn subtree_dump_flags_misplaced_splits() {
+    let mut ait = planner_tree(PlannerThresholds::default());
+    let root = ait.subtree(0, 1, 4).unwrap();
+    assert_eq!((root.start, root.aggregations.count, root.children.len()), (0, DOCS, 2));
+    assert!(!root.split_mismatch && root.children.iter().all(|child| !child.split_mismatch));
+    let right = &root.children[1];
+    assert_eq!(right.start, root.children[0].aggregations.count as usize);
+
+    // A leaf deep in the right half: entries up to the limit, in value order
+    let mut node = right.node;
+    while let Ok(SubtreeNode { children, .. }) = ait.subtree(node, 1, 4) {
+        let Some(last) = children.last() else { break };
+        node = last.node;
+    }
+    let leaf = ait.subtree(node, 5, 4).unwrap();
+    assert_eq!(leaf.values.len(), 4);
+    assert_eq!(leaf.doc_ids, leaf.values.iter().map(|&value| value as u32).collect::<Vec<_>>());
+    assert_eq!(leaf.values.len() + leaf.omitted, leaf.aggregations.count as usize);
+    assert_eq!(leaf.start + leaf.aggregations.count as usize, DOCS as usize);
+
+    if let AggregationTreeNode::Internal { split_value, .. } = &mut ait.nodes[right.node] {
+        *split_value += 1.0;
+    }
+    let corrupted = ait.subtree(0, 1, 4).unwrap();
+    assert!(corrupted.children[1].split_mismatch);
+    assert!(corrupted.to_dot().contains(&format!("n{} [label=\"node {} @", right.node, right.node)));
+    assert!(corrupted.to_dot().contains("color=red"));
+    assert_eq!(ait.subtree(ait.nodes.len(), 1, 4).unwrap_err(), AitError::NoSuchNode(ait.nodes.len()));
+}
+
+#[test]
+fn structure_report_counts_levels_and_leaf_fill() {
+    // Half the docs share one value, so some leaves hold a single value
+    let values: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id, (doc_id % 2 * doc_id) as f64)).collect();
+    let ait = AitBuilder::new().leaf_size(100).build(values).unwrap();
+    let structure = ait.structure(0, 4).unwrap();
+    let leaves = ait.nodes.iter().filter(|node| matches!(node, AggregationTreeNode::Leaf { .. })).count();
+    assert_eq!(structure.leaves(), leaves);
+    assert_eq!(structure.leaf_fill.iter().sum::<usize>(), leaves);
+    assert_eq!(structure.levels.iter().map(|level| level.internal_nodes + level.leaves).sum::<usize>(), ait.nodes.len());
+    assert_eq!(structure.levels.iter().map(|level| level.leaf_values).sum::<usize>(), DOCS as usize);
+    assert_eq!((structure.levels[0].min_docs, structure.levels[0].max_docs), (DOCS, DOCS));
+    assert!(structure.levels.iter().any(|level| level.constant_nodes > 0));
+    assert!(structure.mean_leaf_fill() > 0.5 && structure.mean_leaf_fill() <= 1.0);
+
+    // Blocks cover every position once per level they reach
+    assert_eq!(structure.blocks.iter().filter(|block| block.depth == 3).count(), 8);
+    let level_docs: u32 = structure.blocks.iter().filter(|block| block.depth == 3).map(|block| block.count).sum();
+    assert_eq!(level_docs, DOCS);
+    let html = structure.to_html("test <tree>");
+    assert!(html.contains("test &lt;tree&gt;") && html.matches("<rect").count() >= structure.blocks.len());
+
+    let right = ait.subtree(0, 1, 0).unwrap().children[1].clone();
+    let subtree = ait.structure(right.node, 1).unwrap();
+    assert_eq!((subtree.blocks[0].start, subtree.blocks[0].count), (right.start, right.aggregations.count));
+    assert_eq!(ait.structure(ait.nodes.len(), 1).unwrap_err(), AitError::NoSuchNode(ait.nodes.len()));
+}
+
+#[test]
+fn extended_stats_match_two_pass_moments() {
+    // Squares of a permutation: right-skewed, and far from zero relative to
+    // the spread of the top values
+    let values: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id, (((doc_id * 7919) % DOCS) as f64).powi(2))).collect();
+    let with = AitBuilder::new().extended_stats(true).build(values.clone()).unwrap();
+    let without = AitBuilder::new().build(values.clone()).unwrap();
+    assert_eq!((with.node_moments.len(), without.node_moments.len()), (with.nodes.len(), 0));
+    let close = |actual: Option<f64>, expected: f64| {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() <= 1e-9 * expected.abs().max(1.0), "{} vs {}", actual, expected);
+    };
+    let shapes = planner_shapes().into_iter().map(|(name, bitmap)| (name, Some(bitmap)));
+    for (name, bitmap) in std::iter::once(("all", None)).chain(shapes) {
+        let selected: Vec<f64> = (0..DOCS)
+            .filter(|doc_id| bitmap.as_ref().is_none_or(|bitmap| bitmap.contains(*doc_id)))
+            .map(|doc_id| values[doc_id as usize].1)
+            .collect();
+        let n = selected.len() as f64;
+        let mean = selected.iter().sum::<f64>() / n;
+        let central = |power: i32| selected.iter().map(|value| (value - mean).powi(power)).sum::<f64>() / n;
+        for ait in [&with, &without] {
+            let moments = ait.extended_stats(bitmap.as_ref()).unwrap();
+            assert_eq!(moments.count as usize, selected.len(), "{}", name);
+            if selected.len() > 1 {
+                close(moments.std_dev(), central(2).sqrt());
+                close(moments.skewness(), central(3) / central(2).powf(1.5));
+                close(moments.kurtosis(), central(4) / central(2).powi(2) - 3.0);
+            }
+        }
+    }
+    // Moments merge in any grouping, and the generic aggregator agrees
+    let bitmap = random_bitmap(21, 0.4);
+    let generic = with.aggregate(&ExtendedStats, &bitmap).unwrap();
+    close(generic.kurtosis(), with.extended_stats(Some(&bitmap)).unwrap().kurtosis().unwrap());
+    let constant = AitBuilder::new().extended_stats(true).build(vec![(0, 5.0), (1, 5.0)]).unwrap();
+    assert_eq!(constant.extended_stats(None).unwrap().skewness(), None);
+}
+
+// Geometric means and products from node log sums, and from values without
+// them, match a product taken by multiplying the selected values, under each
+// non-positive policy. Reject refuses zeros and negatives.
+#[test]
+fn geometric_mean_and_product_match_multiplication() {
+    const LEN: u32 = 5000;
+    // Values near 1 keep the product in range; every tenth is zero or negative
+    let value = |doc_id: u32| match doc_id % 20 {
+        0 => 0.0,
+        10 => -3.0,
+        _ => 0.9 + 0.2 * ((doc_id * 7919) % 1000) as f64 / 1000.0,
+    };
+    let values: Vec<(u32, f64)> = (0..LEN).map(|doc_id| (doc_id, value(doc_id))).collect();
+    let mut rng = StdRng::seed_from_u64(515);
+    let filters: Vec<(&str, Option<RoaringBitmap>)> = vec![
+        ("all docs", None),
+        ("random 30%", Some((0..LEN).filter(|_| rng.gen_bool(0.3)).collect())),
+        ("doc range", Some((1234..3456).collect())),
+        ("only non-positive", Some((0..LEN).step_by(10).collect())),
+        ("single doc", Some(std::iter::once(7).collect())),
+    ];
+    let close = |actual: Option<f64>, expected: Option<f64>| match (actual, expected) {
+        (Some(actual), Some(expected)) => (actual - expected).abs() <= 1e-9 * expected.abs(),
+        _ => actual == expected,
+    };
+    for non_positive in [NonPositive::Skip, NonPositive::Floor(0.5)] {
+        let with = AitBuilder::new().geometric_mean(true).non_positive(non_positive).build(values.clone()).unwrap();
+        let without = AitBuilder::new().non_positive(non_positive).build(values.clone()).unwrap();
+        for (name, filter) in &filters {
+            let (mut product, mut count, mut skipped) = (1.0, 0u32, 0u32);
+            for &(doc_id, value) in &values {
+                if filter.as_ref().is_some_and(|bitmap| !bitmap.contains(doc_id)) {
+                    continue;
+                }
+                match non_positive {
+                    NonPositive::Floor(floor) => product *= value.max(floor),
+                    _ if value > 0.0 => product *= value,
+                    _ => {
+                        skipped += 1;
+                        continue;
+                    }
+                }
+                count += 1;
+            }
+            let (product, mean) = match count {
+                0 => (None, None),
+                _ => (Some(product), Some(product.powf(1.0 / count as f64))),
+            };
+            let label = format!("{} with {}", name, non_positive);
+            let generic = match filter {
+                Some(bitmap) => with.aggregate(&GeometricMean { non_positive }, bitmap).unwrap(),
+                None => with.geometric_mean(None).unwrap(),
+            };
+            let from_nodes = with.geometric_mean(filter.as_ref()).unwrap();
+            let from_values = without.geometric_mean(filter.as_ref()).unwrap();
+            for log_sum in [from_nodes, from_values, generic] {
+                assert_eq!((log_sum.count, log_sum.skipped), (count, skipped), "{}", label);
+                assert!(close(log_sum.product(), product), "{}: {:?} vs {:?}", label, log_sum.product(), product);
+                assert!(close(log_sum.geometric_mean(), mean), "{}: {:?} vs {:?}", label, log_sum.geometric_mean(),
+                        mean);
+            }
+        }
+    }
+
+    // The first offending doc in value order is reported: doc 10 holds -3
+    let rejected = AitBuilder::new().non_positive(NonPositive::Reject).build(values.clone()).err();
+    assert_eq!(rejected, Some(AitError::NonPositiveValue(10)));
+    let zero = AitBuilder::new().non_positive(NonPositive::Reject).build(vec![(0, 2.0), (1, 0.0)]).err();
+    assert_eq!(zero, Some(AitError::NonPositiveValue(1)));
+    let negative = AitBuilder::new().non_positive(NonPositive::Reject).build(vec![(0, 2.0), (1, -8.0)]).err();
+    assert_eq!(negative, Some(AitError::NonPositiveValue(1)));
+    let product = AitBuilder::new().non_positive(NonPositive::Reject).build(vec![(0, 2.0), (1, 8.0)]).unwrap();
+    assert!(close(product.geometric_mean(None).unwrap().product(), Some(16.0)));
+    assert!(close(product.geometric_mean(None).unwrap().geometric_mean(), Some(4.0)));
+    let invalid = AitBuilder::new().non_positive(NonPositive::Floor(0.0)).build(values).err();
+    assert_eq!(invalid, Some(AitError::InvalidFloor(0.0)));
+    assert_eq!("floor:0.5".parse::<NonPositive>(), Ok(NonPositive::Floor(0.5)));
+    assert!("floor:-1".parse::<NonPositive>().is_err());
+}
+
+#[test]
+fn top_and_bottom_k_within_filter() {
+    // Values equal doc ids, so the extremes of a filter are its extreme ids
+    let ait = planner_tree(PlannerThresholds::default());
+    let expected = |bitmap: &RoaringBitmap, k: usize, highest: bool| -> Vec<(u32, f64)> {
+        let ids: Vec<u32> = if highest { bitmap.iter().rev().take(k).collect() } else { bitmap.iter().take(k).collect() };
+        ids.into_iter().map(|doc_id| (doc_id, doc_id as f64)).collect()
+    };
+    // A dense filter is scanned from the ends; a sparse one is resolved id by id
+    let dense = random_bitmap(9, 0.3);
+    let sparse: RoaringBitmap = [5, 70, 900, 12_000, 99_999].into_iter().collect();
+    for bitmap in [&dense, &sparse] {
+        assert_eq!(ait.top_k(4, bitmap).unwrap(), expected(bitmap, 4, true));
+        assert_eq!(ait.bottom_k(4, bitmap).unwrap(), expected(bitmap, 4, false));
+    }
+    assert_eq!(ait.top_k(10, &sparse).unwrap().len(), 5);
+    assert_eq!(ait.bottom_k(0, &dense).unwrap(), Vec::new());
+}
+
+#[test]
+fn distinct_counts_from_node_sketches() {
+    let values: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id, ((doc_id * 7919) % DOCS) as f64)).collect();
+    // 20000 users, each with 5 docs spread over the value order
+    let users: Vec<u64> = (0..DOCS as u64).map(|doc_id| doc_id % 20_000).collect();
+    let ait = AitBuilder::new().distinct_ids(users.clone()).build(values.clone()).unwrap();
+    let distinct = ait.distinct.as_ref().unwrap();
+    assert!(distinct.node_sketches.iter().any(Option::is_some));
+
+    // Sketches merge losslessly, so the estimate is that of hashing every id
+    let bitmap = random_bitmap(13, 0.5);
+    for filter in [None, Some(&bitmap)] {
+        let mut direct = HyperLogLog::new();
+        let selected: std::collections::HashSet<u64> = (0..DOCS)
+            .filter(|doc_id| filter.is_none_or(|bitmap| bitmap.contains(*doc_id)))
+            .map(|doc_id| users[doc_id as usize])
+            .collect();
+        selected.iter().for_each(|&user| direct.add(user));
+        let estimate = ait.approx_distinct(filter).unwrap();
+        assert_eq!(estimate, direct.estimate());
+        assert!(estimate.abs_diff(selected.len() as u64) as f64 <= selected.len() as f64 * 0.05);
+    }
+
+    assert!(matches!(AitBuilder::new().distinct_ids(vec![1; 10]).build(values),
+                     Err(AitError::MissingDistinctId(_))));
+    let without = planner_tree(PlannerThresholds::default());
+    assert!(matches!(without.approx_distinct(None), Err(AitError::NoDistinctColumn)));
+}
+
+// A build keeps leaves in one chunk per column, in position order, and so
+// does a split-format load; stitching keeps each part's chunks
+#[test]
+fn leaves_share_one_arena_chunk_per_column() {
+    let values: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id, ((doc_id * 7919) % DOCS) as f64)).collect();
+    let ait = AitBuilder::new().leaf_size(64).build(values.clone()).unwrap();
+    let stats = ait.arena_stats();
+    assert_eq!((stats.chunks, stats.leaves), (2, ait.leaf_count));
+    assert_eq!((stats.allocated_bytes, stats.referenced_bytes), (DOCS as usize * 12, DOCS as usize * 12));
+    let mut expected = values.clone();
+    expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+    for position in [0, 63, 64, 4_999, DOCS as usize - 1] {
+        assert_eq!(ait.get_doc_id_at_position(position), expected[position].0, "{}", position);
+    }
+
+    let without_ids = AitBuilder::new().leaf_size(64).store_doc_ids(false).build(values.clone()).unwrap();
+    assert_eq!((without_ids.arena_stats().chunks, without_ids.arena_stats().allocated_bytes), (1, DOCS as usize * 8));
+
+    let dir = test_dir("leaf_arena");
+    ait.save(&dir).unwrap();
+    let loaded = AggregationIndexTree::load(&dir).unwrap();
+    assert_eq!(loaded.arena_stats(), stats);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let (low, high) = expected.split_at(DOCS as usize / 2);
+    let parts = [low, high].map(|part| AitBuilder::new().leaf_size(64).build(part.to_vec()).unwrap());
+    let stitched = AggregationIndexTree::stitch(parts.into()).unwrap();
+    assert_eq!((stitched.arena_stats().chunks, stitched.arena_stats().allocated_bytes), (4, DOCS as usize * 12));
+    let bitmap = random_bitmap(7, 0.1);
+    assert_eq!(stitched.query_with_bitmap(&bitmap).unwrap().sum, ait.query_with_bitmap(&bitmap).unwrap().sum);
+}
+
+// Prefix and range sums follow a scan through point updates. Ranges past
+// the last doc are cut off there, and empty or reversed ranges sum to zero.
+#[test]
+fn fenwick_sums_follow_updates() {
+    const LEN: usize = 1000;
+    let mut rng = StdRng::seed_from_u64(449);
+    let mut values: Vec<f64> = (0..LEN).map(|_| rng.gen_range(-1000..1000) as f64).collect();
+    let mut fenwick = FenwickSums::new(values.clone());
+    let scan = |values: &[f64], start: usize, end: usize| -> f64 { values[start.min(end)..end].iter().sum() };
+    for end in 0..=LEN + 5 {
+        assert_eq!(fenwick.prefix_sum(end as u32), scan(&values, 0, end.min(LEN)), "prefix {}", end);
+    }
+    for round in 0..200 {
+        let doc_id = rng.gen_range(0..LEN);
+        values[doc_id] = rng.gen_range(-1000..1000) as f64;
+        fenwick.update(doc_id as u32, values[doc_id]).unwrap();
+        let start = rng.gen_range(0..=LEN);
+        let end = rng.gen_range(start..=LEN + 10);
+        assert_eq!(fenwick.range_sum(start as u32..end as u32), scan(&values, start, end.min(LEN)), "round {}", round);
+    }
+    assert_eq!(fenwick.prefix_sum(LEN as u32), values.iter().sum::<f64>());
+    // Updates past the last doc are refused and leave the sums alone
+    for doc_id in [LEN as u32, u32::MAX] {
+        assert_eq!(fenwick.update(doc_id, 1.0), Err(AitError::NoSuchDoc(doc_id)));
+    }
+    assert_eq!(FenwickSums::new(Vec::new()).update(0, 1.0), Err(AitError::NoSuchDoc(0)));
+    assert_eq!(fenwick.prefix_sum(u32::MAX), values.iter().sum::<f64>());
+    assert_eq!(fenwick.range_sum(7..7), 0.0);
+    assert_eq!(fenwick.range_sum(LEN as u32 + 1..LEN as u32 + 9), 0.0);
+    #[allow(clippy::reversed_empty_ranges)]
+    let reversed = fenwick.range_sum(600..500);
+    assert_eq!(reversed, 0.0);
+    assert_eq!(FenwickSums::new(Vec::new()).range_sum(0..10), 0.0);
+}
+
+// Every AggIndex backend answers each metric it keeps like a scan, over
+// bitmaps and doc ranges, and refuses the ones it doesn't
+#[test]
+fn agg_index_backends_match_scan() {
+    let values: Vec<f64> = (0..DOCS).map(|doc_id| ((doc_id * 7919) % 1000) as f64 - 500.0).collect();
+    let pairs = values.iter().enumerate().map(|(doc_id, &value)| (doc_id as u32, value)).collect();
+    let ait = build_aggregation_index_tree(pairs, &BuildOptions::default()).unwrap();
+    let sampled = SampledIndex::new(values.clone(), &BuildOptions::default());
+    let zone_map = ZoneMapColumn::new(values.clone());
+    let fenwick = FenwickSums::new(values.clone());
+    let backends: [&dyn AggIndex; 4] = [&ait, &sampled, &zone_map, &fenwick];
+    let reference = ColumnarStorage { values };
+
+    let mut filters = planner_shapes();
+    filters.extend([(0, 0), (0, 1), (1023, 1025), (DOCS - 10, DOCS + 10), (DOCS + 5, DOCS + 9), (0, DOCS)]
+        .map(|(start, end)| ("doc range", (start..end).collect::<RoaringBitmap>())));
+    for (name, bitmap) in &filters {
+        let expected = reference.query_with_bitmap_naive(bitmap);
+        let range = bitmap.min().unwrap_or(0)..bitmap.max().map_or(0, |max| max + 1);
+        let contiguous = bitmap.len() == (range.end - range.start) as u64;
+        for backend in backends {
+            for metric in Metric::ALL {
+                let label = format!("{} {} {}", backend.name(), name, metric.name());
+                let actual = backend.metric_with_bitmap(bitmap, metric);
+                if !backend.supports(metric) {
+                    assert_eq!(actual, Err(AitError::UnsupportedMetric(metric.name())), "{}", label);
+                    continue;
+                }
+                assert_eq!(actual, Ok(metric.of_matching(&expected)), "{}", label);
+                if contiguous {
+                    assert_eq!(backend.metric_in_doc_range(range.clone(), metric), Ok(metric.of_matching(&expected)),
+                               "{} as a range", label);
+                }
+            }
+        }
+    }
+    assert!(!fenwick.supports(Metric::Min) && !fenwick.supports(Metric::Max));
+    assert_eq!(fenwick.metric_in_doc_range(0..10, Metric::Max), Err(AitError::UnsupportedMetric("max")));
+}
+
+// Docs with shuffled ids and small integer values, negatives and repeats
+// included, so sums are exact in any order
+fn shuffled_pairs(seed: u64, len: u32) -> Vec<(u32, f64)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut ids: Vec<u32> = (0..len).collect();
+    for i in (1..ids.len()).rev() {
+        ids.swap(i, rng.gen_range(0..=i));
+    }
+    ids.into_iter().map(|doc_id| (doc_id, rng.gen_range(-500..500) as f64)).collect()
+}
+
+fn scan_pairs(pairs: &[(u32, f64)], keep: impl Fn(u32, f64) -> bool) -> NodeAggregations {
+    let mut expected = NodeAggregations::empty();
+    for &(doc_id, value) in pairs {
+        if keep(doc_id, value) {
+            expected.add_value(value);
+        }
+    }
+    expected
+}
+
+fn summary(aggregations: NodeAggregations) -> (u32, f64, f64, f64, f64) {
+    (aggregations.count, aggregations.sum, aggregations.min_value, aggregations.max_value, aggregations.sum_squares)
+}
+
+// Global, value-range and bitmap aggregations, sums of squares included,
+// against a scan over `pairs`
+fn assert_answers_like_scan(ait: &AggregationIndexTree, pairs: &[(u32, f64)], label: &str) {
+    assert_eq!(summary(ait.get_global_aggregations()), summary(scan_pairs(pairs, |_, _| true)), "{} global", label);
+    for (min, max) in [(-100.0, 250.0), (-500.0, -500.0), (499.0, 1000.0), (3.5, 3.7), (-1000.0, 1000.0)] {
+        assert_eq!(summary(ait.query_value_range(min, max)),
+                   summary(scan_pairs(pairs, |_, value| (min..=max).contains(&value))),
+                   "{} range {}..={}", label, min, max);
+    }
+    for (name, bitmap) in planner_shapes() {
+        assert_eq!(summary(ait.query_with_bitmap(&bitmap).unwrap()),
+                   summary(scan_pairs(pairs, |doc_id, _| bitmap.contains(doc_id))), "{} {}", label, name);
+    }
+}
+
+// A single-file index as versions 1 and 2 wrote it: aggregations stop after
+// the count, and v1 has neither metadata nor a doc-id flag
+fn legacy_index_bytes(ait: &AggregationIndexTree, version: u32) -> Vec<u8> {
+    let mut bytes = INDEX_MAGIC.to_vec();
+    bytes.extend(version.to_le_bytes());
+    if version == 2 {
+        let metadata = serde_json::to_vec(&ait.metadata).unwrap();
+        bytes.extend((metadata.len() as u64).to_le_bytes());
+        bytes.extend(metadata);
+        bytes.push(1);
+    }
+    bytes.extend((ait.nodes.len() as u64).to_le_bytes());
+    for node in &ait.nodes {
+        let aggregations = match node {
+            AggregationTreeNode::Internal { split_value, left, right, aggregations } => {
+                bytes.push(NODE_TAG_INTERNAL);
+                bytes.extend(split_value.to_le_bytes());
+                bytes.extend((*left as u64).to_le_bytes());
+                bytes.extend((*right as u64).to_le_bytes());
+                aggregations
+            }
+            AggregationTreeNode::Leaf { doc_ids, values, aggregations } => {
+                bytes.push(NODE_TAG_LEAF);
+                bytes.extend((values.len() as u64).to_le_bytes());
+                doc_ids.iter().for_each(|doc_id| bytes.extend(doc_id.to_le_bytes()));
+                values.iter().for_each(|value| bytes.extend(value.to_le_bytes()));
+                aggregations
+            }
+        };
+        bytes.extend(aggregations.min_value.to_le_bytes());
+        bytes.extend(aggregations.max_value.to_le_bytes());
+        bytes.extend(aggregations.sum.to_le_bytes());
+        bytes.extend(aggregations.count.to_le_bytes());
+    }
+    bytes
+}
+
+// v1 and v2 single files load with their sums of squares recomputed and
+// answer like a scan; v1 gets its metadata from the nodes. Unknown magic
+// and versions are refused.
+#[test]
+fn legacy_single_files_migrate() {
+    let pairs = shuffled_pairs(446, DOCS);
+    let options = BuildOptions { leaf_size: 100, ..BuildOptions::default() };
+    let ait = build_aggregation_index_tree(pairs.clone(), &options).unwrap();
+    let dir = test_dir("legacy-formats");
+    fs::create_dir_all(&dir).unwrap();
+    for version in [1, 2] {
+        let path = dir.join(format!("v{}.ait", version));
+        fs::write(&path, legacy_index_bytes(&ait, version)).unwrap();
+        let loaded = AggregationIndexTree::load(&path).unwrap();
+        assert_answers_like_scan(&loaded, &pairs, &format!("v{}", version));
+        assert_eq!(loaded.metadata.row_count, DOCS as u64, "v{}", version);
+    }
+    let v1 = AggregationIndexTree::load(&dir.join("v1.ait")).unwrap();
+    let largest_leaf = ait.nodes.iter().filter_map(|node| match node {
+        AggregationTreeNode::Leaf { values, .. } => Some(values.len()),
+        AggregationTreeNode::Internal { .. } => None,
+    }).max();
+    assert_eq!(v1.metadata.source, "unknown (migrated from format v1)");
+    assert_eq!(Some(v1.metadata.config.leaf_size), largest_leaf);
+    let v2 = AggregationIndexTree::load(&dir.join("v2.ait")).unwrap();
+    assert_eq!((v2.metadata.source, v2.metadata.config.leaf_size), (ait.metadata.source.clone(), 100));
+
+    let mut bytes = legacy_index_bytes(&ait, 2);
+    bytes[4..8].copy_from_slice(&9u32.to_le_bytes());
+    fs::write(dir.join("v9.ait"), &bytes).unwrap();
+    assert!(AggregationIndexTree::load(&dir.join("v9.ait")).is_err());
+    bytes[..4].copy_from_slice(b"NOPE");
+    fs::write(dir.join("magic.ait"), &bytes).unwrap();
+    assert!(AggregationIndexTree::load(&dir.join("magic.ait")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Rewrite a saved v4 index directory as v3 wrote it, without sums of squares
+fn downgrade_to_v3(dir: &Path) {
+    let mut bytes = Vec::new();
+    let nodes = read_split_nodes(dir, true).unwrap();
+    bytes.extend((nodes.len() as u64).to_le_bytes());
+    for node in &nodes {
+        match *node {
+            SplitNode::Internal { split_value, left, right, .. } => {
+                bytes.push(NODE_TAG_INTERNAL);
+                bytes.extend(split_value.to_le_bytes());
+                bytes.extend((left as u64).to_le_bytes());
+                bytes.extend((right as u64).to_le_bytes());
+            }
+            SplitNode::Leaf { start, len, .. } => {
+                bytes.push(NODE_TAG_LEAF);
+                bytes.extend((start as u64).to_le_bytes());
+                bytes.extend((len as u64).to_le_bytes());
+            }
+        }
+        let mut aggregations = Vec::new();
+        write_aggregations(&mut aggregations, &node.aggregations()).unwrap();
+        bytes.extend(&aggregations[..aggregations.len() - 8]);
+    }
+    fs::write(dir.join(INDEX_NODES_FILE), bytes).unwrap();
+    let mut manifest = IndexManifest::read(dir).unwrap();
+    manifest.format_version = 3;
+    fs::write(dir.join(INDEX_META_FILE), serde_json::to_vec(&manifest).unwrap()).unwrap();
+}
+
+// Saved indexes load back and map as segments that answer like a scan, in
+// the current layout and in v3 directories whose sums of squares are
+// recomputed. Without doc ids ranges still work and bitmaps are refused.
+#[test]
+fn split_layout_round_trips() {
+    let pairs = shuffled_pairs(447, DOCS);
+    let dir = test_dir("split-layout");
+    let ait = build_aggregation_index_tree(pairs.clone(), &BuildOptions::default()).unwrap();
+    ait.save(&dir).unwrap();
+    assert_eq!(IndexManifest::read(&dir).unwrap().format_version, INDEX_FORMAT_VERSION);
+    for version in [INDEX_FORMAT_VERSION, 3] {
+        if version == 3 {
+            downgrade_to_v3(&dir);
+        }
+        assert_answers_like_scan(&AggregationIndexTree::load(&dir).unwrap(), &pairs, &format!("v{}", version));
+        let segment = MappedSegment::open(&dir).unwrap();
+        assert_eq!(segment.len(), pairs.len());
+        assert_eq!(summary(segment.get_global_aggregations()), summary(scan_pairs(&pairs, |_, _| true)),
+                   "v{} mapped global", version);
+        for (min, max) in [(-100.0, 250.0), (-500.0, -500.0), (499.0, 1000.0), (3.5, 3.7), (7.0, 6.0)] {
+            assert_eq!(summary(segment.query_value_range(min, max)),
+                       summary(scan_pairs(&pairs, |_, value| (min..=max).contains(&value))),
+                       "v{} mapped range {}..={}", version, min, max);
+        }
+        for (name, bitmap) in planner_shapes() {
+            assert_eq!(summary(segment.query_with_bitmap(&bitmap).unwrap()),
+                       summary(scan_pairs(&pairs, |doc_id, _| bitmap.contains(doc_id))),
+                       "v{} mapped {}", version, name);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    let options = BuildOptions { store_doc_ids: false, ..BuildOptions::default() };
+    build_aggregation_index_tree(pairs.clone(), &options).unwrap().save(&dir).unwrap();
+    assert!(!dir.join(INDEX_DOC_IDS_SLAB).exists());
+    let loaded = AggregationIndexTree::load(&dir).unwrap();
+    let segment = MappedSegment::open(&dir).unwrap();
+    let expected = summary(scan_pairs(&pairs, |_, value| (-100.0..=250.0).contains(&value)));
+    assert_eq!(summary(loaded.query_value_range(-100.0, 250.0)), expected);
+    assert_eq!(summary(segment.query_value_range(-100.0, 250.0)), expected);
+    assert!(matches!(segment.query_with_bitmap(&random_bitmap(1, 0.1)), Err(AitError::DocIdsNotStored)));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Time windows aggregate exactly the docs whose timestamps fall inside them,
+// bounds included, for odd sizes, repeated timestamps, windows outside the
+// data and reversed windows
+#[test]
+fn time_windows_match_scan() {
+    let mut rng = StdRng::seed_from_u64(450);
+    for len in [0, 1, 2, 7, 1000, 4097] {
+        let timestamps: Vec<i64> = (0..len).map(|_| rng.gen_range(-50..200) * 1000).collect();
+        let values: Vec<f64> = (0..len).map(|_| rng.gen_range(-500..500) as f64).collect();
+        let tree = TimeSegmentTree::new(&timestamps, &values);
+        let mut windows = vec![(i64::MIN, i64::MAX), (-1_000_000, -60_000), (300_000, 400_000), (5000, 4000)];
+        windows.extend(timestamps.iter().take(20).map(|&timestamp| (timestamp, timestamp)));
+        windows.extend((0..100).map(|_| {
+            let from = rng.gen_range(-60..210) * 1000 + rng.gen_range(-1..=1);
+            (from, from + rng.gen_range(0..100_000))
+        }));
+        for (from, to) in windows {
+            let mut expected = NodeAggregations::empty();
+            for (&timestamp, &value) in timestamps.iter().zip(&values) {
+                if (from..=to).contains(&timestamp) {
+                    expected.add_value(value);
+                }
+            }
+            assert_eq!(summary(tree.query_time_range(from, to)), summary(expected), "{} docs, {}..={}", len, from, to);
+        }
+    }
+}
+
+// Rank counts the ones before every position, across word and block edges
+#[test]
+fn rank_bit_vec_matches_scan() {
+    let mut rng = StdRng::seed_from_u64(451);
+    let patterns: Vec<Vec<bool>> = vec![
+        Vec::new(),
+        vec![true; 1024],
+        vec![false; 1100],
+        (0..3000).map(|_| rng.gen_bool(0.3)).collect(),
+        (0..513).map(|i| i % 64 == 63).collect(),
+    ];
+    for bits in patterns {
+        let vector = RankBitVec::new(&bits);
+        let mut ones = 0;
+        for i in 0..=bits.len() {
+            assert_eq!(vector.rank1(i), ones, "{} bits, rank at {}", bits.len(), i);
+            ones += bits.get(i).copied().unwrap_or(false) as usize;
+        }
+    }
+}
+
+// With a bucket per distinct value, value-range counts within doc ranges are
+// exact for any bounds, including ones between two values or inside the
+// bucket of one. Shared buckets count at most the values one bucket past
+// each bound.
+#[test]
+fn wavelet_counts_match_scan() {
+    let mut rng = StdRng::seed_from_u64(451);
+    let len = 20_000;
+    let exact: Vec<f64> = (0..len).map(|_| rng.gen_range(-500..500) as f64).collect();
+    let spread: Vec<f64> = (0..len).map(|_| rng.gen_range(0.0..1e6)).collect();
+    let mut queries: Vec<(f64, f64, std::ops::Range<u32>)> = vec![
+        (f64::NEG_INFINITY, f64::INFINITY, 0..len),
+        (3.0, 3.0, 0..len),
+        (-500.0, -500.0, 0..len),
+        (2.9999, 3.0001, 0..len),
+        (3.0001, 3.9999, 0..len),
+        (3.0000001, 4.0, 0..len),
+        (3.0, 3.9999999, 0..len),
+        (-1000.0, -600.0, 0..len),
+        (600.0, 1000.0, 0..len),
+        (10.0, 5.0, 0..len),
+        (0.0, 100.0, 100..100),
+        (0.0, 100.0, std::ops::Range { start: 500, end: 400 }),
+        (0.0, 100.0, len - 10..len + 10),
+        (0.0, 100.0, len + 5..len + 9),
+    ];
+    for _ in 0..300 {
+        let (min, max): (f64, f64) = (rng.gen_range(-600.0..600.0), rng.gen_range(-600.0..600.0));
+        let start = rng.gen_range(0..len);
+        queries.push((min.min(max), min.max(max), start..rng.gen_range(start..=len)));
+        let (low, high) = (exact[rng.gen_range(0..exact.len())], exact[rng.gen_range(0..exact.len())]);
+        queries.push((low.min(high), low.max(high), 0..len));
+    }
+    let count = |values: &[f64], min: f64, max: f64, docs: &std::ops::Range<u32>| {
+        (docs.start..docs.end.min(len)).filter(|&doc_id| (min..=max).contains(&values[doc_id as usize])).count()
+    };
+
+    let matrix = WaveletMatrix::new(&exact);
+    assert!(matrix.exact);
+    for (min, max, docs) in &queries {
+        assert_eq!(matrix.count_value_range_in_docs(*min, *max, docs.clone()), count(&exact, *min, *max, docs),
+                   "{}..={} in {:?}", min, max, docs);
+    }
+
+    let matrix = WaveletMatrix::new(&spread);
+    assert!(!matrix.exact);
+    let step = matrix.step;
+    for _ in 0..300 {
+        let (min, max): (f64, f64) = (rng.gen_range(-1e4..1.01e6), rng.gen_range(-1e4..1.01e6));
+        let (min, max) = (min.min(max), min.max(max));
+        let start = rng.gen_range(0..len);
+        let docs = start..rng.gen_range(start..=len);
+        let actual = matrix.count_value_range_in_docs(min, max, docs.clone());
+        assert!(actual >= count(&spread, min, max, &docs), "{}..={} in {:?}", min, max, docs);
+        assert!(actual <= count(&spread, min - step, max + step, &docs), "{}..={} in {:?}", min, max, docs);
+    }
+
+    for values in [Vec::new(), vec![7.0; 100]] {
+        let matrix = WaveletMatrix::new(&values);
+        assert_eq!(matrix.count_value_range_in_docs(f64::NEG_INFINITY, f64::INFINITY, 0..len), values.len());
+        assert_eq!(matrix.count_value_range_in_docs(7.0, 7.0, 10..20), 10.min(values.len()));
+        assert_eq!(matrix.count_value_range_in_docs(7.5, 8.0, 0..len), 0);
+    }
+}
+
+// Value ranges answer like a scan wherever their ends fall: on a block edge,
+// one position either side of it, inside a single block, or past the data.
+// Small blocks and repeated values make every case occur.
+#[test]
+fn sampled_ranges_match_scan_at_block_edges() {
+    let mut rng = StdRng::seed_from_u64(452);
+    for (len, leaf_size, sample_every) in [(0, 4, 3), (1, 4, 3), (96, 4, 3), (100, 4, 3), (250, 2, 1), (250, 5, 7)] {
+        let values: Vec<f64> = (0..len).map(|_| rng.gen_range(-40..40) as f64).collect();
+        let options = BuildOptions { leaf_size, sample_every, ..BuildOptions::default() };
+        let sampled = SampledIndex::new(values.clone(), &options);
+        assert_eq!(sampled.block_size, leaf_size * sample_every);
+        let reference = ColumnarStorage { values: values.clone() };
+        let label = format!("{} values in blocks of {}", len, sampled.block_size);
+        assert_eq!(summary(sampled.get_global_aggregations()), summary(reference.query_value_range_naive(-1e9, 1e9)),
+                   "{} global", label);
+
+        let mut bounds: Vec<f64> = (-42..=42).map(|value| value as f64).collect();
+        bounds.extend((-42..=42).map(|value| value as f64 + 0.5));
+        for &min in &bounds {
+            for &max in &bounds {
+                assert_eq!(summary(sampled.query_value_range(min, max)),
+                           summary(reference.query_value_range_naive(min, max)), "{} {}..={}", label, min, max);
+            }
+        }
+        for (name, bitmap) in [("random 30%", random_bitmap(3, 0.3)), ("past the data", (len..len + 10).collect())] {
+            assert_eq!(summary(sampled.query_with_bitmap(&bitmap)), summary(reference.query_with_bitmap_naive(&bitmap)),
+                       "{} {}", label, name);
+        }
+    }
+}
+
+// Compaction keeps exactly the undeleted docs, renumbered densely in their
+// old id order, and the compacted tree answers like a scan over them
+#[test]
+fn compaction_matches_scan_of_survivors() {
+    let pairs: Vec<(u32, f64)> = shuffled_pairs(454, DOCS).into_iter().filter(|&(doc_id, _)| doc_id % 7 != 3).collect();
+    let ait = build_aggregation_index_tree(pairs.clone(), &BuildOptions::default()).unwrap();
+    for (name, deleted) in [
+        ("nothing", RoaringBitmap::new()),
+        ("random 30%", random_bitmap(454, 0.3)),
+        ("a prefix and ids never used", (0..DOCS / 2).filter(|doc_id| doc_id % 7 == 3 || doc_id % 2 == 0).collect()),
+        ("everything", (0..DOCS).collect()),
+    ] {
+        let (compacted, remap) = ait.compact(&deleted).unwrap();
+        let mut survivors: Vec<u32> = pairs.iter()
+            .map(|&(doc_id, _)| doc_id)
+            .filter(|&doc_id| !deleted.contains(doc_id))
+            .collect();
+        survivors.sort_unstable();
+        for (new_id, &old_id) in survivors.iter().enumerate() {
+            assert_eq!(remap.new_id(old_id), Some(new_id as u32), "{}: doc {}", name, old_id);
+        }
+        for old_id in deleted.iter().take(1000) {
+            assert_eq!(remap.new_id(old_id), None, "{}: deleted doc {}", name, old_id);
+        }
+        let renumbered: Vec<(u32, f64)> = pairs.iter()
+            .filter_map(|&(doc_id, value)| Some((remap.new_id(doc_id)?, value)))
+            .collect();
+        assert_eq!(renumbered.len(), survivors.len(), "{}", name);
+        assert_answers_like_scan(&compacted, &renumbered, name);
+    }
+
+    let options = BuildOptions { store_doc_ids: false, ..BuildOptions::default() };
+    let without_ids = build_aggregation_index_tree(pairs, &options).unwrap();
+    assert!(matches!(without_ids.compact(&RoaringBitmap::new()), Err(AitError::DocIdsNotStored)));
+}
+
+// Merging two trees with disjoint doc ids answers like a scan over both,
+// whichever goes first and however the values interleave. A shared doc id
+// is refused, and a side without doc ids leaves a value-only tree.
+#[test]
+fn merged_trees_match_scan_of_both() {
+    let pairs = shuffled_pairs(455, DOCS);
+    let build = |pairs: &[(u32, f64)]| build_aggregation_index_tree(pairs.to_vec(), &BuildOptions::default()).unwrap();
+    let (low, high): (Vec<_>, Vec<_>) = pairs.iter().partition(|&&(_, value)| value < 0.0);
+    for (name, split) in [("interleaved", pairs.len() / 3), ("empty side", 0), ("by value", low.len())] {
+        let (left, right) = if name == "by value" {
+            (low.clone(), high.clone())
+        } else {
+            (pairs[..split].to_vec(), pairs[split..].to_vec())
+        };
+        let (left_tree, right_tree) = (build(&left), build(&right));
+        assert_answers_like_scan(&left_tree.merge(&right_tree).unwrap(), &pairs, name);
+        assert_answers_like_scan(&right_tree.merge(&left_tree).unwrap(), &pairs, &format!("{} reversed", name));
+    }
+
+    let (left, right) = pairs.split_at(DOCS as usize / 2);
+    let mut overlapping = right.to_vec();
+    overlapping.push((left[10].0, 1e6));
+    let shared = left[10].0;
+    assert!(matches!(build(left).merge(&build(&overlapping)), Err(AitError::DuplicateDocId(doc_id)) if doc_id == shared));
+
+    let options = BuildOptions { store_doc_ids: false, ..BuildOptions::default() };
+    let without_ids = build_aggregation_index_tree(right.to_vec(), &options).unwrap();
+    let merged = build(left).merge(&without_ids).unwrap();
+    assert!(!merged.has_doc_ids);
+    assert_eq!(summary(merged.get_global_aggregations()), summary(scan_pairs(&pairs, |_, _| true)));
+    assert_eq!(summary(merged.query_value_range(-100.0, 250.0)),
+               summary(scan_pairs(&pairs, |_, value| (-100.0..=250.0).contains(&value))));
+}
+
+// Stitching value-range partitions answers like a scan over all of them on
+// every strategy, with empty partitions, splits inside runs of one value, and
+// partitions without position maps. Out-of-order partitions and shared doc
+// ids are refused.
+#[test]
+fn stitched_partitions_match_scan() {
+    let pairs = shuffled_pairs(456, DOCS);
+    let mut by_value = pairs.clone();
+    by_value.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let len = by_value.len();
+    let build = |part: &[(u32, f64)], options: &BuildOptions| {
+        build_aggregation_index_tree(part.to_vec(), options).unwrap()
+    };
+    let without_map = BuildOptions { position_map: false, ..BuildOptions::default() };
+    for (cuts, options) in [
+        (vec![], BuildOptions::default()),
+        (vec![len / 2], BuildOptions::default()),
+        (vec![0, 1, len / 3, len / 3, len / 3 + 1, len - 1], BuildOptions::default()),
+        (vec![len / 4, len / 2, 3 * len / 4], without_map),
+    ] {
+        let bounds: Vec<usize> = std::iter::once(0).chain(cuts.iter().copied()).chain(std::iter::once(len)).collect();
+        let parts = bounds.windows(2).map(|pair| build(&by_value[pair[0]..pair[1]], &options)).collect();
+        let stitched = AggregationIndexTree::stitch(parts).unwrap();
+        let label = format!("cuts {:?}", cuts);
+        assert_answers_like_scan(&stitched, &pairs, &label);
+        for (name, bitmap) in [("random 5%", random_bitmap(5, 0.05)), ("random 95%", random_bitmap(6, 0.95))] {
+            let expected = summary(scan_pairs(&pairs, |doc_id, _| bitmap.contains(doc_id)));
+            for &strategy in QueryStrategy::ALL.iter() {
+                assert_eq!(summary(stitched.query_with_strategy(&bitmap, strategy).unwrap()), expected,
+                           "{} {} with {}", label, name, strategy.name());
+            }
+        }
+    }
+
+    let (low, high) = by_value.split_at(len / 2);
+    let parts = vec![build(high, &BuildOptions::default()), build(low, &BuildOptions::default())];
+    assert!(matches!(AggregationIndexTree::stitch(parts), Err(AitError::UnorderedPartitions(1))));
+    let mut shared = high.to_vec();
+    shared.push((low[0].0, 1e6));
+    let parts = vec![build(low, &BuildOptions::default()), build(&shared, &BuildOptions::default())];
+    assert!(matches!(AggregationIndexTree::stitch(parts), Err(AitError::DuplicateDocId(_))));
+}
+
+// A rerun resumes exactly the partitions saved intact for the same input and
+// rebuilds the rest: ones without a marker, with altered saved values, with
+// changed input, or built with other doc-id options. Stitched results match a
+// scan every time.
+#[test]
+fn checkpointed_builds_resume_intact_partitions() {
+    let mut pairs = shuffled_pairs(457, DOCS);
+    pairs.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let dir = test_dir("checkpoint");
+    let checkpoint = BuildCheckpoint { dir: dir.clone() };
+    let options = BuildOptions::default();
+    let build = |pairs: &[(u32, f64)], options: &BuildOptions| {
+        let chunks: Vec<&[(u32, f64)]> = pairs.chunks(pairs.len().div_ceil(5)).collect();
+        let (parts, resumed) = checkpoint.build_partitions(&chunks, options).unwrap();
+        (AggregationIndexTree::stitch(parts).unwrap(), resumed)
+    };
+
+    let (stitched, resumed) = build(&pairs, &options);
+    assert_eq!(resumed, 0);
+    assert_answers_like_scan(&stitched, &pairs, "first run");
+    let (stitched, resumed) = build(&pairs, &options);
+    assert_eq!(resumed, 5);
+    assert_answers_like_scan(&stitched, &pairs, "resumed run");
+
+    fs::remove_file(checkpoint.marker_path(1)).unwrap();
+    let slab = checkpoint.partition_dir(2).join(INDEX_VALUES_SLAB);
+    let mut bytes = fs::read(&slab).unwrap();
+    bytes[..8].copy_from_slice(&(-1e9f64).to_le_bytes());
+    fs::write(&slab, bytes).unwrap();
+    let changed = pairs.len() * 3 / 5 + 7;
+    pairs[changed].1 += 0.5;
+    let (stitched, resumed) = build(&pairs, &options);
+    assert_eq!(resumed, 2);
+    assert_answers_like_scan(&stitched, &pairs, "partly resumed run");
+
+    let value_only = BuildOptions { store_doc_ids: false, ..options };
+    let (stitched, resumed) = build(&pairs, &value_only);
+    assert_eq!(resumed, 0);
+    assert_eq!(summary(stitched.get_global_aggregations()), summary(scan_pairs(&pairs, |_, _| true)));
+    assert_eq!(build(&pairs, &value_only).1, 5);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+const LEVELS: [&str; 4] = ["info", "warn", "error", "débug"];
+
+// DOCS docs over two tree fields, a zone-map field and a term field, with
+// their doc-ordered values for brute-force checks
+fn conjunction_fields() -> (FieldIndexSet, Vec<(&'static str, Vec<f64>)>) {
+    let mut rng = StdRng::seed_from_u64(475);
+    let columns = vec![
+        ("size", (0..DOCS).map(|doc_id| ((doc_id * 7919) % 1000) as f64).collect::<Vec<f64>>()),
+        ("latency", (0..DOCS).map(|_| rng.gen_range(-500..500) as f64).collect()),
+        ("score", (0..DOCS).map(|doc_id| ((doc_id * 31) % 977) as f64).collect()),
+    ];
+    let ranges = FieldQueryMix { global: 0, filtered: 0, range: 1 };
+    let globals = FieldQueryMix { global: 1, filtered: 0, range: 0 };
+    let mut set = FieldIndexSet::default();
+    for (name, values) in &columns {
+        let mix = if *name == "score" { &globals } else { &ranges };
+        set.add_field(name, values, mix, &BuildOptions::default()).unwrap();
+    }
+    set.add_terms("level", TermColumn::from_values((0..DOCS).map(|doc_id| LEVELS[(doc_id % 7 % 4) as usize])));
+    (set, columns)
+}
+
+// Docs whose values lie in every (field, min, max) range, by scanning
+fn scan_ranges(columns: &[(&str, Vec<f64>)], ranges: &[(&str, f64, f64)]) -> RoaringBitmap {
+    (0..DOCS)
+        .filter(|&doc_id| {
+            ranges.iter().all(|&(field, min, max)| {
+                let (_, values) = columns.iter().find(|(name, _)| *name == field).unwrap();
+                (min..=max).contains(&values[doc_id as usize])
+            })
+        })
+        .collect()
+}
+
+// Conjunctions of ranges over tree and zone-map fields match the docs a scan
+// finds, for one to three fields, empty and reversed ranges, and ranges
+// matching everything. An unknown field is an error.
+#[test]
+fn multi_range_matches_scan() {
+    let (set, columns) = conjunction_fields();
+    assert!(matches!(set.field("size"), Some(FieldIndex::Tree(_))));
+    assert!(matches!(set.field("score"), Some(FieldIndex::ZoneMap(_))));
+    let mut rng = StdRng::seed_from_u64(476);
+    let mut queries: Vec<Vec<(&str, f64, f64)>> = vec![
+        vec![("size", 100.0, 400.0)],
+        vec![("score", -1e9, 1e9), ("latency", -1e9, 1e9)],
+        vec![("size", 10.0, 10.0), ("latency", 0.0, 499.0)],
+        vec![("size", 500.0, 100.0), ("score", 0.0, 976.0)],
+        vec![("latency", 600.0, 700.0), ("size", 0.0, 999.0), ("score", 0.0, 976.0)],
+    ];
+    for _ in 0..20 {
+        let fields = rng.gen_range(1..=3);
+        queries.push(columns[..fields].iter().map(|&(name, _)| {
+            let low: f64 = rng.gen_range(-500.0..1000.0);
+            (name, low.floor(), (low + rng.gen_range(0.0..600.0)).floor())
+        }).collect());
+    }
+    for ranges in &queries {
+        assert_eq!(set.query_multi_range(ranges).unwrap(), scan_ranges(&columns, ranges), "{:?}", ranges);
+    }
+    assert!(set.query_multi_range(&[("size", 0.0, 1.0), ("missing", 0.0, 1.0)]).is_err());
+}
+
+// Whatever order conjunctions are given in, they run most selective first and
+// match the docs a scan finds, whether later steps intersect or probe. Tree
+// and term estimates are exact counts. An empty intermediate result ends the
+// plan early.
+#[test]
+fn conjunction_order_doesnt_change_results() {
+    let (set, columns) = conjunction_fields();
+    let range = |field: &str, min: f64, max: f64| Predicate::Range { field: field.to_string(), min, max };
+    let term = |term: &str| Predicate::Term { field: "level".to_string(), term: term.to_string() };
+    let scan = |predicates: &[Predicate]| -> RoaringBitmap {
+        let mut matched: RoaringBitmap = (0..DOCS).collect();
+        for predicate in predicates {
+            matched &= match predicate {
+                Predicate::Range { field, min, max } => scan_ranges(&columns, &[(field, *min, *max)]),
+                Predicate::Term { term, .. } => {
+                    (0..DOCS).filter(|&doc_id| LEVELS[(doc_id % 7 % 4) as usize] == term).collect()
+                }
+            };
+        }
+        matched
+    };
+    let conjunctions = [
+        vec![range("latency", -500.0, 300.0), range("size", 10.0, 10.0), term("warn")],
+        vec![range("latency", -500.0, 400.0), range("size", 0.0, 500.0), range("score", 100.0, 900.0)],
+        vec![term("error"), range("score", 0.0, 50.0), range("size", 900.0, 999.0), term("error")],
+        vec![term("missing"), range("size", 0.0, 999.0), range("latency", -500.0, 499.0)],
+    ];
+    for predicates in &conjunctions {
+        let expected = scan(predicates);
+        let mut reordered = predicates.clone();
+        for rotation in 0..predicates.len() {
+            reordered.rotate_left(1);
+            reordered.swap(0, rotation % predicates.len());
+            let (matched, plan) = set.query_conjunction(&reordered).unwrap();
+            assert_eq!(matched, expected, "{}", plan);
+            assert!(plan.steps.windows(2).all(|pair| pair[0].estimate.docs <= pair[1].estimate.docs), "{}", plan);
+            for step in plan.steps.iter().filter(|_| rotation == 0) {
+                if step.estimate.exact {
+                    assert_eq!(step.estimate.docs, scan(std::slice::from_ref(&step.predicate)).len(), "{}", plan);
+                }
+            }
+        }
+    }
+
+    let (_, plan) = set.query_conjunction(&conjunctions[0]).unwrap();
+    let methods = |plan: &ConjunctionPlan| -> Vec<StepMethod> {
+        plan.steps.iter().map(|step| step.executed.unwrap().0).collect()
+    };
+    // Few candidates are probed even against a tree's costlier lookups
+    assert_eq!(methods(&plan), [StepMethod::Intersect, StepMethod::Probe, StepMethod::Probe], "{}", plan);
+    // Half the docs cost more to probe in a tree than materializing its range,
+    // but not in the zone-map column
+    let (_, plan) = set.query_conjunction(&conjunctions[1]).unwrap();
+    let order: Vec<&Predicate> = plan.steps.iter().map(|step| &step.predicate).collect();
+    assert_eq!(order, [&conjunctions[1][1], &conjunctions[1][2], &conjunctions[1][0]], "{}", plan);
+    assert_eq!(methods(&plan), [StepMethod::Intersect, StepMethod::Probe, StepMethod::Intersect], "{}", plan);
+    assert!(!plan.steps[1].estimate.exact);
+    let (matched, plan) = set.query_conjunction(&conjunctions[3]).unwrap();
+    assert!(matched.is_empty());
+    assert_eq!(plan.steps[0].executed, Some((StepMethod::Intersect, 0)));
+    assert!(plan.steps[1..].iter().all(|step| step.executed.is_none()), "{}", plan);
+}
+
+// Time-partitioned ids are a permutation of the docs in timestamp order,
+// blocked by partition. Time ranges select the same docs as a scan of their
+// timestamps, as one id run, and expiry drops exactly the docs of partitions
+// ending by the cutoff. Negative timestamps and empty partitions included.
+#[test]
+fn time_partitioned_ids_match_scan() {
+    let mut rng = StdRng::seed_from_u64(479);
+    const PARTITION_MS: i64 = 60_000;
+    // Clustered around a few minutes with gaps between, so some partitions are empty
+    let doc_timestamps: Vec<i64> = (0..20_000)
+        .map(|_| [-3, 0, 1, 5, 6, 40][rng.gen_range(0..6)] * PARTITION_MS + rng.gen_range(0..PARTITION_MS / 2) * 2)
+        .collect();
+    let (ids, allocated) = TimePartitionedIds::allocate(&doc_timestamps, PARTITION_MS);
+
+    let mut sorted = allocated.clone();
+    sorted.sort_unstable();
+    assert!(sorted.iter().enumerate().all(|(i, &id)| id == i as u32), "allocated ids aren't a permutation");
+    for (doc, &id) in allocated.iter().enumerate() {
+        assert_eq!(ids.timestamps[id as usize], doc_timestamps[doc]);
+    }
+    assert!(ids.timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert_eq!(ids.partitions.len(), 6);
+    assert_eq!(ids.partitions.first().map(|partition| partition.ids.start), Some(0));
+    for (i, partition) in ids.partitions.iter().enumerate() {
+        assert!(ids.timestamps[partition.ids.start as usize..partition.ids.end as usize]
+            .iter()
+            .all(|timestamp| (partition.start_ms..partition.start_ms + PARTITION_MS).contains(timestamp)));
+        if let Some(next) = ids.partitions.get(i + 1) {
+            assert_eq!(partition.ids.end, next.ids.start);
+        }
+    }
+
+    let mut windows = vec![(i64::MIN, i64::MAX), (-10 * PARTITION_MS, -5 * PARTITION_MS), (10, 5),
+                           (2 * PARTITION_MS, 4 * PARTITION_MS), (0, 0), (-1, 1)];
+    windows.extend(doc_timestamps.iter().take(10).map(|&timestamp| (timestamp, timestamp)));
+    windows.extend((0..200).map(|_| {
+        let from = rng.gen_range(-4 * PARTITION_MS..42 * PARTITION_MS);
+        (from, from + rng.gen_range(0..8 * PARTITION_MS))
+    }));
+    for (from, to) in windows {
+        let expected: RoaringBitmap = doc_timestamps.iter().zip(&allocated)
+            .filter(|(timestamp, _)| (from..=to).contains(*timestamp))
+            .map(|(_, &id)| id)
+            .collect();
+        let actual = ids.ids_in_time_range(from, to);
+        assert_eq!(actual, expected, "{}..={}", from, to);
+        if let (Some(min), Some(max)) = (actual.min(), actual.max()) {
+            assert_eq!(actual.len(), (max - min + 1) as u64, "{}..={} isn't one run", from, to);
+        }
+    }
+
+    for cutoff in [i64::MIN, -3 * PARTITION_MS, -2 * PARTITION_MS, PARTITION_MS + 1, 2 * PARTITION_MS, i64::MAX / 2] {
+        let (partitions, expired) = ids.expired_ids(cutoff);
+        let partition_ends = |timestamp: i64| timestamp.div_euclid(PARTITION_MS) * PARTITION_MS + PARTITION_MS;
+        let expected: RoaringBitmap = doc_timestamps.iter().zip(&allocated)
+            .filter(|(&timestamp, _)| partition_ends(timestamp) <= cutoff)
+            .map(|(_, &id)| id)
+            .collect();
+        assert_eq!(expired, expected, "cutoff {}", cutoff);
+        assert_eq!(partitions, ids.partitions.iter().filter(|p| p.start_ms + PARTITION_MS <= cutoff).count());
+    }
+}
+
+// A value-ordered tree gives the doc at position i id i. Filters over caller
+// ids, translated through the mapping, aggregate like a scan of the caller's
+// docs on every strategy, and value-range filters become one id run.
+#[test]
+fn value_ordered_ids_match_scan() {
+    let mut pairs: Vec<(u32, f64)> = shuffled_pairs(480, DOCS).into_iter()
+        .filter(|&(doc_id, _)| doc_id % 5 != 1)
+        .map(|(doc_id, value)| (doc_id * 3, value))
+        .collect();
+    pairs.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let (ait, mapping) = build_value_ordered_tree(pairs.clone(), &BuildOptions::default()).unwrap();
+    assert!(ait.metadata.ids_in_value_order);
+    for (position, &(external, value)) in pairs.iter().enumerate() {
+        assert_eq!(ait.get_doc_id_at_position(position), position as u32);
+        assert_eq!(ait.get_value_at_position(position), value);
+        assert_eq!(mapping.internal_id(external), Some(position as u32));
+        assert_eq!(mapping.external_id(position as u32), Some(external));
+    }
+    assert_eq!((mapping.internal_id(1), mapping.external_id(pairs.len() as u32)), (None, None));
+
+    let in_range: RoaringBitmap = pairs.iter()
+        .filter(|&&(_, value)| (-100.0..=250.0).contains(&value))
+        .map(|&(doc_id, _)| doc_id)
+        .collect();
+    let translated = mapping.translate(&in_range);
+    assert_eq!(translated.len(), in_range.len());
+    assert_eq!(translated.max().unwrap() - translated.min().unwrap() + 1, translated.len() as u32);
+    let mut filters = planner_shapes();
+    filters.push(("value range", in_range));
+    filters.push(("every third id", (0..3 * DOCS).step_by(3).collect()));
+    for (name, external) in filters {
+        let expected = summary(scan_pairs(&pairs, |doc_id, _| external.contains(doc_id)));
+        let internal = mapping.translate(&external);
+        assert_eq!(summary(ait.query_with_bitmap(&internal).unwrap()), expected, "{}", name);
+        for &strategy in QueryStrategy::ALL.iter() {
+            assert_eq!(summary(ait.query_with_strategy(&internal, strategy).unwrap()), expected,
+                       "{} with {}", name, strategy.name());
+        }
+    }
+
+    let mut duplicated = pairs.clone();
+    duplicated.push((pairs[0].0, 1e6));
+    assert!(matches!(build_value_ordered_tree(duplicated, &BuildOptions::default()), Err(AitError::DuplicateDocId(_))));
+}
+
+// Cached translations equal fresh ones. Repeats hit and share the cached
+// bitmap, the least recently used entry goes first, an entry under the same
+// fingerprint for another bitmap isn't served, and a zero-capacity cache
+// translates every time. Concurrent lookups agree with the mapping.
+#[test]
+fn translation_cache_serves_fresh_translations() {
+    let pairs: Vec<(u32, f64)> = (0..DOCS).map(|doc_id| (doc_id * 2, ((doc_id * 7919) % 1000) as f64)).collect();
+    let mut by_value = pairs.clone();
+    by_value.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let (_, mapping) = build_value_ordered_tree(by_value, &BuildOptions::default()).unwrap();
+    let filters: Vec<RoaringBitmap> = (0..5).map(|seed| random_bitmap(seed, 0.02).iter().map(|id| id * 2).collect())
+        .collect();
+    let expected: Vec<RoaringBitmap> = filters.iter().map(|filter| mapping.translate(filter)).collect();
+    let translations = std::cell::Cell::new(0);
+    let translate = |external: &RoaringBitmap| {
+        translations.set(translations.get() + 1);
+        mapping.translate(external)
+    };
+
+    let cache = TranslationCache::new(3);
+    let first = cache.get_or_translate(&filters[0], translate);
+    assert_eq!(*first, expected[0]);
+    assert!(Arc::ptr_eq(&first, &cache.get_or_translate(&filters[0], translate)));
+    cache.get_or_translate(&filters[1], translate);
+    cache.get_or_translate(&filters[2], translate);
+    cache.get_or_translate(&filters[0], translate);
+    assert_eq!((cache.hits(), cache.misses(), translations.get()), (2, 3, 3));
+    // filters[1] is now the least recently used
+    cache.get_or_translate(&filters[3], translate);
+    assert_eq!(*cache.get_or_translate(&filters[0], translate), expected[0]);
+    assert_eq!(*cache.get_or_translate(&filters[1], translate), expected[1]);
+    assert_eq!((cache.hits(), cache.misses(), translations.get()), (3, 5, 5));
+    assert_eq!(cache.entries.lock().unwrap().len(), 3);
+
+    cache.entries.lock().unwrap().insert(bitmap_fingerprint(&filters[4]), CachedTranslation {
+        external: filters[0].clone(),
+        internal: Arc::new(expected[0].clone()),
+        last_used: u64::MAX,
+    });
+    assert_eq!(*cache.get_or_translate(&filters[4], translate), expected[4]);
+    assert_eq!(translations.get(), 6);
+
+    let uncached = TranslationCache::new(0);
+    for _ in 0..3 {
+        assert_eq!(*uncached.get_or_translate(&filters[0], translate), expected[0]);
+    }
+    assert_eq!((uncached.hits(), uncached.misses(), uncached.entries.lock().unwrap().len()), (0, 3, 0));
+
+    let shared = TranslationCache::new(2);
+    std::thread::scope(|scope| {
+        for thread in 0..4 {
+            let (shared, mapping, filters, expected) = (&shared, &mapping, &filters, &expected);
+            scope.spawn(move || {
+                for round in 0..20 {
+                    let i = (thread + round) % filters.len();
+                    let translated = shared.get_or_translate(&filters[i], |external| mapping.translate(external));
+                    assert_eq!(*translated, expected[i]);
+                }
+            });
+        }
+    });
+    assert_eq!(shared.hits() + shared.misses(), 80);
+}
+
+// Rows of an Arrow stream as export writes it: per batch, each column's
+// empty validity buffer then its little-endian data
+fn read_exported_arrow(bytes: &[u8]) -> Vec<ExportRow> {
+    let mut rows = Vec::new();
+    let mut columns = 0;
+    let mut at = 0;
+    loop {
+        assert_eq!(bytes[at..at + 4], [0xff; 4], "continuation marker at {}", at);
+        let len = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().unwrap()) as usize;
+        at += 8;
+        if len == 0 {
+            assert_eq!(at, bytes.len(), "bytes after the end of the stream");
+            return rows;
+        }
+        let message = FlatTable::root(&bytes[at..at + len]).unwrap();
+        let body = &bytes[at + len..at + len + message.i64(3).unwrap().unwrap_or(0) as usize];
+        at += len + body.len();
+        let header = message.table(2).unwrap().unwrap();
+        if message.u8(1).unwrap() == Some(1) {
+            let types: Vec<Option<u8>> = header.tables(1).unwrap().iter().map(|field| field.u8(2).unwrap()).collect();
+            assert!(types == [Some(2), Some(3)] || types == [Some(2), Some(3), Some(10)], "{:?}", types);
+            columns = types.len();
+            continue;
+        }
+        let len = header.i64(0).unwrap().unwrap() as usize;
+        let buffers = header.structs(2, 16).unwrap();
+        assert_eq!(buffers.len(), 2 * columns);
+        let column = |idx: usize, width: usize| -> Vec<&[u8]> {
+            let offset = i64::from_le_bytes(buffers[2 * idx + 1][0..8].try_into().unwrap()) as usize;
+            body[offset..offset + len * width].chunks_exact(width).collect()
+        };
+        let (doc_ids, values) = (column(0, 4), column(1, 8));
+        let times = if columns == 3 { column(2, 8) } else { Vec::new() };
+        for row in 0..len {
+            rows.push((u32::from_le_bytes(doc_ids[row].try_into().unwrap()),
+                       f64::from_le_bytes(values[row].try_into().unwrap()),
+                       times.get(row).map(|time| i64::from_le_bytes((*time).try_into().unwrap()))));
+        }
+    }
+}
+
+fn read_exported_csv(text: &str) -> Vec<ExportRow> {
+    text.lines().skip(1).map(|line| {
+        let fields: Vec<&str> = line.split(',').collect();
+        let timestamp = fields.get(2)
+            .map(|time| chrono::DateTime::parse_from_rfc3339(time).unwrap().timestamp_millis());
+        (fields[0].parse().unwrap(), fields[1].parse().unwrap(), timestamp)
+    }).collect()
+}
+
+// Exports in either format read back as exactly the selected docs' rows in
+// doc-id order, across Arrow batch boundaries and with or without timestamps.
+// Ids the tree doesn't hold are skipped, and a tree without doc ids refuses.
+#[test]
+fn exports_round_trip_selected_rows() {
+    let pairs: Vec<(u32, f64)> = shuffled_pairs(482, DOCS).into_iter()
+        .map(|(doc_id, value)| (doc_id, value / 3.0))
+        .collect();
+    let mut doc_values = vec![0.0; DOCS as usize];
+    pairs.iter().for_each(|&(doc_id, value)| doc_values[doc_id as usize] = value);
+    let timestamps: Vec<i64> = (0..DOCS as i64).map(|doc_id| 1_700_000_000_000 + doc_id * 1_237).collect();
+    let ait = build_aggregation_index_tree(pairs.clone(), &BuildOptions::default()).unwrap();
+    let dir = test_dir("export");
+    fs::create_dir_all(&dir).unwrap();
+    for (name, bitmap) in [
+        ("every doc", (0..DOCS).collect::<RoaringBitmap>()),
+        ("random 30% and ids past the tree", random_bitmap(482, 0.3) | (DOCS..DOCS + 100).collect::<RoaringBitmap>()),
+        ("empty", RoaringBitmap::new()),
+    ] {
+        for with_timestamps in [false, true] {
+            let expected: Vec<ExportRow> = bitmap.iter()
+                .filter(|&doc_id| doc_id < DOCS)
+                .map(|doc_id| {
+                    (doc_id, doc_values[doc_id as usize], with_timestamps.then(|| timestamps[doc_id as usize]))
+                })
+                .collect();
+            let column = with_timestamps.then_some(&timestamps[..]);
+            let label = format!("{}{}", name, if with_timestamps { " with timestamps" } else { "" });
+
+            let path = dir.join("rows.csv");
+            let written = ait.export(&bitmap, ExportFormat::Csv, &path, column).unwrap();
+            assert_eq!(written, expected.len() as u64, "{}", label);
+            assert_eq!(read_exported_csv(&fs::read_to_string(&path).unwrap()), expected, "{} as CSV", label);
+            let path = dir.join("rows.arrow");
+            let written = ait.export(&bitmap, ExportFormat::Arrow, &path, column).unwrap();
+            assert_eq!(written, expected.len() as u64, "{}", label);
+            assert_eq!(read_exported_arrow(&fs::read(&path).unwrap()), expected, "{} as Arrow", label);
+        }
+    }
+
+    let options = BuildOptions { store_doc_ids: false, ..BuildOptions::default() };
+    let without_ids = build_aggregation_index_tree(pairs, &options).unwrap();
+    assert!(without_ids.export(&(0..10).collect(), ExportFormat::Csv, &dir.join("none.csv"), None).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Exporter keeping every finished span for inspection
+#[cfg(feature = "otel")]
+#[derive(Debug, Clone, Default)]
+struct CollectedSpans(Arc<std::sync::Mutex<Vec<opentelemetry_sdk::trace::SpanData>>>);
+
+#[cfg(feature = "otel")]
+impl opentelemetry_sdk::trace::SpanExporter for CollectedSpans {
+    async fn export(&self, batch: Vec<opentelemetry_sdk::trace::SpanData>) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.0.lock().unwrap().extend(batch);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "otel")]
+#[test]
+fn spans_export_strategy_and_cost() {
+    use tracing_subscriber::layer::SubscriberExt;
+    let spans = CollectedSpans::default();
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_simple_exporter(spans.clone())
+        .build();
+    let subscriber = tracing_subscriber::registry().with(crate::otel::tracing_layer(&provider));
+    tracing::subscriber::with_default(subscriber, || {
+        let _request = tracing::info_span!("request").entered();
+        let ait = planner_tree(PlannerThresholds::default());
+        ait.query_with_strategy(&random_bitmap(459, 0.3), QueryStrategy::Complement).unwrap();
+        ait.query_value_range(100.0, 199.0);
+        ait.query_value_range(-2.0, -1.0);
+    });
+
+    let spans = spans.0.lock().unwrap();
+    let attribute = |name: &str, nth: usize, key: &str| {
+        let span = spans.iter().filter(|span| span.name == name).nth(nth).unwrap();
+        span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string())
+    };
+    let request = spans.iter().find(|span| span.name == "request").unwrap();
+    for name in ["ait.build", "ait.query.bitmap", "ait.query.range"] {
+        let span = spans.iter().find(|span| span.name == name).unwrap();
+        assert_eq!(span.parent_span_id, request.span_context.span_id(), "{} under the request", name);
+    }
+    assert_eq!(attribute("ait.build", 0, "rows").as_deref(), Some(DOCS.to_string().as_str()));
+    assert_eq!(attribute("ait.query.bitmap", 0, "strategy").as_deref(), Some("complement"));
+    assert_eq!(attribute("ait.query.bitmap", 0, "cardinality"),
+               Some(random_bitmap(459, 0.3).len().to_string()));
+    assert_eq!(attribute("ait.query.range", 0, "cardinality").as_deref(), Some("100"));
+    assert_ne!(attribute("ait.query.range", 0, "nodes_visited").as_deref(), Some("0"));
+    assert_eq!(attribute("ait.query.range", 1, "cardinality").as_deref(), Some("0"));
+    assert_eq!(attribute("ait.query.range", 1, "nodes_visited").as_deref(), Some("0"));
+}
+
+// Selection vectors with every remainder past the four-wide chunks, over
+// negative and repeated values, aggregate like the naive scan on each path
+#[test]
+fn selection_kernels_match_naive_scan() {
+    let mut rng = StdRng::seed_from_u64(431);
+    let reference = ColumnarStorage { values: (0..64).map(|_| rng.gen_range(-8..8) as f64).collect() };
+    for len in [0, 1, 2, 3, 4, 5, 6, 7, 32, 33, 34, 35] {
+        let bitmap: RoaringBitmap = (0..64).filter(|_| rng.gen_bool(0.8)).take(len).collect();
+        let selection: Vec<u32> = bitmap.iter().collect();
+        assert_eq!(selection.len(), len);
+        let expected = summary(reference.query_with_bitmap_naive(&bitmap));
+        assert_eq!(summary(aggregate_selection_scalar(&reference.values, &selection)), expected, "scalar {}", len);
+        assert_eq!(summary(aggregate_selection(&reference.values, &selection)), expected, "dispatched {}", len);
+        assert_eq!(summary(reference.query_with_selection_vector(&bitmap)), expected, "selection vector {}", len);
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 is available and every selected id is below 64
+            let actual = unsafe { aggregate_selection_avx2(&reference.values, &selection) };
+            assert_eq!(summary(actual), expected, "avx2 {}", len);
+        }
+    }
+
+    // An id past the values panics on the checked path instead of gathering
+    for selection in [vec![1, 2, 3, 64], vec![0, 1, 2, 3, 4, 5, 6, u32::MAX]] {
+        let values = reference.values.clone();
+        assert!(std::panic::catch_unwind(|| aggregate_selection(&values, &selection)).is_err());
+    }
+}
+
+// Position map entries pointing outside any leaf, or at another position in a
+// valid leaf, fall back to tree traversal with unchanged answers; verifying
+// counts them and rebuilding clears them
+#[test]
+fn corrupt_position_map_falls_back_and_repairs() {
+    let pairs = shuffled_pairs(458, DOCS);
+    let mut ait = build_aggregation_index_tree(pairs.clone(), &BuildOptions::default()).unwrap();
+    let stale = ait.position_map[DOCS as usize * 3 / 4];
+    for (pos, corrupt) in [(0, (usize::MAX, 0)), (DOCS as usize / 4, stale), (DOCS as usize / 2, (0, 0)),
+                           (DOCS as usize - 1, (1, usize::MAX))] {
+        ait.position_map[pos] = corrupt;
+    }
+    assert_eq!(ait.verify_position_map(), 4);
+    assert_eq!(ait.position_map_fallbacks(), 0);
+    let stale_doc = ait.get_doc_id_at_position(DOCS as usize / 4);
+    assert_eq!(ait.doc_id_map[&stale_doc], DOCS as usize / 4);
+    assert_eq!(ait.position_map_fallbacks(), 1);
+
+    let every_doc: RoaringBitmap = (0..DOCS).collect();
+    for strategy in [QueryStrategy::Sequential, QueryStrategy::Parallel] {
+        assert_eq!(summary(ait.query_with_strategy(&every_doc, strategy).unwrap()),
+                   summary(scan_pairs(&pairs, |_, _| true)), "{}", strategy.name());
+    }
+    assert!(ait.position_map_fallbacks() >= 4);
+    assert_answers_like_scan(&ait, &pairs, "corrupt position map");
+
+    // Repair writes a rebuilt copy, and never over the index it reads
+    let dir = test_dir("corrupt_position_map");
+    let repaired_dir = test_dir("corrupt_position_map_repaired");
+    ait.save(&dir).unwrap();
+    assert!(repair_index(&dir, &dir, OutputFormat::Quiet).is_err());
+    assert_answers_like_scan(&AggregationIndexTree::load(&dir).unwrap(), &pairs, "saved with a corrupt map");
+    repair_index(&dir, &repaired_dir, OutputFormat::Quiet).unwrap();
+    let repaired = AggregationIndexTree::load(&repaired_dir).unwrap();
+    assert_eq!(repaired.verify_position_map(), 0);
+    assert_answers_like_scan(&repaired, &pairs, "repaired copy");
+    assert_eq!(repaired.position_map_fallbacks(), 0);
+
+    ait.rebuild_position_map();
+    assert_eq!((ait.verify_position_map(), ait.position_map_fallbacks()), (0, 0));
+    assert_answers_like_scan(&ait, &pairs, "rebuilt position map");
+    assert_eq!(ait.position_map_fallbacks(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&repaired_dir).unwrap();
+}
+
+// Sparse doc ids skip the doc-order column rather than allocating their whole
+// span, and a column ending at doc id u32::MAX scans like the tree
+#[test]
+fn doc_values_skip_sparse_ids_and_reach_u32_max() {
+    let sparse = AitBuilder::new().keep_doc_values(true).build(vec![(0, 1.0), (4_000_000_000, 2.0)]).unwrap();
+    assert!(sparse.doc_values.is_none());
+    let bitmap: RoaringBitmap = [0, 4_000_000_000].into_iter().collect();
+    assert_eq!(summary(sparse.query_with_strategy(&bitmap, QueryStrategy::ColumnarScan).unwrap()),
+               summary(scan_pairs(&[(0, 1.0), (4_000_000_000, 2.0)], |_, _| true)));
+
+    // Every third id from u32::MAX down, so the column has gaps but is kept
+    let pairs: Vec<(u32, f64)> = (0..50_000u32).map(|i| (u32::MAX - 3 * i, (i % 97) as f64 - 40.0)).collect();
+    let ait = AitBuilder::new().leaf_size(64).keep_doc_values(true).build(pairs.clone()).unwrap();
+    let column = ait.doc_values.as_ref().unwrap();
+    assert!(column.present.is_some());
+    let mut rng = StdRng::seed_from_u64(483);
+    let random: RoaringBitmap = (u32::MAX - 200_000..=u32::MAX).filter(|_| rng.gen_bool(0.3)).collect();
+    for (name, bitmap) in [
+        ("last id", std::iter::once(u32::MAX).collect::<RoaringBitmap>()),
+        ("top 64K ids", (u32::MAX - 65_535..=u32::MAX).collect()),
+        ("random 30%", random),
+    ] {
+        assert_eq!(summary(ait.query_with_strategy(&bitmap, QueryStrategy::ColumnarScan).unwrap()),
+                   summary(scan_pairs(&pairs, |doc_id, _| bitmap.contains(doc_id))), "{}", name);
+    }
+}
+
+// Expiry drops each partition's docs once the TTL passes its end, only once,
+// and eviction drops cache entries no lookup used since the previous sweep
+#[test]
+fn expiry_and_cache_eviction_tasks() {
+    use std::sync::atomic::{AtomicI64, Ordering};
+    const PARTITION_MS: i64 = 1_000;
+    let mut rng = StdRng::seed_from_u64(469);
+    let timestamps: Vec<i64> = (0..20_000).map(|_| rng.gen_range(0..10 * PARTITION_MS)).collect();
+    let values: Vec<f64> = (0..timestamps.len()).map(|_| rng.gen_range(-50..50) as f64).collect();
+    let (ids, allocated) = TimePartitionedIds::allocate(&timestamps, PARTITION_MS);
+    let mut index = SegmentedIndex::new(BuildOptions::default(), 1_000, MergePolicy::default());
+    for (&id, &value) in allocated.iter().zip(&values).take(500) {
+        index.insert(id, value).unwrap();
+    }
+    let batch: Vec<(u32, f64)> = allocated.iter().copied().zip(values.iter().copied()).skip(500).collect();
+    index.ingest_batch(batch).unwrap();
+    let index = Arc::new(RwLock::new(index));
+
+    let now = Arc::new(AtomicI64::new(0));
+    let clock = Arc::clone(&now);
+    let mut expiry = ExpiryTask::new(Arc::clone(&index), Arc::new(ids), Duration::from_millis(2 * PARTITION_MS as u64),
+                                     Duration::ZERO, Box::new(move || clock.load(Ordering::Relaxed)));
+    for (now_ms, busy) in [(0, false), (3 * PARTITION_MS - 1, false), (3 * PARTITION_MS, true), (3 * PARTITION_MS, false),
+                           (7 * PARTITION_MS + 500, true), (20 * PARTITION_MS, true), (30 * PARTITION_MS, false)] {
+        now.store(now_ms, Ordering::Relaxed);
+        assert_eq!(expiry.run(), Ok(busy), "at {} ms", now_ms);
+        let cutoff = now_ms - 2 * PARTITION_MS;
+        let pairs: Vec<(u32, f64)> = (0..timestamps.len())
+            .filter(|&doc| timestamps[doc].div_euclid(PARTITION_MS) * PARTITION_MS + PARTITION_MS > cutoff)
+            .map(|doc| (allocated[doc], values[doc]))
+            .collect();
+        let index = index.read().unwrap();
+        assert_eq!(summary(index.get_global_aggregations()), summary(scan_pairs(&pairs, |_, _| true)), "at {} ms", now_ms);
+        let filter = random_bitmap(now_ms as u64, 0.3);
+        assert_eq!(summary(index.query_with_bitmap(&filter).unwrap()),
+                   summary(scan_pairs(&pairs, |doc_id, _| filter.contains(doc_id))), "filtered at {} ms", now_ms);
+    }
+
+    let cache = Arc::new(TranslationCache::new(4));
+    let filters: Vec<RoaringBitmap> = (0..3).map(|seed| random_bitmap(seed, 0.01)).collect();
+    let mut eviction = CacheEvictionTask::new(Arc::clone(&cache), Duration::ZERO);
+    for filter in &filters {
+        cache.get_or_translate(filter, |filter| filter.clone());
+    }
+    assert_eq!(eviction.run(), Ok(false));
+    cache.get_or_translate(&filters[1], |_| unreachable!("cached"));
+    assert_eq!(eviction.run(), Ok(true));
+    assert_eq!(cache.entries.lock().unwrap().len(), 1);
+    cache.get_or_translate(&filters[1], |_| unreachable!("kept by the sweep"));
+    assert_eq!(eviction.run(), Ok(false));
+    assert_eq!(eviction.run(), Ok(true));
+    assert!(cache.entries.lock().unwrap().is_empty());
+}
+
+// Leaf sizes outside [MIN_LEAF_SIZE, MAX_LEAF_SIZE] build as the nearest bound
+#[test]
+fn out_of_range_leaf_sizes_are_clamped() {
+    let pairs = shuffled_pairs(491, 5_000);
+    for (leaf_size, clamped) in [(0, MIN_LEAF_SIZE), (1, MIN_LEAF_SIZE), (MIN_LEAF_SIZE, MIN_LEAF_SIZE), (64, 64),
+                                 (MAX_LEAF_SIZE, MAX_LEAF_SIZE), (MAX_LEAF_SIZE + 1, MAX_LEAF_SIZE),
+                                 (usize::MAX, MAX_LEAF_SIZE)] {
+        let ait = AitBuilder::new().leaf_size(leaf_size).build(pairs.clone()).unwrap();
+        let reference = AitBuilder::new().leaf_size(clamped).build(pairs.clone()).unwrap();
+        assert_eq!(ait.metadata.config.leaf_size, clamped, "leaf size {}", leaf_size);
+        assert_eq!((ait.nodes.len(), ait.leaf_count), (reference.nodes.len(), reference.leaf_count), "leaf size {}", leaf_size);
+        assert_answers_like_scan(&ait, &pairs, &format!("leaf size {}", leaf_size));
+    }
+}
+
+// Rewrite a saved index's nodes as a chain of `internal` nodes, each with a
+// one-value leaf on the left, so the last leaf sits `internal + 1` levels deep.
+// The index must hold `internal + 1` docs.
+fn write_node_chain(dir: &Path, internal: usize) {
+    let count = internal + 1;
+    // Internal node i covers positions i.., so build those suffixes back to front
+    let mut suffixes = vec![NodeAggregations::empty(); count];
+    let mut suffix = NodeAggregations::empty();
+    for pos in (0..count).rev() {
+        suffix.add_value(pos as f64);
+        suffixes[pos] = suffix;
+    }
+    let mut nodes = Vec::new();
+    nodes.extend_from_slice(&((internal + count) as u64).to_le_bytes());
+    for (i, aggregations) in suffixes.iter().enumerate().take(internal) {
+        let right = if i + 1 < internal { i + 1 } else { internal + count - 1 };
+        nodes.push(NODE_TAG_INTERNAL);
+        nodes.extend_from_slice(&(i as f64 + 1.0).to_le_bytes());
+        nodes.extend_from_slice(&((internal + i) as u64).to_le_bytes());
+        nodes.extend_from_slice(&(right as u64).to_le_bytes());
+        write_aggregations(&mut nodes, aggregations).unwrap();
+    }
+    for pos in 0..count {
+        let mut aggregations = NodeAggregations::empty();
+        aggregations.add_value(pos as f64);
+        nodes.push(NODE_TAG_LEAF);
+        nodes.extend_from_slice(&(pos as u64).to_le_bytes());
+        nodes.extend_from_slice(&1u64.to_le_bytes());
+        write_aggregations(&mut nodes, &aggregations).unwrap();
+    }
+    fs::write(dir.join(INDEX_NODES_FILE), nodes).unwrap();
+}
+
+// Loading rejects node chains deeper than MAX_TREE_HEIGHT, however deep,
+// instead of overflowing the stack walking them; one at the cap loads
+#[test]
+fn loads_reject_trees_past_the_height_cap() {
+    let dir = test_dir("height_cap");
+    for (internal, loads) in [(MAX_TREE_HEIGHT - 1, true), (MAX_TREE_HEIGHT, false), (1_000_000, false)] {
+        let pairs: Vec<(u32, f64)> = (0..=internal as u32).map(|doc_id| (doc_id, doc_id as f64)).collect();
+        build_aggregation_index_tree(pairs.clone(), &BuildOptions::default()).unwrap().save(&dir).unwrap();
+        write_node_chain(&dir, internal);
+        match AggregationIndexTree::load(&dir) {
+            Ok(ait) => {
+                assert!(loads, "{} levels loaded", internal + 1);
+                assert_eq!(summary(ait.query_value_range(10.0, 40.0)),
+                           summary(scan_pairs(&pairs, |_, value| (10.0..=40.0).contains(&value))));
+            }
+            Err(err) => {
+                assert!(!loads, "{} levels: {}", internal + 1, err);
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+                assert!(err.to_string().contains("deeper than"), "{}", err);
+            }
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Derived metrics parse with the usual precedence and evaluate over the field
+// aggregates, undefined on division by zero; malformed expressions and unknown
+// names are errors
+#[test]
+fn derived_metrics_parse_and_evaluate() {
+    let a: Vec<f64> = (1..=10).map(f64::from).collect();
+    let mut set = FieldIndexSet::default();
+    let ranges = FieldQueryMix { global: 0, filtered: 0, range: 1 };
+    let globals = FieldQueryMix { global: 1, filtered: 0, range: 0 };
+    set.add_field("a", &a, &ranges, &BuildOptions::default()).unwrap();
+    set.add_field("b", &[0.0; 10], &globals, &BuildOptions::default()).unwrap();
+    let evaluate = |text: &str, bitmap: Option<&RoaringBitmap>| {
+        let metric: DerivedMetric = text.parse().unwrap();
+        set.evaluate(&[metric], bitmap).map(|values| values[0])
+    };
+
+    for (text, expected) in [
+        ("1 + 2 * 3", Some(7.0)),
+        ("(1 + 2) * 3", Some(9.0)),
+        ("10 - 4 - 3", Some(3.0)),
+        ("8 / 4 / 2", Some(1.0)),
+        ("-2 * 3 + 1", Some(-5.0)),
+        ("-(2 + 3)", Some(-5.0)),
+        ("count", Some(10.0)),
+        ("count()", Some(10.0)),
+        ("sum(a) / count", Some(5.5)),
+        ("avg(a) * 2", Some(11.0)),
+        ("max(a) - min(a)", Some(9.0)),
+        ("sum(a) / sum(b)", None),
+        ("sum(a) / (count - count)", None),
+        ("1 + sum(a) / max(b)", None),
+    ] {
+        assert_eq!(evaluate(text, None), Ok(expected), "{}", text);
+    }
+    // Over no docs, sums and counts are zero and the rest undefined
+    let none = RoaringBitmap::new();
+    for (text, expected) in [("count", Some(0.0)), ("sum(a)", Some(0.0)), ("min(a)", None), ("avg(a)", None)] {
+        assert_eq!(evaluate(text, Some(&none)), Ok(expected), "{} over no docs", text);
+    }
+    let some: RoaringBitmap = [1, 3].into_iter().collect();
+    assert_eq!(evaluate("sum(a) / count + max(b)", Some(&some)), Ok(Some(3.0)));
+
+    let err = evaluate("sum(c) + 1", None).unwrap_err();
+    assert!(err.contains("unknown field \"c\""), "{}", err);
+    for (text, message) in [
+        ("median(a)", "unknown aggregate \"median\""),
+        ("sum(a) 2", "unexpected 2"),
+        ("(1 + 2))", "unexpected ')'"),
+        ("count count", "unexpected \"count\""),
+        ("1 +", "unexpected end of expression"),
+        ("(1 + 2", "expected ')'"),
+        ("sum a", "expected '(' after \"sum\""),
+        ("sum()", "expected a field name in sum()"),
+        ("1 % 2", "unexpected character '%'"),
+    ] {
+        let err = text.parse::<DerivedMetric>().unwrap_err();
+        assert!(err.contains(message), "{}: {}", text, err);
+    }
+}
+
+// percentile_rank matches the scan over heavily tied values, below the
+// minimum, above the maximum and between values, and is None for a filter
+// that selects no indexed docs
+#[test]
+fn percentile_rank_matches_naive() {
+    let mut rng = StdRng::seed_from_u64(472);
+    let values: Vec<f64> = (0..DOCS).map(|_| rng.gen_range(0..50) as f64).collect();
+    let ait = build_aggregation_index_tree(values.iter().copied().enumerate().map(|(doc_id, value)| (doc_id as u32, value)).collect(),
+                                           &BuildOptions::default()).unwrap();
+    let reference = ColumnarStorage { values };
+    let bitmaps = [(0..DOCS).collect(), random_bitmap(4721, 0.3), random_bitmap(4722, 0.001), [17].into_iter().collect()];
+    for bitmap in &bitmaps {
+        for value in [-1.0, 0.0, 7.0, 24.5, 49.0, 50.0, 1e9] {
+            let rank = ait.percentile_rank(bitmap, value).unwrap();
+            let expected = reference.percentile_rank_naive(bitmap, value);
+            assert!(rank.is_some() && expected.is_some_and(|expected| (rank.unwrap() - expected).abs() < 1e-9),
+                    "{} docs, value {}: {:?} vs {:?}", bitmap.len(), value, rank, expected);
+        }
+        assert_eq!(ait.percentile_rank(bitmap, -1.0).unwrap(), Some(0.0));
+        assert_eq!(ait.percentile_rank(bitmap, 50.0).unwrap(), Some(100.0));
+    }
+
+    let outside: RoaringBitmap = (DOCS..DOCS + 100).collect();
+    for bitmap in [RoaringBitmap::new(), outside] {
+        assert_eq!(ait.percentile_rank(&bitmap, 7.0).unwrap(), None);
+        assert_eq!(reference.percentile_rank_naive(&bitmap, 7.0), None);
+    }
+}
+
+// Buckets as (term, count, min, max, sum, avg), for comparing runs
+fn bucket_fields(buckets: &[TermBucket]) -> Vec<(String, u32, f64, f64, f64, f64)> {
+    buckets.iter().map(|bucket| (bucket.term.clone(), bucket.count, bucket.min, bucket.max, bucket.sum, bucket.avg)).collect()
+}
+
+// Under a memory limit that holds only a few terms' state, terms_stats and
+// both streams take many passes yet return the single-pass buckets; a limit
+// below one term's state is an error
+#[test]
+fn terms_stats_under_memory_limit_match_single_pass() {
+    let mut rng = StdRng::seed_from_u64(490);
+    let values: Vec<f64> = (0..DOCS).map(|_| rng.gen_range(-1000..1000) as f64 / 8.0).collect();
+    let ait = build_aggregation_index_tree(values.iter().copied().enumerate().map(|(doc_id, value)| (doc_id as u32, value)).collect(),
+                                           &BuildOptions::default()).unwrap();
+    // 40 terms of skewed frequency, with codes out of term order
+    let names: Vec<String> = (0..40).map(|term| format!("term-{:02}", (term * 17) % 40)).collect();
+    let terms = TermColumn::from_values((0..DOCS).map(|_| names[rng.gen_range(0..40usize).min(rng.gen_range(0..40))].as_str()));
+    let per_term = std::mem::size_of::<NodeAggregations>();
+    let bitmap = random_bitmap(4901, 0.2);
+
+    for filter in [None, Some(&bitmap)] {
+        let single = ait.terms_stats(&terms, filter, None).unwrap();
+        assert_eq!(single.len(), 40);
+        for limit in [per_term, 3 * per_term + per_term / 2, 39 * per_term] {
+            let label = format!("{} bytes, filtered {}", limit, filter.is_some());
+            assert_eq!(bucket_fields(&ait.terms_stats(&terms, filter, Some(limit)).unwrap()), bucket_fields(&single), "{}", label);
+            let streamed: Vec<TermBucket> = ait.terms_stats_stream(&terms, filter, TermOrder::DocCount, Some(limit)).unwrap().collect();
+            assert_eq!(bucket_fields(&streamed), bucket_fields(&single), "{}", label);
+            let mut by_term: Vec<TermBucket> = ait.terms_stats_stream(&terms, filter, TermOrder::Term, Some(limit)).unwrap().collect();
+            assert!(by_term.windows(2).all(|pair| pair[0].term < pair[1].term), "{}", label);
+            by_term.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.term.cmp(&b.term)));
+            assert_eq!(bucket_fields(&by_term), bucket_fields(&single), "{}", label);
+        }
+    }
+    assert!(matches!(ait.terms_stats(&terms, None, Some(per_term - 1)),
+                     Err(AitError::MemoryLimitExceeded { needed, limit }) if needed == per_term && limit == per_term - 1));
+}
+
+// Removing docs from an index without doc ids is an error that leaves its
+// segments and buffer as they were
+#[test]
+fn remove_docs_without_doc_ids_changes_nothing() {
+    let options = BuildOptions { store_doc_ids: false, ..BuildOptions::default() };
+    let mut index = SegmentedIndex::new(options, 1_000, MergePolicy::default());
+    for batch in shuffled_pairs(4691, 9_000).chunks(3_000) {
+        index.ingest_batch(batch.to_vec()).unwrap();
+    }
+    for doc_id in 9_000..9_400 {
+        index.insert(doc_id, doc_id as f64).unwrap();
+    }
+    let (segments, buffered) = (index.segment_count(), index.buffer.clone());
+    let before = summary(index.get_global_aggregations());
+
+    let deleted: RoaringBitmap = (0..9_400).step_by(3).collect();
+    assert_eq!(index.remove_docs(&deleted), Err(AitError::DocIdsNotStored));
+    assert_eq!((index.segment_count(), &index.buffer), (segments, &buffered));
+    assert_eq!(summary(index.get_global_aggregations()), before);
+}
+
+// Single files whose node count or metadata length overruns the file are
+// rejected as invalid data before anything is allocated for them
+#[test]
+fn single_file_oversized_lengths_are_invalid() {
+    let options = BuildOptions { leaf_size: 100, ..BuildOptions::default() };
+    let ait = build_aggregation_index_tree(shuffled_pairs(4461, 5_000), &options).unwrap();
+    let dir = test_dir("oversized-single-file");
+    fs::create_dir_all(&dir).unwrap();
+    let v2 = legacy_index_bytes(&ait, 2);
+    let metadata_len = u64::from_le_bytes(v2[8..16].try_into().unwrap()) as usize;
+    let v2_count_at = 16 + metadata_len + 1;
+    let min_leaf = (1 + 8 + 28) as u64;
+    let cases = [
+        ("v1-count", legacy_index_bytes(&ait, 1), 8, u64::MAX),
+        ("v2-metadata", v2.clone(), 8, u64::MAX),
+        ("v2-metadata-past-end", v2.clone(), 8, (v2.len() - 16 + 1) as u64),
+        ("v2-count", v2.clone(), v2_count_at, u64::MAX),
+        ("v2-count-past-end", v2.clone(), v2_count_at, (v2.len() - v2_count_at - 8) as u64 / min_leaf + 1),
+    ];
+    for (label, mut bytes, offset, value) in cases {
+        bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        let path = dir.join(format!("{}.ait", label));
+        fs::write(&path, &bytes).unwrap();
+        let err = AggregationIndexTree::load(&path).err().unwrap_or_else(|| panic!("{} loaded", label));
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}: {}", label, err);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// A nodes file whose count overruns it is invalid data, both when loading
+// the index and when mapping it as a segment
+#[test]
+fn split_layout_oversized_node_count_is_invalid() {
+    let ait = build_aggregation_index_tree(shuffled_pairs(4471, 5_000), &BuildOptions::default()).unwrap();
+    let dir = test_dir("oversized-split-layout");
+    ait.save(&dir).unwrap();
+    let nodes_path = dir.join(INDEX_NODES_FILE);
+    let original = fs::read(&nodes_path).unwrap();
+    let past_end = (original.len() as u64 - 8) / (1 + 16 + 36) + 1;
+    for count in [u64::MAX, past_end] {
+        let mut bytes = original.clone();
+        bytes[..8].copy_from_slice(&count.to_le_bytes());
+        fs::write(&nodes_path, &bytes).unwrap();
+        let err = AggregationIndexTree::load(&dir).err().unwrap_or_else(|| panic!("{} loaded", count));
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}: {}", count, err);
+        let err = MappedSegment::open(&dir).err().unwrap_or_else(|| panic!("{} mapped", count));
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}: {}", count, err);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Zero iterations is refused when parsing, and averaging no timings is zero
+// rather than a division by zero
+#[test]
+fn zero_iterations_are_rejected() {
+    let err = <Args as clap::Parser>::try_parse_from(["ait", "--iterations", "0"]).unwrap_err();
+    assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+    assert!(<Args as clap::Parser>::try_parse_from(["ait", "--iterations", "1"]).is_ok());
+    assert_eq!(average_duration(&[]), Duration::ZERO);
+}
+
+// Filter percentages past 100 are refused when parsing
+#[test]
+fn filter_percentage_is_at_most_100() {
+    for (value, ok) in [("0", true), ("100", true), ("101", false)] {
+        let parsed = <Args as clap::Parser>::try_parse_from(["ait", "--filter-percentage", value]);
+        assert_eq!(parsed.is_ok(), ok, "{}", value);
+    }
+}
+
+// Histogram intervals must be positive and finite to parse
+#[test]
+fn histogram_interval_is_positive_and_finite() {
+    for (value, ok) in [("2.5", true), ("0", false), ("-1", false), ("NaN", false), ("inf", false), ("wide", false)] {
+        let parsed = <Args as clap::Parser>::try_parse_from(["ait", &format!("--histogram-interval={}", value)]);
+        assert_eq!(parsed.is_ok(), ok, "{}", value);
+    }
+}
+
+// Thresholds move to the cut between separable points, stay put when every
+// point is on one side, and among equally good cuts take the nearest
+#[test]
+fn fit_threshold_cases() {
+    assert_eq!(fit_threshold(&mut [], 7.0), 7.0);
+    let mut separable = [(40.0, true), (10.0, false), (30.0, true), (20.0, false)];
+    assert_eq!(fit_threshold(&mut separable, 100.0), 25.0);
+    assert_eq!(fit_threshold(&mut [(10.0, true), (20.0, true)], 3.0), 3.0);
+    assert_eq!(fit_threshold(&mut [(10.0, false), (20.0, false)], 50.0), 50.0);
+    // One of the two points at 20 is misclassified by any cut; 15, 20 and 25
+    // all get the rest right
+    let mut ties = [(10.0, false), (20.0, true), (20.0, false), (30.0, true)];
+    assert_eq!(fit_threshold(&mut ties, 100.0), 25.0);
+    assert_eq!(fit_threshold(&mut ties, 0.0), 15.0);
+}
+
+// Refitting moves only the thresholds with points measured under competing
+// strategies, to where the faster strategy would have been picked
+#[test]
+fn planner_profile_refit() {
+    let observation = |cardinality, strategy, latency_ns| PlannerObservation {
+        cardinality,
+        total_count: 1_000_000,
+        strategy,
+        latency_ns,
+    };
+    let thresholds = PlannerThresholds { parallel_min_len: 500_000, ..PlannerThresholds::default() };
+    let mut profile = PlannerProfile { thresholds, observations: Vec::new() };
+    profile.refit();
+    assert_eq!(profile.thresholds, thresholds);
+
+    profile.observations = vec![
+        observation(1_000, QueryStrategy::Sequential, 10),
+        observation(1_000, QueryStrategy::Parallel, 30),
+        observation(100_000, QueryStrategy::Sequential, 900),
+        observation(100_000, QueryStrategy::Sequential, 1_100),
+        observation(100_000, QueryStrategy::Parallel, 400),
+    ];
+    profile.refit();
+    assert_eq!(profile.thresholds, PlannerThresholds { parallel_min_len: 50_500, ..thresholds });
+    // Already consistent with the points, so a second refit changes nothing
+    profile.refit();
+    assert_eq!(profile.thresholds.parallel_min_len, 50_500);
+}
+
+// Single metrics over a value range answer like a scan, including empty
+// trees, one-value trees, ranges outside the values and inverted ranges
+#[test]
+fn metric_value_range_matches_scan() {
+    let options = BuildOptions { leaf_size: 64, ..BuildOptions::default() };
+    let inputs = [
+        ("empty", Vec::new()),
+        ("one doc", vec![(7, 3.5)]),
+        ("one value", (0..1_000).map(|doc_id| (doc_id, 42.0)).collect()),
+        ("shuffled", shuffled_pairs(4421, 10_000)),
+    ];
+    let ranges = [(-100.0, 250.0), (-500.0, -500.0), (42.0, 42.0), (3.5, 3.5), (3.6, 3.7),
+                  (-1e9, -501.0), (500.0, 1e9), (-1e9, 1e9), (250.0, -100.0)];
+    for (label, pairs) in inputs {
+        let ait = build_aggregation_index_tree(pairs.clone(), &options).unwrap();
+        for (min, max) in ranges {
+            let expected = scan_pairs(&pairs, |_, value| (min..=max).contains(&value));
+            for metric in Metric::ALL {
+                let want = if expected.count == 0 {
+                    (metric == Metric::Count).then_some(0.0)
+                } else {
+                    Some(metric.of(&expected))
+                };
+                assert_eq!(ait.query_metric_value_range(min, max, metric), want,
+                           "{} {} of {}..={}", label, metric.name(), min, max);
+            }
+        }
+    }
+}
+
+// Count estimates for tree and term predicates are the exact counts a scan
+// finds; zone-map estimates are exact for ranges outside or around all their
+// values and within two histogram buckets otherwise. Empty and single-valued fields estimate
+// like their contents, and unknown fields are errors.
+#[test]
+fn estimate_count_matches_scan() {
+    let (mut set, mut columns) = conjunction_fields();
+    let ranges = FieldQueryMix { global: 0, filtered: 0, range: 1 };
+    let globals = FieldQueryMix { global: 1, filtered: 0, range: 0 };
+    for (name, values, mix) in [("empty tree", Vec::new(), &ranges), ("empty zones", Vec::new(), &globals),
+                                ("const tree", vec![42.0; 1_000], &ranges), ("const zones", vec![42.0; 1_000], &globals)] {
+        set.add_field(name, &values, mix, &BuildOptions::default()).unwrap();
+        columns.push((name, values));
+    }
+    let bucket_depth = (DOCS as u64).div_ceil(FieldStatistics::BUCKETS as u64);
+    for (field, values) in &columns {
+        let exact = matches!(set.field(field), Some(FieldIndex::Tree(_)));
+        for (min, max) in [(100.0, 400.0), (42.0, 42.0), (0.0, 0.0), (-1e9, -501.0), (1e3, 1e9), (-1e9, 1e9), (41.5, 41.9)] {
+            let predicate = Predicate::Range { field: field.to_string(), min, max };
+            let estimate = set.estimate_count(&predicate).unwrap();
+            let scanned = values.iter().filter(|value| (min..=max).contains(*value)).count() as u64;
+            assert_eq!(estimate.exact, exact, "{} {}..={}", field, min, max);
+            let outside = values.iter().all(|value| *value < min) || values.iter().all(|value| *value > max);
+            let everything = values.iter().all(|value| (min..=max).contains(value));
+            if exact || outside || everything {
+                assert_eq!(estimate.docs, scanned, "{} {}..={}", field, min, max);
+            } else {
+                assert!(estimate.docs.abs_diff(scanned) <= 2 * bucket_depth, "{} {}..={}: {} vs {}",
+                        field, min, max, estimate.docs, scanned);
+            }
+        }
+    }
+    for term in LEVELS.into_iter().chain(["missing", ""]) {
+        let predicate = Predicate::Term { field: "level".to_string(), term: term.to_string() };
+        let scanned = (0..DOCS).filter(|&doc_id| LEVELS[(doc_id % 7 % 4) as usize] == term).count() as u64;
+        assert_eq!(set.estimate_count(&predicate).unwrap(), CountEstimate { docs: scanned, exact: true }, "{}", term);
+    }
+    assert!(set.estimate_count(&Predicate::Range { field: "missing".to_string(), min: 0.0, max: 1.0 }).is_err());
+    assert!(set.estimate_count(&Predicate::Term { field: "size".to_string(), term: "info".to_string() }).is_err());
+}
+
+// Histogram statistics estimate range counts within two buckets of a scan and
+// quantiles within a bucket's rank of the exact one. Ranges outside the values
+// or inverted estimate zero, and a single repeated value is exact.
+#[test]
+fn field_statistics_track_scan() {
+    assert_eq!(FieldStatistics::from_sorted(0, std::iter::empty()), None);
+
+    let constant = FieldStatistics::from_sorted(1_000, std::iter::repeat_n(42.0, 1_000)).unwrap();
+    assert_eq!((constant.buckets.len(), constant.distinct, constant.row_count()), (1, 1, 1_000));
+    for (min, max, expected) in [(42.0, 42.0, 1_000), (0.0, 100.0, 1_000), (0.0, 41.0, 0), (43.0, 50.0, 0), (50.0, 0.0, 0)] {
+        assert_eq!(constant.estimate_range_count(min, max), expected, "{}..={}", min, max);
+    }
+    assert!([0.0, 0.3, 1.0].iter().all(|&q| constant.quantile(q) == 42.0));
+
+    let mut values: Vec<f64> = shuffled_pairs(478, DOCS).into_iter().map(|(_, value)| value).collect();
+    values.sort_by(f64::total_cmp);
+    let statistics = FieldStatistics::from_sorted(values.len(), values.iter().copied()).unwrap();
+    let depth = (DOCS as u64).div_ceil(FieldStatistics::BUCKETS as u64);
+    assert_eq!(statistics.row_count(), DOCS as u64);
+    assert_eq!(statistics.distinct, 1_000);
+    let mut rng = StdRng::seed_from_u64(4781);
+    let mut ranges = vec![(-500.0, 499.0), (-1e9, 1e9), (-1e9, -501.0), (500.0, 1e9), (7.0, 7.0), (300.0, -300.0)];
+    ranges.extend((0..50).map(|_| {
+        let low = rng.gen_range(-520..520) as f64;
+        (low, low + rng.gen_range(0..400) as f64)
+    }));
+    for (min, max) in ranges {
+        let scanned = values.iter().filter(|value| (min..=max).contains(*value)).count() as u64;
+        let estimate = statistics.estimate_range_count(min, max);
+        if scanned == 0 || scanned == DOCS as u64 {
+            assert_eq!(estimate, scanned, "{}..={}", min, max);
+        } else {
+            assert!(estimate.abs_diff(scanned) <= 2 * depth, "{}..={}: {} vs {}", min, max, estimate, scanned);
+        }
+    }
+    assert_eq!((statistics.quantile(0.0), statistics.quantile(1.0)), (values[0], values[values.len() - 1]));
+    for q in [0.01, 0.25, 0.5, 0.9, 0.999] {
+        let estimate = statistics.quantile(q);
+        let rank = values.partition_point(|&value| value <= estimate) as f64;
+        assert!((rank - q * DOCS as f64).abs() <= depth as f64, "q {}: {} at rank {}", q, estimate, rank);
+    }
+}
+
+// Latency summaries count every recorded query in its strategy and band, and
+// report p50 and p99 as the upper bound of the bucket holding the exact
+// quantile, which is within 2x. Nothing recorded means no summaries; latencies
+// past the last bucket report its bound.
+#[test]
+fn query_latencies_summarize_like_a_sort() {
+    let latencies = QueryLatencies::default();
+    assert!(latencies.summaries().is_empty());
+
+    let mut rng = StdRng::seed_from_u64(489);
+    let mut recorded: BTreeMap<(usize, usize), Vec<u64>> = BTreeMap::new();
+    for _ in 0..5_000 {
+        let strategy = rng.gen_range(0..QueryStrategy::ALL.len());
+        let band = rng.gen_range(0..SELECTIVITY_BANDS.len() - 1);
+        let lower = if band == 0 { 0.0 } else { SELECTIVITY_BANDS[band - 1] };
+        let bits = rng.gen_range(0..16);
+        let micros = rng.gen_range(0..1u64 << bits);
+        latencies.record(QueryStrategy::ALL[strategy], (lower + SELECTIVITY_BANDS[band]) / 2.0,
+                         Duration::from_micros(micros));
+        recorded.entry((strategy, band)).or_default().push(micros);
+    }
+    // One latency throughout, and one past the last bucket, in the full band
+    for _ in 0..10 {
+        latencies.record(QueryStrategy::Sequential, 1.0, Duration::from_micros(5));
+        latencies.record(QueryStrategy::Complement, 1.0, Duration::from_secs(20));
+    }
+    let last_band = SELECTIVITY_BANDS.len() - 1;
+    let strategy_idx = |strategy| QueryStrategy::ALL.iter().position(|&s| s == strategy).unwrap();
+    recorded.insert((strategy_idx(QueryStrategy::Sequential), last_band), vec![5; 10]);
+
+    let summaries = latencies.clone().summaries();
+    assert_eq!(summaries.len(), recorded.len() + 1);
+    for summary in summaries {
+        let band = SELECTIVITY_BANDS.iter().position(|&upper| upper == summary.max_selectivity).unwrap();
+        if summary.strategy == QueryStrategy::Complement && band == last_band {
+            assert_eq!((summary.queries, summary.p50_us, summary.p99_us), (10, 1 << 23, 1 << 23));
+            continue;
+        }
+        let mut micros = recorded[&(strategy_idx(summary.strategy), band)].clone();
+        micros.sort_unstable();
+        assert_eq!(summary.queries, micros.len() as u64, "{}", summary);
+        for (q, bound) in [(0.5, summary.p50_us), (0.99, summary.p99_us)] {
+            let exact = micros[((micros.len() as f64 * q).ceil() as usize).max(1) - 1];
+            assert!(exact < bound && (exact == 0 || exact >= bound / 2), "{} q {}: exact {}", summary, q, exact);
+        }
+    }
+}
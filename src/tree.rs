@@ -0,0 +1,2135 @@
+#[cfg(feature = "persist")]
+use crate::persist::*;
+use crate::heap::{self, HeapComponent};
+use crate::{filter::*, query::*, sketch::*};
+use crate::{format_bytes, format_count};
+use memuse::DynamicUsage;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Aggregation Index Tree structures
+#[derive(Debug, Clone)]
+pub struct AggregationIndexTree {
+    pub(crate) nodes: Vec<AggregationTreeNode>,
+    // Whether leaves carry doc ids and the doc_id_map is populated
+    pub(crate) has_doc_ids: bool,
+    // Smallest and largest doc id stored, None without doc ids
+    pub(crate) doc_id_range: Option<(u32, u32)>,
+    pub(crate) leaf_count: usize,
+    // Cut-offs used by choose_strategy
+    pub(crate) planner: PlannerThresholds,
+    pub(crate) metadata: IndexMetadata,
+    // Map from original doc_id to position in the tree's sorted values
+    pub(crate) doc_id_map: HashMap<u32, usize>,
+    // Values in doc-id order, with BuildOptions::keep_doc_values
+    pub(crate) doc_values: Option<DocValues>,
+    // Runs of repeated values, with BuildOptions::value_runs
+    pub(crate) value_runs: Option<ValueRuns>,
+    // t-digest per node index, with BuildOptions::digests; empty without them,
+    // and None for leaves and internal nodes under MIN_DIGEST_WEIGHT values
+    pub(crate) node_digests: Vec<Option<TDigest>>,
+    // Log-bucketed histogram per node index, with BuildOptions::histograms;
+    // empty without them, and None for nodes under MIN_HISTOGRAM_COUNT values
+    pub(crate) node_histograms: Vec<Option<LogHistogram>>,
+    // Central moments per node index, with BuildOptions::extended_stats;
+    // empty without them
+    pub(crate) node_moments: Vec<Moments>,
+    // Sums of logarithms per node index, with BuildOptions::geometric_mean;
+    // empty without them
+    pub(crate) node_log_sums: Vec<LogSum>,
+    // Secondary id column for approx_distinct, attached after the build
+    pub(crate) distinct: Option<DistinctColumn>,
+    // Weight column for weighted_aggregations, attached after the build
+    pub(crate) weights: Option<WeightColumn>,
+    // Timestamp column for date_histogram, attached after the build
+    pub(crate) timestamps: Option<TimestampColumn>,
+    // Map from position to node_idx and offset within node, for faster lookups
+    pub(crate) position_map: Vec<(usize, usize)>, // (node_idx, offset_in_node)
+    // Lookups that found the position map missing or invalid and fell back to
+    // a tree traversal
+    pub(crate) position_fallbacks: FallbackCounter,
+    // Latencies of executed bitmap queries, for spotting poor planner choices
+    pub(crate) query_latencies: QueryLatencies,
+}
+
+// A tree's values in doc-id order, for columnar scans over bitmap filters
+#[derive(Debug, Clone)]
+pub(crate) struct DocValues {
+    // Doc id of the first value
+    pub(crate) base: u32,
+    pub(crate) values: Vec<f64>,
+    // Ids within the column that hold a doc; None when every one does
+    pub(crate) present: Option<RoaringBitmap>,
+}
+
+// Doc-id span per row past which the column isn't kept: it holds a slot for
+// every id in the span, so sparse ids would cost far more than the rows
+pub(crate) const MAX_DOC_VALUES_SPREAD: u64 = 4;
+
+impl DocValues {
+    // From `rows` (doc_id, value) pairs in any order, covering doc ids [min, max].
+    // None when the span is more than MAX_DOC_VALUES_SPREAD slots per row.
+    pub(crate) fn new(pairs: impl Iterator<Item = (u32, f64)>, (min, max): (u32, u32), rows: usize) -> Option<Self> {
+        let span = (max - min) as u64 + 1;
+        if span > (rows as u64).saturating_mul(MAX_DOC_VALUES_SPREAD) {
+            return None;
+        }
+        let mut values = vec![0.0; span as usize];
+        let mut present = RoaringBitmap::new();
+        for (doc_id, value) in pairs {
+            values[(doc_id - min) as usize] = value;
+            present.insert(doc_id);
+        }
+        let present = (present.len() != values.len() as u64).then_some(present);
+        Some(DocValues { base: min, values, present })
+    }
+
+    // Aggregate the selected ids, scanning 64K-id blocks in parallel
+    pub(crate) fn aggregate(&self, bitmap: &RoaringBitmap) -> NodeAggregations {
+        self.aggregate_with(&Summary, bitmap)
+    }
+
+    pub(crate) fn aggregate_with<A: Aggregator>(&self, aggregator: &A, bitmap: &RoaringBitmap) -> A::State {
+        const BLOCK_IDS: u64 = 1 << 16;
+        let restricted;
+        let bitmap = match &self.present {
+            Some(present) => {
+                restricted = bitmap & present;
+                &restricted
+            }
+            None => bitmap,
+        };
+        let end = self.base as u64 + self.values.len() as u64;
+        let blocks: Vec<u64> = (self.base as u64..end).step_by(BLOCK_IDS as usize).collect();
+        crate::aggregate_each(aggregator, blocks, |start| {
+            let mut result = aggregator.init();
+            // The inclusive bound, computed before narrowing: `end` is 2^32
+            // when the column holds doc id u32::MAX
+            let last = ((start + BLOCK_IDS).min(end) - 1) as u32;
+            for doc_id in bitmap.range(start as u32..=last) {
+                aggregator.accumulate(&mut result, self.values[(doc_id - self.base) as usize]);
+            }
+            result
+        })
+    }
+
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.values.capacity() * std::mem::size_of::<f64>()
+            + self.present.as_ref().map_or(0, |present| present.serialized_size())
+    }
+}
+
+// Event counter shared by concurrent queries. Clones start from the current count.
+#[derive(Debug, Default)]
+pub(crate) struct FallbackCounter(pub(crate) AtomicU64);
+
+impl FallbackCounter {
+    // Count an event, returning true for the first one
+    pub(crate) fn record(&self) -> bool {
+        self.0.fetch_add(1, Ordering::Relaxed) == 0
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for FallbackCounter {
+    fn clone(&self) -> Self {
+        FallbackCounter(AtomicU64::new(self.get()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum AggregationTreeNode {
+    Internal {
+        split_value: f64,
+        left: usize,
+        right: usize,
+        aggregations: NodeAggregations,
+    },
+    Leaf {
+        doc_ids: LeafSlice<u32>,
+        values: LeafSlice<f64>,
+        aggregations: NodeAggregations,
+    },
+}
+
+// A leaf's doc ids or values: a range of an arena chunk shared with the other
+// leaves allocated from it, read as a slice. A build allocates one chunk per
+// column sized from its input, so leaves cost no allocations of their own and
+// sit next to each other in position order.
+#[derive(Clone)]
+pub(crate) struct LeafSlice<T> {
+    chunk: Arc<[T]>,
+    start: u32,
+    len: u32,
+}
+
+impl<T> LeafSlice<T> {
+    pub(crate) fn new(chunk: &Arc<[T]>, start: usize, len: usize) -> Self {
+        assert!(start + len <= chunk.len(), "leaf outside its arena chunk");
+        LeafSlice { chunk: Arc::clone(chunk), start: start as u32, len: len as u32 }
+    }
+
+    // The whole chunk as one leaf
+    pub(crate) fn whole(chunk: Arc<[T]>) -> Self {
+        let len = chunk.len() as u32;
+        LeafSlice { chunk, start: 0, len }
+    }
+
+    pub(crate) fn chunk(&self) -> &Arc<[T]> {
+        &self.chunk
+    }
+}
+
+impl<T> From<Vec<T>> for LeafSlice<T> {
+    fn from(values: Vec<T>) -> Self {
+        LeafSlice::whole(values.into())
+    }
+}
+
+impl<T> std::ops::Deref for LeafSlice<T> {
+    type Target = [T];
+
+    #[inline(always)]
+    fn deref(&self) -> &[T] {
+        &self.chunk[self.start as usize..(self.start + self.len) as usize]
+    }
+}
+
+impl<'a, T> IntoIterator for &'a LeafSlice<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for LeafSlice<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// Leaf storage of a tree: distinct arena chunks, the bytes they hold, and the
+// bytes leaves still reference. Chunks outlive leaves dropped by a stitch or
+// a cleared column, which shows as referenced below allocated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ArenaStats {
+    pub chunks: usize,
+    pub leaves: usize,
+    pub allocated_bytes: usize,
+    pub referenced_bytes: usize,
+}
+
+impl AggregationTreeNode {
+    pub(crate) fn aggregations(&self) -> NodeAggregations {
+        match self {
+            AggregationTreeNode::Internal { aggregations, .. } => *aggregations,
+            AggregationTreeNode::Leaf { aggregations, .. } => *aggregations,
+        }
+    }
+}
+
+// Serialized through AggregationsJson so the JSON schema is the same for
+// persisted manifests, reports and query results
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "AggregationsJson", into = "AggregationsJson")]
+pub struct NodeAggregations {
+    pub min_value: f64,
+    pub max_value: f64,
+    pub sum: f64,
+    pub count: u32,
+    // Sum of squared values. Like sum it adds across disjoint sets and
+    // subtracts for complements, which a running mean and M2 would not.
+    pub sum_squares: f64,
+}
+
+impl NodeAggregations {
+    pub fn empty() -> Self {
+        NodeAggregations {
+            min_value: f64::MAX,
+            max_value: f64::MIN,
+            sum: 0.0,
+            count: 0,
+            sum_squares: 0.0,
+        }
+    }
+
+    // Fold a single value into the aggregations
+    #[inline(always)]
+    pub fn add_value(&mut self, value: f64) {
+        self.min_value = self.min_value.min(value);
+        self.max_value = self.max_value.max(value);
+        self.sum += value;
+        self.count += 1;
+        self.sum_squares += value * value;
+    }
+
+    // Mean of the aggregated values, None when there are none. Derived from sum
+    // and count rather than stored, so combining, complement subtraction and
+    // parallel merges keep it consistent without extra work.
+    #[inline]
+    pub fn avg(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    // Population variance, None when there are no values. From the sums as
+    // E[x^2] - E[x]^2, so values far from zero relative to their spread lose
+    // precision; rounding below zero is clamped.
+    #[inline]
+    pub fn variance(&self) -> Option<f64> {
+        let mean = self.avg()?;
+        Some((self.sum_squares / self.count as f64 - mean * mean).max(0.0))
+    }
+
+    // Population standard deviation, None when there are no values
+    #[inline]
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+}
+
+// JSON form of NodeAggregations: min and max are null when nothing was
+// aggregated, instead of the f64::MAX / f64::MIN sentinels. Numbers are still
+// read back as they are, so manifests written with the sentinels stay readable.
+// avg and std_dev are written for consumers of the JSON and recomputed when
+// reading; sum_squares is absent from JSON written before it was recorded.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AggregationsJson {
+    pub(crate) min_value: Option<f64>,
+    pub(crate) max_value: Option<f64>,
+    pub(crate) sum: f64,
+    pub(crate) count: u32,
+    #[serde(default)]
+    pub(crate) sum_squares: f64,
+    #[serde(default, skip_deserializing)]
+    pub(crate) avg: Option<f64>,
+    #[serde(default, skip_deserializing)]
+    pub(crate) std_dev: Option<f64>,
+}
+
+impl From<NodeAggregations> for AggregationsJson {
+    fn from(aggregations: NodeAggregations) -> Self {
+        let present = aggregations.count > 0;
+        AggregationsJson {
+            min_value: present.then_some(aggregations.min_value),
+            max_value: present.then_some(aggregations.max_value),
+            sum: aggregations.sum,
+            count: aggregations.count,
+            sum_squares: aggregations.sum_squares,
+            avg: aggregations.avg(),
+            std_dev: aggregations.std_dev(),
+        }
+    }
+}
+
+impl From<AggregationsJson> for NodeAggregations {
+    fn from(json: AggregationsJson) -> Self {
+        NodeAggregations {
+            min_value: json.min_value.unwrap_or(f64::MAX),
+            max_value: json.max_value.unwrap_or(f64::MIN),
+            sum: json.sum,
+            count: json.count,
+            sum_squares: json.sum_squares,
+        }
+    }
+}
+
+impl Default for NodeAggregations {
+    fn default() -> Self {
+        NodeAggregations::empty()
+    }
+}
+
+// Combining aggregations of disjoint sets. The empty aggregations are the
+// identity, since their min/max sentinels never win a comparison.
+impl std::ops::AddAssign for NodeAggregations {
+    #[inline(always)]
+    fn add_assign(&mut self, other: NodeAggregations) {
+        self.min_value = self.min_value.min(other.min_value);
+        self.max_value = self.max_value.max(other.max_value);
+        self.sum += other.sum;
+        self.count += other.count;
+        self.sum_squares += other.sum_squares;
+    }
+}
+
+impl std::ops::Add for NodeAggregations {
+    type Output = NodeAggregations;
+
+    #[inline(always)]
+    fn add(mut self, other: NodeAggregations) -> NodeAggregations {
+        self += other;
+        self
+    }
+}
+
+// Count, mean and the second to fourth central moments of a set of values,
+// for skewness and kurtosis. Unlike the power sums in NodeAggregations these
+// don't lose precision to values far from zero, but they only merge: the
+// states of disjoint sets combine with the pairwise formulas of Chan et al.
+// and Pébay, and can't be subtracted for complements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Moments {
+    pub count: u32,
+    pub mean: f64,
+    // Sums of the 2nd, 3rd and 4th powers of the deviations from the mean
+    pub m2: f64,
+    pub m3: f64,
+    pub m4: f64,
+}
+
+impl Moments {
+    #[inline(always)]
+    pub fn add_value(&mut self, value: f64) {
+        let n1 = self.count as f64;
+        self.count += 1;
+        let n = self.count as f64;
+        let delta = value - self.mean;
+        let delta_n = delta / n;
+        let delta_n2 = delta_n * delta_n;
+        let term = delta * delta_n * n1;
+        self.mean += delta_n;
+        self.m4 += term * delta_n2 * (n * n - 3.0 * n + 3.0) + 6.0 * delta_n2 * self.m2 - 4.0 * delta_n * self.m3;
+        self.m3 += term * delta_n * (n - 2.0) - 3.0 * delta_n * self.m2;
+        self.m2 += term;
+    }
+
+    // Population variance, None when there are no values
+    pub fn variance(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.m2 / self.count as f64)
+    }
+
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+
+    // Population skewness, None without values or when they are all equal
+    pub fn skewness(&self) -> Option<f64> {
+        (self.count > 0 && self.m2 > 0.0).then(|| (self.count as f64).sqrt() * self.m3 / self.m2.powf(1.5))
+    }
+
+    // Excess kurtosis (0 for a normal distribution), None without values or
+    // when they are all equal
+    pub fn kurtosis(&self) -> Option<f64> {
+        (self.count > 0 && self.m2 > 0.0).then(|| self.count as f64 * self.m4 / (self.m2 * self.m2) - 3.0)
+    }
+}
+
+impl std::ops::AddAssign for Moments {
+    fn add_assign(&mut self, other: Moments) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = other;
+            return;
+        }
+        let (na, nb) = (self.count as f64, other.count as f64);
+        let n = na + nb;
+        let delta = other.mean - self.mean;
+        let delta2 = delta * delta;
+        let m2 = self.m2 + other.m2 + delta2 * na * nb / n;
+        let m3 = self.m3 + other.m3 + delta2 * delta * na * nb * (na - nb) / (n * n)
+            + 3.0 * delta * (na * other.m2 - nb * self.m2) / n;
+        let m4 = self.m4 + other.m4 + delta2 * delta2 * na * nb * (na * na - na * nb + nb * nb) / (n * n * n)
+            + 6.0 * delta2 * (na * na * other.m2 + nb * nb * self.m2) / (n * n)
+            + 4.0 * delta * (na * other.m3 - nb * self.m3) / n;
+        *self = Moments { count: self.count + other.count, mean: self.mean + delta * nb / n, m2, m3, m4 };
+    }
+}
+
+// Moments as an Aggregator, for the generic query paths. Nodes carry no
+// moments in NodeAggregations, so every selected value is read; with
+// BuildOptions::extended_stats, AggregationIndexTree::extended_stats uses the
+// per-node moments instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtendedStats;
+
+impl Aggregator for ExtendedStats {
+    type State = Moments;
+    type Output = Moments;
+
+    fn init(&self) -> Moments {
+        Moments::default()
+    }
+
+    fn accumulate(&self, state: &mut Moments, value: f64) {
+        state.add_value(value);
+    }
+
+    fn merge(&self, state: &mut Moments, other: Moments) {
+        *state += other;
+    }
+
+    fn finalize(&self, state: Moments) -> Moments {
+        state
+    }
+}
+
+// A user-defined aggregation over the values a query selects. Values arrive
+// in no particular order and in chunks that may be aggregated on different
+// threads, so `merge` must combine the states of two disjoint sets the same
+// way accumulating both sets into one state would.
+pub trait Aggregator: Sync {
+    type State: Send;
+    type Output;
+
+    // State of the empty set; merging it into another state changes nothing
+    fn init(&self) -> Self::State;
+
+    fn accumulate(&self, state: &mut Self::State, value: f64);
+
+    fn merge(&self, state: &mut Self::State, other: Self::State);
+
+    fn finalize(&self, state: Self::State) -> Self::Output;
+
+    // State of a whole subtree from its precomputed aggregations. Aggregations
+    // derived from min, max, sum, count and the sum of squares can return it,
+    // so range and complement queries skip the subtree's values; with None
+    // (the default) the values are read.
+    fn node_state(&self, _aggregations: &NodeAggregations) -> Option<Self::State> {
+        None
+    }
+}
+
+// The built-in aggregations as an Aggregator; the NodeAggregations queries
+// run through the same generic paths with it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary;
+
+impl Aggregator for Summary {
+    type State = NodeAggregations;
+    type Output = NodeAggregations;
+
+    #[inline(always)]
+    fn init(&self) -> NodeAggregations {
+        NodeAggregations::empty()
+    }
+
+    #[inline(always)]
+    fn accumulate(&self, state: &mut NodeAggregations, value: f64) {
+        state.add_value(value);
+    }
+
+    #[inline(always)]
+    fn merge(&self, state: &mut NodeAggregations, other: NodeAggregations) {
+        *state += other;
+    }
+
+    fn finalize(&self, state: NodeAggregations) -> NodeAggregations {
+        state
+    }
+
+    fn node_state(&self, aggregations: &NodeAggregations) -> Option<NodeAggregations> {
+        Some(*aggregations)
+    }
+}
+
+// Runs of equal values. Positions are in value order, so each repeated value
+// is one run of positions, possibly spanning leaves. Only values occurring
+// more than once are kept.
+#[derive(Debug, Clone)]
+pub(crate) struct ValueRuns {
+    // Repeated values, ascending, and how many positions each covers
+    pub(crate) values: Vec<f64>,
+    pub(crate) counts: Vec<u32>,
+    // Indices into values by count, most frequent first; ties ascending by value
+    pub(crate) by_count: Vec<u32>,
+}
+
+impl ValueRuns {
+    // `values` must be sorted ascending
+    pub(crate) fn from_sorted(values: impl IntoIterator<Item = f64>) -> Self {
+        let mut runs = ValueRuns { values: Vec::new(), counts: Vec::new(), by_count: Vec::new() };
+        let mut current: Option<(f64, u32)> = None;
+        for value in values.into_iter().map(Some).chain(std::iter::once(None)) {
+            match (current, value) {
+                (Some((run_value, count)), Some(value)) if value == run_value => current = Some((run_value, count + 1)),
+                _ => {
+                    if let Some((run_value, count)) = current.filter(|&(_, count)| count > 1) {
+                        runs.values.push(run_value);
+                        runs.counts.push(count);
+                    }
+                    current = value.map(|value| (value, 1));
+                }
+            }
+        }
+        runs.by_count = (0..runs.values.len() as u32).collect();
+        runs.by_count.sort_by_key(|&idx| std::cmp::Reverse(runs.counts[idx as usize]));
+        runs
+    }
+
+    // Length of the run of `value`, None when it occurs at most once
+    pub(crate) fn count(&self, value: f64) -> Option<u32> {
+        let idx = self.values.partition_point(|&run_value| run_value < value);
+        (self.values.get(idx) == Some(&value)).then(|| self.counts[idx])
+    }
+
+    // The `top_n` most frequent repeated values
+    pub(crate) fn top(&self, top_n: usize) -> Vec<ValueCount> {
+        self.by_count
+            .iter()
+            .take(top_n)
+            .map(|&idx| ValueCount { value: self.values[idx as usize], count: self.counts[idx as usize] })
+            .collect()
+    }
+
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.values.capacity() * std::mem::size_of::<f64>()
+            + (self.counts.capacity() + self.by_count.capacity()) * std::mem::size_of::<u32>()
+    }
+}
+
+// A secondary id column (e.g. user ids) in position order, with a
+// HyperLogLog sketch of the ids under each internal node covering at least
+// MIN_DISTINCT_SKETCH_COUNT docs
+#[derive(Debug, Clone)]
+pub(crate) struct DistinctColumn {
+    pub(crate) ids: Vec<u64>,
+    pub(crate) node_sketches: Vec<Option<HyperLogLog>>,
+}
+
+impl DistinctColumn {
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.ids.capacity() * std::mem::size_of::<u64>()
+            + self.node_sketches.capacity() * std::mem::size_of::<Option<HyperLogLog>>()
+            + self.node_sketches.iter().flatten().map(HyperLogLog::dynamic_usage).sum::<usize>()
+    }
+}
+
+// A weight column (e.g. clicks per event) in position order, with the
+// weighted sums of every node
+#[derive(Debug, Clone)]
+pub(crate) struct WeightColumn {
+    pub(crate) weights: Vec<f64>,
+    pub(crate) node_sums: Vec<WeightedSums>,
+}
+
+impl WeightColumn {
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.weights.capacity() * std::mem::size_of::<f64>()
+            + self.node_sums.capacity() * std::mem::size_of::<WeightedSums>()
+    }
+}
+
+// A timestamp column (epoch milliseconds) in position order, with the values
+// in time order for unfiltered date histograms
+#[derive(Debug, Clone)]
+pub(crate) struct TimestampColumn {
+    pub(crate) timestamps: Vec<i64>,
+    pub(crate) by_time: TimeSegmentTree,
+}
+
+impl TimestampColumn {
+    pub(crate) fn dynamic_usage(&self) -> usize {
+        self.timestamps.capacity() * std::mem::size_of::<i64>()
+            + self.by_time.timestamps.capacity() * std::mem::size_of::<i64>()
+            + self.by_time.nodes.capacity() * std::mem::size_of::<NodeAggregations>()
+    }
+}
+
+// Sum of weights and of weight * value over a set of docs. Both add across
+// disjoint sets and subtract for complements, like NodeAggregations::sum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct WeightedSums {
+    pub weight: f64,
+    pub weighted_sum: f64,
+}
+
+impl WeightedSums {
+    // Weighted mean of the values, None when the weights sum to zero
+    #[inline]
+    pub fn weighted_avg(&self) -> Option<f64> {
+        (self.weight != 0.0).then(|| self.weighted_sum / self.weight)
+    }
+
+    #[inline(always)]
+    pub fn add_weighted(&mut self, value: f64, weight: f64) {
+        self.weight += weight;
+        self.weighted_sum += weight * value;
+    }
+}
+
+impl std::ops::AddAssign for WeightedSums {
+    #[inline(always)]
+    fn add_assign(&mut self, other: WeightedSums) {
+        self.weight += other.weight;
+        self.weighted_sum += other.weighted_sum;
+    }
+}
+
+impl std::ops::SubAssign for WeightedSums {
+    #[inline(always)]
+    fn sub_assign(&mut self, other: WeightedSums) {
+        self.weight -= other.weight;
+        self.weighted_sum -= other.weighted_sum;
+    }
+}
+
+// Values with unit weights; the weighted query paths fill WeightedSums
+// themselves and use this to merge partial sums across threads
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct UnitWeights;
+
+impl Aggregator for UnitWeights {
+    type State = WeightedSums;
+    type Output = WeightedSums;
+
+    fn init(&self) -> WeightedSums {
+        WeightedSums::default()
+    }
+
+    fn accumulate(&self, state: &mut WeightedSums, value: f64) {
+        state.add_weighted(value, 1.0);
+    }
+
+    fn merge(&self, state: &mut WeightedSums, other: WeightedSums) {
+        *state += other;
+    }
+
+    fn finalize(&self, state: WeightedSums) -> WeightedSums {
+        state
+    }
+
+    fn node_state(&self, aggregations: &NodeAggregations) -> Option<WeightedSums> {
+        Some(WeightedSums { weight: aggregations.count as f64, weighted_sum: aggregations.sum })
+    }
+}
+
+// A value and the number of docs holding it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ValueCount {
+    pub value: f64,
+    pub count: u32,
+}
+
+// A fixed-width histogram bucket: values in [key, key + interval)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct IntervalBucket {
+    pub key: f64,
+    pub aggregations: NodeAggregations,
+}
+
+const MINUTE_MS: i64 = 60 * 1000;
+const HOUR_MS: i64 = 60 * MINUTE_MS;
+const DAY_MS: i64 = 24 * HOUR_MS;
+const WEEK_MS: i64 = 7 * DAY_MS;
+
+// Bucket width of a date histogram. Buckets are aligned to UTC, and weeks
+// start on Monday; the epoch was a Thursday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DateInterval {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    // Fixed buckets of this many milliseconds, aligned to the epoch
+    Millis(i64),
+}
+
+impl DateInterval {
+    pub fn millis(&self) -> i64 {
+        match self {
+            DateInterval::Minute => MINUTE_MS,
+            DateInterval::Hour => HOUR_MS,
+            DateInterval::Day => DAY_MS,
+            DateInterval::Week => WEEK_MS,
+            DateInterval::Millis(millis) => *millis,
+        }
+    }
+
+    // Start of the bucket holding `timestamp`
+    pub fn bucket_start(&self, timestamp: i64) -> i64 {
+        let offset = if *self == DateInterval::Week { 4 * DAY_MS } else { 0 };
+        let width = self.millis();
+        (timestamp - offset).div_euclid(width) * width + offset
+    }
+}
+
+impl std::fmt::Display for DateInterval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DateInterval::Minute => write!(f, "minute"),
+            DateInterval::Hour => write!(f, "hour"),
+            DateInterval::Day => write!(f, "day"),
+            DateInterval::Week => write!(f, "week"),
+            DateInterval::Millis(millis) => write!(f, "{}ms", millis),
+        }
+    }
+}
+
+// Parses "minute", "hour", "day", "week" or a width in milliseconds ("90000"
+// or "90000ms")
+impl std::str::FromStr for DateInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "minute" => Ok(DateInterval::Minute),
+            "hour" => Ok(DateInterval::Hour),
+            "day" => Ok(DateInterval::Day),
+            "week" => Ok(DateInterval::Week),
+            _ => match s.strip_suffix("ms").unwrap_or(s).parse::<i64>() {
+                Ok(millis) if millis > 0 => Ok(DateInterval::Millis(millis)),
+                _ => Err(format!("expected minute, hour, day, week or a positive width in ms, got '{}'", s)),
+            },
+        }
+    }
+}
+
+// A date histogram bucket: docs with timestamps in [key_ms, key_ms + interval)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DateBucket {
+    pub key_ms: i64,
+    pub aggregations: NodeAggregations,
+}
+
+// What the geometric mean does with values it can't take the logarithm of.
+// The policy is part of the build options, so node sums and queries that read
+// values agree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonPositive {
+    // Leave zeros and negatives out, counting them as skipped
+    #[default]
+    Skip,
+    // Raise every value below this positive floor to it, e.g. 1 for a field
+    // in ms where 0 means "under a millisecond"
+    Floor(f64),
+    // Fail the build on any value that isn't positive
+    Reject,
+}
+
+impl std::fmt::Display for NonPositive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NonPositive::Skip => write!(f, "skip"),
+            NonPositive::Floor(floor) => write!(f, "floor:{}", floor),
+            NonPositive::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+// Parses "skip", "reject" or "floor:<value>" with a positive, finite value
+impl std::str::FromStr for NonPositive {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "skip" => Ok(NonPositive::Skip),
+            "reject" => Ok(NonPositive::Reject),
+            _ => match s.strip_prefix("floor:").map(str::parse::<f64>) {
+                Some(Ok(floor)) if floor > 0.0 && floor.is_finite() => Ok(NonPositive::Floor(floor)),
+                _ => Err(format!("expected skip, reject or floor:<positive value>, got '{}'", s)),
+            },
+        }
+    }
+}
+
+// Sum of the natural logarithms of a set of values, for their geometric mean
+// and product. Unlike Moments, sums of logs merge by plain addition. Values
+// the NonPositive policy leaves out are counted in `skipped`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LogSum {
+    pub count: u32,
+    pub skipped: u32,
+    pub log_sum: f64,
+}
+
+impl LogSum {
+    #[inline(always)]
+    pub fn add_value(&mut self, value: f64, non_positive: NonPositive) {
+        match non_positive {
+            NonPositive::Floor(floor) => {
+                self.count += 1;
+                self.log_sum += value.max(floor).ln();
+            }
+            _ if value > 0.0 => {
+                self.count += 1;
+                self.log_sum += value.ln();
+            }
+            _ => self.skipped += 1,
+        }
+    }
+
+    // None when no value was counted
+    pub fn geometric_mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| (self.log_sum / self.count as f64).exp())
+    }
+
+    // Product of the counted values, None when there are none. Overflows to
+    // infinity (or underflows to 0) long before log_sum loses precision.
+    pub fn product(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.log_sum.exp())
+    }
+}
+
+impl std::ops::AddAssign for LogSum {
+    fn add_assign(&mut self, other: LogSum) {
+        self.count += other.count;
+        self.skipped += other.skipped;
+        self.log_sum += other.log_sum;
+    }
+}
+
+// LogSum as an Aggregator, for the generic query paths. With
+// BuildOptions::geometric_mean, AggregationIndexTree::geometric_mean adds
+// per-node sums instead of reading every selected value.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GeometricMean {
+    pub non_positive: NonPositive,
+}
+
+impl Aggregator for GeometricMean {
+    type State = LogSum;
+    type Output = LogSum;
+
+    fn init(&self) -> LogSum {
+        LogSum::default()
+    }
+
+    fn accumulate(&self, state: &mut LogSum, value: f64) {
+        state.add_value(value, self.non_positive);
+    }
+
+    fn merge(&self, state: &mut LogSum, other: LogSum) {
+        *state += other;
+    }
+
+    fn finalize(&self, state: LogSum) -> LogSum {
+        state
+    }
+}
+
+// A subtree dumped for debugging, as returned by AggregationIndexTree::subtree
+#[derive(Debug, Clone, Serialize)]
+pub struct SubtreeNode {
+    // Index of the node in the tree, for dumping its own subtree next
+    pub node: usize,
+    // First position the node covers
+    pub start: usize,
+    pub aggregations: NodeAggregations,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub split_value: Option<f64>,
+    // The split value is not the right subtree's minimum, or the left subtree
+    // holds a value above it, so value range lookups go wrong under this node
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub split_mismatch: bool,
+    // Children, left first; empty for leaves and below the depth limit
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<SubtreeNode>,
+    // Leaf contents up to the limit; doc ids are empty without stored doc ids
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub doc_ids: Vec<u32>,
+    // Leaf entries past the limit, or children past the depth limit
+    #[serde(skip_serializing_if = "is_zero")]
+    pub omitted: usize,
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+impl SubtreeNode {
+    // Graphviz DOT graph of the subtree. Nodes with a split mismatch are red;
+    // omitted leaf entries and children show as a count.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph subtree {\n  node [shape=box, fontname=monospace];\n");
+        self.write_dot(&mut dot);
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn write_dot(&self, dot: &mut String) {
+        use std::fmt::Write;
+        let aggregations = &self.aggregations;
+        let mut label = format!("node {} @ {}\\ncount {}, sum {}\\nmin {}, max {}", self.node, self.start,
+                                aggregations.count, aggregations.sum, aggregations.min_value, aggregations.max_value);
+        if let Some(split_value) = self.split_value {
+            let _ = write!(label, "\\nsplit {}", split_value);
+        }
+        if !self.values.is_empty() {
+            let values: Vec<String> = self.values.iter().map(f64::to_string).collect();
+            let _ = write!(label, "\\nvalues {}", values.join(" "));
+        }
+        if self.omitted > 0 {
+            let what = if self.split_value.is_some() { "children" } else { "entries" };
+            let _ = write!(label, "\\n{} more {}", self.omitted, what);
+        }
+        let color = if self.split_mismatch { ", color=red, fontcolor=red" } else { "" };
+        let _ = writeln!(dot, "  n{} [label=\"{}\"{}];", self.node, label, color);
+        for child in &self.children {
+            let _ = writeln!(dot, "  n{} -> n{};", self.node, child.node);
+            child.write_dot(dot);
+        }
+    }
+}
+
+// Shape of one level of a subtree, as reported by AggregationIndexTree::structure
+#[derive(Debug, Clone, Serialize)]
+pub struct LevelStats {
+    pub depth: usize,
+    pub internal_nodes: usize,
+    pub leaves: usize,
+    // Docs under the smallest and largest node at this level
+    pub min_docs: u32,
+    pub max_docs: u32,
+    // Narrowest and widest value range (max - min) of a node at this level
+    pub min_value_span: f64,
+    pub max_value_span: f64,
+    // Nodes holding a single distinct value, a sign of duplicate-heavy data
+    pub constant_nodes: usize,
+    // Values in this level's leaves, for their mean fill
+    pub leaf_values: usize,
+}
+
+// A node drawn in the structure report: its place in position order and the
+// range of values it aggregates
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StructureBlock {
+    pub node: usize,
+    pub depth: usize,
+    pub start: usize,
+    pub count: u32,
+    pub min_value: f64,
+    pub max_value: f64,
+    pub leaf: bool,
+}
+
+// Depth, leaf fill and value ranges per level of a subtree, for spotting skew
+// from duplicate-heavy data or badly sized leaves
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeStructure {
+    pub node: usize,
+    pub leaf_size: usize,
+    pub levels: Vec<LevelStats>,
+    // Leaves by fill (values / leaf_size) in tenths; the last bucket holds full leaves
+    pub leaf_fill: [usize; 11],
+    // Nodes of the top levels, as many as were asked for
+    pub blocks: Vec<StructureBlock>,
+}
+
+impl TreeStructure {
+    pub fn leaves(&self) -> usize {
+        self.levels.iter().map(|level| level.leaves).sum()
+    }
+
+    // Mean values per leaf over leaf_size, 0 without leaves
+    pub fn mean_leaf_fill(&self) -> f64 {
+        let values: usize = self.levels.iter().map(|level| level.leaf_values).sum();
+        let capacity = self.leaves() * self.leaf_size;
+        if capacity == 0 { 0.0 } else { values as f64 / capacity as f64 }
+    }
+
+    // Standalone HTML page: a per-level table, an icicle chart of the top
+    // levels and a histogram of leaf fill. In the icicle each node spans the
+    // positions it covers; constant nodes are orange, other internal nodes
+    // get darker the wider their value range, and leaves go from red to green
+    // as they fill. Hovering a node shows its aggregations.
+    pub fn to_html(&self, title: &str) -> String {
+        use std::fmt::Write;
+        const WIDTH: f64 = 1000.0;
+        const ROW: f64 = 28.0;
+        let title = escape_html(title);
+        let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+            <style>body {{ font-family: sans-serif; margin: 2em; }} table {{ border-collapse: collapse; }} \
+            td, th {{ border: 1px solid #ccc; padding: 2px 8px; text-align: right; }}</style>\n</head>\n<body>\n\
+            <h1>{}</h1>\n", title, title);
+        let total: u32 = self.levels.first().map_or(0, |level| level.max_docs);
+        let _ = writeln!(html, "<p>Node {}: {} docs over {} levels and {} leaves of up to {} values, \
+            {:.1}% full on average</p>", self.node, total, self.levels.len(), self.leaves(), self.leaf_size,
+                         self.mean_leaf_fill() * 100.0);
+
+        html.push_str("<h2>Levels</h2>\n<table>\n<tr><th>depth</th><th>internal</th><th>leaves</th>\
+            <th>docs per node</th><th>value span</th><th>constant nodes</th><th>leaf fill</th></tr>\n");
+        for level in &self.levels {
+            let fill = if level.leaves == 0 {
+                "-".to_string()
+            } else {
+                format!("{:.1}%", level.leaf_values as f64 * 100.0 / (level.leaves * self.leaf_size) as f64)
+            };
+            let _ = writeln!(html, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{} - {}</td><td>{} - {}</td><td>{}</td>\
+                <td>{}</td></tr>", level.depth, level.internal_nodes, level.leaves, level.min_docs, level.max_docs,
+                             level.min_value_span, level.max_value_span, level.constant_nodes, fill);
+        }
+        html.push_str("</table>\n");
+
+        let rows = self.blocks.iter().map(|block| block.depth + 1).max().unwrap_or(0);
+        let span = self.blocks.first().map_or(0.0, |root| root.max_value - root.min_value);
+        let _ = writeln!(html, "<h2>Top {} levels</h2>\n<svg width=\"{}\" height=\"{}\" font-size=\"10\">", rows, WIDTH,
+                         rows as f64 * ROW);
+        for block in &self.blocks {
+            let x = block.start as f64 / total.max(1) as f64 * WIDTH;
+            let width = (block.count as f64 / total.max(1) as f64 * WIDTH).max(0.5);
+            let color = if block.leaf {
+                let fill = (block.count as f64 / self.leaf_size as f64).min(1.0);
+                format!("hsl({:.0}, 60%, 55%)", fill * 120.0)
+            } else if block.max_value == block.min_value {
+                "#e6550d".to_string()
+            } else {
+                let share = if span > 0.0 { (block.max_value - block.min_value) / span } else { 0.0 };
+                format!("hsl(210, 60%, {:.0}%)", 85.0 - share * 50.0)
+            };
+            let _ = writeln!(html, "<rect x=\"{:.2}\" y=\"{}\" width=\"{:.2}\" height=\"{}\" fill=\"{}\" \
+                stroke=\"white\" stroke-width=\"0.5\"><title>node {} @ {}: {} docs, values [{}, {}]{}</title></rect>",
+                             x, block.depth as f64 * ROW, width, ROW - 2.0, color, block.node, block.start, block.count,
+                             block.min_value, block.max_value, if block.leaf { " (leaf)" } else { "" });
+        }
+        html.push_str("</svg>\n");
+
+        let most = self.leaf_fill.iter().copied().max().unwrap_or(0).max(1);
+        html.push_str("<h2>Leaf fill</h2>\n<svg width=\"440\" height=\"150\" font-size=\"10\">\n");
+        for (tenth, &leaves) in self.leaf_fill.iter().enumerate() {
+            let height = leaves as f64 / most as f64 * 110.0;
+            let x = tenth as f64 * 40.0;
+            let _ = writeln!(html, "<rect x=\"{}\" y=\"{:.2}\" width=\"36\" height=\"{:.2}\" \
+                fill=\"hsl({}, 60%, 55%)\"><title>{} leaves</title></rect><text x=\"{}\" y=\"140\">{}%</text>",
+                             x, 120.0 - height, height, tenth * 12, leaves, x + 4.0, tenth * 10);
+        }
+        html.push_str("</svg>\n</body>\n</html>\n");
+        html
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Options controlling what the tree stores. Fields missing from persisted
+// metadata take their defaults.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildOptions {
+    pub leaf_size: usize,
+    // Keep per-leaf doc ids and the doc_id -> position map. Range-only workloads
+    // can drop them to save memory, at the cost of bitmap queries.
+    pub store_doc_ids: bool,
+    pub planner: PlannerThresholds,
+    // Leaves per stored aggregation in the sampled index
+    pub sample_every: usize,
+    // Also keep the values in doc-id order, so the planner can answer bitmap
+    // queries with a columnar scan instead of position lookups. Needs doc ids;
+    // skipped when the ids are too sparse (see MAX_DOC_VALUES_SPREAD).
+    pub keep_doc_values: bool,
+    // Keep the position -> (leaf, offset) map. Without it every position lookup
+    // descends the tree, saving two words per doc at the cost of filtered queries.
+    pub position_map: bool,
+    // Record runs of repeated values, so value_histogram reads the most
+    // frequent values instead of scanning every position
+    pub value_runs: bool,
+    // Attach a t-digest to large internal nodes, so approx_percentiles merges
+    // node sketches where a filter covers whole nodes
+    pub digests: bool,
+    // Attach a log-bucketed histogram to large internal nodes, for
+    // query_histogram over latency-style fields
+    pub histograms: bool,
+    // Keep central moments up to the fourth per node, so extended_stats
+    // (skewness and kurtosis) merges whole nodes instead of reading values
+    pub extended_stats: bool,
+    // Keep the sum of logarithms per node, so geometric_mean adds whole nodes
+    // instead of reading values
+    pub geometric_mean: bool,
+    // How the geometric mean treats zeros and negatives, with or without
+    // per-node sums. Reject fails the build on such a value.
+    pub non_positive: NonPositive,
+}
+
+// Bounds on BuildOptions::leaf_size. The build halves its input until it fits
+// a leaf, so a leaf size of 0 never stops splitting and 1 stores a node per
+// value; past the maximum, every partially covered leaf is a long linear scan.
+pub(crate) const MIN_LEAF_SIZE: usize = 2;
+pub(crate) const MAX_LEAF_SIZE: usize = 1 << 20;
+// Deepest tree accepted when loading. Halving any u32 count of docs reaches a
+// leaf within 32 levels, and stitching partitions adds at most as many again.
+// Deeper trees (or child links that loop) are rejected as corrupt before the
+// recursive walks over them can exhaust the stack.
+#[cfg(feature = "persist")]
+pub(crate) const MAX_TREE_HEIGHT: usize = 64;
+
+impl BuildOptions {
+    // These options with the leaf size clamped to [MIN_LEAF_SIZE, MAX_LEAF_SIZE]
+    pub(crate) fn clamped(&self) -> Self {
+        BuildOptions { leaf_size: self.leaf_size.clamp(MIN_LEAF_SIZE, MAX_LEAF_SIZE), ..*self }
+    }
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            leaf_size: 64,
+            store_doc_ids: true,
+            planner: PlannerThresholds::default(),
+            sample_every: 16,
+            keep_doc_values: false,
+            position_map: true,
+            value_runs: false,
+            digests: false,
+            histograms: false,
+            extended_stats: false,
+            geometric_mean: false,
+            non_positive: NonPositive::Skip,
+        }
+    }
+}
+
+// Build provenance kept with the index so operators can tell what a given
+// index actually contains
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct IndexMetadata {
+    // Where the values came from: an input path or a generator description
+    pub(crate) source: String,
+    // Field path the values were read from
+    pub(crate) field: String,
+    pub(crate) row_count: u64,
+    // RFC 3339 build time; empty without the `persist` feature, where the
+    // metadata never outlives the process
+    pub(crate) built_at: String,
+    pub(crate) crate_version: String,
+    pub(crate) config: BuildOptions,
+    // Value distribution, for cardinality estimates. Absent from indexes saved
+    // before it was recorded; those compute it on load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) statistics: Option<FieldStatistics>,
+    // Doc id i is stored at position i (see build_value_ordered_tree), so ids
+    // translate to positions without the doc_id map
+    #[serde(default)]
+    pub(crate) ids_in_value_order: bool,
+}
+
+// Equi-depth histogram and distinct count of a field's values, computed from
+// the sorted values at build time and persisted with the index metadata
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FieldStatistics {
+    // Smallest value, the lower bound of the first bucket
+    pub(crate) min: f64,
+    // Each bucket covers the values above the previous bucket's upper bound up
+    // to and including its own
+    pub(crate) buckets: Vec<HistogramBucket>,
+    pub(crate) distinct: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HistogramBucket {
+    pub(crate) upper: f64,
+    pub(crate) count: u64,
+    pub(crate) distinct: u64,
+}
+
+impl FieldStatistics {
+    pub(crate) const BUCKETS: usize = 64;
+
+    // `values` must be sorted ascending. Runs of equal values are never split
+    // across buckets, so bucket bounds are distinct and the distinct counts
+    // are exact.
+    pub(crate) fn from_sorted(len: usize, values: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let depth = len.div_ceil(Self::BUCKETS).max(1) as u64;
+        let mut values = values.into_iter().peekable();
+        let min = *values.peek()?;
+        let mut buckets = Vec::with_capacity(Self::BUCKETS);
+        let mut bucket = HistogramBucket { upper: min, count: 0, distinct: 0 };
+        while let Some(value) = values.next() {
+            if bucket.count == 0 || value != bucket.upper {
+                bucket.distinct += 1;
+            }
+            bucket.upper = value;
+            bucket.count += 1;
+            let next = values.peek();
+            if next.is_none() || (bucket.count >= depth && next != Some(&value)) {
+                buckets.push(bucket);
+                bucket.count = 0;
+                bucket.distinct = 0;
+            }
+        }
+        let distinct = buckets.iter().map(|bucket| bucket.distinct).sum();
+        Some(FieldStatistics { min, buckets, distinct })
+    }
+
+    pub(crate) fn row_count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.count).sum()
+    }
+
+    // Estimated values within [min, max]. Values are assumed spread evenly
+    // between a bucket's bounds, and a bucket the range touches contributes at
+    // least one of its distinct values, so narrow and point ranges aren't
+    // estimated at zero. Inverted ranges match nothing.
+    pub(crate) fn estimate_range_count(&self, min: f64, max: f64) -> u64 {
+        if min > max {
+            return 0;
+        }
+        let mut lower = self.min;
+        let mut estimate = 0.0;
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            let touched = if idx == 0 { min <= bucket.upper && max >= lower } else { min <= bucket.upper && max > lower };
+            if touched {
+                let width = bucket.upper - lower;
+                let covered = if width <= 0.0 {
+                    1.0
+                } else {
+                    ((max.min(bucket.upper) - min.max(lower)) / width).clamp(0.0, 1.0)
+                };
+                estimate += bucket.count as f64 * covered.max(1.0 / bucket.distinct as f64);
+            }
+            lower = bucket.upper;
+        }
+        estimate.round() as u64
+    }
+
+    // Approximate value at quantile `q` in [0, 1], interpolated within the
+    // bucket holding it
+    pub(crate) fn quantile(&self, q: f64) -> f64 {
+        let target = q.clamp(0.0, 1.0) * self.row_count() as f64;
+        let mut lower = self.min;
+        let mut seen = 0.0;
+        for bucket in &self.buckets {
+            let count = bucket.count as f64;
+            if seen + count >= target {
+                return lower + (bucket.upper - lower) * ((target - seen) / count).clamp(0.0, 1.0);
+            }
+            seen += count;
+            lower = bucket.upper;
+        }
+        lower
+    }
+}
+
+impl std::fmt::Display for FieldStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} equi-depth buckets, {} distinct values, quartiles {:.2} / {:.2} / {:.2}",
+               self.buckets.len(), format_count(self.distinct), self.quantile(0.25), self.quantile(0.5),
+               self.quantile(0.75))
+    }
+}
+
+// Errors returned by tree queries and tree-to-tree operations
+#[derive(Debug, Clone, PartialEq)]
+pub enum AitError {
+    // Doc-id filters cannot be resolved on a tree built without doc ids
+    DocIdsNotStored,
+    // This doc id is given more than once, in one input or across trees being combined
+    DuplicateDocId(u32),
+    // Partition at this index has values below the end of the previous one
+    UnorderedPartitions(usize),
+    // Query state needs at least `needed` bytes, more than the `limit` allowed
+    MemoryLimitExceeded { needed: usize, limit: usize },
+    // This doc's value is NaN, which has no place in value order
+    NanValue(u32),
+    // Distinct counts need a secondary id column attached to the tree
+    NoDistinctColumn,
+    // The secondary id column has no entry for this doc id
+    MissingDistinctId(u32),
+    // Weighted aggregations need a weight column attached to the tree
+    NoWeightColumn,
+    // The weight column has no entry for this doc id
+    MissingWeight(u32),
+    // Histogram intervals must be positive and finite
+    InvalidInterval(f64),
+    // The tree has no node at this index
+    NoSuchNode(usize),
+    // The index holds no doc with this id
+    NoSuchDoc(u32),
+    // Date histograms need a timestamp column attached to the tree
+    NoTimestampColumn,
+    // The timestamp column has no entry for this doc id
+    MissingTimestamp(u32),
+    // This doc's value isn't positive, and the build rejects such values for
+    // the geometric mean
+    NonPositiveValue(u32),
+    // A NonPositive::Floor must be positive and finite
+    InvalidFloor(f64),
+    // This backend doesn't keep the named metric
+    UnsupportedMetric(&'static str),
+}
+
+impl std::fmt::Display for AitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AitError::DocIdsNotStored => {
+                write!(f, "tree was built without doc ids; bitmap queries are unavailable")
+            }
+            AitError::DuplicateDocId(doc_id) => write!(f, "doc id {} is stored more than once", doc_id),
+            AitError::UnorderedPartitions(idx) => {
+                write!(f, "partition {} starts below the previous partition's maximum value", idx)
+            }
+            AitError::NanValue(doc_id) => write!(f, "doc id {} has a NaN value, which can't be ordered", doc_id),
+            AitError::NoDistinctColumn => write!(f, "tree has no secondary id column for distinct counts"),
+            AitError::MissingDistinctId(doc_id) => write!(f, "doc id {} has no entry in the secondary id column", doc_id),
+            AitError::NoWeightColumn => write!(f, "tree has no weight column for weighted aggregations"),
+            AitError::MissingWeight(doc_id) => write!(f, "doc id {} has no entry in the weight column", doc_id),
+            AitError::NoSuchNode(node_idx) => write!(f, "tree has no node {}", node_idx),
+            AitError::NoSuchDoc(doc_id) => write!(f, "index has no doc id {}", doc_id),
+            AitError::NoTimestampColumn => write!(f, "tree has no timestamp column for date histograms"),
+            AitError::MissingTimestamp(doc_id) => write!(f, "doc id {} has no entry in the timestamp column", doc_id),
+            AitError::NonPositiveValue(doc_id) => {
+                write!(f, "doc id {} has a value that isn't positive, rejected for the geometric mean", doc_id)
+            }
+            AitError::InvalidFloor(floor) => write!(f, "geometric mean floor {} is not positive and finite", floor),
+            AitError::UnsupportedMetric(metric) => write!(f, "backend doesn't keep the {} metric", metric),
+            AitError::InvalidInterval(interval) => {
+                write!(f, "histogram interval {} is not positive and finite", interval)
+            }
+            AitError::MemoryLimitExceeded { needed, limit } => {
+                write!(f, "query needs at least {} of state, over the {} memory limit",
+                       format_bytes(*needed), format_bytes(*limit))
+            }
+        }
+    }
+}
+
+impl std::error::Error for AitError {}
+
+impl DynamicUsage for AggregationIndexTree {
+    fn dynamic_usage(&self) -> usize {
+        let mut size = self.nodes.len() * std::mem::size_of::<AggregationTreeNode>();
+        // Leaf arenas, each chunk counted once however many leaves share it
+        size += self.arena_stats().allocated_bytes;
+        // Add size of doc_id_map
+        size += std::mem::size_of::<HashMap<u32, usize>>() + 
+                self.doc_id_map.capacity() * (std::mem::size_of::<u32>() + std::mem::size_of::<usize>());
+        size += self.doc_values.as_ref().map_or(0, DocValues::dynamic_usage);
+        size += self.value_runs.as_ref().map_or(0, ValueRuns::dynamic_usage);
+        size += self.node_digests.capacity() * std::mem::size_of::<Option<TDigest>>()
+            + self.node_digests.iter().flatten().map(TDigest::dynamic_usage).sum::<usize>();
+        size += self.node_histograms.capacity() * std::mem::size_of::<Option<LogHistogram>>()
+            + self.node_histograms.iter().flatten().map(LogHistogram::dynamic_usage).sum::<usize>();
+        size += self.node_moments.capacity() * std::mem::size_of::<Moments>();
+        size += self.node_log_sums.capacity() * std::mem::size_of::<LogSum>();
+        size += self.distinct.as_ref().map_or(0, DistinctColumn::dynamic_usage);
+        size += self.weights.as_ref().map_or(0, WeightColumn::dynamic_usage);
+        size += self.timestamps.as_ref().map_or(0, TimestampColumn::dynamic_usage);
+        size
+    }
+
+    fn dynamic_usage_bounds(&self) -> (usize, Option<usize>) {
+        // Provide a simple implementation for bounds
+        (self.dynamic_usage(), Some(self.dynamic_usage()))
+    }
+}
+
+// Build Aggregation Index Tree from (doc_id, value) pairs, normally sorted by
+// value: sorted input costs one pass to confirm, and anything else is sorted
+// here first. The input is consumed: the leaf arena is filled from the back
+// of the vector and the vector is shrunk as it drains, so the pairs are
+// released while the arena fills. With doc ids stored, each id must appear
+// once: a repeated id would map to only one of its positions, and filtered
+// queries would miscount.
+pub fn build_aggregation_index_tree(values: Vec<(u32, f64)>, options: &BuildOptions)
+    -> Result<AggregationIndexTree, AitError> {
+    build_tree(values, options, false)
+}
+
+// build_aggregation_index_tree, sorting unsorted input on the rayon pool
+// when `parallel_sort` is set and the `parallel` feature is enabled
+pub(crate) fn build_tree(mut values: Vec<(u32, f64)>, options: &BuildOptions, parallel_sort: bool)
+    -> Result<AggregationIndexTree, AitError> {
+    let options = &options.clamped();
+    let len = values.len();
+    let _span = tracing::info_span!("ait.build", rows = len, leaf_size = options.leaf_size).entered();
+    // Checked before the sortedness test, which a lone NaN passes
+    if let Some(&(doc_id, _)) = values.iter().find(|(_, value)| value.is_nan()) {
+        return Err(AitError::NanValue(doc_id));
+    }
+    if !values.is_sorted_by(|a, b| a.1 <= b.1) {
+        sort_by_value(&mut values, parallel_sort);
+    }
+    match options.non_positive {
+        NonPositive::Floor(floor) if !(floor > 0.0 && floor.is_finite()) => return Err(AitError::InvalidFloor(floor)),
+        // Sorted, so the smallest value comes first
+        NonPositive::Reject => match values.first() {
+            Some(&(doc_id, value)) if value <= 0.0 => return Err(AitError::NonPositiveValue(doc_id)),
+            _ => {}
+        },
+        _ => {}
+    }
+    // Mapping from original doc_id to position in sorted array, filled with the leaf arena
+    let mut doc_id_map = if options.store_doc_ids {
+        heap::scoped(HeapComponent::Maps, || HashMap::with_capacity(len))
+    } else {
+        HashMap::new()
+    };
+    
+    let doc_id_range = values
+        .iter()
+        .map(|&(doc_id, _)| doc_id)
+        .fold(None, |range: Option<(u32, u32)>, doc_id| match range {
+            Some((min, max)) => Some((min.min(doc_id), max.max(doc_id))),
+            None => Some((doc_id, doc_id)),
+        })
+        .filter(|_| options.store_doc_ids);
+    let statistics = FieldStatistics::from_sorted(len, values.iter().map(|&(_, value)| value));
+    let doc_values = doc_id_range
+        .filter(|_| options.keep_doc_values)
+        .and_then(|range| heap::scoped(HeapComponent::Columns, || DocValues::new(values.iter().copied(), range, len)));
+    let value_runs = options.value_runs.then(|| {
+        heap::scoped(HeapComponent::Dictionaries, || ValueRuns::from_sorted(values.iter().map(|&(_, value)| value)))
+    });
+
+    let arena = LeafArena::fill(values, options.store_doc_ids.then_some(&mut doc_id_map));
+    let mut nodes = Vec::new();
+    // Make sure the root is index 0 by building the tree from index 0
+    build_tree_recursive(&mut nodes, &arena, 0, len, options);
+    drop(arena);
+    
+    // Create position map for faster value lookups
+    let mut position_map = heap::scoped(HeapComponent::Maps, || vec![(0, 0); len]);
+    build_position_map(&nodes, 0, &mut position_map, 0);
+
+    // A repeated doc id leaves fewer map entries than values; the first
+    // position whose id maps elsewhere holds one
+    if doc_id_map.len() != len && options.store_doc_ids {
+        let duplicate = position_map.iter().enumerate().find_map(|(pos, &(node_idx, offset))| {
+            let AggregationTreeNode::Leaf { doc_ids, .. } = &nodes[node_idx] else {
+                unreachable!("position map entries point at leaves");
+            };
+            (doc_id_map[&doc_ids[offset]] != pos).then_some(doc_ids[offset])
+        });
+        return Err(AitError::DuplicateDocId(duplicate.expect("a doc id maps to a single position")));
+    }
+    if !options.position_map {
+        position_map = Vec::new();
+    }
+    
+    let leaf_count = nodes
+        .iter()
+        .filter(|node| matches!(node, AggregationTreeNode::Leaf { .. }))
+        .count();
+    
+    let mut tree = AggregationIndexTree {
+        nodes,
+        has_doc_ids: options.store_doc_ids,
+        doc_id_range,
+        leaf_count,
+        planner: options.planner,
+        metadata: IndexMetadata {
+            source: String::new(),
+            field: String::new(),
+            row_count: len as u64,
+            built_at: build_timestamp(),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            config: *options,
+            statistics,
+            ids_in_value_order: false,
+        },
+        doc_id_map,
+        doc_values,
+        value_runs,
+        node_digests: Vec::new(),
+        node_histograms: Vec::new(),
+        node_moments: Vec::new(),
+        node_log_sums: Vec::new(),
+        distinct: None,
+        weights: None,
+        timestamps: None,
+        position_map,
+        position_fallbacks: FallbackCounter::default(),
+        query_latencies: QueryLatencies::default(),
+    };
+    tree.collect_node_sketches();
+    Ok(tree)
+}
+
+// Stable sort of (doc_id, value) pairs by value, on the rayon pool when asked
+// and the `parallel` feature is enabled
+fn sort_by_value(values: &mut [(u32, f64)], parallel: bool) {
+    #[cfg(feature = "parallel")]
+    if parallel {
+        use rayon::slice::ParallelSliceMut;
+        values.par_sort_by(|a, b| a.1.total_cmp(&b.1));
+        return;
+    }
+    #[cfg(not(feature = "parallel"))]
+    let _ = parallel;
+    values.sort_by(|a, b| a.1.total_cmp(&b.1));
+}
+
+// Builder for trees, for callers setting a few options and taking defaults
+// for the rest. New options are added here without changing signatures:
+//
+//     let tree = AitBuilder::new().leaf_size(128).position_map(false).build(pairs)?;
+#[derive(Debug, Clone, Default)]
+pub struct AitBuilder {
+    options: BuildOptions,
+    parallel: bool,
+    distinct_ids: Option<Vec<u64>>,
+    weights: Option<Vec<f64>>,
+    timestamps: Option<Vec<i64>>,
+}
+
+impl AitBuilder {
+    pub fn new() -> Self {
+        AitBuilder::default()
+    }
+
+    // Start from existing options, e.g. those a loaded index was built with
+    pub fn from_options(options: BuildOptions) -> Self {
+        AitBuilder { options, parallel: false, distinct_ids: None, weights: None, timestamps: None }
+    }
+
+    // Values per leaf, clamped to [2, 1048576] at build time
+    pub fn leaf_size(mut self, leaf_size: usize) -> Self {
+        self.options.leaf_size = leaf_size;
+        self
+    }
+
+    // Keep doc ids for bitmap queries; see BuildOptions::store_doc_ids
+    pub fn store_doc_ids(mut self, store_doc_ids: bool) -> Self {
+        self.options.store_doc_ids = store_doc_ids;
+        self
+    }
+
+    // Keep the position map; see BuildOptions::position_map
+    pub fn position_map(mut self, position_map: bool) -> Self {
+        self.options.position_map = position_map;
+        self
+    }
+
+    // Keep values in doc-id order too; see BuildOptions::keep_doc_values
+    pub fn keep_doc_values(mut self, keep_doc_values: bool) -> Self {
+        self.options.keep_doc_values = keep_doc_values;
+        self
+    }
+
+    // Attach t-digests to large nodes; see BuildOptions::digests
+    pub fn digests(mut self, digests: bool) -> Self {
+        self.options.digests = digests;
+        self
+    }
+
+    // Secondary ids indexed by doc id, e.g. the user of each event, for
+    // approx_distinct; see AggregationIndexTree::attach_distinct_column
+    pub fn distinct_ids(mut self, ids_by_doc: Vec<u64>) -> Self {
+        self.distinct_ids = Some(ids_by_doc);
+        self
+    }
+
+    // Weights indexed by doc id, e.g. the clicks of each event, for
+    // weighted_aggregations; see AggregationIndexTree::attach_weight_column
+    pub fn weights(mut self, weights_by_doc: Vec<f64>) -> Self {
+        self.weights = Some(weights_by_doc);
+        self
+    }
+
+    // Epoch milliseconds indexed by doc id, e.g. the time of each event, for
+    // date_histogram; see AggregationIndexTree::attach_timestamp_column
+    pub fn timestamps(mut self, timestamps_by_doc: Vec<i64>) -> Self {
+        self.timestamps = Some(timestamps_by_doc);
+        self
+    }
+
+    // Attach log-bucketed histograms to large nodes; see BuildOptions::histograms
+    pub fn histograms(mut self, histograms: bool) -> Self {
+        self.options.histograms = histograms;
+        self
+    }
+
+    // Keep central moments per node; see BuildOptions::extended_stats
+    pub fn extended_stats(mut self, extended_stats: bool) -> Self {
+        self.options.extended_stats = extended_stats;
+        self
+    }
+
+    // Keep sums of logarithms per node; see BuildOptions::geometric_mean
+    pub fn geometric_mean(mut self, geometric_mean: bool) -> Self {
+        self.options.geometric_mean = geometric_mean;
+        self
+    }
+
+    // Treatment of zeros and negatives in the geometric mean; see
+    // BuildOptions::non_positive
+    pub fn non_positive(mut self, non_positive: NonPositive) -> Self {
+        self.options.non_positive = non_positive;
+        self
+    }
+
+    // Record runs of repeated values; see BuildOptions::value_runs
+    pub fn value_runs(mut self, value_runs: bool) -> Self {
+        self.options.value_runs = value_runs;
+        self
+    }
+
+    pub fn sample_every(mut self, sample_every: usize) -> Self {
+        self.options.sample_every = sample_every;
+        self
+    }
+
+    pub fn planner(mut self, planner: PlannerThresholds) -> Self {
+        self.options.planner = planner;
+        self
+    }
+
+    // Sort unsorted input on the rayon pool
+    #[cfg(feature = "parallel")]
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    pub fn options(&self) -> &BuildOptions {
+        &self.options
+    }
+
+    // Build from (doc_id, value) pairs as build_aggregation_index_tree does
+    pub fn build(&self, values: Vec<(u32, f64)>) -> Result<AggregationIndexTree, AitError> {
+        let mut tree = build_tree(values, &self.options, self.parallel)?;
+        if let Some(ids_by_doc) = &self.distinct_ids {
+            tree.attach_distinct_column(ids_by_doc)?;
+        }
+        if let Some(weights_by_doc) = &self.weights {
+            tree.attach_weight_column(weights_by_doc)?;
+        }
+        if let Some(timestamps_by_doc) = &self.timestamps {
+            tree.attach_timestamp_column(timestamps_by_doc)?;
+        }
+        Ok(tree)
+    }
+}
+
+// Sorted input split into one doc id chunk and one value chunk, each a single
+// allocation that every leaf of the build slices. Without a doc id map to fill
+// the doc id chunk is empty.
+pub(crate) struct LeafArena {
+    doc_ids: Arc<[u32]>,
+    values: Arc<[f64]>,
+}
+
+impl LeafArena {
+    pub(crate) fn fill(mut pairs: Vec<(u32, f64)>, mut doc_id_map: Option<&mut HashMap<u32, usize>>) -> Self {
+        let _heap = heap::enter(HeapComponent::Leaves);
+        let len = pairs.len();
+        let mut doc_ids = Arc::<[u32]>::new_uninit_slice(if doc_id_map.is_some() { len } else { 0 });
+        let mut values = Arc::<[f64]>::new_uninit_slice(len);
+        let (doc_id_slots, value_slots) = (Arc::get_mut(&mut doc_ids).unwrap(), Arc::get_mut(&mut values).unwrap());
+        // Copy the back half of the remaining pairs and release it, so input
+        // memory shrinks as the chunks fill; the halving keeps the
+        // reallocation cost O(n). The doc id map is filled in the same pass,
+        // where the pairs are read anyway.
+        while !pairs.is_empty() {
+            let keep = pairs.len() / 2;
+            let tail = &pairs[keep..];
+            if let Some(doc_id_map) = doc_id_map.as_deref_mut() {
+                let slots = doc_id_slots[keep..pairs.len()].iter_mut().zip(tail);
+                for (offset, (slot, &(doc_id, _))) in slots.enumerate() {
+                    slot.write(doc_id);
+                    doc_id_map.insert(doc_id, keep + offset);
+                }
+            }
+            for (slot, &(_, value)) in value_slots[keep..pairs.len()].iter_mut().zip(tail) {
+                slot.write(value);
+            }
+            pairs.truncate(keep);
+            pairs.shrink_to_fit();
+        }
+        // SAFETY: every position in 0..len was written above, and the doc id
+        // chunk is empty unless doc ids were written too
+        unsafe { LeafArena { doc_ids: doc_ids.assume_init(), values: values.assume_init() } }
+    }
+
+    fn leaf(&self, start: usize, len: usize) -> (LeafSlice<u32>, LeafSlice<f64>) {
+        let doc_ids = if self.doc_ids.is_empty() {
+            LeafSlice::new(&self.doc_ids, 0, 0)
+        } else {
+            LeafSlice::new(&self.doc_ids, start, len)
+        };
+        (doc_ids, LeafSlice::new(&self.values, start, len))
+    }
+}
+
+// Placeholder pushed to reserve a node index until its children are built
+pub(crate) fn placeholder_node() -> AggregationTreeNode {
+    AggregationTreeNode::Internal { split_value: 0.0, left: 0, right: 0, aggregations: NodeAggregations::empty() }
+}
+
+// Build the subtree over positions start..start + len of the arena. Subtrees
+// are built right to left, so a right child's index is below its sibling's.
+pub(crate) fn build_tree_recursive(
+    nodes: &mut Vec<AggregationTreeNode>,
+    arena: &LeafArena,
+    start: usize,
+    len: usize,
+    options: &BuildOptions,
+) -> usize {
+    let current_idx = nodes.len(); // Save the current index before adding the new node
+    
+    if len <= options.leaf_size {
+        let (doc_ids, values) = arena.leaf(start, len);
+        let mut aggregations = NodeAggregations::empty();
+        for &value in &values {
+            aggregations.add_value(value);
+        }
+        
+        let node = AggregationTreeNode::Leaf {
+            doc_ids,
+            values,
+            aggregations,
+        };
+        
+        heap::scoped(HeapComponent::Nodes, || nodes.push(node));
+    } else {
+        // Create internal node
+        let left_len = len / 2;
+        
+        // First add a placeholder for this node to preserve the index
+        heap::scoped(HeapComponent::Nodes, || nodes.push(placeholder_node()));
+        
+        let right_idx = build_tree_recursive(nodes, arena, start + left_len, len - left_len, options);
+        let left_idx = build_tree_recursive(nodes, arena, start, left_len, options);
+        
+        // Get aggregations from children
+        let left_aggs = nodes[left_idx].aggregations();
+        let right_aggs = nodes[right_idx].aggregations();
+        
+        // Replace the placeholder with real internal node. The split value is the
+        // first value of the right subtree, which is its minimum in sorted input.
+        nodes[current_idx] = AggregationTreeNode::Internal {
+            split_value: right_aggs.min_value,
+            left: left_idx,
+            right: right_idx,
+            aggregations: left_aggs + right_aggs,
+        };
+    }
+    
+    current_idx
+}
+
+#[cfg(feature = "persist")]
+fn build_timestamp() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+#[cfg(not(feature = "persist"))]
+fn build_timestamp() -> String {
+    String::new()
+}
+
+// Levels from the root to the deepest leaf, walking iteratively so a deep
+// tree can't overflow the stack. Stops counting once past MAX_TREE_HEIGHT.
+#[cfg(feature = "persist")]
+pub(crate) fn tree_height(nodes: &[AggregationTreeNode]) -> usize {
+    let mut height = 0;
+    let mut stack = if nodes.is_empty() { Vec::new() } else { vec![(0, 1)] };
+    while let Some((node_idx, depth)) = stack.pop() {
+        height = height.max(depth);
+        if depth > MAX_TREE_HEIGHT {
+            break;
+        }
+        if let AggregationTreeNode::Internal { left, right, .. } = nodes[node_idx] {
+            stack.push((left, depth + 1));
+            stack.push((right, depth + 1));
+        }
+    }
+    height
+}
+
+// Build a map from global position to (node_idx, offset) for fast lookups
+pub(crate) fn build_position_map(nodes: &[AggregationTreeNode], node_idx: usize, 
+                     position_map: &mut [(usize, usize)], start_pos: usize) -> usize {
+    match &nodes[node_idx] {
+        AggregationTreeNode::Internal { left, right, .. } => {
+            // First map positions in left subtree
+            let left_size = build_position_map(nodes, *left, position_map, start_pos);
+            
+            // Then map positions in right subtree
+            let right_size = build_position_map(nodes, *right, position_map, start_pos + left_size);
+            
+            // Return total size
+            left_size + right_size
+        },
+        AggregationTreeNode::Leaf { values, .. } => {
+            // Map all positions in this leaf
+            for i in 0..values.len() {
+                position_map[start_pos + i] = (node_idx, i);
+            }
+            
+            values.len()
+        }
+    }
+}
+
+// Build a balanced layer of internal nodes over partition roots, given as
+// (node index, aggregations) in value order. Returns the subtree's root index
+// and aggregations; pushes exactly roots.len() - 1 nodes.
+pub(crate) fn stitch_top_nodes(nodes: &mut Vec<AggregationTreeNode>, roots: &[(usize, NodeAggregations)]) -> (usize, NodeAggregations) {
+    if roots.len() == 1 {
+        return roots[0];
+    }
+
+    let current_idx = nodes.len();
+    nodes.push(placeholder_node());
+    let mid = roots.len() / 2;
+    let (left, left_aggs) = stitch_top_nodes(nodes, &roots[..mid]);
+    let (right, right_aggs) = stitch_top_nodes(nodes, &roots[mid..]);
+    let aggregations = left_aggs + right_aggs;
+    nodes[current_idx] = AggregationTreeNode::Internal {
+        split_value: right_aggs.min_value,
+        left,
+        right,
+        aggregations,
+    };
+    (current_idx, aggregations)
+}
+
+// Mapping from pre-compaction doc ids to the dense ids assigned by compact().
+// Survivors keep their relative order, so a new id is the survivor's rank.
+#[derive(Debug, Clone)]
+pub struct IdRemap {
+    pub(crate) survivors: RoaringBitmap,
+}
+
+impl IdRemap {
+    pub fn new_id(&self, old_id: u32) -> Option<u32> {
+        self.survivors
+            .contains(old_id)
+            .then(|| (self.survivors.rank(old_id) - 1) as u32)
+    }
+}
+
+// Build a tree with docs renumbered in value order: the doc at position i gets
+// id i. Filters over the new ids that select value ranges are then id runs,
+// which the run-range strategy answers from pre-aggregated nodes. `values`
+// must be sorted by value; the mapping translates the caller's ids. Suits
+// static datasets, where ids can be assigned once.
+#[cfg_attr(not(feature = "bench"), allow(dead_code))]
+pub(crate) fn build_value_ordered_tree(values: Vec<(u32, f64)>, options: &BuildOptions)
+    -> Result<(AggregationIndexTree, ValueOrderMapping), AitError> {
+    let external: Vec<u32> = values.iter().map(|&(doc_id, _)| doc_id).collect();
+    let mut internal = HashMap::with_capacity(external.len());
+    for (pos, &doc_id) in external.iter().enumerate() {
+        if internal.insert(doc_id, pos as u32).is_some() {
+            return Err(AitError::DuplicateDocId(doc_id));
+        }
+    }
+    let values = values.into_iter().enumerate().map(|(pos, (_, value))| (pos as u32, value)).collect();
+    let options = BuildOptions { store_doc_ids: true, ..*options };
+    let mut tree = build_aggregation_index_tree(values, &options)?;
+    tree.metadata.ids_in_value_order = true;
+    Ok((tree, ValueOrderMapping { external, internal }))
+}
+
+impl AggregationIndexTree {
+    // Drop deleted docs and renumber the survivors densely from 0 in their old
+    // id order, rebuilding the tree from the surviving positions. The remap lets
+    // callers holding external doc ids translate them.
+    pub fn compact(&self, deleted: &RoaringBitmap) -> Result<(AggregationIndexTree, IdRemap), AitError> {
+        if !self.has_doc_ids {
+            return Err(AitError::DocIdsNotStored);
+        }
+
+        let survivors: RoaringBitmap = (0..self.position_count())
+            .map(|pos| self.get_doc_id_at_position(pos))
+            .filter(|&doc_id| !deleted.contains(doc_id))
+            .collect();
+        let remap = IdRemap { survivors };
+
+        // Positions are already in value order, so no re-sort is needed
+        let values: Vec<(u32, f64)> = (0..self.position_count())
+            .filter_map(|pos| {
+                let new_id = remap.new_id(self.get_doc_id_at_position(pos))?;
+                Some((new_id, self.get_value_at_position(pos)))
+            })
+            .collect();
+
+        let options = BuildOptions {
+            store_doc_ids: true,
+            ..self.metadata.config
+        };
+        let source = format!("{} (compacted)", self.metadata.source);
+        let compacted = build_aggregation_index_tree(values, &options)?.with_provenance(&source, &self.metadata.field);
+        Ok((compacted, remap))
+    }
+
+    // Checksum of the tree's (doc_id, value) pairs in position order, doc ids
+    // taken as 0 when not stored. Matches entries_checksum of the build input.
+    #[cfg(feature = "persist")]
+    pub(crate) fn checksum(&self) -> u64 {
+        entries_checksum((0..self.position_count()).map(|pos| {
+            let doc_id = if self.has_doc_ids { self.get_doc_id_at_position(pos) } else { 0 };
+            (doc_id, self.get_value_at_position(pos))
+        }))
+    }
+
+    // Stitch trees built independently over value-range partitions under new
+    // top-level nodes, without touching their leaves. Contract: partitions are
+    // given in value order, each partition's values are >= every value of the
+    // previous one, and doc ids are disjoint across partitions. The caller
+    // partitions the input by value; trees may be built in other processes or
+    // machines and loaded. Empty partitions are skipped.
+    pub fn stitch(parts: Vec<AggregationIndexTree>) -> Result<AggregationIndexTree, AitError> {
+        let parts: Vec<AggregationIndexTree> = parts
+            .into_iter()
+            .filter(|part| part.get_global_aggregations().count > 0)
+            .collect();
+        for (idx, pair) in parts.windows(2).enumerate() {
+            if pair[1].get_global_aggregations().min_value < pair[0].get_global_aggregations().max_value {
+                return Err(AitError::UnorderedPartitions(idx + 1));
+            }
+        }
+
+        let has_doc_ids = parts.iter().all(|part| part.has_doc_ids);
+        if has_doc_ids {
+            let mut seen = RoaringBitmap::new();
+            for part in &parts {
+                for &doc_id in part.doc_id_map.keys() {
+                    if !seen.insert(doc_id) {
+                        return Err(AitError::DuplicateDocId(doc_id));
+                    }
+                }
+            }
+        }
+
+        let Some(first) = parts.first() else {
+            return build_aggregation_index_tree(Vec::new(), &BuildOptions::default());
+        };
+        let metadata = IndexMetadata {
+            source: format!("stitched from {} partitions", parts.len()),
+            row_count: parts.iter().map(|part| part.metadata.row_count).sum(),
+            config: BuildOptions {
+                store_doc_ids: has_doc_ids,
+                ..first.metadata.config
+            },
+            // Partition histograms don't combine; rebuilt below from the stitched positions
+            statistics: None,
+            ids_in_value_order: false,
+            ..first.metadata.clone()
+        };
+
+        // Top-level nodes come first so the root stays at index 0; partition
+        // nodes follow, shifted by the nodes placed before them
+        let mut node_offsets = Vec::with_capacity(parts.len());
+        let mut offset = parts.len() - 1;
+        for part in &parts {
+            node_offsets.push(offset);
+            offset += part.nodes.len();
+        }
+        let roots: Vec<(usize, NodeAggregations)> = parts
+            .iter()
+            .zip(&node_offsets)
+            .map(|(part, &offset)| (offset, part.get_global_aggregations()))
+            .collect();
+
+        let mut nodes = Vec::with_capacity(offset);
+        stitch_top_nodes(&mut nodes, &roots);
+
+        let total_positions: usize = parts.iter().map(|part| part.position_count()).sum();
+        // Part maps are shifted and concatenated when every part kept one
+        let concat_maps = parts.iter().all(|part| part.position_map.len() == part.position_count());
+        let mut position_map = Vec::with_capacity(if concat_maps { total_positions } else { 0 });
+        let mut doc_id_map = HashMap::with_capacity(if has_doc_ids { total_positions } else { 0 });
+        let mut doc_id_range: Option<(u32, u32)> = None;
+        let mut leaf_count = 0;
+        let mut position_offset = 0;
+        for (part, &offset) in parts.into_iter().zip(&node_offsets) {
+            if concat_maps {
+                position_map.extend(part.position_map.iter().map(|&(node_idx, leaf_offset)| (node_idx + offset, leaf_offset)));
+            }
+            if has_doc_ids {
+                doc_id_map.extend(part.doc_id_map.iter().map(|(&doc_id, &pos)| (doc_id, pos + position_offset)));
+                doc_id_range = match (doc_id_range, part.doc_id_range) {
+                    (Some((min_a, max_a)), Some((min_b, max_b))) => Some((min_a.min(min_b), max_a.max(max_b))),
+                    (range, None) | (None, range) => range,
+                };
+            }
+            leaf_count += part.leaf_count;
+            position_offset += part.position_count();
+            nodes.extend(part.nodes.into_iter().map(|node| match node {
+                AggregationTreeNode::Internal { split_value, left, right, aggregations } => AggregationTreeNode::Internal {
+                    split_value,
+                    left: left + offset,
+                    right: right + offset,
+                    aggregations,
+                },
+                AggregationTreeNode::Leaf { doc_ids, values, aggregations } => AggregationTreeNode::Leaf {
+                    doc_ids: if has_doc_ids { doc_ids } else { Vec::new().into() },
+                    values,
+                    aggregations,
+                },
+            }));
+        }
+
+        let mut tree = AggregationIndexTree {
+            nodes,
+            has_doc_ids,
+            doc_id_range,
+            leaf_count,
+            planner: metadata.config.planner,
+            metadata,
+            doc_id_map,
+            doc_values: None,
+            value_runs: None,
+            node_digests: Vec::new(),
+            node_histograms: Vec::new(),
+            node_moments: Vec::new(),
+            node_log_sums: Vec::new(),
+            distinct: None,
+            weights: None,
+            timestamps: None,
+            position_map,
+            position_fallbacks: FallbackCounter::default(),
+            query_latencies: QueryLatencies::default(),
+        };
+        if !concat_maps && tree.metadata.config.position_map {
+            tree.rebuild_position_map();
+        }
+        if tree.metadata.statistics.is_none() {
+            tree.metadata.statistics = tree.compute_statistics();
+        }
+        tree.doc_values = tree.collect_doc_values();
+        tree.value_runs = tree.collect_value_runs();
+        tree.collect_node_sketches();
+        if !tree.metadata.config.position_map {
+            tree.position_map = Vec::new();
+        }
+        Ok(tree)
+    }
+
+    // Merge two trees in O(n). Both are already value-sorted, so their positions
+    // are merged in one pass and the result is built bottom-up from the merged
+    // run. Doc ids must be disjoint; if either tree has no doc ids the merged
+    // tree is value-only.
+    pub fn merge(&self, other: &AggregationIndexTree) -> Result<AggregationIndexTree, AitError> {
+        let store_doc_ids = self.has_doc_ids && other.has_doc_ids;
+        let ranges_overlap = match (self.doc_id_range, other.doc_id_range) {
+            (Some((min_a, max_a)), Some((min_b, max_b))) => min_a <= max_b && min_b <= max_a,
+            _ => false,
+        };
+        if store_doc_ids && ranges_overlap {
+            if let Some(&doc_id) = self.doc_id_map.keys().find(|doc_id| other.doc_id_map.contains_key(doc_id)) {
+                return Err(AitError::DuplicateDocId(doc_id));
+            }
+        }
+
+        let entry = |tree: &AggregationIndexTree, pos: usize| {
+            let doc_id = if store_doc_ids { tree.get_doc_id_at_position(pos) } else { 0 };
+            (doc_id, tree.get_value_at_position(pos))
+        };
+        let (len_a, len_b) = (self.position_count(), other.position_count());
+        let mut values = Vec::with_capacity(len_a + len_b);
+        let (mut pos_a, mut pos_b) = (0, 0);
+        while pos_a < len_a || pos_b < len_b {
+            let take_a = pos_b == len_b
+                || (pos_a < len_a && self.get_value_at_position(pos_a) <= other.get_value_at_position(pos_b));
+            if take_a {
+                values.push(entry(self, pos_a));
+                pos_a += 1;
+            } else {
+                values.push(entry(other, pos_b));
+                pos_b += 1;
+            }
+        }
+
+        let options = BuildOptions {
+            store_doc_ids,
+            ..self.metadata.config
+        };
+        let source = format!("merge of {} and {}", self.metadata.source, other.metadata.source);
+        Ok(build_aggregation_index_tree(values, &options)?.with_provenance(&source, &self.metadata.field))
+    }
+}